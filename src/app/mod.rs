@@ -1,5 +1,7 @@
+mod rate_limit;
 mod server;
 mod shell;
 
+pub use rate_limit::*;
 pub use server::*;
 pub use shell::*;
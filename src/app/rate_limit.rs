@@ -0,0 +1,119 @@
+use std::collections::{BTreeMap, HashMap};
+use std::net::IpAddr;
+use std::sync::Mutex;
+use std::time::Instant;
+
+/// Most clients tracked at once. Past it, the least recently seen client is
+/// forgotten. Buckets refill within a second, so it only loses tokens it was
+/// still owed if more clients than this submitted in the last second.
+const MAX_TRACKED_CLIENTS: usize = 10_000;
+
+/// Per-client token buckets holding up to `per_second` tokens, refilled at
+/// `per_second` tokens a second. Every allowed request takes one token.
+pub struct RateLimiter {
+    per_second: f64,
+    buckets: Mutex<Buckets>,
+}
+
+#[derive(Default)]
+struct Buckets {
+    by_client: HashMap<IpAddr, Bucket>,
+    /// Clients by the request they were last seen at, oldest first.
+    by_last_seen: BTreeMap<u64, IpAddr>,
+    requests: u64,
+}
+
+struct Bucket {
+    tokens: f64,
+    refilled_at: Instant,
+    last_seen: u64,
+}
+
+impl Bucket {
+    fn refill(&mut self, now: Instant, per_second: f64) {
+        let elapsed = now.duration_since(self.refilled_at).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * per_second).min(per_second);
+        self.refilled_at = now;
+    }
+}
+
+impl RateLimiter {
+    pub fn new(per_second: u32) -> Self {
+        Self {
+            per_second: per_second as f64,
+            buckets: Mutex::new(Buckets::default()),
+        }
+    }
+
+    /// Takes a token from `client`'s bucket, returning false if it is empty.
+    pub fn try_acquire(&self, client: IpAddr) -> bool {
+        let now = Instant::now();
+        let mut buckets = self.buckets.lock().unwrap();
+        let Buckets {
+            by_client,
+            by_last_seen,
+            requests,
+        } = &mut *buckets;
+        *requests += 1;
+        if by_client.len() >= MAX_TRACKED_CLIENTS && !by_client.contains_key(&client) {
+            if let Some((_, oldest)) = by_last_seen.pop_first() {
+                by_client.remove(&oldest);
+            }
+        }
+        let bucket = by_client.entry(client).or_insert(Bucket {
+            tokens: self.per_second,
+            refilled_at: now,
+            last_seen: *requests,
+        });
+        by_last_seen.remove(&bucket.last_seen);
+        bucket.last_seen = *requests;
+        by_last_seen.insert(*requests, client);
+        bucket.refill(now, self.per_second);
+        if bucket.tokens < 1.0 {
+            return false;
+        }
+        bucket.tokens -= 1.0;
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::Ipv4Addr;
+
+    fn client(index: u32) -> IpAddr {
+        IpAddr::V4(Ipv4Addr::from(index))
+    }
+
+    fn tracked_clients(limiter: &RateLimiter) -> usize {
+        limiter.buckets.lock().unwrap().by_client.len()
+    }
+
+    #[test]
+    fn empty_bucket_refuses_until_it_refills() {
+        let limiter = RateLimiter::new(2);
+        assert!(limiter.try_acquire(client(1)));
+        assert!(limiter.try_acquire(client(1)));
+        assert!(!limiter.try_acquire(client(1)));
+        // Other clients have their own bucket.
+        assert!(limiter.try_acquire(client(2)));
+    }
+
+    #[test]
+    fn tracked_clients_are_capped_by_forgetting_the_least_recently_seen() {
+        let limiter = RateLimiter::new(1);
+        for index in 0..MAX_TRACKED_CLIENTS as u32 {
+            assert!(limiter.try_acquire(client(index)));
+        }
+        // Client 0 is seen again, so client 1 is now the least recently seen.
+        assert!(!limiter.try_acquire(client(0)));
+
+        assert!(limiter.try_acquire(client(u32::MAX)));
+        assert_eq!(tracked_clients(&limiter), MAX_TRACKED_CLIENTS);
+        assert!(!limiter.try_acquire(client(0)));
+        // Client 1 was forgotten, so it starts over with a full bucket.
+        assert!(limiter.try_acquire(client(1)));
+        assert_eq!(tracked_clients(&limiter), MAX_TRACKED_CLIENTS);
+    }
+}
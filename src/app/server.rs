@@ -1,21 +1,31 @@
+use futures::{SinkExt, StreamExt};
 use hex::decode;
 use poem::{
     error::ResponseError,
     handler,
     http::StatusCode,
     listener,
-    web::{Data, Json},
-    EndpointExt, IntoResponse, Response, Route, Server,
+    web::{
+        websocket::{Message, WebSocket},
+        Data, Json, Path, Query, RemoteAddr,
+    },
+    Body, EndpointExt, IntoResponse, Request, Response, Route, Server,
 };
 use serde::{Deserialize, Serialize};
 use serde_json::{json, Value};
+use std::net::SocketAddr;
 use std::sync::Arc;
-use tokio::sync::RwLock;
-use tracing::info;
+use std::time::{Duration, Instant};
+use tokio::sync::{
+    broadcast::{self, error::RecvError},
+    watch, RwLock,
+};
+use tracing::{info, warn};
 
+use super::RateLimiter;
 use crate::{
-    verify_signature, KvStoreTxPool, State, Storage, Transaction, TransactionReceipt,
-    TransactionWithAccount,
+    canonical_address, metrics::METRICS, Blockchain, KvError, KvStoreTxPool, PipelineExecutor,
+    State, Storage, Transaction, TransactionReceipt, TransactionWithAccount, TxnRejection,
 };
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
@@ -38,6 +48,26 @@ pub enum TransactionError {
     KeyNotFound,
     #[error("Invalid transaction hash")]
     InvalidTransactionHash,
+    #[error("Storage error: {0}")]
+    StorageError(KvError),
+    #[error("Gas estimation failed: {0}")]
+    EstimationFailed(String),
+    #[error("Simulation failed: {0}")]
+    SimulationFailed(String),
+    #[error("{0}")]
+    InvalidAddress(String),
+    #[error("{0}")]
+    PayloadTooLarge(String),
+    /// Well formed, but could never be included in a block as it is.
+    #[error("{0}")]
+    InvalidTransaction(String),
+    #[error("Too many transactions, try again later")]
+    RateLimited,
+    #[error("{0}")]
+    InvalidEncoding(String),
+    /// Several senders committed a transaction with this hash; the senders are listed.
+    #[error("Several transactions have this hash, pass one of their senders")]
+    AmbiguousTransactionHash(Vec<String>),
 }
 
 impl IntoResponse for TransactionError {
@@ -47,7 +77,7 @@ impl IntoResponse for TransactionError {
                 .status(StatusCode::from_u16(500).unwrap())
                 .body(json!({"error": err.to_string()}).to_string()),
             TransactionError::InvalidSignature(err) => Response::builder()
-                .status(StatusCode::from_u16(500).unwrap())
+                .status(StatusCode::from_u16(400).unwrap())
                 .body(json!({"error": err.to_string()}).to_string()),
             TransactionError::TransactionNotFound => Response::builder()
                 .status(StatusCode::from_u16(404).unwrap())
@@ -59,8 +89,50 @@ impl IntoResponse for TransactionError {
                 .status(StatusCode::from_u16(404).unwrap())
                 .body(json!({"error": "Key not found"}).to_string()),
             TransactionError::InvalidTransactionHash => Response::builder()
-                .status(StatusCode::from_u16(500).unwrap())
+                .status(StatusCode::from_u16(400).unwrap())
                 .body(json!({"error": "Invalid transaction hash"}).to_string()),
+            TransactionError::StorageError(err) => Response::builder()
+                .status(StatusCode::from_u16(500).unwrap())
+                .body(json!({"error": err.to_string()}).to_string()),
+            TransactionError::EstimationFailed(err) => Response::builder()
+                .status(StatusCode::from_u16(400).unwrap())
+                .body(json!({"error": err}).to_string()),
+            TransactionError::SimulationFailed(err) => Response::builder()
+                .status(StatusCode::from_u16(400).unwrap())
+                .body(json!({"error": err}).to_string()),
+            TransactionError::InvalidAddress(err) => Response::builder()
+                .status(StatusCode::from_u16(400).unwrap())
+                .body(json!({"error": err}).to_string()),
+            TransactionError::PayloadTooLarge(err) => Response::builder()
+                .status(StatusCode::from_u16(413).unwrap())
+                .body(json!({"error": err}).to_string()),
+            TransactionError::InvalidTransaction(err) => Response::builder()
+                .status(StatusCode::from_u16(422).unwrap())
+                .body(json!({"error": err}).to_string()),
+            TransactionError::RateLimited => Response::builder()
+                .status(StatusCode::from_u16(429).unwrap())
+                .body(json!({"error": "Too many transactions, try again later"}).to_string()),
+            TransactionError::InvalidEncoding(err) => Response::builder()
+                .status(StatusCode::from_u16(400).unwrap())
+                .body(json!({"error": err}).to_string()),
+            TransactionError::AmbiguousTransactionHash(senders) => Response::builder()
+                .status(StatusCode::from_u16(409).unwrap())
+                .body(
+                    json!({
+                        "error": "Several transactions have this hash, pass one of their senders",
+                        "senders": senders,
+                    })
+                    .to_string(),
+                ),
+        }
+    }
+}
+
+impl From<TxnRejection> for TransactionError {
+    fn from(rejection: TxnRejection) -> Self {
+        match rejection {
+            TxnRejection::PayloadTooLarge(err) => TransactionError::PayloadTooLarge(err),
+            TxnRejection::Invalid(err) => TransactionError::InvalidTransaction(err),
         }
     }
 }
@@ -69,45 +141,223 @@ impl ResponseError for TransactionError {
     fn status(&self) -> StatusCode {
         match self {
             TransactionError::SerializationError(_) => StatusCode::from_u16(500).unwrap(),
-            TransactionError::InvalidSignature(_) => StatusCode::from_u16(500).unwrap(),
+            TransactionError::InvalidSignature(_) => StatusCode::from_u16(400).unwrap(),
             TransactionError::TransactionNotFound => StatusCode::from_u16(404).unwrap(),
             TransactionError::AccountNotFound => StatusCode::from_u16(404).unwrap(),
             TransactionError::KeyNotFound => StatusCode::from_u16(404).unwrap(),
-            TransactionError::InvalidTransactionHash => StatusCode::from_u16(500).unwrap(),
+            TransactionError::InvalidTransactionHash => StatusCode::from_u16(400).unwrap(),
+            TransactionError::StorageError(_) => StatusCode::from_u16(500).unwrap(),
+            TransactionError::EstimationFailed(_) => StatusCode::from_u16(400).unwrap(),
+            TransactionError::SimulationFailed(_) => StatusCode::from_u16(400).unwrap(),
+            TransactionError::InvalidAddress(_) => StatusCode::from_u16(400).unwrap(),
+            TransactionError::PayloadTooLarge(_) => StatusCode::from_u16(413).unwrap(),
+            TransactionError::InvalidTransaction(_) => StatusCode::from_u16(422).unwrap(),
+            TransactionError::RateLimited => StatusCode::from_u16(429).unwrap(),
+            TransactionError::InvalidEncoding(_) => StatusCode::from_u16(400).unwrap(),
+            TransactionError::AmbiguousTransactionHash(_) => StatusCode::from_u16(409).unwrap(),
         }
     }
 }
 
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct AccountResponse {
+    /// Block the account was read at; `state_root` is the root after it.
+    pub block_number: u64,
+    pub nonce: u64,
+    pub balance: u64,
+    pub keys_count: usize,
+    pub state_root: String,
+}
+
+/// Body of `/account/{addr}/export`. `entries` can be sent back as is in a `RestoreKV`.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ExportResponse {
+    pub address: String,
+    pub block_number: u64,
+    pub state_root: String,
+    pub entries: Vec<(String, String)>,
+}
+
+/// Accounts `/accounts` returns when no `limit` is given.
+const DEFAULT_ACCOUNTS_LIMIT: usize = 100;
+/// Most accounts `/accounts` returns in one page.
+const MAX_ACCOUNTS_LIMIT: usize = 1000;
+
+/// Query of `/tx/{hash}`. Transactions signed by different senders over the
+/// same payload share a hash, and `sender` picks one of them.
+#[derive(Deserialize, Debug)]
+struct ReceiptQuery {
+    sender: Option<String>,
+}
+
+#[derive(Deserialize, Debug)]
+struct AccountsQuery {
+    #[serde(default)]
+    offset: usize,
+    limit: Option<usize>,
+}
+
+/// One account in the body of `/accounts`.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct AccountSummary {
+    pub address: String,
+    pub nonce: u64,
+    pub balance: u64,
+    pub keys_count: usize,
+}
+
+/// Body of `/accounts`. `total` counts every account, not only this page.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct AccountsResponse {
+    pub block_number: u64,
+    pub total: usize,
+    pub offset: usize,
+    pub accounts: Vec<AccountSummary>,
+}
+
 #[derive(Clone)]
 struct Context {
+    pub blockchain: Arc<Blockchain>,
     pub state: Arc<RwLock<State>>,
     pub storage: Arc<dyn Storage>,
     pub mempool: KvStoreTxPool,
+    /// `/health` reports a stall when no block was committed for this long.
+    pub health_stall: Duration,
+    pub started: Instant,
+    /// Limits transaction submissions per client IP, if set.
+    pub tx_rate_limit: Option<Arc<RateLimiter>>,
 }
 
+impl Context {
+    /// Fails with `RateLimited` if the client at `remote_addr` submitted too many
+    /// transactions. Clients without an IP address, e.g. over a Unix socket, are
+    /// not limited.
+    fn check_rate_limit(&self, remote_addr: &RemoteAddr) -> Result<(), TransactionError> {
+        let Some(limiter) = &self.tx_rate_limit else {
+            return Ok(());
+        };
+        match remote_addr.as_socket_addr() {
+            Some(addr) if !limiter.try_acquire(addr.ip()) => Err(TransactionError::RateLimited),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Older name of `/tx`, kept for existing clients.
 #[handler]
 async fn add_txn(
-    Json(transaction): Json<Transaction>,
+    request: &Request,
+    body: Body,
+    remote_addr: &RemoteAddr,
     Data(context): Data<&Arc<Context>>,
 ) -> poem::Result<Json<Value>> {
-    info!("add_txn: transaction: {:?}", transaction);
-    let account_address =
-        verify_signature(&transaction).map_err(|e| TransactionError::InvalidSignature(e))?;
-    info!(
-        "add_txn: txn {:?}, address: {}",
-        transaction, account_address
-    );
-    let txn_with_account = TransactionWithAccount {
-        txn: transaction,
-        address: account_address,
-    };
-    let txn_hash = context.mempool.add_raw_txn(txn_with_account);
+    queue_transaction(request, body, remote_addr, context).await
+}
+
+/// Content type of a transaction in its binary wire format, see `Transaction::to_wire_bytes`.
+const BCS_CONTENT_TYPE: &str = "application/x-bcs";
+
+/// Decodes a submitted transaction from BCS when sent as `BCS_CONTENT_TYPE`,
+/// and from JSON otherwise.
+async fn read_transaction(request: &Request, body: Body) -> Result<Transaction, TransactionError> {
+    let bytes = body
+        .into_vec()
+        .await
+        .map_err(|e| TransactionError::InvalidEncoding(e.to_string()))?;
+    let content_type = request.content_type().unwrap_or_default();
+    match content_type.split(';').next().map(str::trim) {
+        Some(BCS_CONTENT_TYPE) => {
+            Transaction::from_wire_bytes(&bytes).map_err(TransactionError::InvalidEncoding)
+        }
+        _ => serde_json::from_slice(&bytes)
+            .map_err(|e| TransactionError::InvalidEncoding(format!("Invalid transaction: {}", e))),
+    }
+}
+
+#[handler]
+async fn submit_txn(
+    request: &Request,
+    body: Body,
+    remote_addr: &RemoteAddr,
+    Data(context): Data<&Arc<Context>>,
+) -> poem::Result<Json<Value>> {
+    queue_transaction(request, body, remote_addr, context).await
+}
+
+/// Adds the transaction in `body` to the mempool once its signature checks out.
+async fn queue_transaction(
+    request: &Request,
+    body: Body,
+    remote_addr: &RemoteAddr,
+    context: &Context,
+) -> poem::Result<Json<Value>> {
+    // Checked first, so a limited client's body is not even read.
+    context.check_rate_limit(remote_addr)?;
+    let transaction = read_transaction(request, body).await?;
+    info!("queue_transaction: transaction: {:?}", transaction);
+    let txn_with_account = TransactionWithAccount::from_signed(transaction)
+        .map_err(|e| TransactionError::InvalidSignature(e))?;
+    let txn_hash = context
+        .mempool
+        .add_raw_txn(txn_with_account)
+        .map_err(TransactionError::from)?;
     Ok(Json(json!({
         "status": "success",
         "txn_hash": hex::encode(txn_hash.0.as_ref()),
     })))
 }
 
+#[handler]
+async fn estimate_gas(
+    Json(transaction): Json<Transaction>,
+    Data(context): Data<&Arc<Context>>,
+) -> poem::Result<Json<Value>> {
+    info!("estimate_gas: transaction: {:?}", transaction);
+    let state = context.state.read().await;
+    let gas_used = PipelineExecutor::estimate_gas(&transaction, &state)
+        .map_err(|e| TransactionError::EstimationFailed(e.to_string()))?;
+    Ok(Json(json!({ "gas_used": gas_used })))
+}
+
+#[handler]
+async fn simulate(
+    Json(transaction): Json<Transaction>,
+    Data(context): Data<&Arc<Context>>,
+) -> poem::Result<Json<Value>> {
+    info!("simulate: transaction: {:?}", transaction);
+    let state = context.state.read().await;
+    let receipt = PipelineExecutor::simulate(&transaction, &state, context.blockchain.config())
+        .map_err(|e| TransactionError::SimulationFailed(e.to_string()))?;
+    let value = serde_json::to_value(&receipt).map_err(TransactionError::SerializationError)?;
+    Ok(Json(value))
+}
+
+/// Liveness probe: 200 while blocks keep being committed, 503 once none was
+/// for `health_stall`. Before the first block the wait counts from startup.
+#[handler]
+async fn health(Data(context): Data<&Arc<Context>>) -> Response {
+    let since_commit = METRICS
+        .since_last_commit()
+        .unwrap_or_else(|| context.started.elapsed());
+    let healthy = since_commit <= context.health_stall;
+    let body = json!({
+        "status": if healthy { "ok" } else { "stalled" },
+        "block_number": METRICS.committed_height(),
+        "mempool_size": context.mempool.len(),
+    });
+    let status = if healthy {
+        StatusCode::OK
+    } else {
+        StatusCode::SERVICE_UNAVAILABLE
+    };
+    Response::builder().status(status).body(body.to_string())
+}
+
+#[handler]
+async fn metrics(Data(context): Data<&Arc<Context>>) -> String {
+    METRICS.render(context.mempool.len())
+}
+
 fn parse_transaction_hash(hash: &str) -> Result<[u8; 32], TransactionError> {
     let bytes = decode(hash).map_err(|_| TransactionError::InvalidTransactionHash)?;
     if bytes.len() != 32 {
@@ -124,13 +374,47 @@ async fn get_receipt(
     Data(context): Data<&Arc<Context>>,
 ) -> poem::Result<Json<Value>> {
     info!("get_receipt: transaction_hash: {}", transaction_hash);
-    let transaction_hash = parse_transaction_hash(&transaction_hash)?;
+    lookup_receipt(&context, &transaction_hash, None).await
+}
+
+#[handler]
+async fn get_txn(
+    Path(transaction_hash): Path<String>,
+    Query(query): Query<ReceiptQuery>,
+    Data(context): Data<&Arc<Context>>,
+) -> poem::Result<Json<Value>> {
+    info!("get_txn: transaction_hash: {}", transaction_hash);
+    lookup_receipt(&context, &transaction_hash, query.sender.as_deref()).await
+}
+
+/// The receipt of the transaction with `transaction_hash` sent by `sender`,
+/// which is only needed when several senders committed one with that hash.
+async fn lookup_receipt(
+    context: &Context,
+    transaction_hash: &str,
+    sender: Option<&str>,
+) -> poem::Result<Json<Value>> {
+    let transaction_hash = parse_transaction_hash(transaction_hash)?;
 
-    let receipt = context
+    let mut receipts = context
         .storage
-        .get_transaction_receipt(transaction_hash)
+        .get_transaction_receipts(transaction_hash)
         .await
-        .map_err(|_| TransactionError::InvalidTransactionHash)?
+        .map_err(TransactionError::StorageError)?;
+    if let Some(sender) = sender {
+        let sender = canonical_address(sender).map_err(TransactionError::InvalidAddress)?;
+        receipts.retain(|receipt| receipt.sender().is_ok_and(|signer| signer.0 == sender));
+    }
+    if receipts.len() > 1 {
+        let senders = receipts
+            .iter()
+            .filter_map(|receipt| receipt.sender().ok())
+            .map(|sender| sender.0)
+            .collect();
+        return Err(TransactionError::AmbiguousTransactionHash(senders).into());
+    }
+    let receipt = receipts
+        .pop()
         .ok_or(TransactionError::TransactionNotFound)?;
 
     let value = serde_json::to_value(&receipt).map_err(TransactionError::SerializationError)?;
@@ -146,6 +430,8 @@ async fn get_value(
         "get_value: account_address: {}, key: {}",
         account_address, key
     );
+    let account_address =
+        canonical_address(&account_address).map_err(TransactionError::InvalidAddress)?;
     // Retrieve the value from the account's key-value store
     match context
         .state
@@ -154,35 +440,203 @@ async fn get_value(
         .get_account(account_address.as_str())
     {
         Some(account) => match account.kv_store.get(&key) {
-            Some(value) => Ok(Json(json!(value))),
+            // Values in another encoding than UTF-8 say which, so they can be decoded.
+            Some(value) => match account.encodings.get(&key) {
+                Some(encoding) => Ok(Json(
+                    json!({ "value": value, "encoding": encoding.as_str() }),
+                )),
+                None => Ok(Json(json!(value))),
+            },
             None => Err(TransactionError::KeyNotFound.into()),
         },
         None => Err(TransactionError::AccountNotFound.into()),
     }
 }
 
+#[handler]
+async fn get_account(
+    Json(account_address): Json<String>,
+    Data(context): Data<&Arc<Context>>,
+) -> poem::Result<Json<Value>> {
+    info!("get_account: account_address: {}", account_address);
+    let account_address =
+        canonical_address(&account_address).map_err(TransactionError::InvalidAddress)?;
+    // Blocks are swapped into the state whole, so reading the account, block
+    // number and root under one lock gives a view of a single block.
+    let state = context.state.read().await;
+    let account = state
+        .get_account(&account_address)
+        .ok_or(TransactionError::AccountNotFound)?;
+    let response = AccountResponse {
+        block_number: state.get_current_block_number(),
+        nonce: account.nonce,
+        balance: account.balance,
+        keys_count: account.kv_store.len(),
+        state_root: state.get_state_root().to_hex(),
+    };
+    let value = serde_json::to_value(&response).map_err(TransactionError::SerializationError)?;
+    Ok(Json(value))
+}
+
+#[handler]
+async fn export_account(
+    Path(account_address): Path<String>,
+    Data(context): Data<&Arc<Context>>,
+) -> poem::Result<Json<Value>> {
+    info!("export_account: account_address: {}", account_address);
+    let address = canonical_address(&account_address).map_err(TransactionError::InvalidAddress)?;
+    // Entries, block number and root are read under one lock so they match.
+    let state = context.state.read().await;
+    let account = state
+        .get_account(&address)
+        .ok_or(TransactionError::AccountNotFound)?;
+    let response = ExportResponse {
+        address,
+        block_number: state.get_current_block_number(),
+        state_root: state.get_state_root().to_hex(),
+        entries: account.kv_store.into_iter().collect(),
+    };
+    let value = serde_json::to_value(&response).map_err(TransactionError::SerializationError)?;
+    Ok(Json(value))
+}
+
+#[handler]
+async fn list_accounts(
+    Query(query): Query<AccountsQuery>,
+    Data(context): Data<&Arc<Context>>,
+) -> poem::Result<Json<Value>> {
+    info!(
+        "list_accounts: offset: {}, limit: {:?}",
+        query.offset, query.limit
+    );
+    let limit = query
+        .limit
+        .unwrap_or(DEFAULT_ACCOUNTS_LIMIT)
+        .min(MAX_ACCOUNTS_LIMIT);
+    let state = context.state.read().await;
+    let accounts = state
+        .list_accounts(query.offset, limit)
+        .into_iter()
+        .map(|(address, account)| AccountSummary {
+            address,
+            nonce: account.nonce,
+            balance: account.balance,
+            keys_count: account.kv_store.len(),
+        })
+        .collect();
+    let response = AccountsResponse {
+        block_number: state.get_current_block_number(),
+        total: state.account_count(),
+        offset: query.offset,
+        accounts,
+    };
+    let value = serde_json::to_value(&response).map_err(TransactionError::SerializationError)?;
+    Ok(Json(value))
+}
+
+/// Streams a JSON `BlockSummary` to the client for every persisted block:
+/// `{"block_number": u64, "state_root": hex, "timestamp": u64, "txn_count": usize}`.
+#[handler]
+fn subscribe_blocks(ws: WebSocket, Data(context): Data<&Arc<Context>>) -> impl IntoResponse {
+    stream_json(ws, context.blockchain.subscribe_blocks(), "block")
+}
+
+/// Streams the JSON `TransactionReceipt` of every persisted transaction, in
+/// commit order, in the same form `/tx/{hash}` returns it.
+#[handler]
+fn subscribe_receipts(ws: WebSocket, Data(context): Data<&Arc<Context>>) -> impl IntoResponse {
+    stream_json(ws, context.blockchain.subscribe_receipts(), "receipt")
+}
+
+/// Sends every message from `receiver` to the WebSocket client as JSON until
+/// either side closes. A client that lags skips the messages it missed, which
+/// is logged, so it never holds up commits.
+fn stream_json<T: Serialize + Clone + Send + 'static>(
+    ws: WebSocket,
+    mut receiver: broadcast::Receiver<T>,
+    kind: &'static str,
+) -> impl IntoResponse {
+    ws.on_upgrade(move |socket| async move {
+        let (mut sink, mut stream) = socket.split();
+        loop {
+            tokio::select! {
+                msg = stream.next() => match msg {
+                    Some(Ok(Message::Close(_))) | Some(Err(_)) | None => break,
+                    Some(Ok(_)) => {}
+                },
+                item = receiver.recv() => match item {
+                    Ok(item) => {
+                        let text = match serde_json::to_string(&item) {
+                            Ok(text) => text,
+                            Err(e) => {
+                                warn!("failed to serialize {}: {}", kind, e);
+                                continue;
+                            }
+                        };
+                        if sink.send(Message::Text(text)).await.is_err() {
+                            break;
+                        }
+                    }
+                    Err(RecvError::Lagged(skipped)) => {
+                        warn!("{} subscriber lagged, skipped {} messages", kind, skipped);
+                    }
+                    Err(RecvError::Closed) => break,
+                },
+            }
+        }
+    })
+}
+
 pub struct ServerApp {
     context: Arc<Context>,
+    read_only: bool,
 }
 
 impl ServerApp {
+    /// A read-only server does not expose the endpoints that submit transactions.
+    /// `/health` fails once no block was committed for `health_stall`. Each
+    /// client IP may submit up to `tx_rate_limit` transactions a second, if set.
     pub fn new(
-        state: Arc<RwLock<State>>,
-        storage: Arc<dyn Storage>,
+        blockchain: Arc<Blockchain>,
         mempool: KvStoreTxPool,
+        read_only: bool,
+        health_stall: Duration,
+        tx_rate_limit: Option<u32>,
     ) -> Self {
         Self {
             context: Arc::new(Context {
-                state,
-                storage,
+                state: blockchain.state(),
+                storage: blockchain.storage.clone(),
+                blockchain,
                 mempool,
+                health_stall,
+                started: Instant::now(),
+                tx_rate_limit: tx_rate_limit.map(|limit| Arc::new(RateLimiter::new(limit))),
             }),
+            read_only,
         }
     }
 
-    pub async fn start(&self, addr: &str) -> Result<(), Box<dyn std::error::Error>> {
-        let app = Route::new()
-            .at("/add_txn", poem::post(add_txn.data(self.context.clone())))
+    /// Serves requests until `shutdown` is set to true, then stops accepting
+    /// connections and gives open ones a few seconds to finish.
+    pub async fn start(
+        &self,
+        addr: SocketAddr,
+        mut shutdown: watch::Receiver<bool>,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let mut app = Route::new();
+        if !self.read_only {
+            app = app
+                .at("/add_txn", poem::post(add_txn.data(self.context.clone())))
+                .at("/tx", poem::post(submit_txn.data(self.context.clone())));
+        }
+        let app = app
+            .at("/tx/:hash", poem::get(get_txn.data(self.context.clone())))
+            .at(
+                "/estimate-gas",
+                poem::post(estimate_gas.data(self.context.clone())),
+            )
+            .at("/simulate", poem::post(simulate.data(self.context.clone())))
             .at(
                 "/get_receipt",
                 poem::post(get_receipt.data(self.context.clone())),
@@ -190,13 +644,645 @@ impl ServerApp {
             .at(
                 "/get_value",
                 poem::post(get_value.data(self.context.clone())),
+            )
+            .at(
+                "/get_account",
+                poem::post(get_account.data(self.context.clone())),
+            )
+            .at(
+                "/accounts",
+                poem::get(list_accounts.data(self.context.clone())),
+            )
+            .at(
+                "/account/:addr/export",
+                poem::get(export_account.data(self.context.clone())),
+            )
+            .at("/metrics", poem::get(metrics.data(self.context.clone())))
+            .at("/health", poem::get(health.data(self.context.clone())))
+            .at(
+                "/subscribe/blocks",
+                poem::get(subscribe_blocks.data(self.context.clone())),
+            )
+            .at(
+                "/subscribe/receipts",
+                poem::get(subscribe_receipts.data(self.context.clone())),
             );
 
         info!("Server running at {}", addr);
+        let signal = async move {
+            while !*shutdown.borrow() {
+                if shutdown.changed().await.is_err() {
+                    break;
+                }
+            }
+        };
         Server::new(listener::TcpListener::bind(addr))
-            .run(app)
+            .run_with_graceful_shutdown(app, signal, Some(Duration::from_secs(5)))
             .await?;
 
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{test_utils::*, AccountId, AccountKey, AccountState, MemStorage, RecentTxns};
+
+    /// A node whose state holds Alice's account after one block, served on a
+    /// free port.
+    async fn serve(
+        alice: &AccountKey,
+    ) -> (String, Arc<Blockchain>, KvStoreTxPool, watch::Sender<bool>) {
+        let blockchain =
+            Arc::new(Blockchain::new(Arc::new(MemStorage::new()), None, config()).unwrap());
+        let txns = vec![sign(alice, 0, set_kv("key", "value"))];
+        execute_block(
+            &mut *blockchain.state.write().await,
+            &config(),
+            &mut RecentTxns::new(0),
+            txns,
+        );
+        let mempool = mempool();
+        let (url, shutdown) = start_server(blockchain.clone(), mempool.clone()).await;
+        (url, blockchain, mempool, shutdown)
+    }
+
+    /// Opens a WebSocket on `path`, returning the stream once the server
+    /// accepted the upgrade.
+    async fn open_websocket(url: &str, path: &str) -> tokio::net::TcpStream {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+        let mut stream = tokio::net::TcpStream::connect(url.trim_start_matches("http://"))
+            .await
+            .unwrap();
+        let request = format!(
+            "GET {} HTTP/1.1\r\nHost: localhost\r\nUpgrade: websocket\r\n\
+             Connection: Upgrade\r\nSec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\n\
+             Sec-WebSocket-Version: 13\r\n\r\n",
+            path
+        );
+        stream.write_all(request.as_bytes()).await.unwrap();
+        // Read the response byte by byte, so no frame is consumed with it.
+        let mut response = vec![];
+        while !response.ends_with(b"\r\n\r\n") {
+            response.push(stream.read_u8().await.unwrap());
+        }
+        let response = String::from_utf8(response).unwrap();
+        assert!(response.starts_with("HTTP/1.1 101"), "{}", response);
+        stream
+    }
+
+    /// Reads the next frame the server sent on `stream`, which must be text.
+    async fn read_text_frame(stream: &mut tokio::net::TcpStream) -> String {
+        use tokio::io::AsyncReadExt;
+        // A final text frame, unmasked as every frame from a server is.
+        assert_eq!(stream.read_u8().await.unwrap(), 0x81);
+        let len = match stream.read_u8().await.unwrap() {
+            126 => stream.read_u16().await.unwrap() as usize,
+            127 => stream.read_u64().await.unwrap() as usize,
+            len => len as usize,
+        };
+        let mut payload = vec![0; len];
+        stream.read_exact(&mut payload).await.unwrap();
+        String::from_utf8(payload).unwrap()
+    }
+
+    #[tokio::test]
+    async fn block_subscribers_get_a_summary_of_every_persisted_block() {
+        let alice = new_key();
+        let (url, blockchain, _, _shutdown) = serve(&alice).await;
+        let mut socket = open_websocket(&url, "/subscribe/blocks").await;
+
+        let mut blocks = vec![];
+        for nonce in 1..3 {
+            let txns = vec![
+                sign(&alice, nonce, set_kv("key", "value")),
+                sign(&new_key(), 0, set_kv("key", "value")),
+            ];
+            let (block, _) = execute_block(
+                &mut *blockchain.state.write().await,
+                &config(),
+                &mut RecentTxns::new(0),
+                txns,
+            );
+            blockchain.publish_block(&block);
+            blocks.push(block);
+        }
+
+        for block in blocks {
+            let summary: Value = serde_json::from_str(&read_text_frame(&mut socket).await).unwrap();
+            assert_eq!(
+                summary,
+                json!({
+                    "block_number": block.header.number,
+                    "state_root": hex::encode(block.header.state_root),
+                    "timestamp": block.header.usecs,
+                    "txn_count": 2,
+                })
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn read_only_server_serves_reads_and_refuses_transactions() {
+        let alice = new_key();
+        let blockchain =
+            Arc::new(Blockchain::new(Arc::new(MemStorage::new()), None, config()).unwrap());
+        let mempool = mempool();
+        let (url, _shutdown) = start_app(ServerApp::new(
+            blockchain.clone(),
+            mempool.clone(),
+            true,
+            Duration::from_secs(60),
+            None,
+        ))
+        .await;
+        let client = reqwest::Client::new();
+
+        let txn = sign(&alice, 0, set_kv("key", "value"));
+        for path in ["/tx", "/add_txn"] {
+            let response = client
+                .post(format!("{}{}", url, path))
+                .json(&txn.txn)
+                .send()
+                .await
+                .unwrap();
+            assert_eq!(
+                response.status(),
+                reqwest::StatusCode::NOT_FOUND,
+                "{}",
+                path
+            );
+        }
+        assert_eq!(mempool.len(), 0);
+
+        let response = client
+            .post(format!("{}/get_account", url))
+            .json(&alice.address())
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), reqwest::StatusCode::NOT_FOUND);
+        assert_eq!(
+            response.json::<Value>().await.unwrap(),
+            json!({ "error": "Account not found" })
+        );
+        let response = client
+            .get(format!("{}/accounts", url))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), reqwest::StatusCode::OK);
+    }
+
+    /// A server over an empty chain reporting a stall after `health_stall`.
+    async fn serve_with_health_stall(health_stall: Duration) -> (String, watch::Sender<bool>) {
+        let blockchain =
+            Arc::new(Blockchain::new(Arc::new(MemStorage::new()), None, config()).unwrap());
+        start_app(ServerApp::new(
+            blockchain,
+            mempool(),
+            false,
+            health_stall,
+            None,
+        ))
+        .await
+    }
+
+    #[tokio::test]
+    async fn health_is_ok_until_blocks_stall() {
+        let client = reqwest::Client::new();
+        let (url, _shutdown) = serve_with_health_stall(Duration::from_secs(3600)).await;
+        let response = client.get(format!("{}/health", url)).send().await.unwrap();
+        assert_eq!(response.status(), reqwest::StatusCode::OK);
+        let body = response.json::<Value>().await.unwrap();
+        assert_eq!(body["status"], "ok");
+        assert_eq!(body["mempool_size"], 0);
+        assert!(body["block_number"].is_u64());
+
+        // Other tests record commits in the same process, so the check is
+        // retried until none happened in the last millisecond.
+        let (url, _shutdown) = serve_with_health_stall(Duration::ZERO).await;
+        let mut stalled = None;
+        for _ in 0..50 {
+            tokio::time::sleep(Duration::from_millis(2)).await;
+            let response = client.get(format!("{}/health", url)).send().await.unwrap();
+            if response.status() == reqwest::StatusCode::SERVICE_UNAVAILABLE {
+                stalled = Some(response.json::<Value>().await.unwrap());
+                break;
+            }
+        }
+        assert_eq!(stalled.unwrap()["status"], "stalled");
+    }
+
+    #[tokio::test]
+    async fn get_account_returns_the_account_at_the_current_block() {
+        let alice = new_key();
+        let (url, blockchain, _, _shutdown) = serve(&alice).await;
+        let client = reqwest::Client::new();
+
+        let response = client
+            .post(format!("{}/get_account", url))
+            .json(&format!("0x{}", alice.address().to_uppercase()))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), reqwest::StatusCode::OK);
+        let state_root = blockchain.state.read().await.get_state_root().to_hex();
+        assert_eq!(
+            response.json::<Value>().await.unwrap(),
+            json!({
+                "block_number": 1,
+                "nonce": 1,
+                "balance": 1000,
+                "keys_count": 1,
+                "state_root": state_root,
+            })
+        );
+
+        let response = client
+            .post(format!("{}/get_account", url))
+            .json(&new_key().address())
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), reqwest::StatusCode::NOT_FOUND);
+        assert_eq!(
+            response.json::<Value>().await.unwrap(),
+            json!({ "error": "Account not found" })
+        );
+
+        let response = client
+            .post(format!("{}/get_account", url))
+            .json(&"not an address")
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), reqwest::StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn accounts_are_listed_in_address_order_one_capped_page_at_a_time() {
+        let blockchain =
+            Arc::new(Blockchain::new(Arc::new(MemStorage::new()), None, config()).unwrap());
+        let count = MAX_ACCOUNTS_LIMIT + 5;
+        {
+            let mut state = blockchain.state.write().await;
+            // Inserted out of order, to be listed sorted.
+            for index in (0..count as u64).rev() {
+                let account = AccountState {
+                    nonce: 0,
+                    balance: index,
+                    kv_store: Default::default(),
+                    expiries: Default::default(),
+                    last_faucet_block: None,
+                    encodings: Default::default(),
+                    writers: Default::default(),
+                };
+                state.put_account(&AccountId(format!("{:040x}", index)), account);
+            }
+            state.recompute_state_root();
+        }
+        let (url, _shutdown) = start_server(blockchain, mempool()).await;
+        let page = |query: &str| {
+            let url = format!("{}/accounts{}", url, query);
+            async move {
+                reqwest::get(url)
+                    .await
+                    .unwrap()
+                    .json::<AccountsResponse>()
+                    .await
+                    .unwrap()
+            }
+        };
+
+        let mut listed = vec![];
+        for offset in (0..count).step_by(400) {
+            let response = page(&format!("?offset={}&limit=400", offset)).await;
+            assert_eq!((response.total, response.offset), (count, offset));
+            assert_eq!(response.accounts.len(), 400.min(count - offset));
+            listed.extend(response.accounts);
+        }
+        let expected = (0..count as u64)
+            .map(|index| (format!("{:040x}", index), index))
+            .collect::<Vec<_>>();
+        let listed = listed
+            .into_iter()
+            .map(|account| (account.address, account.balance))
+            .collect::<Vec<_>>();
+        assert_eq!(listed, expected);
+
+        assert_eq!(page("").await.accounts.len(), DEFAULT_ACCOUNTS_LIMIT);
+        let capped = page("?limit=100000").await;
+        assert_eq!(capped.accounts.len(), MAX_ACCOUNTS_LIMIT);
+        let past_the_end = page(&format!("?offset={}", count)).await;
+        assert!(past_the_end.accounts.is_empty());
+        assert_eq!(past_the_end.total, count);
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn get_account_during_commits_returns_a_committed_block_view() {
+        let alice = new_key();
+        let config = config();
+        // The state after each block, to swap in as the executor does.
+        let mut state = State::new(None).unwrap();
+        let mut recent_txns = RecentTxns::new(0);
+        let mut committed = vec![];
+        for nonce in 0..20 {
+            let txns = vec![
+                sign(&alice, nonce, set_kv(&format!("key{}", nonce), "value")),
+                sign(&new_key(), 0, set_kv("key", "value")),
+            ];
+            execute_block(&mut state, &config, &mut recent_txns, txns);
+            committed.push(state.clone());
+        }
+        let expected = committed
+            .iter()
+            .map(|state| {
+                let account = state.get_account(&alice.address()).unwrap();
+                json!({
+                    "block_number": state.get_current_block_number(),
+                    "nonce": account.nonce,
+                    "balance": account.balance,
+                    "keys_count": account.kv_store.len(),
+                    "state_root": state.get_state_root().to_hex(),
+                })
+            })
+            .collect::<Vec<_>>();
+
+        let blockchain =
+            Arc::new(Blockchain::new(Arc::new(MemStorage::new()), None, config).unwrap());
+        *blockchain.state.write().await = committed[0].clone();
+        let (url, _shutdown) = start_server(blockchain.clone(), mempool()).await;
+        let writer = tokio::spawn(async move {
+            for state in committed.into_iter().skip(1) {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+                *blockchain.state.write().await = state;
+            }
+        });
+
+        let client = reqwest::Client::new();
+        let mut seen = std::collections::BTreeSet::new();
+        while !writer.is_finished() {
+            let response: Value = client
+                .post(format!("{}/get_account", url))
+                .json(&alice.address())
+                .send()
+                .await
+                .unwrap()
+                .json()
+                .await
+                .unwrap();
+            let block_number = response["block_number"].as_u64().unwrap();
+            assert_eq!(response, expected[block_number as usize - 1]);
+            seen.insert(block_number);
+        }
+        writer.await.unwrap();
+        assert!(seen.len() > 1, "only saw blocks {:?}", seen);
+    }
+
+    #[tokio::test]
+    async fn oversized_payloads_get_413_and_unincludable_transactions_422() {
+        let blockchain =
+            Arc::new(Blockchain::new(Arc::new(MemStorage::new()), None, config()).unwrap());
+        let limits = crate::PayloadLimits {
+            max_key_bytes: 16,
+            max_value_bytes: 64,
+            max_memo_bytes: 8,
+        };
+        let gas_limit = crate::GAS_BASE + 32 * crate::GAS_PER_BYTE;
+        let mempool = KvStoreTxPool::new(limits, None, Some(gas_limit), Default::default());
+        let (url, _shutdown) = start_server(blockchain, mempool).await;
+        let client = reqwest::Client::new();
+        let alice = new_key();
+        let submit = |txn: Transaction| client.post(format!("{}/tx", url)).json(&txn).send();
+
+        let oversized = sign(&alice, 0, set_kv("key", &"v".repeat(65))).txn;
+        let response = submit(oversized).await.unwrap();
+        assert_eq!(response.status(), reqwest::StatusCode::PAYLOAD_TOO_LARGE);
+
+        let over_gas = sign(&alice, 0, set_kv("key", &"v".repeat(40))).txn;
+        let response = submit(over_gas).await.unwrap();
+        assert_eq!(response.status(), reqwest::StatusCode::UNPROCESSABLE_ENTITY);
+
+        let long_memo = crate::crypto::TransactionBuilder::new(&alice, CHAIN_ID, 0)
+            .memo("m".repeat(9))
+            .build(set_kv("key", "value"))
+            .txn;
+        let response = submit(long_memo).await.unwrap();
+        assert_eq!(response.status(), reqwest::StatusCode::UNPROCESSABLE_ENTITY);
+
+        let fitting = sign(&alice, 0, set_kv("key", &"v".repeat(29))).txn;
+        let response = submit(fitting).await.unwrap();
+        assert_eq!(response.status(), reqwest::StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn rate_limited_client_gets_429_before_its_body_is_read() {
+        let blockchain =
+            Arc::new(Blockchain::new(Arc::new(MemStorage::new()), None, config()).unwrap());
+        let server = ServerApp::new(
+            blockchain,
+            mempool(),
+            false,
+            Duration::from_secs(60),
+            Some(1),
+        );
+        let (url, _shutdown) = start_app(server).await;
+        let client = reqwest::Client::new();
+
+        let txn = sign(&new_key(), 0, set_kv("key", "value")).txn;
+        let response = client
+            .post(format!("{}/tx", url))
+            .json(&txn)
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), reqwest::StatusCode::OK);
+
+        // Malformed, but refused for the rate before it is decoded.
+        let response = client
+            .post(format!("{}/tx", url))
+            .body("not a transaction")
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), reqwest::StatusCode::TOO_MANY_REQUESTS);
+    }
+
+    #[tokio::test]
+    async fn json_and_bcs_submissions_give_the_same_hash() {
+        let alice = new_key();
+        let txn = sign(&alice, 1, set_kv("key", "other")).txn;
+        let client = reqwest::Client::new();
+        let mut hashes = vec![];
+        for bcs in [false, true] {
+            let (url, _, mempool, _shutdown) = serve(&alice).await;
+            let request = client.post(format!("{}/tx", url));
+            let request = if bcs {
+                request
+                    .header("content-type", BCS_CONTENT_TYPE)
+                    .body(txn.to_wire_bytes())
+            } else {
+                request.json(&txn)
+            };
+            let response = request.send().await.unwrap();
+            assert_eq!(response.status(), reqwest::StatusCode::OK);
+            let body = response.json::<Value>().await.unwrap();
+            hashes.push(body["txn_hash"].as_str().unwrap().to_string());
+            assert_eq!(mempool.len(), 1);
+        }
+        assert_eq!(hashes[0], hashes[1]);
+        assert_eq!(
+            hashes[0],
+            hex::encode(crate::compute_transaction_hash(&txn.unsigned))
+        );
+
+        let (url, _, _, _shutdown) = serve(&alice).await;
+        let mut bytes = txn.to_wire_bytes();
+        bytes.push(0);
+        let response = client
+            .post(format!("{}/tx", url))
+            .header("content-type", BCS_CONTENT_TYPE)
+            .body(bytes)
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), reqwest::StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn receipt_of_a_submitted_transaction_is_served_once_committed() {
+        let alice = new_key();
+        let (url, blockchain, mempool, _shutdown) = serve(&alice).await;
+        let client = reqwest::Client::new();
+        let txn = sign(&alice, 1, set_kv("key", "other"));
+
+        let response = client
+            .post(format!("{}/tx", url))
+            .json(&txn.txn)
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), reqwest::StatusCode::OK);
+        let body = response.json::<Value>().await.unwrap();
+        let hash = body["txn_hash"].as_str().unwrap().to_string();
+        assert_eq!(
+            hash,
+            hex::encode(crate::compute_transaction_hash(&txn.txn.unsigned))
+        );
+        assert_eq!(mempool.len(), 1);
+
+        let receipt_url = format!("{}/tx/{}", url, hash);
+        let response = client.get(&receipt_url).send().await.unwrap();
+        assert_eq!(response.status(), reqwest::StatusCode::NOT_FOUND);
+
+        // Stands in for consensus ordering the transaction into block 2.
+        commit_block(
+            blockchain.storage.as_ref(),
+            &mut *blockchain.state.write().await,
+            &config(),
+            &mut RecentTxns::new(0),
+            vec![txn.clone()],
+        )
+        .await;
+
+        let response = client.get(&receipt_url).send().await.unwrap();
+        assert_eq!(response.status(), reqwest::StatusCode::OK);
+        let receipt = response.json::<Value>().await.unwrap();
+        assert_eq!(receipt["status"], json!(true));
+        assert_eq!(
+            receipt["transaction"]["signature"],
+            json!(txn.txn.signature)
+        );
+        assert!(receipt["gas_used"].is_number());
+        assert!(receipt["state_updates"].is_array());
+        assert!(receipt["logs"].is_array());
+
+        let response = client
+            .get(format!("{}/tx/not-hex", url))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), reqwest::StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn transaction_with_a_bad_signature_is_rejected_on_both_endpoints() {
+        let alice = new_key();
+        let (url, _, mempool, _shutdown) = serve(&alice).await;
+        let client = reqwest::Client::new();
+        let mut forged = sign(&alice, 1, set_kv("key", "other")).txn;
+        forged.signature = "00".repeat(65);
+
+        for endpoint in ["tx", "add_txn"] {
+            let response = client
+                .post(format!("{}/{}", url, endpoint))
+                .json(&forged)
+                .send()
+                .await
+                .unwrap();
+            assert_eq!(response.status(), reqwest::StatusCode::BAD_REQUEST);
+            let body = response.json::<Value>().await.unwrap();
+            assert!(body["error"].is_string());
+        }
+        assert_eq!(mempool.len(), 0);
+
+        // The same transaction correctly signed goes through either one.
+        let txn = sign(&alice, 1, set_kv("key", "other")).txn;
+        let response = client
+            .post(format!("{}/add_txn", url))
+            .json(&txn)
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), reqwest::StatusCode::OK);
+        assert_eq!(mempool.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn receipts_of_two_senders_with_the_same_payload_are_kept_apart() {
+        let (bob, carol) = (new_key(), new_key());
+        let (url, blockchain, _, _shutdown) = serve(&new_key()).await;
+        let client = reqwest::Client::new();
+        let from_bob = sign(&bob, 0, set_kv("shared", "value"));
+        let from_carol = sign(&carol, 0, set_kv("shared", "value"));
+        let hash = crate::compute_transaction_hash(&from_bob.txn.unsigned);
+        assert_eq!(
+            hash,
+            crate::compute_transaction_hash(&from_carol.txn.unsigned)
+        );
+
+        let receipts = commit_block(
+            blockchain.storage.as_ref(),
+            &mut *blockchain.state.write().await,
+            &config(),
+            &mut RecentTxns::new(0),
+            vec![from_bob.clone(), from_carol.clone()],
+        )
+        .await;
+        assert_eq!(receipts.len(), 2);
+
+        let receipt_url = format!("{}/tx/{}", url, hex::encode(hash));
+        let response = client.get(&receipt_url).send().await.unwrap();
+        assert_eq!(response.status(), reqwest::StatusCode::CONFLICT);
+        let body = response.json::<Value>().await.unwrap();
+        assert_eq!(body["senders"].as_array().unwrap().len(), 2);
+
+        for (key, txn) in [(&bob, &from_bob), (&carol, &from_carol)] {
+            let response = client
+                .get(&receipt_url)
+                .query(&[("sender", key.address())])
+                .send()
+                .await
+                .unwrap();
+            assert_eq!(response.status(), reqwest::StatusCode::OK);
+            let receipt = response.json::<Value>().await.unwrap();
+            assert_eq!(
+                receipt["transaction"]["signature"],
+                json!(txn.txn.signature)
+            );
+            assert_eq!(receipt["status"], json!(true));
+        }
+    }
+}
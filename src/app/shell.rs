@@ -1,45 +1,126 @@
 use crate::{
-    crypto::{self, KeyPair},
-    KvStoreTxPool, State, Storage, Transaction, TransactionKind, TransactionWithAccount,
-    UnsignedTransaction,
+    crypto::{self, keystore::Keystore, AccountKey},
+    external_account_address, BlockSummary, Blockchain, KvError, KvStoreTxPool, SignatureScheme,
+    State, Storage, Transaction, TransactionKind, TxnRejection, TxnStatus, ValueEncoding,
 };
 use bytes::buf::Reader;
-use rustyline::{error::ReadlineError, DefaultEditor};
 use rustyline::Editor;
-use secp256k1::{PublicKey, Secp256k1, SecretKey};
-use std::{fs::File, io::BufReader, sync::Arc};
-use tokio::sync::RwLock;
+use rustyline::{error::ReadlineError, DefaultEditor};
+use serde_json::json;
+use std::{
+    collections::BTreeMap,
+    fs::File,
+    io::{BufRead, BufReader},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+};
+use tokio::sync::{broadcast::error::RecvError, Notify, RwLock};
+
+#[derive(Debug, thiserror::Error)]
+pub enum ShellError {
+    #[error("Usage: {0}")]
+    Usage(&'static str),
+    #[error("Error: {0}")]
+    Failed(String),
+}
+
+impl From<KvError> for ShellError {
+    fn from(error: KvError) -> Self {
+        ShellError::Failed(error.to_string())
+    }
+}
+
+impl From<TxnRejection> for ShellError {
+    fn from(rejection: TxnRejection) -> Self {
+        ShellError::Failed(rejection.to_string())
+    }
+}
+
+/// How command results and errors are printed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OutputFormat {
+    Text,
+    Json,
+}
+
+/// Commands whose arguments may hold secrets, kept out of the history file.
+const SENSITIVE_COMMANDS: &[&str] = &["user", "user-mnemonic", "keygen", "keyload"];
+
+const NO_USER_CONTEXT: &str = "No user context. Please use 'user <private_key>' to set a user.";
+
+/// Hands Ctrl+C to a running `watch` command instead of stopping the node.
+#[derive(Default)]
+pub struct ShellInterrupt {
+    watching: AtomicBool,
+    notify: Notify,
+}
+
+impl ShellInterrupt {
+    /// Stops the running `watch` command, if any. Returns `false` when none is
+    /// running, in which case Ctrl+C should stop the node as usual.
+    pub fn interrupt(&self) -> bool {
+        if !self.watching.load(Ordering::SeqCst) {
+            return false;
+        }
+        self.notify.notify_waiters();
+        true
+    }
+}
 
 pub struct Shell {
+    blockchain: Arc<Blockchain>,
     state: Arc<RwLock<State>>,
     storage: Arc<dyn Storage>,
     mempool: KvStoreTxPool,
-    keypair: Option<KeyPair>,
+    chain_id: u64,
+    read_only: bool,
+    key: Option<AccountKey>,
+    format: OutputFormat,
+    /// Where command results and errors are printed, stdout unless replaced.
+    print: Box<dyn Fn(&str) + Send + Sync>,
+    exit_requested: bool,
+    interrupt: Arc<ShellInterrupt>,
 }
 
 impl Shell {
     pub fn new(
-        state: Arc<RwLock<State>>,
-        storage: Arc<dyn Storage>,
+        blockchain: Arc<Blockchain>,
         mempool: KvStoreTxPool,
+        chain_id: u64,
+        read_only: bool,
     ) -> Self {
         Self {
-            state,
-            storage,
+            state: blockchain.state(),
+            storage: blockchain.storage.clone(),
+            blockchain,
             mempool,
-            keypair: None,
+            chain_id,
+            read_only,
+            key: None,
+            format: OutputFormat::Text,
+            print: Box::new(|line| println!("{}", line)),
+            exit_requested: false,
+            interrupt: Arc::new(ShellInterrupt::default()),
         }
     }
 
-    pub async fn run(&mut self) {
+    /// The handle Ctrl+C must be passed to while the shell runs, so it can stop
+    /// a `watch` command.
+    pub fn interrupt(&self) -> Arc<ShellInterrupt> {
+        self.interrupt.clone()
+    }
+
+    /// Runs the interactive shell until `exit`, Ctrl+C or Ctrl+D, then saves
+    /// the command history to `history_file`.
+    pub async fn run(&mut self, history_file: &str) {
         let mut rl = DefaultEditor::new().unwrap();
-        if rl.load_history("history.txt").is_err() {
-            println!("No previous history.");
-        }
+        load_history(&mut rl, history_file);
 
         loop {
-            let prompt = if let Some(keypair) = &self.keypair {
-                let address = crypto::public_key_to_address(&keypair.public_key);
+            let prompt = if let Some(key) = &self.key {
+                let address = key.address();
                 let address_str = format!("{}", address);
                 let short_address = if address_str.len() > 10 {
                     format!(
@@ -57,12 +138,19 @@ impl Shell {
             let readline = rl.readline(&prompt);
             match readline {
                 Ok(line) => {
-                    rl.add_history_entry(line.as_str()).unwrap();
+                    if let Some(entry) = history_entry(&line) {
+                        rl.add_history_entry(entry).unwrap();
+                    }
                     let args: Vec<&str> = line.trim().split_whitespace().collect();
                     if args.is_empty() {
                         continue;
                     }
-                    self.handle_command(args).await;
+                    if let Err(e) = self.handle_command(args).await {
+                        self.print_error(&e);
+                    }
+                    if self.exit_requested {
+                        break;
+                    }
                 }
                 Err(ReadlineError::Interrupted) => {
                     println!("CTRL-C");
@@ -78,160 +166,1481 @@ impl Shell {
                 }
             }
         }
+        if let Err(e) = rl.save_history(history_file) {
+            println!("Warning: failed to save history to {}: {}", history_file, e);
+        }
+    }
+
+    /// Runs the commands in the file at `path`, one per line, echoing each one.
+    /// Blank lines and lines starting with `#` are skipped. Stops at the first
+    /// failing command unless `continue_on_error` is set.
+    pub async fn run_script(
+        &mut self,
+        path: &str,
+        continue_on_error: bool,
+    ) -> Result<(), ShellError> {
+        let file = File::open(path)
+            .map_err(|e| ShellError::Failed(format!("Failed to open script {}: {}", path, e)))?;
+        for (index, line) in BufReader::new(file).lines().enumerate() {
+            let line = line.map_err(|e| {
+                ShellError::Failed(format!("Failed to read script {}: {}", path, e))
+            })?;
+            let args: Vec<&str> = line.split_whitespace().collect();
+            if args.is_empty() || args[0].starts_with('#') {
+                continue;
+            }
+            println!(">> {}", line.trim());
+            if let Err(e) = self.handle_command(args).await {
+                self.print_error(&e);
+                if !continue_on_error {
+                    return Err(ShellError::Failed(format!(
+                        "Script stopped at line {}",
+                        index + 1
+                    )));
+                }
+            }
+            if self.exit_requested {
+                break;
+            }
+        }
+        Ok(())
     }
 
-    async fn handle_command(&mut self, args: Vec<&str>) {
+    async fn handle_command(&mut self, args: Vec<&str>) -> Result<(), ShellError> {
         match args[0] {
             "user" => self.handle_user_command(args).await,
+            "user-mnemonic" => self.handle_user_mnemonic_command(args).await,
+            "keygen" => self.handle_keygen_command(args),
+            "keyload" => self.handle_keyload_command(args),
             "set" => self.handle_set_command(args).await,
+            "add" => self.handle_add_command(args).await,
+            "set_for" => self.handle_set_for_command(args).await,
+            "grant" | "revoke" => self.handle_grant_command(args).await,
+            "faucet" => self.handle_faucet_command(args).await,
+            "resend" => self.handle_resend_command(args).await,
             "get" => self.handle_get_command(args).await,
+            "balance" => self.handle_balance_command(args).await,
+            "keys" => self.handle_keys_command(args).await,
             "query_txn" => self.handle_query_txn_command(args).await,
-            "help" => self.print_help(),
-            "?" => self.print_help(),
+            "query_block" => self.handle_query_block_command(args).await,
+            "state_root" => self.handle_state_root_command(args).await,
+            "history" => self.handle_history_command(args).await,
+            "mempool" => self.handle_mempool_command(args).await,
+            "diff" => self.handle_diff_command(args).await,
+            "verify" => self.handle_verify_command(args),
+            "watch" => self.handle_watch_command().await,
+            "format" => self.handle_format_command(args),
+            "help" => {
+                self.print_help();
+                Ok(())
+            }
+            "?" => {
+                self.print_help();
+                Ok(())
+            }
             "exit" => {
                 println!("Exiting.");
-                std::process::exit(0);
+                self.exit_requested = true;
+                Ok(())
             }
             _ => {
-                println!("Unknown command: {}", args[0]);
                 self.print_help();
+                Err(ShellError::Failed(format!("Unknown command: {}", args[0])))
             }
         }
     }
 
-    async fn handle_user_command(&mut self, args: Vec<&str>) {
-        if args.len() < 2 {
-            println!("Usage: user <private_key_hex>");
-            return;
+    /// Prints `text` in text mode and `value` in JSON mode.
+    fn print_output(&self, text: String, value: serde_json::Value) {
+        match self.format {
+            OutputFormat::Text => (self.print)(&text),
+            OutputFormat::Json => (self.print)(&value.to_string()),
         }
+    }
 
-        let private_key_hex = args[1];
-        let private_key_bytes = match hex::decode(private_key_hex) {
-            Ok(bytes) => bytes,
-            Err(e) => {
-                println!("Error: Invalid private key hex: {}", e);
-                return;
+    fn print_error(&self, error: &ShellError) {
+        match self.format {
+            OutputFormat::Text => (self.print)(&error.to_string()),
+            OutputFormat::Json => {
+                let message = match error {
+                    ShellError::Usage(usage) => format!("Usage: {}", usage),
+                    ShellError::Failed(message) => message.clone(),
+                };
+                (self.print)(&json!({ "error": message }).to_string());
             }
+        }
+    }
+
+    fn handle_format_command(&mut self, args: Vec<&str>) -> Result<(), ShellError> {
+        self.format = match args.get(1) {
+            Some(&"text") => OutputFormat::Text,
+            Some(&"json") => OutputFormat::Json,
+            _ => return Err(ShellError::Usage("format <text|json>")),
         };
+        Ok(())
+    }
+
+    fn current_address(&self) -> Result<String, ShellError> {
+        match &self.key {
+            Some(key) => Ok(key.address()),
+            None => Err(ShellError::Failed(NO_USER_CONTEXT.to_string())),
+        }
+    }
 
-        let secret_key = match SecretKey::from_slice(&private_key_bytes) {
-            Ok(sk) => sk,
-            Err(e) => {
-                println!("Error: Invalid private key: {}", e);
-                return;
+    async fn handle_user_command(&mut self, args: Vec<&str>) -> Result<(), ShellError> {
+        const USAGE: &str = "user <private_key_hex> [--ed25519] | user --keystore <path>";
+        let key = match (args.get(1), args.get(2)) {
+            (Some(&"--keystore"), Some(path)) => load_keystore(path)?,
+            (Some(&"--keystore"), None) | (None, _) => return Err(ShellError::Usage(USAGE)),
+            (Some(private_key_hex), scheme) => {
+                let scheme = match scheme {
+                    None => SignatureScheme::Secp256k1,
+                    Some(&"--ed25519") => SignatureScheme::Ed25519,
+                    Some(_) => return Err(ShellError::Usage(USAGE)),
+                };
+                AccountKey::from_hex(private_key_hex, scheme).map_err(ShellError::Failed)?
             }
         };
 
-        let secp = Secp256k1::new();
-        let public_key = PublicKey::from_secret_key(&secp, &secret_key);
+        let address = key.address();
+        self.key = Some(key);
+        println!("Switched user to: {}", address);
+        Ok(())
+    }
 
-        self.keypair = Some(KeyPair {
-            secret_key,
-            public_key,
-        });
+    async fn handle_user_mnemonic_command(&mut self, args: Vec<&str>) -> Result<(), ShellError> {
+        let (words, passphrase) = match args.iter().position(|arg| *arg == "--passphrase") {
+            Some(index) => (&args[1..index], args.get(index + 1).copied().unwrap_or("")),
+            None => (&args[1..], ""),
+        };
+        if words.is_empty() {
+            return Err(ShellError::Usage(
+                "user-mnemonic \"<words>\" [--passphrase <passphrase>]",
+            ));
+        }
+
+        let phrase = words.join(" ");
+        let phrase = phrase.trim_matches('"');
+        let keypair =
+            crypto::mnemonic_to_keypair(phrase, passphrase).map_err(ShellError::Failed)?;
 
-        let address = crypto::public_key_to_address(&public_key);
+        let address = crypto::public_key_to_address(&keypair.public_key);
+        self.key = Some(keypair.into());
         println!("Switched user to: {}", address);
+        Ok(())
     }
 
-    async fn handle_set_command(&mut self, args: Vec<&str>) {
+    /// Generates a secp256k1 key, switches to it and optionally saves it,
+    /// encrypted with a passphrase read from the terminal.
+    fn handle_keygen_command(&mut self, args: Vec<&str>) -> Result<(), ShellError> {
+        let path = match (args.get(1), args.get(2)) {
+            (None, _) => None,
+            (Some(&"--save"), Some(path)) => Some(*path),
+            _ => return Err(ShellError::Usage("keygen [--save <path>]")),
+        };
+
+        let key = AccountKey::from(crypto::generate_keypair());
+        if let Some(path) = path {
+            let passphrase = read_passphrase("New keystore passphrase: ")?;
+            if read_passphrase("Repeat passphrase: ")? != passphrase {
+                return Err(ShellError::Failed("Passphrases do not match".to_string()));
+            }
+            Keystore::encrypt(&key, &passphrase)
+                .and_then(|keystore| keystore.save(path))
+                .map_err(ShellError::Failed)?;
+            println!("Saved key to {}", path);
+        }
+
+        let address = key.address();
+        self.key = Some(key);
+        println!("Switched user to: {}", address);
+        Ok(())
+    }
+
+    fn handle_keyload_command(&mut self, args: Vec<&str>) -> Result<(), ShellError> {
+        let path = args.get(1).ok_or(ShellError::Usage("keyload <path>"))?;
+        let key = load_keystore(path)?;
+
+        let address = key.address();
+        self.key = Some(key);
+        println!("Switched user to: {}", address);
+        Ok(())
+    }
+
+    async fn handle_set_command(&mut self, args: Vec<&str>) -> Result<(), ShellError> {
+        const USAGE: &str =
+            "set <key> <value> [--expires-at <block> | --encoding <utf8|base64|hex>]";
         if args.len() < 3 {
-            println!("Usage: set <key> <value>");
-            return;
+            return Err(ShellError::Usage(USAGE));
         }
 
         let key = args[1].to_string();
         let value = args[2].to_string();
-
-        let keypair = match &self.keypair {
-            Some(kp) => kp,
-            None => {
-                println!("Error: No user context. Please use 'user <private_key>' to set a user.");
-                return;
+        let kind = match args.get(3) {
+            None => TransactionKind::SetKV { key, value },
+            Some(&"--expires-at") => {
+                let expires_at_block = args
+                    .get(4)
+                    .and_then(|block| block.parse::<u64>().ok())
+                    .ok_or(ShellError::Usage(USAGE))?;
+                TransactionKind::SetKVTtl {
+                    key,
+                    value,
+                    expires_at_block,
+                }
             }
+            Some(&"--encoding") => {
+                let encoding = args
+                    .get(4)
+                    .and_then(|encoding| encoding.parse::<ValueEncoding>().ok())
+                    .ok_or(ShellError::Usage(USAGE))?;
+                TransactionKind::SetKVEncoded {
+                    key,
+                    value,
+                    encoding,
+                }
+            }
+            Some(_) => return Err(ShellError::Usage(USAGE)),
         };
+        self.send_transaction(kind).await
+    }
 
-        let address = crypto::public_key_to_address(&keypair.public_key);
+    async fn handle_add_command(&mut self, args: Vec<&str>) -> Result<(), ShellError> {
+        if args.len() < 3 {
+            return Err(ShellError::Usage("add <key> <delta>"));
+        }
 
-        let unsigned_transaction = UnsignedTransaction {
-            nonce: self
-                .state
-                .read()
-                .await
-                .get_account(&address)
-                .map(|s| s.nonce)
-                .unwrap_or(0),
-            kind: TransactionKind::SetKV { key, value },
-        };
+        let key = args[1].to_string();
+        let delta = args[2]
+            .parse::<i64>()
+            .map_err(|_| ShellError::Usage("add <key> <delta>"))?;
+        self.send_transaction(TransactionKind::AddKV { key, delta })
+            .await
+    }
 
-        let signature = crypto::sign_transaction(&unsigned_transaction, &keypair.secret_key);
+    async fn handle_set_for_command(&mut self, args: Vec<&str>) -> Result<(), ShellError> {
+        if args.len() < 4 {
+            return Err(ShellError::Usage("set_for <address> <key> <value>"));
+        }
+        let target = crypto::canonical_address(args[1]).map_err(ShellError::Failed)?;
+        self.send_transaction(TransactionKind::SetKVFor {
+            target,
+            key: args[2].to_string(),
+            value: args[3].to_string(),
+        })
+        .await
+    }
 
-        let transaction = Transaction {
-            unsigned: unsigned_transaction,
-            signature,
+    async fn handle_grant_command(&mut self, args: Vec<&str>) -> Result<(), ShellError> {
+        let grantee = match args.get(1) {
+            Some(address) => crypto::canonical_address(address).map_err(ShellError::Failed)?,
+            None => return Err(ShellError::Usage("grant <address> | revoke <address>")),
         };
-
-        let txn_with_account = TransactionWithAccount {
-            txn: transaction,
-            address,
+        let kind = match args[0] {
+            "grant" => TransactionKind::GrantWrite { grantee },
+            _ => TransactionKind::RevokeWrite { grantee },
         };
+        self.send_transaction(kind).await
+    }
+
+    async fn handle_faucet_command(&mut self, args: Vec<&str>) -> Result<(), ShellError> {
+        if args.len() < 3 {
+            return Err(ShellError::Usage("faucet <address> <amount>"));
+        }
+
+        let receiver = crypto::canonical_address(args[1]).map_err(ShellError::Failed)?;
+        let amount = args[2]
+            .parse::<u64>()
+            .map_err(|_| ShellError::Usage("faucet <address> <amount>"))?;
+        self.send_transaction(TransactionKind::Mint { receiver, amount })
+            .await
+    }
+
+    /// Signs `kind` with the current user's key at its next nonce and adds it to the mempool.
+    async fn send_transaction(&mut self, kind: TransactionKind) -> Result<(), ShellError> {
+        if self.read_only {
+            return Err(ShellError::Failed(
+                "This node is a read-only replica and cannot send transactions".to_string(),
+            ));
+        }
+        let key = self
+            .key
+            .as_ref()
+            .ok_or_else(|| ShellError::Failed(NO_USER_CONTEXT.to_string()))?;
+
+        let address = key.address();
+        let state_nonce = self
+            .state
+            .read()
+            .await
+            .get_account(&address)
+            .map(|s| s.nonce)
+            .unwrap_or(0);
+        let account = external_account_address(&address).map_err(ShellError::Failed)?;
+        let nonce = self.mempool.next_nonce(&account, state_nonce);
+
+        let txn_with_account =
+            crypto::TransactionBuilder::new(key, self.chain_id, nonce).build(kind);
 
-        let txn_hash = self.mempool.add_raw_txn(txn_with_account);
+        let txn_hash = self.mempool.add_raw_txn(txn_with_account)?;
         println!("Transaction sent! Hash: {}", hex::encode(txn_hash.0));
+        Ok(())
     }
 
-    async fn handle_get_command(&mut self, args: Vec<&str>) {
+    /// Re-signs a transaction stuck in the mempool behind a nonce gap at the
+    /// first missing nonce, replacing the original.
+    async fn handle_resend_command(&mut self, args: Vec<&str>) -> Result<(), ShellError> {
         if args.len() < 2 {
-            println!("Usage: get <key>");
-            return;
+            return Err(ShellError::Usage("resend <txn_hash>"));
         }
-        let key = args[1];
-
-        let keypair = match &self.keypair {
-            Some(kp) => kp,
-            None => {
-                println!("Error: No user context. Please use 'user <private_key>' to set a user.");
-                return;
+        if self.read_only {
+            return Err(ShellError::Failed(
+                "This node is a read-only replica and cannot send transactions".to_string(),
+            ));
+        }
+        let txn_hash = parse_hash(args[1])?;
+        let committed = ShellError::Failed(format!("Transaction {} is already committed", args[1]));
+        let Some((original, status)) = self.mempool.get_txn(&txn_hash) else {
+            if self
+                .storage
+                .get_transaction_receipts(txn_hash)
+                .await?
+                .is_empty()
+            {
+                return Err(ShellError::Failed(format!(
+                    "Transaction {} is not in the mempool",
+                    args[1]
+                )));
             }
+            return Err(committed);
         };
-        let address = crypto::public_key_to_address(&keypair.public_key);
+        if self
+            .storage
+            .get_transaction_receipt(&original.txn)
+            .await?
+            .is_some()
+        {
+            return Err(committed);
+        }
+        let key = self
+            .key
+            .as_ref()
+            .ok_or_else(|| ShellError::Failed(NO_USER_CONTEXT.to_string()))?;
+        if key.address() != original.address {
+            return Err(ShellError::Failed(format!(
+                "Only its sender {} can resend this transaction",
+                original.address
+            )));
+        }
+
+        let state_nonce = self
+            .state
+            .read()
+            .await
+            .get_account(&original.address)
+            .map(|s| s.nonce)
+            .unwrap_or(0);
+        let original_nonce = original.sequence_number();
+        if status == TxnStatus::Pending && original_nonce >= state_nonce {
+            return Err(ShellError::Failed(format!(
+                "Transaction {} is pending, not stuck",
+                args[1]
+            )));
+        }
+        let account = external_account_address(&original.address).map_err(ShellError::Failed)?;
+        // The first missing nonce unblocks the account; without a gap, as when the
+        // original nonce was already used, the next free one is taken. Either way
+        // it differs from the original's, which is still queued.
+        let nonce = self
+            .mempool
+            .nonce_gaps(&account)
+            .into_iter()
+            .find(|nonce| *nonce >= state_nonce)
+            .unwrap_or_else(|| self.mempool.next_nonce(&account, state_nonce));
+
+        let unsigned = original.txn.unsigned;
+        let mut builder =
+            crypto::TransactionBuilder::new(key, self.chain_id, nonce).read_set(unsigned.read_set);
+        if let Some(valid_until_block) = unsigned.valid_until_block {
+            builder = builder.valid_until_block(valid_until_block);
+        }
+        if let Some(memo) = unsigned.memo {
+            builder = builder.memo(memo);
+        }
+        // The original is only removed once its replacement is queued, so a
+        // rejected replacement leaves it in the mempool.
+        let resent_hash = self.mempool.add_raw_txn(builder.build(unsigned.kind))?;
+        self.mempool.remove_txn(&account, original_nonce);
+        println!(
+            "Transaction resent with nonce {} (was {})! Hash: {}",
+            nonce,
+            original_nonce,
+            hex::encode(resent_hash.0)
+        );
+        Ok(())
+    }
+
+    async fn handle_get_command(&mut self, args: Vec<&str>) -> Result<(), ShellError> {
+        if args.len() < 2 {
+            return Err(ShellError::Usage("get <key>"));
+        }
+        let key = args[1];
+        let address = self.current_address()?;
 
         match self.state.read().await.get_account(&address) {
             Some(account) => match account.kv_store.get(key) {
-                Some(value) => println!("Value: {}", value),
-                None => println!("Error: Key not found '{}' for account {}", key, address),
+                Some(value) => {
+                    let encoding = account.encodings.get(key).copied().unwrap_or_default();
+                    let text = match encoding {
+                        ValueEncoding::Utf8 => format!("Value: {}", value),
+                        _ => {
+                            let bytes = encoding.decode(value).map_err(ShellError::Failed)?;
+                            // Decoded bytes that are not text are shown as hex.
+                            let decoded = String::from_utf8(bytes.clone())
+                                .unwrap_or_else(|_| format!("0x{}", hex::encode(&bytes)));
+                            format!(
+                                "Value: {} ({}, {} bytes: {})",
+                                value,
+                                encoding.as_str(),
+                                bytes.len(),
+                                decoded
+                            )
+                        }
+                    };
+                    self.print_output(
+                        text,
+                        json!({ "key": key, "value": value, "encoding": encoding.as_str() }),
+                    );
+                    Ok(())
+                }
+                None => Err(ShellError::Failed(format!(
+                    "Key not found '{}' for account {}",
+                    key, address
+                ))),
             },
-            None => println!("Error: Account not found {}", address),
+            None => Err(ShellError::Failed(format!("Account not found {}", address))),
+        }
+    }
+
+    async fn handle_balance_command(&self, args: Vec<&str>) -> Result<(), ShellError> {
+        let address = match args.get(1) {
+            Some(address) => crypto::canonical_address(address).map_err(ShellError::Failed)?,
+            None => self.current_address()?,
+        };
+        match self.state.read().await.get_account(&address) {
+            Some(account) => {
+                self.print_output(
+                    format!("Balance: {}", account.balance),
+                    json!({ "address": address, "balance": account.balance }),
+                );
+                Ok(())
+            }
+            None => Err(ShellError::Failed(format!("Account not found {}", address))),
+        }
+    }
+
+    async fn handle_mempool_command(&self, args: Vec<&str>) -> Result<(), ShellError> {
+        let address = match args.get(1) {
+            Some(address) => crypto::canonical_address(address).map_err(ShellError::Failed)?,
+            None => self.current_address()?,
+        };
+        let state_nonce = self
+            .state
+            .read()
+            .await
+            .get_account(&address)
+            .map(|s| s.nonce)
+            .unwrap_or(0);
+        let account = external_account_address(&address).map_err(ShellError::Failed)?;
+        let queued = self.mempool.queued_nonces(&account);
+        // The water mark only counts transactions queued since the node started,
+        // so nonces already committed are not missing.
+        let missing = self
+            .mempool
+            .nonce_gaps(&account)
+            .into_iter()
+            .filter(|nonce| *nonce >= state_nonce)
+            .collect::<Vec<_>>();
+
+        let join = |nonces: &[u64]| {
+            nonces
+                .iter()
+                .map(|nonce| nonce.to_string())
+                .collect::<Vec<_>>()
+                .join(", ")
+        };
+        let mut text = format!(
+            "Mempool: {} transactions queued\n{}: {} queued",
+            self.mempool.len(),
+            address,
+            queued.len()
+        );
+        if !queued.is_empty() {
+            text += &format!(" (nonces {})", join(&queued));
+        }
+        if !missing.is_empty() {
+            text += &format!(
+                "\n  missing nonces: {}, resend them to unblock the rest",
+                join(&missing)
+            );
         }
+        self.print_output(
+            text,
+            json!({
+                "total": self.mempool.len(),
+                "address": address,
+                "state_nonce": state_nonce,
+                "queued": queued,
+                "missing": missing,
+            }),
+        );
+        Ok(())
     }
 
-    async fn handle_query_txn_command(&self, args: Vec<&str>) {
+    async fn handle_keys_command(&self, args: Vec<&str>) -> Result<(), ShellError> {
+        let mut address = None;
+        let mut prefix = None;
+        let mut verbose = false;
+        let mut iter = args.iter().skip(1);
+        while let Some(arg) = iter.next() {
+            match *arg {
+                "-v" => verbose = true,
+                "--prefix" => match iter.next() {
+                    Some(p) => prefix = Some(*p),
+                    None => {
+                        return Err(ShellError::Usage("keys [address] [-v] [--prefix <prefix>]"))
+                    }
+                },
+                _ => address = Some(crypto::canonical_address(arg).map_err(ShellError::Failed)?),
+            }
+        }
+
+        let address = match address {
+            Some(address) => address,
+            None => self.current_address()?,
+        };
+
+        match self.state.read().await.get_account(&address) {
+            Some(account) => {
+                let entries = filter_keys(&account.kv_store, prefix);
+                if entries.is_empty() {
+                    println!("No keys found for account {}", address);
+                    return Ok(());
+                }
+                for (key, value) in entries {
+                    if verbose {
+                        println!("{} = {}", key, value);
+                    } else {
+                        println!("{}", key);
+                    }
+                }
+                Ok(())
+            }
+            None => Err(ShellError::Failed(format!("Account not found {}", address))),
+        }
+    }
+
+    async fn handle_query_txn_command(&self, args: Vec<&str>) -> Result<(), ShellError> {
         if args.len() < 2 {
-            println!("Usage: query_txn <txn_hash>");
-            return;
+            return Err(ShellError::Usage("query_txn <txn_hash> [sender]"));
+        }
+        let txn_hash = parse_hash(args[1])?;
+        let mut receipts = self.storage.get_transaction_receipts(txn_hash).await?;
+        // Transactions signed by different senders over the same payload share a hash.
+        if let Some(sender) = args.get(2) {
+            let sender = crypto::canonical_address(sender).map_err(ShellError::Failed)?;
+            receipts.retain(|receipt| receipt.sender().is_ok_and(|signer| signer.0 == sender));
         }
-        let res = hex::decode(args[1]);
-        if res.is_err() {
-            println!("Error: Invalid transaction hash: {}", res.err().unwrap());
-            return;
+        if receipts.len() > 1 {
+            let senders = receipts
+                .iter()
+                .filter_map(|receipt| receipt.sender().ok())
+                .map(|sender| sender.0)
+                .collect::<Vec<_>>();
+            return Err(ShellError::Failed(format!(
+                "Several transactions have this hash, pass one of their senders: {}",
+                senders.join(", ")
+            )));
         }
-        let mut txn_hash = [0u8; 32];
-        txn_hash.copy_from_slice(res.unwrap().as_slice());
-        let res = self.storage.get_transaction_receipt(txn_hash).await;
-        match res {
-            Ok(Some(receipt)) => println!("Transaction receipt: {:?}", receipt),
-            Ok(None) => println!("Transaction receipt not found"),
-            Err(e) => println!("Error: {}", e),
+        match receipts.pop() {
+            Some(receipt) => {
+                self.print_output(
+                    format!("Transaction receipt: {:?}", receipt),
+                    json!({
+                        "transaction_hash": hex::encode(receipt.transaction_hash),
+                        "status": receipt.status,
+                        "gas_used": receipt.gas_used,
+                        "nonce": receipt.transaction.unsigned.nonce,
+                        "kind": receipt.transaction.unsigned.kind,
+                        "memo": receipt.transaction.unsigned.memo,
+                        "logs": receipt.logs,
+                        "signer_public_key": receipt.signer_public_key,
+                    }),
+                );
+                Ok(())
+            }
+            None => Err(ShellError::Failed(
+                "Transaction receipt not found".to_string(),
+            )),
         }
     }
 
+    /// Checks offline that a signed transaction was signed by `address`.
+    fn handle_verify_command(&self, args: Vec<&str>) -> Result<(), ShellError> {
+        const USAGE: &str = "verify <address> <txn_json>";
+        if args.len() < 3 {
+            return Err(ShellError::Usage(USAGE));
+        }
+        let address = crypto::canonical_address(args[1]).map_err(ShellError::Failed)?;
+        // The JSON was split on whitespace along with the rest of the line.
+        let transaction: Transaction = serde_json::from_str(&args[2..].join(" "))
+            .map_err(|e| ShellError::Failed(format!("Invalid transaction JSON: {}", e)))?;
+        let signer = crypto::verify_signature(&transaction)
+            .map_err(|e| ShellError::Failed(format!("Invalid signature: {}", e)))?;
+        if signer.0 != address {
+            return Err(ShellError::Failed(format!(
+                "Signature does not match: signed by {}, not {}",
+                signer.0, address
+            )));
+        }
+        self.print_output(
+            format!("Signature valid: signed by {}", address),
+            json!({ "valid": true, "address": address }),
+        );
+        Ok(())
+    }
+
+    async fn handle_diff_command(&self, args: Vec<&str>) -> Result<(), ShellError> {
+        const USAGE: &str = "diff <from> <to>";
+        let (from, to) = match (args.get(1), args.get(2)) {
+            (Some(from), Some(to)) => (
+                from.parse::<u64>().map_err(|_| ShellError::Usage(USAGE))?,
+                to.parse::<u64>().map_err(|_| ShellError::Usage(USAGE))?,
+            ),
+            _ => return Err(ShellError::Usage(USAGE)),
+        };
+
+        let diffs = self.storage.state_diff(from, to).await?;
+        let text = if diffs.is_empty() {
+            format!("No accounts changed in blocks {} to {}", from, to)
+        } else {
+            diffs
+                .iter()
+                .map(|diff| {
+                    let show = |value: Option<String>| value.unwrap_or_else(|| "-".to_string());
+                    let mut text = format!(
+                        "{}
+  balance: {} -> {}
+  nonce:   {} -> {}",
+                        diff.address,
+                        show(
+                            diff.before
+                                .as_ref()
+                                .map(|account| account.balance.to_string())
+                        ),
+                        diff.after.balance,
+                        show(
+                            diff.before
+                                .as_ref()
+                                .map(|account| account.nonce.to_string())
+                        ),
+                        diff.after.nonce
+                    );
+                    for key in &diff.changed_keys {
+                        text += &format!(
+                            "
+  {}: {} -> {}",
+                            key.key,
+                            show(key.before.clone()),
+                            show(key.after.clone())
+                        );
+                    }
+                    text
+                })
+                .collect::<Vec<_>>()
+                .join("\n")
+        };
+        self.print_output(text, json!({ "from": from, "to": to, "accounts": diffs }));
+        Ok(())
+    }
+
+    /// Prints every block committed from now on until Ctrl+C.
+    async fn handle_watch_command(&self) -> Result<(), ShellError> {
+        let mut receiver = self.blockchain.subscribe_blocks();
+        // Registered before `watching` is set, so no interrupt can be missed.
+        let interrupted = self.interrupt.notify.notified();
+        tokio::pin!(interrupted);
+        interrupted.as_mut().enable();
+        self.interrupt.watching.store(true, Ordering::SeqCst);
+        println!("Watching new blocks, press Ctrl+C to stop.");
+
+        let result = loop {
+            tokio::select! {
+                _ = interrupted.as_mut() => break Ok(()),
+                received = receiver.recv() => match received {
+                    Ok(summary) => self.print_block_summary(&summary),
+                    Err(RecvError::Lagged(skipped)) => {
+                        println!("Skipped {} blocks", skipped);
+                    }
+                    Err(RecvError::Closed) => {
+                        let message = "The node stopped committing blocks".to_string();
+                        break Err(ShellError::Failed(message));
+                    }
+                },
+            }
+        };
+        self.interrupt.watching.store(false, Ordering::SeqCst);
+        println!();
+        result
+    }
+
+    fn print_block_summary(&self, summary: &BlockSummary) {
+        let text = format!(
+            "Block {}: {} transactions, state_root {}, timestamp (usecs) {}",
+            summary.block_number, summary.txn_count, summary.state_root, summary.timestamp
+        );
+        self.print_output(text, json!(summary));
+    }
+
+    async fn handle_query_block_command(&self, args: Vec<&str>) -> Result<(), ShellError> {
+        if args.len() < 2 {
+            return Err(ShellError::Usage("query_block <number>"));
+        }
+        let number = args[1]
+            .parse::<u64>()
+            .map_err(|_| ShellError::Usage("query_block <number>"))?;
+        match self.storage.get_block_by_number(number).await {
+            Ok(Some(block)) => {
+                let hashes = block
+                    .transactions
+                    .iter()
+                    .map(|txn| hex::encode(crypto::compute_transaction_hash(&txn.txn.unsigned)))
+                    .collect::<Vec<_>>();
+                let mut text = format!("Block {}\n", block.header.number);
+                text += &format!(
+                    "  parent_state_root: {}\n",
+                    hex::encode(block.header.parent_state_root)
+                );
+                text += &format!(
+                    "  state_root:        {}\n",
+                    hex::encode(block.header.state_root)
+                );
+                text += &format!(
+                    "  txn_root:          {}\n",
+                    hex::encode(block.header.txn_root)
+                );
+                text += &format!("  timestamp (usecs): {}\n", block.header.usecs);
+                text += &format!("  transactions:      {}", hashes.len());
+                for hash in &hashes {
+                    text += &format!("\n    {}", hash);
+                }
+                self.print_output(
+                    text,
+                    json!({
+                        "number": block.header.number,
+                        "parent_state_root": hex::encode(block.header.parent_state_root),
+                        "state_root": hex::encode(block.header.state_root),
+                        "txn_root": hex::encode(block.header.txn_root),
+                        "timestamp": block.header.usecs,
+                        "transactions": hashes,
+                    }),
+                );
+                Ok(())
+            }
+            Ok(None) => Err(ShellError::Failed(format!("Block {} not found", number))),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    async fn handle_state_root_command(&self, args: Vec<&str>) -> Result<(), ShellError> {
+        let number = match args.get(1).map(|arg| arg.parse::<u64>()) {
+            Some(Ok(number)) => number,
+            _ => return Err(ShellError::Usage("state_root <block>")),
+        };
+        match self.storage.get_state_root(number).await {
+            Ok(Some(root)) => {
+                self.print_output(
+                    format!("State root at block {}: {}", number, root.to_hex()),
+                    json!({ "block_number": number, "state_root": root.to_hex() }),
+                );
+                Ok(())
+            }
+            Ok(None) => Err(ShellError::Failed(format!(
+                "No state root stored for block {}",
+                number
+            ))),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    async fn handle_history_command(&self, args: Vec<&str>) -> Result<(), ShellError> {
+        const USAGE: &str = "history [address] [--limit <n>] [--offset <n>]";
+        let mut address = None;
+        let mut limit = 10;
+        let mut offset = 0;
+        let mut iter = args.iter().skip(1);
+        while let Some(arg) = iter.next() {
+            match *arg {
+                "--limit" => {
+                    limit = iter
+                        .next()
+                        .and_then(|n| n.parse().ok())
+                        .ok_or(ShellError::Usage(USAGE))?
+                }
+                "--offset" => {
+                    offset = iter
+                        .next()
+                        .and_then(|n| n.parse().ok())
+                        .ok_or(ShellError::Usage(USAGE))?
+                }
+                _ => address = Some(crypto::canonical_address(arg).map_err(ShellError::Failed)?),
+            }
+        }
+
+        let address = match address {
+            Some(address) => address,
+            None => self.current_address()?,
+        };
+
+        let receipts = self
+            .storage
+            .get_account_transactions(&address, limit, offset)
+            .await?;
+        let text = if receipts.is_empty() {
+            format!("No transactions found for account {}", address)
+        } else {
+            receipts
+                .iter()
+                .map(|receipt| {
+                    format!(
+                        "{} nonce {} {} {:?}",
+                        hex::encode(receipt.transaction_hash),
+                        receipt.transaction.unsigned.nonce,
+                        if receipt.status { "ok" } else { "failed" },
+                        receipt.transaction.unsigned.kind
+                    )
+                })
+                .collect::<Vec<_>>()
+                .join("\n")
+        };
+        let entries = receipts
+            .iter()
+            .map(|receipt| {
+                json!({
+                    "transaction_hash": hex::encode(receipt.transaction_hash),
+                    "nonce": receipt.transaction.unsigned.nonce,
+                    "status": receipt.status,
+                    "kind": receipt.transaction.unsigned.kind,
+                })
+            })
+            .collect::<Vec<_>>();
+        self.print_output(text, json!({ "address": address, "transactions": entries }));
+        Ok(())
+    }
+
     fn print_help(&self) {
         println!("Available commands:");
-        println!("  user <private_key_hex>   - Switch user context by providing a private key.");
-        println!("  set <key> <value>        - Set a key-value pair for the current user.");
+        println!("  user <private_key_hex> [--ed25519] | user --keystore <path>");
+        println!("                           - Switch user context by providing a private key.");
+        println!("  user-mnemonic \"<words>\" [--passphrase <passphrase>]");
+        println!("                           - Switch user context using a BIP39 mnemonic.");
+        println!("  keygen [--save <path>]   - Switch to a new key, optionally saved encrypted.");
+        println!("  keyload <path>           - Switch to a key saved with keygen --save.");
+        println!("  set <key> <value> [--expires-at <block> | --encoding <utf8|base64|hex>]");
+        println!("                           - Set a key-value pair for the current user.");
+        println!(
+            "  add <key> <delta>        - Add a signed integer to a counter key, 0 if absent."
+        );
+        println!("  set_for <address> <key> <value>");
+        println!("                           - Set a key-value pair in another account that granted access.");
+        println!(
+            "  grant <address>          - Allow an account to write into the current user's store."
+        );
+        println!("  revoke <address>         - Withdraw a write access given with grant.");
+        println!("  faucet <address> <amount>");
+        println!(
+            "                           - Mint funds to an address. The user must be the faucet."
+        );
+        println!("  resend <txn_hash>        - Re-sign a transaction stuck behind a nonce gap.");
         println!("  get <key>                - Get a value for a key for the current user.");
-        println!("  query_txn <txn_hash>     - Query the status of a transaction (not implemented yet).");
+        println!("  balance [address]        - Show the balance of an account.");
+        println!("  keys [address] [-v] [--prefix <prefix>]");
+        println!(
+            "                           - List keys (and values with -v) of an account, sorted."
+        );
+        println!("  query_txn <txn_hash> [sender]");
+        println!("                           - Query the status of a transaction.");
+        println!("  query_block <number>     - Show a block header and its transaction hashes.");
+        println!("  state_root <block>       - Show the state root committed at a block.");
+        println!("  history [address] [--limit <n>] [--offset <n>]");
+        println!("                           - List an account's transactions, newest first.");
+        println!("  mempool [address]        - Show an account's queued and missing nonces.");
+        println!(
+            "  diff <from> <to>         - List the accounts and keys changed in a block range."
+        );
+        println!("  verify <address> <txn_json>");
+        println!("                           - Check that a signed transaction was signed by an address.");
+        println!(
+            "  watch                    - Print each new block as it is committed, until Ctrl+C."
+        );
+        println!("  format <text|json>       - Print results and errors as text or JSON.");
         println!("  help                     - Show this help message.");
         println!("  exit                     - Exit the shell.");
     }
 }
+
+/// The history entry to record for `line`, or `None` if it starts with one of
+/// the `SENSITIVE_COMMANDS`.
+pub fn history_entry(line: &str) -> Option<&str> {
+    match line.split_whitespace().next() {
+        Some(command) if SENSITIVE_COMMANDS.contains(&command) => None,
+        _ => Some(line),
+    }
+}
+
+/// Loads the history saved at `path`. A missing file starts an empty history,
+/// and an unreadable or corrupt one is set aside with a warning so the shell
+/// still starts, and is overwritten on exit.
+fn load_history(rl: &mut DefaultEditor, path: &str) {
+    match rl.load_history(path) {
+        Ok(()) => {}
+        Err(ReadlineError::Io(e)) if e.kind() == std::io::ErrorKind::NotFound => {
+            println!("No previous history.");
+        }
+        Err(e) => {
+            println!("Warning: ignoring history file {}: {}", path, e);
+            let _ = rl.clear_history();
+        }
+    }
+}
+
+/// Decrypts the keystore file at `path` with a passphrase read from the terminal.
+fn load_keystore(path: &str) -> Result<AccountKey, ShellError> {
+    let keystore = Keystore::load(path).map_err(ShellError::Failed)?;
+    let passphrase = read_passphrase(&format!("Passphrase for {}: ", keystore.address))?;
+    keystore.decrypt(&passphrase).map_err(ShellError::Failed)
+}
+
+/// Reads a passphrase from the terminal without echoing it.
+fn read_passphrase(prompt: &str) -> Result<String, ShellError> {
+    rpassword::prompt_password(prompt)
+        .map_err(|e| ShellError::Failed(format!("Failed to read passphrase: {}", e)))
+}
+
+fn parse_hash(hash: &str) -> Result<[u8; 32], ShellError> {
+    let bytes = hex::decode(hash)
+        .map_err(|e| ShellError::Failed(format!("Invalid transaction hash: {}", e)))?;
+    bytes
+        .try_into()
+        .map_err(|_| ShellError::Failed("Invalid transaction hash: expected 32 bytes".to_string()))
+}
+
+/// Returns the entries of `kv_store` whose key starts with `prefix`, sorted by key.
+pub fn filter_keys<'a>(
+    kv_store: &'a BTreeMap<String, String>,
+    prefix: Option<&str>,
+) -> Vec<(&'a String, &'a String)> {
+    kv_store
+        .iter()
+        .filter(|(key, _)| prefix.map_or(true, |p| key.starts_with(p)))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        compute_transaction_hash, test_utils::*, ExecutorConfig, MemStorage, RecentTxns,
+        TransactionBuilder,
+    };
+
+    /// A shell over an empty chain, signing as `key`.
+    fn shell(key: AccountKey) -> Shell {
+        let blockchain =
+            Arc::new(Blockchain::new(Arc::new(MemStorage::new()), None, config()).unwrap());
+        let mut shell = Shell::new(blockchain, mempool(), CHAIN_ID, false);
+        shell.key = Some(key);
+        shell
+    }
+
+    fn hash_of(txn: &crate::TransactionWithAccount) -> [u8; 32] {
+        compute_transaction_hash(&txn.txn.unsigned)
+    }
+
+    #[tokio::test]
+    async fn resend_moves_a_stuck_transaction_to_the_missing_nonce() {
+        let alice = new_key();
+        let account = external_account_address(&alice.address()).unwrap();
+        let stuck = sign(&alice, 2, set_kv("key", "value"));
+        // Same payload at the first missing nonce, so the same hash.
+        let resent = sign(&alice, 0, set_kv("key", "value"));
+        let mut shell = shell(alice);
+        shell.mempool.add_raw_txn(stuck.clone()).unwrap();
+
+        let hash = hex::encode(hash_of(&stuck));
+        shell
+            .handle_resend_command(vec!["resend", &hash])
+            .await
+            .unwrap();
+
+        assert_eq!(shell.mempool.queued_nonces(&account), [0]);
+        let (_, status) = shell.mempool.get_txn(&hash_of(&resent)).unwrap();
+        assert_eq!(status, TxnStatus::Pending);
+        assert!(shell.mempool.get_txn(&hash_of(&stuck)).is_none());
+    }
+
+    #[tokio::test]
+    async fn refused_resend_leaves_the_original_queued() {
+        let alice = new_key();
+        let account = external_account_address(&alice.address()).unwrap();
+        let pending = sign(&alice, 0, set_kv("key", "value"));
+        let stuck = sign(&alice, 2, set_kv("key", "other"));
+        let mut shell = shell(alice);
+        shell.mempool.add_raw_txn(pending.clone()).unwrap();
+        shell.mempool.add_raw_txn(stuck.clone()).unwrap();
+
+        // A pending transaction is not stuck.
+        let hash = hex::encode(hash_of(&pending));
+        assert!(shell
+            .handle_resend_command(vec!["resend", &hash])
+            .await
+            .is_err());
+        assert_eq!(shell.mempool.queued_nonces(&account), [0, 2]);
+
+        // Nor is a committed one.
+        commit_block(
+            shell.storage.as_ref(),
+            &mut *shell.state.write().await,
+            &config(),
+            &mut RecentTxns::new(0),
+            vec![pending.clone()],
+        )
+        .await;
+        assert!(shell
+            .handle_resend_command(vec!["resend", &hash])
+            .await
+            .is_err());
+        assert_eq!(shell.mempool.queued_nonces(&account), [0, 2]);
+
+        // Only the sender may resend.
+        shell.key = Some(new_key());
+        let hash = hex::encode(hash_of(&stuck));
+        assert!(shell
+            .handle_resend_command(vec!["resend", &hash])
+            .await
+            .is_err());
+        assert_eq!(shell.mempool.queued_nonces(&account), [0, 2]);
+    }
+
+    /// Makes `shell` print into the returned buffer, one entry per line.
+    fn capture_output(shell: &mut Shell) -> Arc<std::sync::Mutex<Vec<String>>> {
+        let lines = Arc::new(std::sync::Mutex::new(vec![]));
+        let printed = lines.clone();
+        shell.print = Box::new(move |line| printed.lock().unwrap().push(line.to_string()));
+        lines
+    }
+
+    /// Runs `line` as a command, printing its error as the shell loop does.
+    async fn run(shell: &mut Shell, line: &str) {
+        let args = line.split_whitespace().collect::<Vec<_>>();
+        if let Err(e) = shell.handle_command(args).await {
+            shell.print_error(&e);
+        }
+    }
+
+    /// The JSON of the last line `shell` printed.
+    fn last_json(lines: &std::sync::Mutex<Vec<String>>) -> serde_json::Value {
+        serde_json::from_str(lines.lock().unwrap().last().unwrap()).unwrap()
+    }
+
+    #[tokio::test]
+    async fn transactions_are_signed_for_the_shell_chain_id() {
+        let alice = new_key();
+        let expected = TransactionBuilder::new(&alice, 7, 0).build(set_kv("key", "value"));
+        let blockchain =
+            Arc::new(Blockchain::new(Arc::new(MemStorage::new()), None, config()).unwrap());
+        let mut shell = Shell::new(blockchain, mempool(), 7, false);
+        shell.key = Some(alice);
+        run(&mut shell, "set key value").await;
+
+        let (queued, _) = shell.mempool.get_txn(&hash_of(&expected)).unwrap();
+        assert_eq!(queued.txn.unsigned.chain_id, 7);
+        // Only a node on the same chain accepts it.
+        let (_, receipts) = execute_block(
+            &mut State::new(None).unwrap(),
+            &config(),
+            &mut RecentTxns::new(0),
+            vec![queued.clone()],
+        );
+        assert!(!receipts[0].status);
+        let chain_seven = ExecutorConfig {
+            chain_id: 7,
+            ..config()
+        };
+        let (_, receipts) = execute_block(
+            &mut State::new(None).unwrap(),
+            &chain_seven,
+            &mut RecentTxns::new(0),
+            vec![queued],
+        );
+        assert!(receipts[0].status);
+    }
+
+    #[tokio::test]
+    async fn watch_prints_each_published_block_until_interrupted() {
+        let mut shell = shell(new_key());
+        let lines = capture_output(&mut shell);
+        run(&mut shell, "format json").await;
+        let blockchain = shell.blockchain.clone();
+        let interrupt = shell.interrupt();
+        assert!(!interrupt.interrupt());
+
+        let mut state = State::new(None).unwrap();
+        let mut recent_txns = RecentTxns::new(0);
+        let blocks = (0..2)
+            .map(|_| {
+                let txns = vec![sign(&new_key(), 0, set_kv("key", "value"))];
+                execute_block(&mut state, &config(), &mut recent_txns, txns).0
+            })
+            .collect::<Vec<_>>();
+        let drive = async {
+            // `watching` is set once the command is subscribed.
+            while !interrupt.watching.load(Ordering::SeqCst) {
+                tokio::task::yield_now().await;
+            }
+            for block in &blocks {
+                blockchain.publish_block(block);
+            }
+            while lines.lock().unwrap().len() < blocks.len() {
+                tokio::task::yield_now().await;
+            }
+            assert!(interrupt.interrupt());
+        };
+        let watch = async { tokio::join!(shell.handle_watch_command(), drive).0 };
+        tokio::time::timeout(std::time::Duration::from_secs(5), watch)
+            .await
+            .unwrap()
+            .unwrap();
+
+        let printed = lines
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|line| serde_json::from_str::<serde_json::Value>(line).unwrap())
+            .collect::<Vec<_>>();
+        let expected = blocks
+            .iter()
+            .map(|block| json!(BlockSummary::from(block)))
+            .collect::<Vec<_>>();
+        assert_eq!(printed, expected);
+        // Back at the prompt, Ctrl+C stops the node again.
+        assert!(!interrupt.interrupt());
+    }
+
+    #[tokio::test]
+    async fn base64_values_are_stored_with_their_encoding_and_shown_decoded() {
+        let alice = new_key();
+        let same_key = AccountKey::from_hex(
+            &hex::encode(alice.secret_bytes()),
+            SignatureScheme::Secp256k1,
+        )
+        .unwrap();
+        let base64 = |key: &str, value: &str| TransactionKind::SetKVEncoded {
+            key: key.to_string(),
+            value: value.to_string(),
+            encoding: ValueEncoding::Base64,
+        };
+        let mut shell = shell(alice);
+        let lines = capture_output(&mut shell);
+        run(&mut shell, "set bin AP8= --encoding base64").await;
+        run(&mut shell, "set text aGk= --encoding base64").await;
+        let txns = [
+            sign(&same_key, 0, base64("bin", "AP8=")),
+            sign(&same_key, 1, base64("text", "aGk=")),
+            // Not valid base64, so it fails without storing anything.
+            sign(&same_key, 2, base64("bad", "not base64!")),
+        ];
+        let queued = txns[..2]
+            .iter()
+            .map(|txn| shell.mempool.get_txn(&hash_of(txn)).unwrap().0)
+            .collect::<Vec<_>>();
+        let receipts = commit_block(
+            shell.storage.as_ref(),
+            &mut *shell.state.write().await,
+            &config(),
+            &mut RecentTxns::new(0),
+            queued.into_iter().chain([txns[2].clone()]).collect(),
+        )
+        .await;
+        let statuses = receipts
+            .iter()
+            .map(|receipt| receipt.status)
+            .collect::<Vec<_>>();
+        assert_eq!(statuses, [true, true, false]);
+
+        run(&mut shell, "get bin").await;
+        assert_eq!(
+            lines.lock().unwrap().last().unwrap(),
+            "Value: AP8= (base64, 2 bytes: 0x00ff)"
+        );
+        run(&mut shell, "get text").await;
+        assert_eq!(
+            lines.lock().unwrap().last().unwrap(),
+            "Value: aGk= (base64, 2 bytes: hi)"
+        );
+        run(&mut shell, "get bad").await;
+        assert!(lines
+            .lock()
+            .unwrap()
+            .last()
+            .unwrap()
+            .starts_with("Error: Key not found"));
+        run(&mut shell, "format json").await;
+        run(&mut shell, "get bin").await;
+        assert_eq!(
+            last_json(&lines),
+            json!({ "key": "bin", "value": "AP8=", "encoding": "base64" })
+        );
+    }
+
+    #[tokio::test]
+    async fn verify_checks_the_signer_against_the_claimed_address() {
+        let alice = new_key();
+        let address = alice.address();
+        let txn = sign(&alice, 0, set_kv("key", "a value with spaces")).txn;
+        let mut shell = shell(new_key());
+        let lines = capture_output(&mut shell);
+        run(&mut shell, "format json").await;
+
+        let json = serde_json::to_string(&txn).unwrap();
+        run(&mut shell, &format!("verify 0x{} {}", address, json)).await;
+        assert_eq!(
+            last_json(&lines),
+            json!({ "valid": true, "address": address })
+        );
+
+        let stranger = new_key().address();
+        run(&mut shell, &format!("verify {} {}", stranger, json)).await;
+        let error = last_json(&lines)["error"].as_str().unwrap().to_string();
+        assert!(error.starts_with("Signature does not match"), "{}", error);
+
+        let mut tampered = txn.clone();
+        tampered.unsigned.nonce = 1;
+        let json = serde_json::to_string(&tampered).unwrap();
+        run(&mut shell, &format!("verify {} {}", address, json)).await;
+        let error = last_json(&lines)["error"].as_str().unwrap().to_string();
+        assert!(
+            error.starts_with("Signature does not match") || error.starts_with("Invalid signature"),
+            "{}",
+            error
+        );
+
+        run(&mut shell, &format!("verify {} not-json", address)).await;
+        let error = last_json(&lines)["error"].as_str().unwrap().to_string();
+        assert!(error.starts_with("Invalid transaction JSON"), "{}", error);
+    }
+
+    #[tokio::test]
+    async fn json_format_prints_results_and_errors_as_objects() {
+        let alice = new_key();
+        let txn = sign(&alice, 0, set_kv("k", "v"));
+        let mut shell = shell(alice);
+        commit_block(
+            shell.storage.as_ref(),
+            &mut *shell.state.write().await,
+            &config(),
+            &mut RecentTxns::new(0),
+            vec![txn.clone()],
+        )
+        .await;
+        let lines = capture_output(&mut shell);
+
+        run(&mut shell, "get k").await;
+        assert_eq!(lines.lock().unwrap().last().unwrap(), "Value: v");
+        run(&mut shell, "format json").await;
+        run(&mut shell, "get k").await;
+        assert_eq!(
+            last_json(&lines),
+            json!({ "key": "k", "value": "v", "encoding": "utf8" })
+        );
+
+        let hash = hex::encode(hash_of(&txn));
+        run(&mut shell, &format!("query_txn {}", hash)).await;
+        let receipt = last_json(&lines);
+        assert_eq!(receipt["transaction_hash"], hash);
+        assert_eq!(receipt["status"], true);
+        assert_eq!(receipt["nonce"], 0);
+        assert!(receipt["gas_used"].as_u64().unwrap() > 0);
+        assert_eq!(
+            receipt["kind"],
+            serde_json::to_value(&txn.txn.unsigned.kind).unwrap()
+        );
+        for field in ["memo", "logs", "signer_public_key"] {
+            assert!(receipt.get(field).is_some(), "{}", field);
+        }
+
+        run(&mut shell, "get missing").await;
+        let error = last_json(&lines);
+        assert!(error["error"]
+            .as_str()
+            .unwrap()
+            .starts_with("Key not found"));
+        run(&mut shell, &format!("query_txn {}", "00".repeat(32))).await;
+        assert_eq!(
+            last_json(&lines),
+            json!({ "error": "Transaction receipt not found" })
+        );
+        run(&mut shell, "get").await;
+        assert_eq!(last_json(&lines), json!({ "error": "Usage: get <key>" }));
+    }
+
+    #[tokio::test]
+    async fn faucet_queues_a_mint_to_the_canonical_address() {
+        let (faucet, alice) = (new_key(), new_key());
+        let account = external_account_address(&faucet.address()).unwrap();
+        let expected = sign(
+            &faucet,
+            0,
+            TransactionKind::Mint {
+                receiver: alice.address(),
+                amount: 50,
+            },
+        );
+        let mut shell = shell(faucet);
+
+        let receiver = format!("0x{}", alice.address().to_uppercase());
+        shell
+            .handle_faucet_command(vec!["faucet", &receiver, "50"])
+            .await
+            .unwrap();
+        assert_eq!(shell.mempool.queued_nonces(&account), [0]);
+        assert!(shell.mempool.get_txn(&hash_of(&expected)).is_some());
+
+        for args in [vec!["faucet", &receiver], vec!["faucet", &receiver, "-1"]] {
+            assert!(matches!(
+                shell.handle_faucet_command(args).await,
+                Err(ShellError::Usage(_))
+            ));
+        }
+        assert!(shell
+            .handle_faucet_command(vec!["faucet", "0x12", "50"])
+            .await
+            .is_err());
+    }
+
+    /// Writes `lines` to a fresh script file and returns its path.
+    fn script(lines: &[String]) -> String {
+        let path = temp_dir().with_extension("txt");
+        std::fs::write(&path, lines.join("\n")).unwrap();
+        path.to_str().unwrap().to_string()
+    }
+
+    #[tokio::test]
+    async fn script_stops_at_the_first_failing_command_unless_told_to_continue() {
+        let alice = new_key();
+        let account = external_account_address(&alice.address()).unwrap();
+        let lines = [
+            "# Sets two keys as Alice.".to_string(),
+            format!("user {}", hex::encode(alice.secret_bytes())),
+            String::new(),
+            "set a 1".to_string(),
+            "bogus".to_string(),
+            "set b 2".to_string(),
+            "exit".to_string(),
+            "set c 3".to_string(),
+        ];
+        let path = script(&lines);
+
+        let mut stopping = shell(new_key());
+        let error = stopping.run_script(&path, false).await.unwrap_err();
+        assert_eq!(error.to_string(), "Error: Script stopped at line 5");
+        assert_eq!(stopping.mempool.queued_nonces(&account), [0]);
+
+        let mut continuing = shell(new_key());
+        continuing.run_script(&path, true).await.unwrap();
+        // `exit` ends the script before its last line.
+        assert_eq!(continuing.mempool.queued_nonces(&account), [0, 1]);
+        assert!(continuing.exit_requested);
+
+        std::fs::remove_file(&path).unwrap();
+        assert!(continuing.run_script(&path, true).await.is_err());
+    }
+
+    #[test]
+    fn filter_keys_keeps_keys_with_the_prefix_in_order() {
+        let kv_store = [
+            ("user:2", "b"),
+            ("order:1", "c"),
+            ("user:1", "a"),
+            ("use", "d"),
+        ]
+        .into_iter()
+        .map(|(key, value)| (key.to_string(), value.to_string()))
+        .collect::<BTreeMap<_, _>>();
+        let keys = |prefix| {
+            filter_keys(&kv_store, prefix)
+                .into_iter()
+                .map(|(key, _)| key.as_str())
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(keys(Some("user:")), ["user:1", "user:2"]);
+        assert_eq!(keys(Some("use")), ["use", "user:1", "user:2"]);
+        assert_eq!(keys(Some("missing")), Vec::<&str>::new());
+        assert_eq!(keys(Some("")), ["order:1", "use", "user:1", "user:2"]);
+        assert_eq!(keys(None), ["order:1", "use", "user:1", "user:2"]);
+        assert_eq!(filter_keys(&kv_store, Some("order"))[0].1, "c");
+    }
+
+    #[test]
+    fn sensitive_commands_are_not_recorded() {
+        let key = "4c0883a69102937d6231471b5dbb6204fe5129617082792ae468d01a3f362318";
+        for line in [
+            format!("user {}", key),
+            format!("  user   {}", key),
+            "user-mnemonic abandon abandon about".to_string(),
+            "keygen wallet.json".to_string(),
+            "keyload wallet.json".to_string(),
+        ] {
+            assert_eq!(history_entry(&line), None, "{}", line);
+        }
+    }
+
+    #[test]
+    fn other_commands_are_recorded_verbatim() {
+        for line in ["set key value", "get key", "users", "exit"] {
+            assert_eq!(history_entry(line), Some(line));
+        }
+    }
+
+    #[test]
+    fn missing_or_corrupt_history_starts_empty_and_is_overwritten() {
+        use rustyline::history::History;
+        let path = temp_dir().with_extension("txt");
+        let path = path.to_str().unwrap();
+
+        let mut rl = DefaultEditor::new().unwrap();
+        load_history(&mut rl, path);
+        assert!(rl.history().is_empty());
+        rl.add_history_entry("set key value").unwrap();
+        rl.add_history_entry("get key").unwrap();
+        rl.save_history(path).unwrap();
+        let mut rl = DefaultEditor::new().unwrap();
+        load_history(&mut rl, path);
+        assert_eq!(rl.history().len(), 2);
+
+        // A valid line before the corrupt one is dropped as well.
+        let mut corrupt = std::fs::read(path).unwrap();
+        corrupt.extend_from_slice(b"\xff\xfe\n");
+        std::fs::write(path, corrupt).unwrap();
+        let mut rl = DefaultEditor::new().unwrap();
+        load_history(&mut rl, path);
+        assert!(rl.history().is_empty());
+        rl.add_history_entry("get key").unwrap();
+        rl.save_history(path).unwrap();
+        let mut rl = DefaultEditor::new().unwrap();
+        load_history(&mut rl, path);
+        assert_eq!(rl.history().len(), 1);
+        std::fs::remove_file(path).unwrap();
+    }
+}
@@ -1,6 +1,9 @@
-use clap::Parser;
+use clap::{CommandFactory, Parser, Subcommand, ValueEnum};
 use gravity_sdk::api::GravityNodeArgs;
 use std::ffi::OsString;
+use std::net::{SocketAddr, ToSocketAddrs};
+
+use crate::TxnOrdering;
 
 /// This is the entrypoint to the executable.
 #[derive(Debug, Parser)]
@@ -15,11 +18,344 @@ pub struct Cli {
     #[arg(long = "genesis_path")]
     pub genesis_path: Option<String>,
 
-    #[arg(long = "listen_url")]
-    pub listen_url: String,
+    /// Timestamp, in microseconds since the Unix epoch, of the genesis block
+    /// persisted when the node starts on an empty database.
+    #[arg(long = "genesis_timestamp_usecs", default_value_t = 0)]
+    pub genesis_timestamp_usecs: u64,
+
+    /// Start even if recovery finds a block whose state root does not match the
+    /// stored one, from the last block that passed. Without it the node refuses
+    /// to start, naming the block, so a corrupt database is not built upon.
+    #[arg(long = "force_recover")]
+    pub force_recover: bool,
+
+    /// Address the HTTP server listens on, as `host:port`. IPv6 addresses go in
+    /// brackets, as in `[::1]:9006`.
+    #[arg(long = "listen_url", value_parser = parse_listen_addr)]
+    pub listen_url: SocketAddr,
 
     #[arg(long = "db_dir")]
     pub db_dir: String,
+
+    /// Chain id used by consensus and required in every signed transaction, so
+    /// a transaction signed for one chain is rejected by every other.
+    #[arg(long = "chain_id", default_value_t = 1337)]
+    pub chain_id: u64,
+
+    #[arg(long = "storage_backend", value_enum, default_value_t = StorageBackend::Sled)]
+    pub storage_backend: StorageBackend,
+
+    /// Balance given to an account the first time it sends a transaction.
+    /// Accounts listed in the genesis file keep their own balance.
+    #[arg(long = "default_balance", default_value_t = 0)]
+    pub default_balance: u64,
+
+    /// Only accounts from the genesis file may send transactions, and
+    /// transfers or mints to other accounts fail.
+    #[arg(long = "genesis_only")]
+    pub genesis_only: bool,
+
+    /// Fee charged per unit of gas, deducted from the sender of every transaction.
+    #[arg(long = "gas_price", default_value_t = 0)]
+    pub gas_price: u64,
+
+    /// Address allowed to mint funds with `Mint` transactions, e.g. via the
+    /// shell's `faucet` command. Minting is disabled when unset.
+    #[arg(long = "faucet_address")]
+    pub faucet_address: Option<String>,
+
+    /// Largest amount a single `Mint` may grant. Unlimited when unset.
+    #[arg(long = "faucet_max_amount")]
+    pub faucet_max_amount: Option<u64>,
+
+    /// Blocks an account must wait after receiving a `Mint` before it can
+    /// receive another. No cooldown when 0.
+    #[arg(long = "faucet_cooldown_blocks", default_value_t = 0)]
+    pub faucet_cooldown_blocks: u64,
+
+    /// Largest key, in bytes, a transaction may write or read.
+    #[arg(long = "max_key_bytes", default_value_t = 1024)]
+    pub max_key_bytes: usize,
+
+    /// Largest value, in bytes, a transaction may write or read.
+    #[arg(long = "max_value_bytes", default_value_t = 64 * 1024)]
+    pub max_value_bytes: usize,
+
+    /// Longest memo, in bytes, a transaction may carry to enter the mempool.
+    #[arg(long = "max_memo_bytes", default_value_t = 256)]
+    pub max_memo_bytes: usize,
+
+    /// Threads verifying and executing the transactions of a block in parallel,
+    /// including blocks replayed on startup, one per core by default. Blocks
+    /// themselves are always executed and committed in order.
+    #[arg(long = "executor_workers")]
+    pub executor_workers: Option<usize>,
+
+    /// Most transactions proposed for a block. Larger ordered blocks are still
+    /// executed, in chunks of this size, with a warning. Unbounded by default.
+    #[arg(long = "max_block_txns")]
+    pub max_block_txns: Option<usize>,
+
+    /// Most gas the transactions of a block may use together. Proposals stop
+    /// before the first transaction that would go over it, and larger ordered
+    /// blocks are cut short, leaving the rest in the mempool. Unbounded by default.
+    #[arg(long = "block_gas_limit")]
+    pub block_gas_limit: Option<u64>,
+
+    /// Order in which transactions of different accounts are proposed.
+    /// `round-robin` takes one transaction per account in turn.
+    #[arg(long = "mempool_ordering", value_enum, default_value_t = TxnOrdering::Sequential)]
+    pub mempool_ordering: TxnOrdering,
+
+    /// Committed blocks between flushes of the database to disk. Blocks
+    /// committed since the last flush may be lost if the node crashes.
+    #[arg(
+        long = "flush_every_n_blocks",
+        default_value_t = 1,
+        value_parser = clap::value_parser!(u64).range(1..)
+    )]
+    pub flush_every_n_blocks: u64,
+
+    /// Blocks behind the committed height after which a block is considered
+    /// final. Only final blocks are ever pruned. The hashes of transactions
+    /// executed within the window are remembered, and those transactions are
+    /// never executed again.
+    #[arg(long = "finality_window", default_value_t = 1000)]
+    pub finality_window: u64,
+
+    /// Prune final blocks, their receipts and state roots as new checkpoints
+    /// are persisted, keeping the last `finality_window` blocks.
+    #[arg(long = "auto_prune")]
+    pub auto_prune: bool,
+
+    /// Milliseconds to wait before polling the block buffer again when it is
+    /// empty. At least 1, so an idle node does not poll in a tight loop.
+    #[arg(
+        long = "poll_interval_ms",
+        default_value_t = 10,
+        value_parser = clap::value_parser!(u64).range(1..)
+    )]
+    pub poll_interval_ms: u64,
+
+    /// Cap in milliseconds on the wait between retries after block buffer errors,
+    /// which doubles from `poll_interval_ms` on every consecutive error.
+    #[arg(long = "max_backoff_ms", default_value_t = 1000)]
+    pub max_backoff_ms: u64,
+
+    /// Run as a read-only replica: follow the blocks another node persists to
+    /// `--primary_db_dir` instead of joining consensus. Requires the rocksdb
+    /// backend; `--db_dir` then only holds the replica's own bookkeeping files.
+    #[arg(long = "replica", requires = "primary_db_dir")]
+    pub replica: bool,
+
+    /// Database directory of the node a replica follows.
+    #[arg(long = "primary_db_dir")]
+    pub primary_db_dir: Option<String>,
+
+    /// Transactions each client IP may submit per second through `/tx` and
+    /// `/add_txn`, with bursts of up to as many. Further ones get a 429.
+    /// Unlimited by default.
+    #[arg(
+        long = "tx_rate_limit",
+        value_parser = clap::value_parser!(u32).range(1..)
+    )]
+    pub tx_rate_limit: Option<u32>,
+
+    /// Seconds without a committed block after which `/health` reports the node as stalled.
+    #[arg(long = "health_stall_secs", default_value_t = 30)]
+    pub health_stall_secs: u64,
+
+    #[arg(long = "log_format", value_enum, default_value_t = LogFormat::Text)]
+    pub log_format: LogFormat,
+
+    /// Run the shell commands in this file instead of the interactive shell, then exit.
+    #[arg(long = "exec_script")]
+    pub exec_script: Option<String>,
+
+    /// Keep running the script after a command fails.
+    #[arg(long = "continue_on_error", requires = "exec_script")]
+    pub continue_on_error: bool,
+
+    /// File the interactive shell loads its command history from and saves it to on exit.
+    #[arg(long = "history_file", default_value = "history.txt")]
+    pub history_file: String,
+}
+
+/// Offline tools, run instead of the node when the first argument names one,
+/// e.g. `gravity_sdk_kvstore genesis init --accounts 3 --balance 1000 --out genesis.json`.
+#[derive(Debug, Parser)]
+#[command(
+    name = "KVStore",
+    version,
+    about = "Tools for the gravity-sdk KV store"
+)]
+pub struct ToolCli {
+    #[command(subcommand)]
+    pub command: ToolCommand,
+}
+
+#[derive(Debug, Subcommand)]
+pub enum ToolCommand {
+    /// Work with genesis files.
+    #[command(subcommand)]
+    Genesis(GenesisCommand),
+
+    /// Print what a stopped node's sled database holds, as JSON. Only getters
+    /// are used, so nothing in the database is changed.
+    Inspect {
+        /// Database directory of the node, as passed to its `--db_dir`.
+        #[arg(long = "db_dir")]
+        db_dir: String,
+
+        #[command(subcommand)]
+        command: InspectCommand,
+    },
+
+    /// Write the state of a stopped node's sled database, as of its latest
+    /// checkpoint, to a newline-delimited JSON file.
+    ExportState {
+        /// Database directory of the node, as passed to its `--db_dir`.
+        #[arg(long = "db_dir")]
+        db_dir: String,
+
+        /// Path of the file to write.
+        #[arg(long = "out")]
+        out: String,
+    },
+
+    /// Sign one transaction, submit it to a running node's `/tx` endpoint and
+    /// print its hash, e.g. `send --key <hex> --set mykey myvalue`.
+    Send {
+        /// Base URL of the node's HTTP server.
+        #[arg(long = "url", default_value = "http://127.0.0.1:9006")]
+        url: String,
+
+        /// Private key of the sender, in hex.
+        #[arg(long = "key")]
+        key: String,
+
+        /// The key is an ed25519 key rather than a secp256k1 one.
+        #[arg(long = "ed25519")]
+        ed25519: bool,
+
+        /// Chain the transaction is signed for; must match the node's.
+        #[arg(long = "chain_id", default_value_t = 1337)]
+        chain_id: u64,
+
+        /// Nonce to sign with. By default the sender's committed nonce is
+        /// fetched from the node, which ignores transactions still queued.
+        #[arg(long = "nonce")]
+        nonce: Option<u64>,
+
+        /// Set a key of the sender's store.
+        #[arg(
+            long = "set",
+            num_args = 2,
+            value_names = ["KEY", "VALUE"],
+            required_unless_present = "transfer",
+            conflicts_with = "transfer"
+        )]
+        set: Option<Vec<String>>,
+
+        /// Transfer an amount to another account.
+        #[arg(long = "transfer", num_args = 2, value_names = ["TO", "AMOUNT"])]
+        transfer: Option<Vec<String>>,
+    },
+
+    /// Load a state written by `export-state` into an empty sled database, as a
+    /// checkpoint a node started on it recovers from.
+    ImportState {
+        /// Database directory to create, to pass to the node's `--db_dir`.
+        #[arg(long = "db_dir")]
+        db_dir: String,
+
+        /// Path of the file to read.
+        #[arg(long = "in")]
+        input: String,
+    },
+}
+
+#[derive(Debug, Subcommand)]
+pub enum InspectCommand {
+    /// The number of the last persisted block.
+    Latest,
+    /// A persisted block.
+    Block { number: u64 },
+    /// An account as of the latest checkpoint.
+    Account { address: String },
+}
+
+#[derive(Debug, Subcommand)]
+pub enum GenesisCommand {
+    /// Generate funded accounts, write them to a genesis file and print their keys.
+    Init {
+        /// Number of accounts to generate.
+        #[arg(long = "accounts")]
+        accounts: usize,
+
+        /// Starting balance of every account.
+        #[arg(long = "balance")]
+        balance: u64,
+
+        /// Path of the genesis file to write.
+        #[arg(long = "out")]
+        out: String,
+    },
+}
+
+impl ToolCli {
+    /// Parses the process arguments as a tool invocation if the first one names a
+    /// tool, exiting with a usage error if the rest is malformed.
+    pub fn parse_if_tool() -> Option<Self> {
+        let name = std::env::args().nth(1)?;
+        Self::command().find_subcommand(&name)?;
+        Some(Self::parse())
+    }
+}
+
+/// Format of the lines written to the log file.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum LogFormat {
+    Text,
+    Json,
+}
+
+/// Database used to persist blocks, receipts and state roots.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum StorageBackend {
+    Sled,
+    Rocksdb,
+}
+
+/// Parses a `host:port` listen address. The host is an IPv4 address, an IPv6
+/// address in brackets, or a name resolved to its first address.
+fn parse_listen_addr(value: &str) -> Result<SocketAddr, String> {
+    if let Ok(addr) = value.parse::<SocketAddr>() {
+        return Ok(addr);
+    }
+    let (host, port) = value.rsplit_once(':').ok_or_else(|| {
+        format!(
+            "expected host:port, such as 127.0.0.1:9006, got {:?}",
+            value
+        )
+    })?;
+    let port: u16 = port
+        .parse()
+        .map_err(|_| format!("invalid port {:?}, expected a number up to 65535", port))?;
+    if host.is_empty() {
+        return Err(format!("missing host before port {}", port));
+    }
+    if host.contains(':') || host.starts_with('[') {
+        return Err(format!(
+            "invalid IPv6 address {:?}, expected one in brackets, such as [::1]:{}",
+            host, port
+        ));
+    }
+    (host, port)
+        .to_socket_addrs()
+        .map_err(|e| format!("cannot resolve host {:?}: {}", host, e))?
+        .next()
+        .ok_or_else(|| format!("host {:?} has no address", host))
 }
 
 impl Cli {
@@ -37,3 +373,53 @@ impl Cli {
         Self::try_parse_from(itr)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::ffi::OsStr;
+
+    /// Parses `value` as `Cli`'s argument `name` would be.
+    fn parse_arg(name: &str, value: &str) -> Result<(), clap::Error> {
+        let command = Cli::command();
+        let arg = command
+            .get_arguments()
+            .find(|arg| arg.get_id().as_str() == name)
+            .unwrap();
+        arg.get_value_parser()
+            .parse_ref(&command, Some(arg), OsStr::new(value))
+            .map(|_| ())
+    }
+
+    #[test]
+    fn poll_interval_must_be_at_least_one_millisecond() {
+        assert!(parse_arg("poll_interval_ms", "0").is_err());
+        assert!(parse_arg("poll_interval_ms", "1").is_ok());
+        assert!(parse_arg("poll_interval_ms", "250").is_ok());
+    }
+
+    #[test]
+    fn listen_addr_accepts_ipv4_bracketed_ipv6_and_host_names() {
+        let addr = parse_listen_addr("127.0.0.1:9006").unwrap();
+        assert_eq!(addr, SocketAddr::from(([127, 0, 0, 1], 9006)));
+        let addr = parse_listen_addr("[::1]:9006").unwrap();
+        assert!(addr.is_ipv6());
+        assert_eq!(addr.port(), 9006);
+        let addr = parse_listen_addr("localhost:9006").unwrap();
+        assert!(addr.ip().is_loopback());
+        assert!(parse_arg("listen_url", "[::]:0").is_ok());
+    }
+
+    #[test]
+    fn malformed_listen_addrs_are_rejected_with_the_reason() {
+        let error = |value| parse_listen_addr(value).unwrap_err();
+        assert!(error("127.0.0.1").contains("expected host:port"));
+        assert!(error("127.0.0.1:").contains("invalid port"));
+        assert!(error("127.0.0.1:65536").contains("invalid port"));
+        assert!(error(":9006").contains("missing host"));
+        assert!(error("::1:9006").contains("in brackets"));
+        assert!(error("[::1:9006").contains("in brackets"));
+        assert!(error("host.invalid:9006").contains("cannot resolve"));
+        assert!(parse_arg("listen_url", "localhost").is_err());
+    }
+}
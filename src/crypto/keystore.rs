@@ -0,0 +1,191 @@
+//! Passphrase-encrypted key files, so private keys need not be typed or stored in hex.
+//!
+//! The key is encrypted with AES-256-GCM under a key derived from the passphrase
+//! with scrypt, and stored in a JSON envelope alongside the derivation parameters.
+
+use std::fs;
+
+use aes_gcm::{aead::Aead, Aes256Gcm, KeyInit, Nonce};
+use rand::{rngs::OsRng, RngCore};
+use serde::{Deserialize, Serialize};
+
+use super::AccountKey;
+use crate::SignatureScheme;
+
+const KEYSTORE_VERSION: u32 = 1;
+
+/// scrypt cost parameters for new keystores: N = 2^15, r = 8, p = 1.
+const SCRYPT_LOG_N: u8 = 15;
+const SCRYPT_R: u32 = 8;
+const SCRYPT_P: u32 = 1;
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Keystore {
+    pub version: u32,
+    /// Address of the stored key, readable without the passphrase.
+    pub address: String,
+    pub scheme: SignatureScheme,
+    pub crypto: KeystoreCrypto,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct KeystoreCrypto {
+    pub cipher: String,
+    pub ciphertext: String,
+    pub nonce: String,
+    pub kdf: String,
+    pub kdfparams: ScryptParams,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ScryptParams {
+    pub log_n: u8,
+    pub r: u32,
+    pub p: u32,
+    pub salt: String,
+}
+
+impl Keystore {
+    /// Encrypts `key` with `passphrase` under a fresh salt and nonce.
+    pub fn encrypt(key: &AccountKey, passphrase: &str) -> Result<Self, String> {
+        let mut salt = [0u8; 32];
+        OsRng.fill_bytes(&mut salt);
+        let mut nonce = [0u8; 12];
+        OsRng.fill_bytes(&mut nonce);
+        let kdfparams = ScryptParams {
+            log_n: SCRYPT_LOG_N,
+            r: SCRYPT_R,
+            p: SCRYPT_P,
+            salt: hex::encode(salt),
+        };
+
+        let cipher = Aes256Gcm::new_from_slice(&derive_key(passphrase, &kdfparams)?)
+            .map_err(|e| format!("Invalid keystore key: {}", e))?;
+        let ciphertext = cipher
+            .encrypt(Nonce::from_slice(&nonce), key.secret_bytes().as_slice())
+            .map_err(|_| "Failed to encrypt key".to_string())?;
+
+        Ok(Self {
+            version: KEYSTORE_VERSION,
+            address: key.address(),
+            scheme: key.scheme(),
+            crypto: KeystoreCrypto {
+                cipher: "aes-256-gcm".to_string(),
+                ciphertext: hex::encode(ciphertext),
+                nonce: hex::encode(nonce),
+                kdf: "scrypt".to_string(),
+                kdfparams,
+            },
+        })
+    }
+
+    /// Decrypts the stored key. Fails on a wrong passphrase or a tampered file.
+    pub fn decrypt(&self, passphrase: &str) -> Result<AccountKey, String> {
+        if self.version != KEYSTORE_VERSION {
+            return Err(format!("Unsupported keystore version {}", self.version));
+        }
+        if self.crypto.cipher != "aes-256-gcm" || self.crypto.kdf != "scrypt" {
+            return Err(format!(
+                "Unsupported keystore cipher {} or kdf {}",
+                self.crypto.cipher, self.crypto.kdf
+            ));
+        }
+        let nonce = hex::decode(&self.crypto.nonce)
+            .ok()
+            .filter(|nonce| nonce.len() == 12)
+            .ok_or_else(|| "Invalid keystore nonce".to_string())?;
+        let ciphertext = hex::decode(&self.crypto.ciphertext)
+            .map_err(|e| format!("Invalid keystore ciphertext: {}", e))?;
+
+        let cipher = Aes256Gcm::new_from_slice(&derive_key(passphrase, &self.crypto.kdfparams)?)
+            .map_err(|e| format!("Invalid keystore key: {}", e))?;
+        let secret = cipher
+            .decrypt(Nonce::from_slice(&nonce), ciphertext.as_slice())
+            .map_err(|_| "Wrong passphrase or corrupted keystore".to_string())?;
+
+        let key = AccountKey::from_hex(&hex::encode(secret), self.scheme)?;
+        if key.address() != self.address {
+            return Err(format!(
+                "Keystore address {} does not match its key {}",
+                self.address,
+                key.address()
+            ));
+        }
+        Ok(key)
+    }
+
+    pub fn load(path: &str) -> Result<Self, String> {
+        let json = fs::read_to_string(path)
+            .map_err(|e| format!("Failed to read keystore {}: {}", path, e))?;
+        serde_json::from_str(&json).map_err(|e| format!("Invalid keystore {}: {}", path, e))
+    }
+
+    pub fn save(&self, path: &str) -> Result<(), String> {
+        let json = serde_json::to_string_pretty(self)
+            .map_err(|e| format!("Failed to serialize keystore: {}", e))?;
+        fs::write(path, json).map_err(|e| format!("Failed to write keystore {}: {}", path, e))
+    }
+}
+
+fn derive_key(passphrase: &str, params: &ScryptParams) -> Result<[u8; 32], String> {
+    let salt = hex::decode(&params.salt).map_err(|e| format!("Invalid keystore salt: {}", e))?;
+    let scrypt_params = scrypt::Params::new(params.log_n, params.r, params.p, 32)
+        .map_err(|e| format!("Invalid scrypt parameters: {}", e))?;
+    let mut key = [0u8; 32];
+    scrypt::scrypt(passphrase.as_bytes(), &salt, &scrypt_params, &mut key)
+        .map_err(|e| format!("Failed to derive keystore key: {}", e))?;
+    Ok(key)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::*;
+
+    #[test]
+    fn saved_keystore_loads_back_to_the_same_key() {
+        let key = new_key();
+        let path = temp_dir().with_extension("json");
+        let path = path.to_str().unwrap();
+        Keystore::encrypt(&key, "passphrase")
+            .unwrap()
+            .save(path)
+            .unwrap();
+
+        let keystore = Keystore::load(path).unwrap();
+        std::fs::remove_file(path).unwrap();
+        assert_eq!(keystore.address, key.address());
+        let loaded = keystore.decrypt("passphrase").unwrap();
+        assert_eq!(loaded.address(), key.address());
+        assert_eq!(loaded.secret_bytes(), key.secret_bytes());
+    }
+
+    #[test]
+    fn keystore_decrypts_only_with_its_passphrase() {
+        let secret = hex::encode(rand::random::<[u8; 32]>());
+        for scheme in [SignatureScheme::Secp256k1, SignatureScheme::Ed25519] {
+            let key = AccountKey::from_hex(&secret, scheme).unwrap();
+            let keystore = Keystore::encrypt(&key, "correct horse").unwrap();
+            assert_eq!(keystore.scheme, scheme);
+            assert!(!keystore.crypto.ciphertext.contains(&secret));
+
+            let decrypted = keystore.decrypt("correct horse").unwrap();
+            assert_eq!(decrypted.scheme(), scheme);
+            assert_eq!(decrypted.address(), key.address());
+            assert_eq!(
+                keystore.decrypt("wrong horse").unwrap_err(),
+                "Wrong passphrase or corrupted keystore"
+            );
+        }
+
+        // A changed ciphertext fails authentication rather than giving another key.
+        let mut keystore = Keystore::encrypt(&new_key(), "passphrase").unwrap();
+        let mut ciphertext = hex::decode(&keystore.crypto.ciphertext).unwrap();
+        ciphertext[0] ^= 1;
+        keystore.crypto.ciphertext = hex::encode(ciphertext);
+        assert_eq!(
+            keystore.decrypt("passphrase").unwrap_err(),
+            "Wrong passphrase or corrupted keystore"
+        );
+    }
+}
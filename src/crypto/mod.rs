@@ -0,0 +1,691 @@
+pub mod keystore;
+
+use bip39::Mnemonic;
+use ed25519_dalek::Signer;
+use hmac::{Hmac, Mac};
+use rand::{rngs::OsRng, RngCore};
+use secp256k1::{
+    ecdsa::{RecoverableSignature, RecoveryId},
+    Message, PublicKey, Scalar, Secp256k1, SecretKey,
+};
+use sha2::Sha512;
+use sha3::{Digest, Keccak256};
+
+use crate::txpool::{
+    AccountId, SignatureScheme, Transaction, TransactionKind, TransactionWithAccount,
+    UnsignedTransaction,
+};
+
+#[derive(Debug)]
+pub struct KeyPair {
+    pub secret_key: SecretKey,
+    pub public_key: PublicKey,
+}
+
+/// A key an account signs transactions with, under either signature scheme.
+#[derive(Debug)]
+pub enum AccountKey {
+    Secp256k1(KeyPair),
+    Ed25519(ed25519_dalek::SigningKey),
+}
+
+impl AccountKey {
+    /// Parses a hex private key of 32 bytes for `scheme`.
+    pub fn from_hex(private_key_hex: &str, scheme: SignatureScheme) -> Result<Self, String> {
+        let bytes =
+            hex::decode(private_key_hex).map_err(|e| format!("Invalid private key hex: {}", e))?;
+        match scheme {
+            SignatureScheme::Secp256k1 => {
+                let secret_key = SecretKey::from_slice(&bytes)
+                    .map_err(|e| format!("Invalid private key: {}", e))?;
+                let public_key = PublicKey::from_secret_key(&Secp256k1::new(), &secret_key);
+                Ok(Self::Secp256k1(KeyPair {
+                    secret_key,
+                    public_key,
+                }))
+            }
+            SignatureScheme::Ed25519 => {
+                let bytes: [u8; 32] = bytes
+                    .try_into()
+                    .map_err(|_| "Invalid private key: expected 32 bytes".to_string())?;
+                Ok(Self::Ed25519(ed25519_dalek::SigningKey::from_bytes(&bytes)))
+            }
+        }
+    }
+
+    /// The 32-byte private key, as accepted by `from_hex`.
+    pub fn secret_bytes(&self) -> [u8; 32] {
+        match self {
+            Self::Secp256k1(keypair) => keypair.secret_key.secret_bytes(),
+            Self::Ed25519(signing_key) => signing_key.to_bytes(),
+        }
+    }
+
+    pub fn scheme(&self) -> SignatureScheme {
+        match self {
+            Self::Secp256k1(_) => SignatureScheme::Secp256k1,
+            Self::Ed25519(_) => SignatureScheme::Ed25519,
+        }
+    }
+
+    /// The address the key's transactions are sent from.
+    pub fn address(&self) -> String {
+        match self {
+            Self::Secp256k1(keypair) => public_key_to_address(&keypair.public_key),
+            Self::Ed25519(signing_key) => {
+                ed25519_public_key_to_address(&signing_key.verifying_key())
+            }
+        }
+    }
+
+    /// Signs `tx`, whose `scheme` must be this key's, in the encoding
+    /// `verify_signature` expects.
+    pub fn sign(&self, tx: &UnsignedTransaction) -> String {
+        debug_assert_eq!(tx.scheme, self.scheme());
+        match self {
+            Self::Secp256k1(keypair) => sign_transaction(tx, &keypair.secret_key),
+            Self::Ed25519(signing_key) => {
+                let signature = signing_key.sign(&compute_transaction_hash(tx));
+                let mut signature_bytes = Vec::with_capacity(96);
+                signature_bytes.extend_from_slice(signing_key.verifying_key().as_bytes());
+                signature_bytes.extend_from_slice(&signature.to_bytes());
+                hex::encode(signature_bytes)
+            }
+        }
+    }
+}
+
+impl From<KeyPair> for AccountKey {
+    fn from(keypair: KeyPair) -> Self {
+        Self::Secp256k1(keypair)
+    }
+}
+
+pub fn generate_keypair() -> KeyPair {
+    let secp = Secp256k1::new();
+    let mut rng = OsRng::default();
+    let (secret_key, public_key) = secp.generate_keypair(&mut rng);
+
+    KeyPair {
+        secret_key,
+        public_key,
+    }
+}
+
+type HmacSha512 = Hmac<Sha512>;
+
+const HARDENED: u32 = 0x8000_0000;
+
+/// BIP44 path `m/44'/60'/0'/0/0`, the first account of the usual Ethereum wallets.
+const DERIVATION_PATH: [u32; 5] = [44 | HARDENED, 60 | HARDENED, HARDENED, 0, 0];
+
+/// Generates a fresh English BIP39 phrase of 12 or 24 words.
+pub fn generate_mnemonic(word_count: usize) -> Result<String, String> {
+    let entropy_len = match word_count {
+        12 => 16,
+        24 => 32,
+        _ => return Err(format!("Unsupported word count: {}", word_count)),
+    };
+    let mut entropy = vec![0u8; entropy_len];
+    OsRng.fill_bytes(&mut entropy);
+    let mnemonic =
+        Mnemonic::from_entropy(&entropy).map_err(|e| format!("Invalid entropy: {}", e))?;
+    Ok(mnemonic.to_string())
+}
+
+/// Derives the keypair at `m/44'/60'/0'/0/0` from a BIP39 phrase and passphrase.
+pub fn mnemonic_to_keypair(phrase: &str, passphrase: &str) -> Result<KeyPair, String> {
+    let mnemonic = Mnemonic::parse(phrase).map_err(|e| format!("Invalid mnemonic: {}", e))?;
+    let seed = mnemonic.to_seed(passphrase);
+    let secret_key = derive_secret_key(&seed)?;
+    let public_key = PublicKey::from_secret_key(&Secp256k1::new(), &secret_key);
+    Ok(KeyPair {
+        secret_key,
+        public_key,
+    })
+}
+
+/// BIP32 derivation of the secret key at `DERIVATION_PATH` from a BIP39 seed.
+fn derive_secret_key(seed: &[u8]) -> Result<SecretKey, String> {
+    let secp = Secp256k1::new();
+    let mut mac = HmacSha512::new_from_slice(b"Bitcoin seed").unwrap();
+    mac.update(seed);
+    let output = mac.finalize().into_bytes();
+    let mut secret_key =
+        SecretKey::from_slice(&output[..32]).map_err(|e| format!("Invalid master key: {}", e))?;
+    let mut chain_code = [0u8; 32];
+    chain_code.copy_from_slice(&output[32..]);
+
+    for index in DERIVATION_PATH {
+        let mut mac = HmacSha512::new_from_slice(&chain_code).unwrap();
+        if index & HARDENED != 0 {
+            mac.update(&[0]);
+            mac.update(&secret_key.secret_bytes());
+        } else {
+            mac.update(&PublicKey::from_secret_key(&secp, &secret_key).serialize());
+        }
+        mac.update(&index.to_be_bytes());
+        let output = mac.finalize().into_bytes();
+
+        let mut tweak = [0u8; 32];
+        tweak.copy_from_slice(&output[..32]);
+        let tweak = Scalar::from_be_bytes(tweak).map_err(|_| "Invalid child key".to_string())?;
+        secret_key = secret_key
+            .add_tweak(&tweak)
+            .map_err(|e| format!("Invalid child key: {}", e))?;
+        chain_code.copy_from_slice(&output[32..]);
+    }
+
+    Ok(secret_key)
+}
+
+pub fn sign_transaction(tx: &UnsignedTransaction, secret_key: &SecretKey) -> String {
+    let secp = Secp256k1::new();
+    let message = compute_transaction_hash(tx);
+    let message = Message::from_slice(&message).unwrap();
+
+    let recoverable_signature = secp.sign_ecdsa_recoverable(&message, secret_key);
+    let (recovery_id, rec_sig_bytes) = recoverable_signature.serialize_compact();
+
+    let v = recovery_id.to_i32() as u8 + 27;
+
+    let mut signature_bytes = Vec::with_capacity(65);
+    signature_bytes.extend_from_slice(&rec_sig_bytes);
+    signature_bytes.push(v);
+
+    hex::encode(signature_bytes)
+}
+
+/// Builds transactions signed by `key`, ready for the mempool.
+pub struct TransactionBuilder<'a> {
+    key: &'a AccountKey,
+    chain_id: u64,
+    nonce: u64,
+    read_set: Vec<(String, String)>,
+    valid_until_block: Option<u64>,
+    memo: Option<String>,
+}
+
+impl<'a> TransactionBuilder<'a> {
+    /// Starts a transaction from `key` for `chain_id` at `nonce`, usually the
+    /// sender's next nonce from `KvStoreTxPool::next_nonce`.
+    pub fn new(key: &'a AccountKey, chain_id: u64, nonce: u64) -> Self {
+        Self {
+            key,
+            chain_id,
+            nonce,
+            read_set: vec![],
+            valid_until_block: None,
+            memo: None,
+        }
+    }
+
+    /// Sets the keys and values the transaction requires; see `UnsignedTransaction::read_set`.
+    pub fn read_set(mut self, read_set: Vec<(String, String)>) -> Self {
+        self.read_set = read_set;
+        self
+    }
+
+    /// Sets the last block the transaction may be included in; see
+    /// `UnsignedTransaction::valid_until_block`.
+    pub fn valid_until_block(mut self, block_number: u64) -> Self {
+        self.valid_until_block = Some(block_number);
+        self
+    }
+
+    /// Attaches a memo; see `UnsignedTransaction::memo`.
+    pub fn memo(mut self, memo: String) -> Self {
+        self.memo = Some(memo);
+        self
+    }
+
+    /// Signs a transaction of `kind`, wrapped with the key's address.
+    pub fn build(self, kind: TransactionKind) -> TransactionWithAccount {
+        let unsigned = UnsignedTransaction {
+            chain_id: self.chain_id,
+            nonce: self.nonce,
+            kind,
+            read_set: self.read_set,
+            scheme: self.key.scheme(),
+            valid_until_block: self.valid_until_block,
+            memo: self.memo,
+        };
+        let signature = self.key.sign(&unsigned);
+        TransactionWithAccount {
+            txn: Transaction {
+                unsigned,
+                signature,
+            },
+            address: self.key.address(),
+        }
+    }
+}
+
+/// The account that signed a transaction, with the public key it signed with.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RecoveredSender {
+    pub address: AccountId,
+    /// Hex of the compressed secp256k1 key or of the ed25519 key.
+    pub public_key: String,
+}
+
+/// Recovers the sender of `tx`, in the same form `public_key_to_address` gives,
+/// checking the signature under the scheme `tx` names.
+pub fn verify_signature(tx: &Transaction) -> Result<AccountId, String> {
+    recover_sender(&Secp256k1::verification_only(), tx).map(|sender| sender.address)
+}
+
+/// Like `verify_signature`, also returning the public key that signed `tx`.
+pub fn recover_signer(tx: &Transaction) -> Result<RecoveredSender, String> {
+    recover_sender(&Secp256k1::verification_only(), tx)
+}
+
+/// Verifies every signature in `txns` on up to `workers` threads, returning the
+/// recovered sender or the same error `verify_signature` would give, in input order.
+///
+/// This is not batch verification: each signature is still checked on its own,
+/// so one bad signature only fails its own transaction. Only the verification
+/// context is shared. It blocks until every signature is checked, so async code
+/// must call it from a blocking thread.
+pub fn verify_signatures_parallel(
+    txns: &[Transaction],
+    workers: usize,
+) -> Vec<Result<RecoveredSender, String>> {
+    let secp = Secp256k1::verification_only();
+    let verify = |tx: &Transaction| recover_sender(&secp, tx);
+    let workers = workers.max(1);
+    if txns.len() < 2 * workers {
+        return txns.iter().map(verify).collect();
+    }
+
+    let chunk_size = txns.len().div_ceil(workers);
+    std::thread::scope(|scope| {
+        let verify = &verify;
+        let handles = txns
+            .chunks(chunk_size)
+            .map(|chunk| scope.spawn(move || chunk.iter().map(verify).collect::<Vec<_>>()))
+            .collect::<Vec<_>>();
+        handles
+            .into_iter()
+            .flat_map(|handle| handle.join().unwrap())
+            .collect()
+    })
+}
+
+fn recover_sender<C: secp256k1::Verification>(
+    secp: &Secp256k1<C>,
+    tx: &Transaction,
+) -> Result<RecoveredSender, String> {
+    match tx.unsigned.scheme {
+        SignatureScheme::Secp256k1 => recover_secp256k1_sender(secp, tx),
+        SignatureScheme::Ed25519 => verify_ed25519_sender(tx),
+    }
+}
+
+fn verify_ed25519_sender(tx: &Transaction) -> Result<RecoveredSender, String> {
+    let signature_bytes =
+        hex::decode(&tx.signature).map_err(|e| format!("Invalid signature hex: {}", e))?;
+    if signature_bytes.len() != 96 {
+        return Err("Invalid signature length".to_string());
+    }
+
+    let public_key: [u8; 32] = signature_bytes[..32].try_into().unwrap();
+    let public_key = ed25519_dalek::VerifyingKey::from_bytes(&public_key)
+        .map_err(|_| "Invalid ed25519 public key".to_string())?;
+    let signature: [u8; 64] = signature_bytes[32..].try_into().unwrap();
+    let signature = ed25519_dalek::Signature::from_bytes(&signature);
+    public_key
+        .verify_strict(&compute_transaction_hash(&tx.unsigned), &signature)
+        .map_err(|_| "Invalid ed25519 signature".to_string())?;
+
+    Ok(RecoveredSender {
+        address: AccountId(ed25519_public_key_to_address(&public_key)),
+        public_key: hex::encode(public_key.as_bytes()),
+    })
+}
+
+fn recover_secp256k1_sender<C: secp256k1::Verification>(
+    secp: &Secp256k1<C>,
+    tx: &Transaction,
+) -> Result<RecoveredSender, String> {
+    let message = compute_transaction_hash(&tx.unsigned);
+    let message = Message::from_slice(&message).map_err(|e| format!("Invalid message: {}", e))?;
+
+    let signature_bytes =
+        hex::decode(&tx.signature).map_err(|e| format!("Invalid signature hex: {}", e))?;
+
+    if signature_bytes.len() != 65 {
+        return Err("Invalid signature length".to_string());
+    }
+
+    let rs_bytes = &signature_bytes[0..64];
+    let recovery_id = signature_bytes[64]
+        .checked_sub(27)
+        .and_then(|v| RecoveryId::from_i32(v as i32).ok())
+        .ok_or_else(|| "Invalid signature recovery id".to_string())?;
+    let signature = RecoverableSignature::from_compact(rs_bytes, recovery_id)
+        .map_err(|_| "Invalid recoverable signature".to_string())?;
+
+    let public_key = secp
+        .recover_ecdsa(&message, &signature)
+        .map_err(|_| "Failed to recover public key".to_string())?;
+
+    Ok(RecoveredSender {
+        address: AccountId(public_key_to_address(&public_key)),
+        public_key: hex::encode(public_key.serialize()),
+    })
+}
+
+/// Prefix of every signed transaction hash, so a signature over a transaction
+/// cannot be mistaken for a signature over any other kind of message.
+const TRANSACTION_DOMAIN: &[u8] = b"gravity-kvstore/transaction/v1";
+
+pub fn compute_transaction_hash(tx: &UnsignedTransaction) -> [u8; 32] {
+    let mut hasher = Keccak256::new();
+    let encoded = bincode::serialize(tx).unwrap();
+    hasher.update(TRANSACTION_DOMAIN);
+    hasher.update(&encoded);
+    hasher.finalize().into()
+}
+
+/// The address of `public_key`: the last 20 bytes of the Keccak hash of the
+/// uncompressed key, as 40 lowercase hex digits without a `0x` prefix. This is
+/// the form accounts are stored under.
+pub fn public_key_to_address(public_key: &PublicKey) -> String {
+    let mut hasher = Keccak256::new();
+    hasher.update(&public_key.serialize_uncompressed()[1..]);
+    let result = hasher.finalize();
+    hex::encode(&result[12..])
+}
+
+/// The address of an ed25519 `public_key`: the last 20 bytes of the Keccak
+/// hash of its 32 bytes, in the same form as `public_key_to_address`.
+pub fn ed25519_public_key_to_address(public_key: &ed25519_dalek::VerifyingKey) -> String {
+    let mut hasher = Keccak256::new();
+    hasher.update(public_key.as_bytes());
+    let result = hasher.finalize();
+    hex::encode(&result[12..])
+}
+
+/// Converts a user supplied address to the form `public_key_to_address` gives,
+/// accepting an optional `0x` prefix and any letter case.
+pub fn canonical_address(address: &str) -> Result<String, String> {
+    let hex_digits = address
+        .strip_prefix("0x")
+        .or_else(|| address.strip_prefix("0X"))
+        .unwrap_or(address)
+        .to_lowercase();
+    match hex::decode(&hex_digits) {
+        Ok(bytes) if bytes.len() == 20 => Ok(hex_digits),
+        Ok(bytes) => Err(format!(
+            "Invalid address {}: expected 20 bytes, got {}",
+            address,
+            bytes.len()
+        )),
+        Err(e) => Err(format!("Invalid address {}: {}", address, e)),
+    }
+}
+
+pub fn compute_merkle_root(transactions: &[Transaction]) -> [u8; 32] {
+    if transactions.is_empty() {
+        return [0; 32];
+    }
+
+    let mut hashes: Vec<[u8; 32]> = transactions
+        .iter()
+        .map(|tx| {
+            let mut hasher = Keccak256::new();
+            let encoded = bincode::serialize(tx).unwrap();
+            hasher.update(&encoded);
+            hasher.finalize().into()
+        })
+        .collect();
+
+    while hashes.len() > 1 {
+        if hashes.len() % 2 != 0 {
+            hashes.push(hashes.last().unwrap().clone());
+        }
+
+        let mut new_hashes = Vec::new();
+        for chunk in hashes.chunks(2) {
+            let mut hasher = Keccak256::new();
+            hasher.update(&chunk[0]);
+            hasher.update(&chunk[1]);
+            new_hashes.push(hasher.finalize().into());
+        }
+        hashes = new_hashes;
+    }
+
+    hashes[0]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::*;
+    use std::time::Instant;
+
+    fn signed_txns(count: usize) -> Vec<Transaction> {
+        (0..count)
+            .map(|_| sign(&new_key(), 0, set_kv("key", "value")).txn)
+            .collect()
+    }
+
+    #[test]
+    fn mnemonic_derives_the_usual_first_ethereum_account() {
+        // The default development account of Hardhat and Anvil.
+        let phrase = "test test test test test test test test test test test junk";
+        let keypair = mnemonic_to_keypair(phrase, "").unwrap();
+        assert_eq!(
+            hex::encode(keypair.secret_key.secret_bytes()),
+            "ac0974bec39a17e36ba4a6b4d238ff944bacb478cbed5efcae784d7bf4f2ff80"
+        );
+        assert_eq!(
+            public_key_to_address(&keypair.public_key),
+            canonical_address("0xf39Fd6e51aad88F6F4ce6aB8827279cffFb92266").unwrap()
+        );
+
+        let with_passphrase = mnemonic_to_keypair(phrase, "passphrase").unwrap();
+        assert_ne!(with_passphrase.secret_key, keypair.secret_key);
+    }
+
+    #[test]
+    fn mnemonic_with_a_bad_checksum_is_rejected() {
+        // Twelve times the first word: the valid phrase ends in "about".
+        let phrase = vec!["abandon"; 12].join(" ");
+        let error = mnemonic_to_keypair(&phrase, "").unwrap_err();
+        assert!(error.starts_with("Invalid mnemonic"), "{}", error);
+        assert!(
+            mnemonic_to_keypair(&format!("{} about", vec!["abandon"; 11].join(" ")), "").is_ok()
+        );
+        assert!(mnemonic_to_keypair("test test junk", "").is_err());
+
+        for word_count in [12, 24] {
+            let phrase = generate_mnemonic(word_count).unwrap();
+            assert_eq!(phrase.split(' ').count(), word_count);
+            assert!(mnemonic_to_keypair(&phrase, "").is_ok());
+        }
+        assert!(generate_mnemonic(13).is_err());
+    }
+
+    #[test]
+    fn recovered_signer_is_the_key_that_signed() {
+        let key = new_key();
+        let public_key = match &key {
+            AccountKey::Secp256k1(keypair) => keypair.public_key,
+            AccountKey::Ed25519(_) => unreachable!(),
+        };
+        let txn = sign(&key, 3, set_kv("key", "value")).txn;
+
+        let signer = recover_signer(&txn).unwrap();
+        assert_eq!(signer.address.0, key.address());
+        assert_eq!(signer.address.0, public_key_to_address(&public_key));
+        assert_eq!(signer.public_key, hex::encode(public_key.serialize()));
+        assert_eq!(verify_signature(&txn).unwrap(), signer.address);
+
+        // The signature no longer covers a changed transaction, so it recovers
+        // some other key.
+        let mut tampered = txn.clone();
+        tampered.unsigned.nonce += 1;
+        if let Ok(other) = recover_signer(&tampered) {
+            assert_ne!(other.address.0, key.address());
+        }
+    }
+
+    #[test]
+    fn signed_transactions_verify_to_the_signer_under_both_schemes() {
+        let secret = hex::encode(rand::random::<[u8; 32]>());
+        for scheme in [SignatureScheme::Secp256k1, SignatureScheme::Ed25519] {
+            let key = AccountKey::from_hex(&secret, scheme).unwrap();
+            assert_eq!(key.scheme(), scheme);
+            let (address, public_key) = match &key {
+                AccountKey::Secp256k1(keypair) => (
+                    public_key_to_address(&keypair.public_key),
+                    hex::encode(keypair.public_key.serialize()),
+                ),
+                AccountKey::Ed25519(signing_key) => (
+                    ed25519_public_key_to_address(&signing_key.verifying_key()),
+                    hex::encode(signing_key.verifying_key().as_bytes()),
+                ),
+            };
+            assert_eq!(key.address(), address);
+
+            let txn = sign(&key, 0, set_kv("key", "value")).txn;
+            assert_eq!(txn.unsigned.scheme, scheme);
+            assert_eq!(verify_signature(&txn).unwrap().0, address);
+            assert_eq!(
+                recover_signer(&txn).unwrap(),
+                RecoveredSender {
+                    address: AccountId(address.clone()),
+                    public_key,
+                }
+            );
+
+            // Any change to the signed payload breaks the signature.
+            let mut tampered = txn.clone();
+            tampered.unsigned.kind = set_kv("key", "other");
+            assert_ne!(
+                verify_signature(&tampered).map(|sender| sender.0),
+                Ok(address)
+            );
+        }
+        // The same secret gives a different account under each scheme.
+        assert_ne!(
+            AccountKey::from_hex(&secret, SignatureScheme::Secp256k1)
+                .unwrap()
+                .address(),
+            AccountKey::from_hex(&secret, SignatureScheme::Ed25519)
+                .unwrap()
+                .address()
+        );
+    }
+
+    #[test]
+    fn builder_signs_every_field_for_the_key_address() {
+        for key in [
+            new_key(),
+            AccountKey::from_hex(&hex::encode([7; 32]), SignatureScheme::Ed25519).unwrap(),
+        ] {
+            let built = TransactionBuilder::new(&key, CHAIN_ID, 4)
+                .read_set(vec![("key".to_string(), "value".to_string())])
+                .valid_until_block(9)
+                .memo("order-1".to_string())
+                .build(set_kv("key", "other"));
+            assert_eq!(built.address, key.address());
+            let unsigned = &built.txn.unsigned;
+            assert_eq!((unsigned.chain_id, unsigned.nonce), (CHAIN_ID, 4));
+            assert_eq!(unsigned.scheme, key.scheme());
+            assert_eq!(
+                unsigned.read_set,
+                [("key".to_string(), "value".to_string())]
+            );
+            assert_eq!(unsigned.valid_until_block, Some(9));
+            assert_eq!(unsigned.memo.as_deref(), Some("order-1"));
+            assert_eq!(verify_signature(&built.txn).unwrap().0, key.address());
+
+            // Each optional field is covered by the signature.
+            let mut tampered = [built.txn.clone(), built.txn.clone(), built.txn.clone()];
+            tampered[0].unsigned.read_set.clear();
+            tampered[1].unsigned.valid_until_block = None;
+            tampered[2].unsigned.memo = None;
+            for txn in &tampered {
+                assert_ne!(
+                    verify_signature(txn).map(|sender| sender.0),
+                    Ok(key.address())
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn one_bad_signature_fails_only_its_own_transaction() {
+        let mut txns = signed_txns(40);
+        txns[17].signature = "00".repeat(65);
+        let senders = txns
+            .iter()
+            .map(|tx| verify_signature(tx).ok())
+            .collect::<Vec<_>>();
+
+        for workers in [1, 4] {
+            let verified = verify_signatures_parallel(&txns, workers);
+            for (index, result) in verified.into_iter().enumerate() {
+                if index == 17 {
+                    assert!(result.is_err());
+                } else {
+                    assert_eq!(Some(result.unwrap().address), senders[index]);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn parallel_verification_gives_the_same_result_per_transaction() {
+        let ed25519 = AccountKey::from_hex(
+            &hex::encode(rand::random::<[u8; 32]>()),
+            SignatureScheme::Ed25519,
+        )
+        .unwrap();
+        let mut txns = signed_txns(200);
+        txns.push(sign(&ed25519, 0, set_kv("key", "value")).txn);
+        for (index, tx) in txns.iter_mut().enumerate() {
+            match index % 7 {
+                1 => tx.signature = "00".repeat(65),
+                2 => tx.signature = "not hex".to_string(),
+                3 => tx.signature.truncate(10),
+                _ => {}
+            }
+        }
+
+        let one_by_one = txns.iter().map(recover_signer).collect::<Vec<_>>();
+        assert_eq!(verify_signatures_parallel(&txns, 4), one_by_one);
+        assert!(one_by_one.iter().any(Result::is_err));
+        assert_eq!(
+            one_by_one.last().unwrap().as_ref().unwrap().address.0,
+            ed25519.address()
+        );
+    }
+
+    /// Run with `cargo test --release -- --ignored --nocapture bench`.
+    #[test]
+    #[ignore]
+    fn bench_parallel_signature_verification() {
+        let txns = signed_txns(1000);
+
+        let start = Instant::now();
+        let one_by_one = txns.iter().map(recover_signer).collect::<Vec<_>>();
+        let serial = start.elapsed();
+        let start = Instant::now();
+        let workers = crate::default_workers();
+        let parallel = verify_signatures_parallel(&txns, workers);
+        let parallel_time = start.elapsed();
+
+        assert_eq!(parallel, one_by_one);
+        println!(
+            "1000 signatures: {:?} one by one, {:?} on {} workers, {:.1}x",
+            serial,
+            parallel_time,
+            workers,
+            serial.as_secs_f64() / parallel_time.as_secs_f64()
+        );
+    }
+}
@@ -0,0 +1,110 @@
+use std::time::Duration;
+
+/// During a run of errors, only the first and then every this many are logged.
+const LOG_EVERY_N_ERRORS: u64 = 100;
+
+/// Delays between fetches of the block buffer, so an idle or failing buffer
+/// is not polled in a tight loop.
+///
+/// An empty fetch waits `interval`. Consecutive errors double the wait, starting
+/// at `interval` and capped at `max`. Any fetch that returns blocks resets it.
+/// Consecutive errors are counted so an outage is not logged on every retry.
+#[derive(Debug, Clone)]
+pub struct Backoff {
+    interval: Duration,
+    max: Duration,
+    current: Duration,
+    errors: u64,
+}
+
+impl Backoff {
+    pub fn new(interval: Duration, max: Duration) -> Self {
+        let max = max.max(interval);
+        Self {
+            interval,
+            max,
+            current: interval,
+            errors: 0,
+        }
+    }
+
+    /// Delay before fetching again after an empty fetch.
+    pub fn idle_delay(&self) -> Duration {
+        self.interval
+    }
+
+    /// Delay before retrying after a failed fetch. Doubles on every call until reset.
+    pub fn error_delay(&mut self) -> Duration {
+        self.errors += 1;
+        let delay = self.current;
+        self.current = (self.current * 2).min(self.max);
+        delay
+    }
+
+    /// Whether the error just counted by `error_delay` should be logged: the
+    /// first of a run, then every `LOG_EVERY_N_ERRORS`th.
+    pub fn should_log_error(&self) -> bool {
+        self.errors % LOG_EVERY_N_ERRORS == 1
+    }
+
+    /// Errors since the last reset.
+    pub fn consecutive_errors(&self) -> u64 {
+        self.errors
+    }
+
+    pub fn reset(&mut self) {
+        self.current = self.interval;
+        self.errors = 0;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ms(millis: u64) -> Duration {
+        Duration::from_millis(millis)
+    }
+
+    #[test]
+    fn error_delay_doubles_up_to_the_cap() {
+        let mut backoff = Backoff::new(ms(10), ms(70));
+        let delays = (0..6).map(|_| backoff.error_delay()).collect::<Vec<_>>();
+        assert_eq!(delays, [ms(10), ms(20), ms(40), ms(70), ms(70), ms(70)]);
+        assert_eq!(backoff.consecutive_errors(), 6);
+        // Idle polls keep their interval during a run of errors.
+        assert_eq!(backoff.idle_delay(), ms(10));
+    }
+
+    #[test]
+    fn reset_starts_the_next_run_of_errors_over() {
+        let mut backoff = Backoff::new(ms(10), ms(1000));
+        for _ in 0..3 {
+            backoff.error_delay();
+        }
+        backoff.reset();
+        assert_eq!(backoff.consecutive_errors(), 0);
+        assert_eq!(backoff.error_delay(), ms(10));
+        assert_eq!(backoff.error_delay(), ms(20));
+    }
+
+    #[test]
+    fn cap_below_the_interval_is_raised_to_it() {
+        let mut backoff = Backoff::new(ms(50), ms(10));
+        assert_eq!(backoff.error_delay(), ms(50));
+        assert_eq!(backoff.error_delay(), ms(50));
+    }
+
+    #[test]
+    fn only_the_first_and_then_every_hundredth_error_is_logged() {
+        let mut backoff = Backoff::new(ms(1), ms(1));
+        let logged = (1..=250)
+            .filter(|_| {
+                backoff.error_delay();
+                backoff.should_log_error()
+            })
+            .count();
+        // Errors 1, 101 and 201.
+        assert_eq!(logged, 3);
+    }
+}
@@ -1,56 +1,375 @@
 use crate::{
-    compute_transaction_hash, verify_signature, AccountId, AccountState, Block, BlockHeader,
-    KvStoreTxPool, State, StateRoot, Storage, Transaction, TransactionKind, TransactionReceipt,
-    TransactionWithAccount,
+    canonical_address, compute_transaction_hash, metrics::METRICS, recover_signer, replay_key,
+    txn_root, verify_signatures_parallel, AccountId, AccountState, Backoff, Block, BlockCommitLog,
+    BlockHeader, BlockSummary, KvError, KvStoreTxPool, PayloadLimits, ReceiptLog, RecentTxns,
+    RecoveredSender, State, StateCheckpoint, StateRoot, Storage, Transaction, TransactionKind,
+    TransactionReceipt, TransactionWithAccount, ValueEncoding,
 };
 
 use futures::lock::Mutex;
 use gravity_sdk::block_buffer_manager::get_block_buffer_manager;
 use gravity_sdk::gaptos::api_types::ExternalBlock;
-use std::collections::HashMap;
+use std::borrow::Cow;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::ops::RangeInclusive;
 use std::sync::Arc;
-use tokio::sync::RwLock;
+use std::time::Instant;
+use tokio::sync::{broadcast, watch, RwLock};
 use tracing::*;
 
+/// A full state checkpoint is taken every this many blocks so storage can be pruned.
+pub const CHECKPOINT_INTERVAL: u64 = 100;
+
+/// Waves smaller than this are executed on the calling thread.
+const PARALLEL_WAVE_THRESHOLD: usize = 16;
+
+/// Gas charged for every transaction, and all a `Transfer` or `Mint` costs.
+pub const GAS_BASE: u64 = 21000;
+
+/// Gas charged per byte of key and value written by a `SetKV`, `SetKVTtl` or `RestoreKV`.
+pub const GAS_PER_BYTE: u64 = 16;
+
+/// Gas charged for a `SetKV` writing the value the key already holds. The
+/// per-byte gas paid upfront is refunded.
+pub const GAS_NOOP_WRITE: u64 = GAS_BASE;
+
+/// Most key and value bytes a `RestoreKV` may write.
+pub const MAX_RESTORE_BYTES: usize = 64 * 1024;
+
+/// An executed block waiting to be committed, with its state root, receipts and
+/// the checkpoint to persist with it, if any.
+type PendingBlock = (
+    StateRoot,
+    Block,
+    Vec<TransactionReceipt>,
+    Option<StateCheckpoint>,
+);
+
+type PendingBlocks = HashMap<u64, PendingBlock>;
+
+/// Chain rules applied when executing transactions. Replaying blocks must use
+/// the same config they were first executed with.
+#[derive(Debug, Clone, Default)]
+pub struct ExecutorConfig {
+    /// Transactions signed for any other chain are rejected.
+    pub chain_id: u64,
+    /// Balance credited to a sender the first time it is seen.
+    pub default_balance: u64,
+    /// Only accounts already in the state, initially those of the genesis
+    /// file, may send transactions. Unknown senders are skipped, and transfers
+    /// or mints to unknown accounts fail, so no account is ever created.
+    pub genesis_only: bool,
+    /// Fee charged per unit of gas. Every transaction must be able to pay
+    /// `gas_used * gas_price` before it has any effect.
+    pub gas_price: u64,
+    /// The only address allowed to send `Mint` transactions. Minting is
+    /// disabled when unset.
+    pub faucet_address: Option<String>,
+    /// Largest amount a single `Mint` may grant. Unlimited when unset.
+    pub faucet_max_amount: Option<u64>,
+    /// Blocks an account must wait after receiving a `Mint` before it can
+    /// receive another. Grants are not tracked when 0.
+    pub faucet_cooldown_blocks: u64,
+    /// Transactions with a larger key or value fail, in case one bypassed the mempool check.
+    pub payload_limits: PayloadLimits,
+    /// Most gas, as given by `compute_gas`, the transactions of a block may use.
+    /// Unbounded when unset.
+    pub block_gas_limit: Option<u64>,
+    /// Blocks whose executed transaction hashes are remembered. A transaction
+    /// with one of those hashes is skipped instead of executed again.
+    pub replay_window: u64,
+}
+
+/// Gas used by a transaction of `kind`: `GAS_BASE`, plus `GAS_PER_BYTE` for
+/// every byte of key and value a `SetKV`, `SetKVTtl`, `SetKVEncoded`,
+/// `SetKVFor` or `RestoreKV` writes.
+/// An `AddKV` is charged for its key and the 8 bytes of its delta.
+pub fn compute_gas(kind: &TransactionKind) -> u64 {
+    match kind {
+        TransactionKind::Transfer { .. }
+        | TransactionKind::Mint { .. }
+        | TransactionKind::GrantWrite { .. }
+        | TransactionKind::RevokeWrite { .. } => GAS_BASE,
+        TransactionKind::SetKV { key, value }
+        | TransactionKind::SetKVTtl { key, value, .. }
+        | TransactionKind::SetKVEncoded { key, value, .. }
+        | TransactionKind::SetKVFor { key, value, .. } => {
+            let bytes = (key.len() + value.len()) as u64;
+            GAS_BASE.saturating_add(bytes.saturating_mul(GAS_PER_BYTE))
+        }
+        TransactionKind::RestoreKV { entries } => {
+            let bytes = restore_size(entries) as u64;
+            GAS_BASE.saturating_add(bytes.saturating_mul(GAS_PER_BYTE))
+        }
+        TransactionKind::AddKV { key, .. } => {
+            let bytes = (key.len() + std::mem::size_of::<i64>()) as u64;
+            GAS_BASE.saturating_add(bytes.saturating_mul(GAS_PER_BYTE))
+        }
+    }
+}
+
+/// Logs an error if the total supply went from `before` to `after` over a block
+/// with `receipts` outside of `expected_supply`.
+fn check_supply(
+    block_number: u64,
+    before: u128,
+    after: u128,
+    receipts: &[TransactionReceipt],
+    config: &ExecutorConfig,
+) {
+    let expected = expected_supply(before, receipts, config);
+    if !expected.contains(&after) {
+        error!(
+            "Total supply invariant violated in block {}: {} before, {} after, expected {} to {}",
+            block_number,
+            before,
+            after,
+            expected.start(),
+            expected.end()
+        );
+    }
+}
+
+/// Total supply after a block with `receipts`, starting from `before`, changed
+/// only by minting, crediting new senders their default balance and burning
+/// fees. A failed transaction may or may not have paid its fee, so only a range
+/// is known for those.
+fn expected_supply(
+    before: u128,
+    receipts: &[TransactionReceipt],
+    config: &ExecutorConfig,
+) -> RangeInclusive<u128> {
+    let mut credited = 0u128;
+    let mut burned = 0u128;
+    let mut maybe_burned = 0u128;
+    for receipt in receipts {
+        let fee = receipt.gas_used as u128 * config.gas_price as u128;
+        if receipt.status {
+            burned += fee;
+        } else {
+            maybe_burned += fee;
+        }
+        // Receivers are created empty; only new senders get the default balance.
+        let receiver = match &receipt.transaction.unsigned.kind {
+            TransactionKind::Transfer { receiver, .. } | TransactionKind::Mint { receiver, .. } => {
+                Some(canonical_account(receiver))
+            }
+            _ => None,
+        };
+        for log in &receipt.logs {
+            match log {
+                ReceiptLog::Mint { amount, .. } => credited += *amount as u128,
+                ReceiptLog::AccountCreated { address } if Some(address) != receiver.as_ref() => {
+                    credited += config.default_balance as u128;
+                }
+                _ => {}
+            }
+        }
+    }
+    let max = (before + credited).saturating_sub(burned);
+    let min = max.saturating_sub(maybe_burned);
+    min..=max
+}
+
+/// Number of leading `txns` whose gas adds up to at most `limit`.
+fn gas_limit_prefix(txns: &[TransactionWithAccount], limit: u64) -> usize {
+    let mut used = 0u64;
+    txns.iter()
+        .take_while(|txn| {
+            used = used.saturating_add(compute_gas(&txn.txn.unsigned.kind));
+            used <= limit
+        })
+        .count()
+}
+
+/// Replay keys of the transactions `receipts` were given for.
+pub(crate) fn receipt_replay_keys(receipts: &[TransactionReceipt]) -> Vec<[u8; 32]> {
+    receipts
+        .iter()
+        .map(|receipt| replay_key(&receipt.transaction))
+        .collect()
+}
+
+/// `kind` with the account it names, the receiver of a transfer or mint or the
+/// target of a `SetKVFor`, in the form account keys are stored in. Fails if
+/// that account is not a valid address.
+fn canonical_kind(kind: &TransactionKind) -> Result<Cow<'_, TransactionKind>, String> {
+    Ok(match kind {
+        TransactionKind::Transfer { receiver, amount } => Cow::Owned(TransactionKind::Transfer {
+            receiver: canonical_address(receiver)?,
+            amount: *amount,
+        }),
+        TransactionKind::Mint { receiver, amount } => Cow::Owned(TransactionKind::Mint {
+            receiver: canonical_address(receiver)?,
+            amount: *amount,
+        }),
+        TransactionKind::SetKVFor { target, key, value } => Cow::Owned(TransactionKind::SetKVFor {
+            target: canonical_address(target)?,
+            key: key.clone(),
+            value: value.clone(),
+        }),
+        _ => Cow::Borrowed(kind),
+    })
+}
+
+/// `address` in canonical form, or as is if it is not a valid address.
+fn canonical_account(address: &str) -> String {
+    canonical_address(address).unwrap_or_else(|_| address.to_string())
+}
+
+/// Key and value bytes written by a `RestoreKV` of `entries`.
+fn restore_size(entries: &[(String, String)]) -> usize {
+    entries
+        .iter()
+        .map(|(key, value)| key.len() + value.len())
+        .sum()
+}
+
+/// Threads used to execute the transactions of a block when none are configured:
+/// one per available core.
+pub fn default_workers() -> usize {
+    std::thread::available_parallelism()
+        .map(|n| n.get())
+        .unwrap_or(1)
+}
+
 pub struct PipelineExecutor;
 
 impl PipelineExecutor {
+    /// Runs the execute and commit tasks until `shutdown` is set to true.
+    /// Blocks are executed one after the other, each spreading its transactions
+    /// over `workers` threads, and committed strictly in block order. Blocks with
+    /// more than `max_block_txns` transactions are executed in chunks of that size.
+    /// Storage is flushed after every `flush_every_n_blocks` committed blocks.
+    /// With a `prune_window`, blocks older than that many blocks behind the
+    /// committed height are pruned at every checkpoint.
+    ///
+    /// `recent_txns` holds the transactions executed in the blocks before
+    /// `start_num`, as rebuilt by recovery.
+    ///
+    /// On shutdown each task finishes the blocks it already fetched before
+    /// returning, so a block is never left half persisted. Blocks executed but
+    /// not yet committed are dropped and executed again after a restart.
     pub async fn run(
         start_num: u64,
         storage: Arc<dyn Storage>,
         state: Arc<RwLock<State>>,
         pool: KvStoreTxPool,
+        block_sender: broadcast::Sender<BlockSummary>,
+        receipt_sender: broadcast::Sender<TransactionReceipt>,
+        config: ExecutorConfig,
+        recent_txns: RecentTxns,
+        workers: usize,
+        max_block_txns: Option<usize>,
+        flush_every_n_blocks: u64,
+        prune_window: Option<u64>,
+        backoff: Backoff,
+        shutdown: watch::Receiver<bool>,
     ) {
         let pending_blocks = Arc::new(Mutex::new(HashMap::new()));
         let pending_blocks_clone = pending_blocks.clone();
-        tokio::spawn(async move {
-            Self::execute_task(start_num, None, state, pending_blocks).await;
+        let execute_backoff = backoff.clone();
+        let execute_shutdown = shutdown.clone();
+        let execute = tokio::spawn(async move {
+            Self::execute_task(
+                start_num,
+                None,
+                state,
+                pending_blocks,
+                config,
+                recent_txns,
+                workers,
+                max_block_txns,
+                execute_backoff,
+                execute_shutdown,
+            )
+            .await;
         });
-        tokio::spawn(async move {
-            Self::commit_task(start_num, None, storage, pending_blocks_clone, pool).await;
+        let commit = tokio::spawn(async move {
+            Self::commit_task(
+                start_num,
+                None,
+                storage,
+                pending_blocks_clone,
+                pool,
+                block_sender,
+                receipt_sender,
+                flush_every_n_blocks,
+                prune_window,
+                backoff,
+                shutdown,
+            )
+            .await;
         });
+        let (execute, commit) = tokio::join!(execute, commit);
+        if let Err(e) = execute.and(commit) {
+            warn!("executor task failed: {}", e);
+        }
     }
 
     pub async fn execute_task(
         mut start_num: u64,
         max_size: Option<usize>,
         state: Arc<RwLock<State>>,
-        pending_blocks: Arc<Mutex<HashMap<u64, (StateRoot, Block, Vec<TransactionReceipt>)>>>,
+        pending_blocks: Arc<Mutex<PendingBlocks>>,
+        config: ExecutorConfig,
+        mut recent_txns: RecentTxns,
+        workers: usize,
+        max_block_txns: Option<usize>,
+        mut backoff: Backoff,
+        mut shutdown: watch::Receiver<bool>,
     ) {
         loop {
-            let ordered_blocks = get_block_buffer_manager()
-                .get_ordered_blocks(start_num, max_size)
-                .await;
-            if let Err(e) = ordered_blocks {
-                warn!("failed to get ordered blocks: {}", e);
-                continue;
+            if *shutdown.borrow() {
+                break;
+            }
+            let ordered_blocks = tokio::select! {
+                blocks = get_block_buffer_manager().get_ordered_blocks(start_num, max_size) => blocks,
+                _ = shutdown.changed() => break,
+            };
+            let delay = match &ordered_blocks {
+                Err(e) => {
+                    let delay = backoff.error_delay();
+                    if backoff.should_log_error() {
+                        warn!(
+                            "failed to get ordered blocks ({} consecutive failures): {}",
+                            backoff.consecutive_errors(),
+                            e
+                        );
+                    }
+                    Some(delay)
+                }
+                Ok(blocks) if blocks.is_empty() => Some(backoff.idle_delay()),
+                Ok(_) => None,
+            };
+            if let Some(delay) = delay {
+                tokio::select! {
+                    _ = tokio::time::sleep(delay) => continue,
+                    _ = shutdown.changed() => break,
+                }
+            }
+            if backoff.consecutive_errors() > 0 {
+                info!(
+                    "got ordered blocks again after {} failures",
+                    backoff.consecutive_errors()
+                );
             }
+            backoff.reset();
             let ordered_blocks = ordered_blocks.unwrap();
             start_num += ordered_blocks.len() as u64;
             for (block, _) in ordered_blocks {
                 let block_num = block.block_meta.block_number;
                 let block_id = block.block_meta.block_id;
-                let exec_res = Self::execute_block(block, &state, &pending_blocks).await;
+                let exec_res = Self::execute_block(
+                    block,
+                    &state,
+                    &pending_blocks,
+                    &config,
+                    &mut recent_txns,
+                    workers,
+                    max_block_txns,
+                )
+                .await;
                 let res = get_block_buffer_manager()
                     .set_compute_res(block_id, exec_res, block_num, Arc::new(None), vec![])
                     .await;
@@ -59,70 +378,412 @@ impl PipelineExecutor {
                 }
             }
         }
+        info!("Execute task stopped before block {}", start_num);
     }
 
     async fn execute_block(
         block: ExternalBlock,
         state: &Arc<RwLock<State>>,
-        pending_blocks: &Arc<Mutex<HashMap<u64, (StateRoot, Block, Vec<TransactionReceipt>)>>>,
+        pending_blocks: &Arc<Mutex<PendingBlocks>>,
+        config: &ExecutorConfig,
+        recent_txns: &mut RecentTxns,
+        workers: usize,
+        max_block_txns: Option<usize>,
     ) -> [u8; 32] {
-        // TODO: implement account dependencies when enable pipeline
-        let mut state = state.write().await;
-        let block_txns = block
+        let start = Instant::now();
+        // The block runs on a copy, so readers keep seeing the previous block's
+        // state until the result is swapped in at the end.
+        let next = state.read().await.clone();
+        let mut block_txns = block
             .txns
             .into_iter()
             .map(|tx| TransactionWithAccount::from(tx))
             .collect::<Vec<_>>();
-        let parent_state_root = state.get_state_root().clone().0;
-        let mut receipts = vec![];
-        for tx in &block_txns {
-            let receipt = Self::execute_transaction(&tx.txn, &state).unwrap();
-            if let Some(receipt) = receipt {
-                for (account_id, state_update) in receipt.state_updates.clone() {
-                    state
-                        .update_account_state(&account_id, state_update)
-                        .await
-                        .unwrap();
-                }
-                receipts.push(receipt);
+        let parent_state_root = next.get_state_root().clone().0;
+        let supply_before = cfg!(debug_assertions).then(|| next.total_supply());
+        let block_number = block.block_meta.block_number;
+        // Proposals already stop at the gas limit. Transactions past it are left
+        // out of the block, and so stay in the mempool for a later one.
+        if let Some(limit) = config.block_gas_limit {
+            let fitting = gas_limit_prefix(&block_txns, limit);
+            if fitting < block_txns.len() {
+                warn!(
+                    "Block {} needs more than {} gas; dropping its last {} transactions",
+                    block_number,
+                    limit,
+                    block_txns.len() - fitting
+                );
+                block_txns.truncate(fitting);
             }
         }
-        let current_state_root = state.get_state_root().0;
+        let txn_hashes = block_txns
+            .iter()
+            .map(|tx| compute_transaction_hash(&tx.txn.unsigned))
+            .collect::<Vec<_>>();
+        // Consensus already ordered the block, so an oversized one cannot be
+        // rejected. It is executed in chunks instead.
+        let chunk_size = max_block_txns.unwrap_or(block_txns.len()).max(1);
+        if block_txns.len() > chunk_size {
+            warn!(
+                "Block {} has {} transactions, over the limit of {}; executing it in chunks",
+                block_number,
+                block_txns.len(),
+                chunk_size
+            );
+        }
+        let (mut next, receipts) = Self::apply_in_chunks(
+            &block_txns,
+            block_number,
+            next,
+            config,
+            recent_txns,
+            workers,
+            chunk_size,
+        )
+        .await;
+        recent_txns.record(block_number, receipt_replay_keys(&receipts));
+        if let Some(supply_before) = supply_before {
+            check_supply(
+                block_number,
+                supply_before,
+                next.total_supply(),
+                &receipts,
+                config,
+            );
+        }
+        next.set_current_block_number(block.block_meta.block_number);
+        let current_state_root = next.get_state_root().0;
         let block = Block {
             header: BlockHeader {
                 number: block.block_meta.block_number,
                 parent_state_root,
                 state_root: current_state_root,
                 usecs: block.block_meta.usecs,
+                txn_root: txn_root(&txn_hashes),
             },
             transactions: block_txns,
         };
+        let checkpoint = if block.header.number % CHECKPOINT_INTERVAL == 0 {
+            Some(next.checkpoint())
+        } else {
+            None
+        };
+        *state.write().await = next;
         let mut pending_blocks = pending_blocks.lock().await;
-        pending_blocks.insert(block.header.number, (StateRoot(current_state_root), block, receipts));
-        state.get_state_root().0
+        pending_blocks.insert(
+            block.header.number,
+            (StateRoot(current_state_root), block, receipts, checkpoint),
+        );
+        METRICS.record_execution(start.elapsed());
+        current_state_root
+    }
+
+    /// Executes `txns` as block `block_number` against `state` in chunks of at
+    /// most `chunk_size`, which gives the same result as executing them at once.
+    /// Each chunk runs on a blocking thread, since verifying and executing it
+    /// keeps `workers` threads busy. Returns the state after the last chunk.
+    async fn apply_in_chunks(
+        txns: &[TransactionWithAccount],
+        block_number: u64,
+        mut state: State,
+        config: &ExecutorConfig,
+        recent_txns: &mut RecentTxns,
+        workers: usize,
+        chunk_size: usize,
+    ) -> (State, Vec<TransactionReceipt>) {
+        let mut receipts = vec![];
+        let mut chunk_start = 0;
+        loop {
+            let chunk_end = (chunk_start + chunk_size).min(txns.len());
+            let chunk = txns[chunk_start..chunk_end].to_vec();
+            let chunk_config = config.clone();
+            let chunk_recent_txns = std::mem::take(recent_txns);
+            let (next, chunk_recent_txns, chunk_receipts) =
+                tokio::task::spawn_blocking(move || {
+                    let receipts = Self::apply_transactions(
+                        &chunk,
+                        block_number,
+                        &mut state,
+                        &chunk_config,
+                        &chunk_recent_txns,
+                        workers,
+                    );
+                    (state, chunk_recent_txns, receipts)
+                })
+                .await
+                .unwrap_or_else(|e| std::panic::resume_unwind(e.into_panic()));
+            state = next;
+            *recent_txns = chunk_recent_txns;
+            receipts.extend(chunk_receipts);
+            chunk_start = chunk_end;
+            if chunk_start >= txns.len() {
+                break;
+            }
+        }
+        (state, receipts)
+    }
+
+    /// Executes `txns` as block `block_number` against `state`, applying every
+    /// state update, and returns the receipts of the transactions that were
+    /// executed, in block order. Keys expired at `block_number` are removed first.
+    ///
+    /// Transactions in `recent_txns` were executed in a recent block. They are
+    /// kept in the block, so they leave the mempool on commit, but are skipped.
+    ///
+    /// Transactions are grouped into waves of account-disjoint transactions which
+    /// run concurrently on up to `workers` threads. The state root only depends on the resulting accounts,
+    /// so the result is identical to executing the block serially.
+    pub(crate) fn apply_transactions(
+        txns: &[TransactionWithAccount],
+        block_number: u64,
+        state: &mut State,
+        config: &ExecutorConfig,
+        recent_txns: &RecentTxns,
+        workers: usize,
+    ) -> Vec<TransactionReceipt> {
+        state.purge_expired(block_number);
+        let txns = &txns
+            .iter()
+            .filter(|tx| {
+                let replayed = recent_txns.contains(&tx.txn);
+                if replayed {
+                    warn!(
+                        "Skipping already executed transaction {} in block {}",
+                        hex::encode(compute_transaction_hash(&tx.txn.unsigned)),
+                        block_number
+                    );
+                }
+                !replayed
+            })
+            .cloned()
+            .collect::<Vec<_>>();
+        let senders = verify_signatures_parallel(
+            &txns.iter().map(|tx| tx.txn.clone()).collect::<Vec<_>>(),
+            workers,
+        );
+        let mut results = vec![None; txns.len()];
+        for wave in Self::schedule_waves(txns, &senders) {
+            let wave_receipts =
+                Self::execute_wave(&wave, txns, &senders, block_number, state, config, workers);
+            for (index, receipt) in wave.into_iter().zip(wave_receipts) {
+                if let Some(receipt) = &receipt {
+                    for (account_id, state_update) in &receipt.state_updates {
+                        state.put_account(account_id, state_update.clone());
+                    }
+                }
+                results[index] = receipt;
+            }
+        }
+
+        state.recompute_state_root();
+        results.into_iter().flatten().collect()
+    }
+
+    /// Groups transaction indices into waves. Transactions within a wave touch
+    /// disjoint accounts, and each transaction is placed after every earlier
+    /// transaction sharing one of its accounts, which keeps per-account order.
+    fn schedule_waves(
+        txns: &[TransactionWithAccount],
+        senders: &[Result<RecoveredSender, String>],
+    ) -> Vec<Vec<usize>> {
+        let mut account_waves: HashMap<String, usize> = HashMap::new();
+        let mut waves: Vec<Vec<usize>> = vec![];
+        for (index, tx) in txns.iter().enumerate() {
+            let accounts = Self::touched_accounts(&tx.txn, &senders[index]);
+            let wave = accounts
+                .iter()
+                .filter_map(|account| account_waves.get(account))
+                .map(|wave| wave + 1)
+                .max()
+                .unwrap_or(0);
+            for account in accounts {
+                account_waves.insert(account, wave);
+            }
+            if waves.len() <= wave {
+                waves.resize(wave + 1, vec![]);
+            }
+            waves[wave].push(index);
+        }
+        waves
+    }
+
+    /// Accounts read or written by `tx`: the sender, plus the receiver of a
+    /// transfer or mint, or the target of a `SetKVFor`.
+    fn touched_accounts(tx: &Transaction, sender: &Result<RecoveredSender, String>) -> Vec<String> {
+        let mut accounts = vec![];
+        if let Ok(sender) = sender {
+            accounts.push(sender.address.0.clone());
+        }
+        match &tx.unsigned.kind {
+            TransactionKind::Transfer { receiver, .. } | TransactionKind::Mint { receiver, .. } => {
+                accounts.push(canonical_account(receiver));
+            }
+            TransactionKind::SetKVFor { target, .. } => accounts.push(canonical_account(target)),
+            TransactionKind::SetKV { .. }
+            | TransactionKind::SetKVTtl { .. }
+            | TransactionKind::SetKVEncoded { .. }
+            | TransactionKind::GrantWrite { .. }
+            | TransactionKind::RevokeWrite { .. }
+            | TransactionKind::RestoreKV { .. }
+            | TransactionKind::AddKV { .. } => {}
+        }
+        accounts
+    }
+
+    /// Executes the transactions of `wave` against `state`, spread over up to
+    /// `workers` threads. It blocks until they are done, so it only runs under
+    /// `apply_transactions`, which async code calls from a blocking thread.
+    fn execute_wave(
+        wave: &[usize],
+        txns: &[TransactionWithAccount],
+        senders: &[Result<RecoveredSender, String>],
+        block_number: u64,
+        state: &State,
+        config: &ExecutorConfig,
+        workers: usize,
+    ) -> Vec<Option<TransactionReceipt>> {
+        // A transaction whose signature does not verify has no sender to
+        // charge, so it fails without any effect instead of aborting the block.
+        let execute = |index: &usize| {
+            let tx = &txns[*index].txn;
+            Self::execute_transaction(tx, &senders[*index], block_number, state, config)
+                .unwrap_or_else(|e| {
+                    tracing::warn!("Rejecting transaction: {}", e);
+                    Some(Self::rejected_receipt(tx))
+                })
+        };
+        if wave.len() < PARALLEL_WAVE_THRESHOLD || workers <= 1 {
+            return wave.iter().map(execute).collect();
+        }
+
+        let chunk_size = wave.len().div_ceil(workers);
+        std::thread::scope(|scope| {
+            let handles = wave
+                .chunks(chunk_size)
+                .map(|chunk| scope.spawn(move || chunk.iter().map(execute).collect::<Vec<_>>()))
+                .collect::<Vec<_>>();
+            handles
+                .into_iter()
+                .flat_map(|handle| {
+                    handle
+                        .join()
+                        .unwrap_or_else(|panic| std::panic::resume_unwind(panic))
+                })
+                .collect()
+        })
+    }
+
+    /// Gas `txn` would use if it were executed now, found by running it against
+    /// `state` without applying the result.
+    ///
+    /// The chain config is not applied, so a transaction that would fail for lack
+    /// of funds or an unauthorized mint still reports the gas it is charged, and
+    /// the transaction is assumed to target the right chain. Fails if the
+    /// signature is invalid or the nonce is not the sender's next one.
+    pub fn estimate_gas(txn: &Transaction, state: &State) -> Result<u64, KvError> {
+        let config = ExecutorConfig {
+            chain_id: txn.unsigned.chain_id,
+            ..ExecutorConfig::default()
+        };
+        Self::simulate(txn, state, &config).map(|receipt| receipt.gas_used)
+    }
+
+    /// Receipt `txn` would get if it were executed under `config` in the block
+    /// after `state`'s. The updates are only collected into the receipt, never
+    /// applied, so `state` is neither written nor copied.
+    ///
+    /// Fails if the signature is invalid or the transaction would be skipped
+    /// rather than executed: `InvalidNonce` if its nonce is not the sender's next
+    /// one, `UnknownSender` if only genesis accounts may send.
+    pub fn simulate(
+        txn: &Transaction,
+        state: &State,
+        config: &ExecutorConfig,
+    ) -> Result<TransactionReceipt, KvError> {
+        let sender = recover_signer(txn);
+        let block_number = state.get_current_block_number() + 1;
+        if let Some(receipt) = Self::execute_transaction(txn, &sender, block_number, state, config)?
+        {
+            return Ok(receipt);
+        }
+        let address = sender.map_err(KvError::InvalidSignature)?.address.0;
+        Err(match state.get_account(&address) {
+            None if config.genesis_only => KvError::UnknownSender(address),
+            account => KvError::InvalidNonce {
+                expected: account.map_or(0, |account| account.nonce),
+                actual: txn.unsigned.nonce,
+            },
+        })
     }
 
+    /// Executes `tx` in block `block_number`, its signature already checked,
+    /// `sender` being the result of that check. The receipt records the public
+    /// key that signed it.
     fn execute_transaction(
         tx: &Transaction,
+        sender: &Result<RecoveredSender, String>,
+        block_number: u64,
+        state: &State,
+        config: &ExecutorConfig,
+    ) -> Result<Option<TransactionReceipt>, KvError> {
+        let signer = sender.clone().map_err(KvError::InvalidSignature)?;
+        let mut receipt = Self::execute_signed(tx, signer.address, block_number, state, config)?;
+        if let Some(receipt) = &mut receipt {
+            receipt.signer_public_key = Some(signer.public_key);
+        }
+        Ok(receipt)
+    }
+
+    /// Executes `tx` sent by `sender_id` in block `block_number`, which deadlines,
+    /// expiries and the faucet cooldown are checked against.
+    fn execute_signed(
+        tx: &Transaction,
+        sender_id: AccountId,
+        block_number: u64,
         state: &State,
-    ) -> Result<Option<TransactionReceipt>, String> {
-        let sender = verify_signature(tx)?;
-        let sender_id = AccountId(sender.clone());
+        config: &ExecutorConfig,
+    ) -> Result<Option<TransactionReceipt>, KvError> {
+        let sender = sender_id.0.clone();
         let mut updates = vec![];
+        let mut logs = vec![];
         tracing::info!(
             "Executing transaction from {} nonce {}",
             sender,
             tx.unsigned.nonce
         );
 
-        let mut sender_state = state
-            .get_account(&sender_id.0)
-            .map(|account| account.clone())
-            .unwrap_or_else(|| AccountState {
-                nonce: 0,
-                balance: 5000000000,
-                kv_store: HashMap::new(),
-            });
+        if tx.unsigned.chain_id != config.chain_id {
+            // Charging the sender or using up its nonce would let anyone replay
+            // its transactions from another chain to drain it, so nothing changes.
+            tracing::warn!(
+                "Rejecting transaction for chain {} on chain {}",
+                tx.unsigned.chain_id,
+                config.chain_id
+            );
+            return Ok(Some(Self::rejected_receipt(tx)));
+        }
+
+        let mut sender_state = match state.get_account(&sender_id.0) {
+            Some(account) => account,
+            None if config.genesis_only => {
+                tracing::warn!("Skipping transaction from unknown account {}", sender);
+                return Ok(None);
+            }
+            None => {
+                logs.push(ReceiptLog::AccountCreated {
+                    address: sender.clone(),
+                });
+                AccountState {
+                    nonce: 0,
+                    balance: config.default_balance,
+                    kv_store: BTreeMap::new(),
+                    expiries: BTreeMap::new(),
+                    last_faucet_block: None,
+                    encodings: BTreeMap::new(),
+                    writers: BTreeSet::new(),
+                }
+            }
+        };
 
         if tx.unsigned.nonce < sender_state.nonce {
             tracing::warn!(
@@ -136,37 +797,469 @@ impl PipelineExecutor {
         }
 
         if tx.unsigned.nonce > sender_state.nonce {
-            return Err(format!(
-                "Invalid nonce, tx nonce {}, tx {:?}, state nonce {}, whole state {:?}",
-                tx.unsigned.nonce, tx, sender_state.nonce, state,
-            ));
+            // A nonce gap must not abort the whole block, so the transaction is skipped.
+            tracing::warn!(
+                "Skipping future nonce, tx nonce {}, tx {:?}, state nonce {}",
+                tx.unsigned.nonce,
+                tx,
+                sender_state.nonce,
+            );
+            return Ok(None);
         }
 
-        match &tx.unsigned.kind {
+        let mut gas_used = compute_gas(&tx.unsigned.kind);
+        let fee = gas_used.saturating_mul(config.gas_price);
+        if sender_state.balance < fee {
+            tracing::warn!(
+                "Insufficient balance for gas, fee {}, balance {}",
+                fee,
+                sender_state.balance
+            );
+            sender_state.nonce += 1;
+            return Ok(Some(Self::failed_receipt(
+                tx,
+                gas_used,
+                sender_id,
+                sender_state,
+                logs,
+            )));
+        }
+        sender_state.balance -= fee;
+
+        if let Err(e) = config.payload_limits.check(&tx.unsigned) {
+            tracing::warn!("Rejecting oversized transaction: {}", e);
+            sender_state.nonce += 1;
+            return Ok(Some(Self::failed_receipt(
+                tx,
+                gas_used,
+                sender_id,
+                sender_state,
+                logs,
+            )));
+        }
+
+        if let Some(valid_until) = tx.unsigned.valid_until_block {
+            if block_number > valid_until {
+                tracing::warn!(
+                    "Rejecting expired transaction, valid until block {}, in block {}",
+                    valid_until,
+                    block_number
+                );
+                sender_state.nonce += 1;
+                return Ok(Some(Self::failed_receipt(
+                    tx,
+                    gas_used,
+                    sender_id,
+                    sender_state,
+                    logs,
+                )));
+            }
+        }
+
+        let stale_read = tx
+            .unsigned
+            .read_set
+            .iter()
+            .find(|(key, expected)| sender_state.kv_store.get(key) != Some(expected));
+        if let Some((key, expected)) = stale_read {
+            tracing::warn!(
+                "Read set mismatch on key {}, expected {}, found {:?}",
+                key,
+                expected,
+                sender_state.kv_store.get(key)
+            );
+            sender_state.nonce += 1;
+            return Ok(Some(Self::failed_receipt(
+                tx,
+                gas_used,
+                sender_id,
+                sender_state,
+                logs,
+            )));
+        }
+
+        let kind = match canonical_kind(&tx.unsigned.kind) {
+            Ok(kind) => kind,
+            Err(e) => {
+                tracing::warn!("Rejecting transaction to an invalid account: {}", e);
+                sender_state.nonce += 1;
+                return Ok(Some(Self::failed_receipt(
+                    tx,
+                    gas_used,
+                    sender_id,
+                    sender_state,
+                    logs,
+                )));
+            }
+        };
+        match kind.as_ref() {
             TransactionKind::Transfer { receiver, amount } => {
                 if sender_state.balance < *amount {
-                    return Err(format!("Insufficient balance"));
+                    tracing::warn!(
+                        "Insufficient balance, amount {}, balance {}",
+                        amount,
+                        sender_state.balance
+                    );
+                    sender_state.nonce += 1;
+                    return Ok(Some(Self::failed_receipt(
+                        tx,
+                        gas_used,
+                        sender_id,
+                        sender_state,
+                        logs,
+                    )));
                 }
 
-                let mut receiver_state = if let Some(account) = state.get_account(receiver) {
-                    AccountState {
-                        nonce: account.nonce,
-                        balance: account.balance,
-                        kv_store: account.kv_store.clone(),
+                let mut receiver_state = match state.get_account(receiver) {
+                    Some(account) => account,
+                    // Only genesis accounts exist in this mode, and a created
+                    // receiver could then send.
+                    None if config.genesis_only => {
+                        tracing::warn!("Rejecting transfer to unknown account {}", receiver);
+                        sender_state.nonce += 1;
+                        return Ok(Some(Self::failed_receipt(
+                            tx,
+                            gas_used,
+                            sender_id,
+                            sender_state,
+                            logs,
+                        )));
                     }
-                } else {
-                    AccountState {
-                        nonce: 0,
-                        balance: 0,
-                        kv_store: HashMap::new(),
+                    None => {
+                        logs.push(ReceiptLog::AccountCreated {
+                            address: receiver.clone(),
+                        });
+                        AccountState {
+                            nonce: 0,
+                            balance: 0,
+                            kv_store: BTreeMap::new(),
+                            expiries: BTreeMap::new(),
+                            last_faucet_block: None,
+                            encodings: BTreeMap::new(),
+                            writers: BTreeSet::new(),
+                        }
                     }
                 };
                 sender_state.balance -= amount;
                 receiver_state.balance += amount;
                 updates.push((AccountId(receiver.clone()), receiver_state));
+                logs.push(ReceiptLog::Transfer {
+                    from: sender.clone(),
+                    to: receiver.clone(),
+                    amount: *amount,
+                });
+            }
+            TransactionKind::SetKV { key, value }
+                if sender_state.kv_store.get(key) == Some(value)
+                    && !sender_state.expiries.contains_key(key)
+                    && !sender_state.encodings.contains_key(key) =>
+            {
+                let refund = (gas_used - GAS_NOOP_WRITE).saturating_mul(config.gas_price);
+                sender_state.balance = sender_state.balance.saturating_add(refund);
+                gas_used = GAS_NOOP_WRITE;
+                logs.push(ReceiptLog::NoOpWrite { key: key.clone() });
             }
             TransactionKind::SetKV { key, value } => {
-                sender_state.kv_store.insert(key.clone(), value.clone());
+                let old_value = sender_state.kv_store.insert(key.clone(), value.clone());
+                sender_state.expiries.remove(key);
+                sender_state.encodings.remove(key);
+                logs.push(ReceiptLog::KvSet {
+                    key: key.clone(),
+                    old_value,
+                    new_value: value.clone(),
+                });
+            }
+            TransactionKind::SetKVEncoded {
+                key,
+                value,
+                encoding,
+            } => {
+                if let Err(e) = encoding.decode(value) {
+                    tracing::warn!("Rejecting value of key {}: {}", key, e);
+                    sender_state.nonce += 1;
+                    return Ok(Some(Self::failed_receipt(
+                        tx,
+                        gas_used,
+                        sender_id,
+                        sender_state,
+                        logs,
+                    )));
+                }
+                let old_value = sender_state.kv_store.insert(key.clone(), value.clone());
+                sender_state.expiries.remove(key);
+                // UTF-8 is the default, so it is not recorded.
+                match encoding {
+                    ValueEncoding::Utf8 => sender_state.encodings.remove(key),
+                    _ => sender_state.encodings.insert(key.clone(), *encoding),
+                };
+                logs.push(ReceiptLog::KvSet {
+                    key: key.clone(),
+                    old_value,
+                    new_value: value.clone(),
+                });
+            }
+            TransactionKind::SetKVFor { target, key, value } => {
+                // Only an existing account can have granted access, and an
+                // account may always write into its own store.
+                let target_state = if *target == sender {
+                    None
+                } else {
+                    match state.get_account(target) {
+                        Some(account) if account.writers.contains(&sender) => Some(account),
+                        _ => {
+                            tracing::warn!(
+                                "Rejecting write by {} into {} without access",
+                                sender,
+                                target
+                            );
+                            sender_state.nonce += 1;
+                            return Ok(Some(Self::failed_receipt(
+                                tx,
+                                gas_used,
+                                sender_id,
+                                sender_state,
+                                logs,
+                            )));
+                        }
+                    }
+                };
+                let write = |account: &mut AccountState| {
+                    account.expiries.remove(key);
+                    account.encodings.remove(key);
+                    account.kv_store.insert(key.clone(), value.clone())
+                };
+                let old_value = match target_state {
+                    Some(mut target_state) => {
+                        let old_value = write(&mut target_state);
+                        updates.push((AccountId(target.clone()), target_state));
+                        old_value
+                    }
+                    None => write(&mut sender_state),
+                };
+                logs.push(ReceiptLog::KvSetFor {
+                    target: target.clone(),
+                    key: key.clone(),
+                    old_value,
+                    new_value: value.clone(),
+                });
+            }
+            TransactionKind::GrantWrite { grantee } | TransactionKind::RevokeWrite { grantee } => {
+                // Stored in the form senders are compared in.
+                let grantee = match canonical_address(grantee) {
+                    Ok(grantee) => grantee,
+                    Err(e) => {
+                        tracing::warn!("Rejecting write access for {}: {}", grantee, e);
+                        sender_state.nonce += 1;
+                        return Ok(Some(Self::failed_receipt(
+                            tx,
+                            gas_used,
+                            sender_id,
+                            sender_state,
+                            logs,
+                        )));
+                    }
+                };
+                if let TransactionKind::GrantWrite { .. } = &tx.unsigned.kind {
+                    sender_state.writers.insert(grantee.clone());
+                    logs.push(ReceiptLog::WriteGranted { grantee });
+                } else {
+                    sender_state.writers.remove(&grantee);
+                    logs.push(ReceiptLog::WriteRevoked { grantee });
+                }
+            }
+            TransactionKind::SetKVTtl {
+                key,
+                value,
+                expires_at_block,
+            } => {
+                // A key expiring in the block it is written in would never be readable.
+                if *expires_at_block <= block_number {
+                    tracing::warn!(
+                        "Rejecting key {} expiring at past block {}",
+                        key,
+                        expires_at_block
+                    );
+                    sender_state.nonce += 1;
+                    return Ok(Some(Self::failed_receipt(
+                        tx,
+                        gas_used,
+                        sender_id,
+                        sender_state,
+                        logs,
+                    )));
+                }
+                let old_value = sender_state.kv_store.insert(key.clone(), value.clone());
+                sender_state.expiries.insert(key.clone(), *expires_at_block);
+                sender_state.encodings.remove(key);
+                logs.push(ReceiptLog::KvSet {
+                    key: key.clone(),
+                    old_value,
+                    new_value: value.clone(),
+                });
+            }
+            TransactionKind::RestoreKV { entries } => {
+                let kv_store = entries.iter().cloned().collect::<BTreeMap<_, _>>();
+                if kv_store.len() != entries.len() || restore_size(entries) > MAX_RESTORE_BYTES {
+                    tracing::warn!(
+                        "Rejecting restore of {} entries, {} bytes, with duplicate keys or over {} bytes",
+                        entries.len(),
+                        restore_size(entries),
+                        MAX_RESTORE_BYTES
+                    );
+                    sender_state.nonce += 1;
+                    return Ok(Some(Self::failed_receipt(
+                        tx,
+                        gas_used,
+                        sender_id,
+                        sender_state,
+                        logs,
+                    )));
+                }
+                // The old store is dropped as a whole, expiries and encodings
+                // included, so the account never holds a mix of old and restored entries.
+                sender_state.kv_store = kv_store;
+                sender_state.expiries.clear();
+                sender_state.encodings.clear();
+                logs.push(ReceiptLog::KvRestored {
+                    key_count: entries.len(),
+                });
+            }
+            TransactionKind::AddKV { key, delta } => {
+                let old_value = sender_state.kv_store.get(key).cloned();
+                // Only UTF-8 text holds a counter.
+                let sum = match &old_value {
+                    _ if sender_state.encodings.contains_key(key) => None,
+                    Some(value) => value.parse::<i64>().ok(),
+                    None => Some(0),
+                }
+                .and_then(|current| current.checked_add(*delta));
+                let new_value = match sum {
+                    Some(sum) => sum.to_string(),
+                    None => {
+                        tracing::warn!(
+                            "Cannot add {} to key {} holding {:?}",
+                            delta,
+                            key,
+                            old_value
+                        );
+                        sender_state.nonce += 1;
+                        return Ok(Some(Self::failed_receipt(
+                            tx,
+                            gas_used,
+                            sender_id,
+                            sender_state,
+                            logs,
+                        )));
+                    }
+                };
+                // Any expiry is kept, so a counter set with `SetKVTtl` still expires.
+                sender_state.kv_store.insert(key.clone(), new_value.clone());
+                logs.push(ReceiptLog::KvSet {
+                    key: key.clone(),
+                    old_value,
+                    new_value,
+                });
+            }
+            TransactionKind::Mint { receiver, amount } => {
+                let authorized = config
+                    .faucet_address
+                    .as_ref()
+                    .is_some_and(|faucet| faucet.eq_ignore_ascii_case(&sender));
+                if !authorized {
+                    tracing::warn!("Rejecting mint from non-faucet account {}", sender);
+                    sender_state.nonce += 1;
+                    return Ok(Some(Self::failed_receipt(
+                        tx,
+                        gas_used,
+                        sender_id,
+                        sender_state,
+                        logs,
+                    )));
+                }
+                if config.faucet_max_amount.is_some_and(|max| *amount > max) {
+                    tracing::warn!("Rejecting mint of {} over the faucet limit", amount);
+                    sender_state.nonce += 1;
+                    return Ok(Some(Self::failed_receipt(
+                        tx,
+                        gas_used,
+                        sender_id,
+                        sender_state,
+                        logs,
+                    )));
+                }
+
+                let last_grant = if *receiver == sender {
+                    sender_state.last_faucet_block
+                } else {
+                    state
+                        .get_account(receiver)
+                        .and_then(|account| account.last_faucet_block)
+                };
+                if let Some(last_grant) = last_grant {
+                    if block_number < last_grant.saturating_add(config.faucet_cooldown_blocks) {
+                        tracing::warn!(
+                            "Rejecting mint to {} cooling down since block {}",
+                            receiver,
+                            last_grant
+                        );
+                        sender_state.nonce += 1;
+                        return Ok(Some(Self::failed_receipt(
+                            tx,
+                            gas_used,
+                            sender_id,
+                            sender_state,
+                            logs,
+                        )));
+                    }
+                }
+                // Grants are only recorded with a cooldown, so state roots of
+                // chains without one stay the same.
+                let last_faucet_block = (config.faucet_cooldown_blocks > 0).then_some(block_number);
+
+                if *receiver == sender {
+                    sender_state.balance = sender_state.balance.saturating_add(*amount);
+                    if last_faucet_block.is_some() {
+                        sender_state.last_faucet_block = last_faucet_block;
+                    }
+                } else {
+                    let mut receiver_state = match state.get_account(receiver) {
+                        Some(account) => account,
+                        None if config.genesis_only => {
+                            tracing::warn!("Rejecting mint to unknown account {}", receiver);
+                            sender_state.nonce += 1;
+                            return Ok(Some(Self::failed_receipt(
+                                tx,
+                                gas_used,
+                                sender_id,
+                                sender_state,
+                                logs,
+                            )));
+                        }
+                        None => {
+                            logs.push(ReceiptLog::AccountCreated {
+                                address: receiver.clone(),
+                            });
+                            AccountState {
+                                nonce: 0,
+                                balance: 0,
+                                kv_store: BTreeMap::new(),
+                                expiries: BTreeMap::new(),
+                                last_faucet_block: None,
+                                encodings: BTreeMap::new(),
+                                writers: BTreeSet::new(),
+                            }
+                        }
+                    };
+                    receiver_state.balance = receiver_state.balance.saturating_add(*amount);
+                    if last_faucet_block.is_some() {
+                        receiver_state.last_faucet_block = last_faucet_block;
+                    }
+                    updates.push((AccountId(receiver.clone()), receiver_state));
+                }
+                logs.push(ReceiptLog::Mint {
+                    to: receiver.clone(),
+                    amount: *amount,
+                });
             }
         }
         sender_state.nonce += 1;
@@ -176,61 +1269,2123 @@ impl PipelineExecutor {
             transaction_hash: compute_transaction_hash(&tx.unsigned),
             status: true,
             state_updates: updates,
-            gas_used: 21000, // to simplify, we use one fiexd gas num
-            logs: Vec::new(),
+            gas_used,
+            logs,
+            signer_public_key: None,
         }))
     }
 
+    /// Receipt for a transaction rejected without any effect, not even on its
+    /// sender's nonce.
+    fn rejected_receipt(tx: &Transaction) -> TransactionReceipt {
+        TransactionReceipt {
+            transaction: tx.clone(),
+            transaction_hash: compute_transaction_hash(&tx.unsigned),
+            status: false,
+            state_updates: vec![],
+            gas_used: 0,
+            logs: vec![],
+            signer_public_key: None,
+        }
+    }
+
+    /// Receipt for a transaction that was rejected after its nonce was consumed:
+    /// only the sender's nonce and any fee already charged are applied. `logs`
+    /// holds the sender's `AccountCreated` if the sender is new.
+    fn failed_receipt(
+        tx: &Transaction,
+        gas_used: u64,
+        sender_id: AccountId,
+        sender_state: AccountState,
+        logs: Vec<ReceiptLog>,
+    ) -> TransactionReceipt {
+        TransactionReceipt {
+            transaction: tx.clone(),
+            transaction_hash: compute_transaction_hash(&tx.unsigned),
+            status: false,
+            state_updates: vec![(sender_id, sender_state)],
+            gas_used,
+            logs,
+            signer_public_key: None,
+        }
+    }
+
     pub async fn commit_task(
         mut start_num: u64,
         max_size: Option<usize>,
         storage: Arc<dyn Storage>,
-        pending_blocks: Arc<Mutex<HashMap<u64, (StateRoot, Block, Vec<TransactionReceipt>)>>>,
+        pending_blocks: Arc<Mutex<PendingBlocks>>,
         pool: KvStoreTxPool,
+        block_sender: broadcast::Sender<BlockSummary>,
+        receipt_sender: broadcast::Sender<TransactionReceipt>,
+        flush_every_n_blocks: u64,
+        prune_window: Option<u64>,
+        mut backoff: Backoff,
+        mut shutdown: watch::Receiver<bool>,
     ) {
+        let mut unflushed = 0;
         loop {
-            let committed_blocks = get_block_buffer_manager()
-                .get_committed_blocks(start_num, max_size)
-                .await;
-            if let Err(e) = committed_blocks {
-                warn!("failed to get committed blocks: {}", e);
-                continue;
+            if *shutdown.borrow() {
+                break;
+            }
+            let committed_blocks = tokio::select! {
+                blocks = get_block_buffer_manager().get_committed_blocks(start_num, max_size) => blocks,
+                _ = shutdown.changed() => break,
+            };
+            let delay = match &committed_blocks {
+                Err(e) => {
+                    let delay = backoff.error_delay();
+                    if backoff.should_log_error() {
+                        warn!(
+                            "failed to get committed blocks ({} consecutive failures): {}",
+                            backoff.consecutive_errors(),
+                            e
+                        );
+                    }
+                    Some(delay)
+                }
+                Ok(blocks) if blocks.is_empty() => Some(backoff.idle_delay()),
+                Ok(_) => None,
+            };
+            if let Some(delay) = delay {
+                tokio::select! {
+                    _ = tokio::time::sleep(delay) => continue,
+                    _ = shutdown.changed() => break,
+                }
             }
+            if backoff.consecutive_errors() > 0 {
+                info!(
+                    "got committed blocks again after {} failures",
+                    backoff.consecutive_errors()
+                );
+            }
+            backoff.reset();
             let committed_blocks = committed_blocks.unwrap();
             start_num += committed_blocks.len() as u64;
             for block_id_num_hash in committed_blocks {
-                let res = Self::persist_block(
-                    block_id_num_hash.num,
-                    &pending_blocks,
-                    storage.as_ref(),
-                    &pool,
-                )
-                .await;
-                if let Err(e) = res {
-                    warn!("failed to persist block: {}", e);
+                let block_number = block_id_num_hash.num;
+                // A block that fails to persist is retried until it succeeds,
+                // never skipped: every later block must extend it.
+                loop {
+                    let res = Self::persist_block(
+                        block_number,
+                        &pending_blocks,
+                        storage.as_ref(),
+                        &pool,
+                        &block_sender,
+                        &receipt_sender,
+                    )
+                    .await;
+                    let Err(e) = res else {
+                        backoff.reset();
+                        break;
+                    };
+                    let delay = backoff.error_delay();
+                    if backoff.should_log_error() {
+                        error!(
+                            "failed to persist block {} ({} consecutive failures): {}",
+                            block_number,
+                            backoff.consecutive_errors(),
+                            e
+                        );
+                    }
+                    tokio::select! {
+                        _ = tokio::time::sleep(delay) => {}
+                        _ = shutdown.changed() => {
+                            info!("Commit task stopped before block {}", block_number);
+                            return;
+                        }
+                    }
+                }
+                unflushed += 1;
+                if unflushed >= flush_every_n_blocks {
+                    if let Err(e) = storage.flush().await {
+                        warn!("failed to flush storage: {}", e);
+                    }
+                    unflushed = 0;
                 }
+                if let Some(window) = prune_window {
+                    Self::prune_final_blocks(storage.as_ref(), block_number, window).await;
+                }
+            }
+        }
+        info!("Commit task stopped before block {}", start_num);
+    }
+
+    /// Prunes the blocks more than `window` blocks behind `block_number`, the
+    /// one just persisted. Pruning stops at the latest checkpoint anyway, so it
+    /// is only worth trying once a new one is persisted.
+    async fn prune_final_blocks(storage: &dyn Storage, block_number: u64, window: u64) {
+        if block_number % CHECKPOINT_INTERVAL != 0 || block_number <= window {
+            return;
+        }
+        match storage.prune_below(block_number - window).await {
+            Ok(0) => {}
+            Ok(pruned) => info!(
+                "Pruned {} blocks below block {}",
+                pruned,
+                block_number - window
+            ),
+            Err(e) => warn!("failed to prune blocks: {}", e),
+        }
+    }
+
+    /// Writes the executed block `block_number` to `storage` once it is checked
+    /// to extend the last committed state.
+    async fn commit_pending_block(
+        block_number: u64,
+        (state_root, block, receipts, checkpoint): &PendingBlock,
+        storage: &dyn Storage,
+    ) -> Result<(), KvError> {
+        // Only block 1 of a database created before block 0 was persisted has
+        // no parent root to check.
+        let parent_root = match block_number.checked_sub(1) {
+            Some(parent) => storage.get_state_root(parent).await?,
+            None => None,
+        };
+        match parent_root {
+            Some(expected) if block.header.parent_state_root != expected.0 => {
+                let actual = StateRoot(block.header.parent_state_root);
+                error!(
+                    expected = %expected.to_hex(),
+                    actual = %actual.to_hex(),
+                    "Block {} does not extend the committed state, refusing to persist it",
+                    block_number
+                );
+                return Err(KvError::StateRootMismatch {
+                    block_number,
+                    expected: expected.to_hex(),
+                    actual: actual.to_hex(),
+                });
+            }
+            Some(_) => {}
+            None if block_number <= 1 => {}
+            None => {
+                return Err(KvError::NotFound(format!(
+                    "State root of block {}",
+                    block_number - 1
+                )))
             }
         }
+        storage
+            .commit_block_atomic(block, receipts, state_root, checkpoint.as_ref())
+            .await
     }
 
     async fn persist_block(
         block_number: u64,
-        pending_blocks: &Mutex<HashMap<u64, (StateRoot, Block, Vec<TransactionReceipt>)>>,
+        pending_blocks: &Mutex<PendingBlocks>,
         storage: &dyn Storage,
         pool: &KvStoreTxPool,
-    ) -> Result<(), String> {
-        let mut pending_blocks = pending_blocks.lock().await;
-        let (state_root, final_block, receipts) = pending_blocks.remove(&block_number).unwrap();
+        block_sender: &broadcast::Sender<BlockSummary>,
+        receipt_sender: &broadcast::Sender<TransactionReceipt>,
+    ) -> Result<(), KvError> {
+        let start = Instant::now();
+        // Taken out, so the execute task can add blocks while this one is written.
+        let pending = pending_blocks
+            .lock()
+            .await
+            .remove(&block_number)
+            .ok_or_else(|| KvError::NotFound(format!("Executed block {}", block_number)))?;
+        // A block that failed to persist is put back, so the attempt can be retried.
+        if let Err(e) = Self::commit_pending_block(block_number, &pending, storage).await {
+            pending_blocks.lock().await.insert(block_number, pending);
+            return Err(e);
+        }
+        let (state_root, final_block, receipts, _) = pending;
         for txn in &final_block.transactions {
             pool.remove_txn(&txn.account(), txn.sequence_number());
         }
-        storage.save_block(&final_block).await.unwrap();
-        storage.save_transaction_receipts(receipts).await.unwrap();
-        storage
-            .save_state_root(final_block.header.number, state_root)
-            .await
-            .unwrap();
-        info!("Block {} persisted", block_number);
+        let expired = pool.remove_expired(block_number + 1);
+        if expired > 0 {
+            info!("Dropped {} expired transactions from the mempool", expired);
+        }
+        let commit_log = BlockCommitLog {
+            block_number,
+            state_root: state_root.to_hex(),
+            txn_count: final_block.transactions.len(),
+            gas_used: receipts.iter().map(|receipt| receipt.gas_used).sum(),
+            commit_latency_ms: start.elapsed().as_millis() as u64,
+        };
+        METRICS.record_commit(block_number, commit_log.txn_count, start.elapsed());
+        for txn in &final_block.transactions {
+            METRICS.record_transaction_kind(txn.txn.unsigned.kind_tag());
+        }
+        info!(
+            block_number = commit_log.block_number,
+            state_root = %commit_log.state_root,
+            txn_count = commit_log.txn_count,
+            gas_used = commit_log.gas_used,
+            commit_latency_ms = commit_log.commit_latency_ms,
+            "Block {} persisted",
+            block_number
+        );
+        // Sending only fails when nobody is subscribed, which is fine. Sending
+        // never waits: a lagging subscriber loses the oldest messages instead.
+        let _ = block_sender.send(BlockSummary::from(&final_block));
+        for receipt in receipts {
+            let _ = receipt_sender.send(receipt);
+        }
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        test_utils::*, AccountKey, MemStorage, SignatureScheme, SledStorage, TransactionBuilder,
+        TxnOrdering,
+    };
+
+    /// Storage with one committed block, and block 2 executed on top of it.
+    async fn block_after_one(storage: &MemStorage) -> PendingBlock {
+        let config = config();
+        let mut state = State::new(None).unwrap();
+        let mut recent_txns = RecentTxns::new(0);
+        let txns = vec![sign(&new_key(), 0, set_kv("key", "value"))];
+        commit_block(storage, &mut state, &config, &mut recent_txns, txns).await;
+        let txns = vec![sign(&new_key(), 0, set_kv("key", "value"))];
+        let (block, receipts) = execute_block(&mut state, &config, &mut recent_txns, txns);
+        (state.get_state_root().clone(), block, receipts, None)
+    }
+
+    async fn persist(
+        block_number: u64,
+        pending_blocks: &Mutex<PendingBlocks>,
+        storage: &dyn Storage,
+    ) -> Result<(), KvError> {
+        let pool = KvStoreTxPool::new(PayloadLimits::default(), None, None, TxnOrdering::default());
+        let (block_sender, _) = broadcast::channel(1);
+        let (receipt_sender, _) = broadcast::channel(1);
+        PipelineExecutor::persist_block(
+            block_number,
+            pending_blocks,
+            storage,
+            &pool,
+            &block_sender,
+            &receipt_sender,
+        )
+        .await
+    }
+
+    #[tokio::test]
+    async fn block_extending_the_committed_state_is_persisted() {
+        let storage = MemStorage::new();
+        let pending = block_after_one(&storage).await;
+        let pending_blocks = Mutex::new(HashMap::from([(2, pending)]));
+
+        assert_eq!(persist(2, &pending_blocks, &storage).await, Ok(()));
+        assert!(storage.get_block(2).await.unwrap().is_some());
+        assert!(pending_blocks.lock().await.is_empty());
+    }
+
+    /// Log output written to a shared buffer.
+    #[derive(Clone, Default)]
+    struct CapturedLogs(Arc<std::sync::Mutex<Vec<u8>>>);
+
+    impl std::io::Write for CapturedLogs {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[tokio::test]
+    async fn persisted_block_is_logged_with_structured_fields() {
+        let storage = MemStorage::new();
+        let pending = block_after_one(&storage).await;
+        let state_root = pending.0.to_hex();
+        let gas_used = pending
+            .2
+            .iter()
+            .map(|receipt| receipt.gas_used)
+            .sum::<u64>();
+        assert!(gas_used > 0);
+        let pending_blocks = Mutex::new(HashMap::from([(2, pending)]));
+
+        let logs = CapturedLogs::default();
+        let writer = logs.clone();
+        let subscriber = tracing_subscriber::fmt()
+            .json()
+            .with_writer(move || writer.clone())
+            .finish();
+        // The test runs on one thread, which the subscriber is set for.
+        let guard = tracing::subscriber::set_default(subscriber);
+        persist(2, &pending_blocks, &storage).await.unwrap();
+        drop(guard);
+
+        let output = String::from_utf8(logs.0.lock().unwrap().clone()).unwrap();
+        let fields = output
+            .lines()
+            .map(|line| serde_json::from_str::<serde_json::Value>(line).unwrap())
+            .find(|line| line["fields"]["message"] == "Block 2 persisted")
+            .unwrap()["fields"]
+            .clone();
+        assert_eq!(fields["block_number"], 2);
+        assert_eq!(fields["state_root"], state_root);
+        assert_eq!(fields["txn_count"], 1);
+        assert_eq!(fields["gas_used"], gas_used);
+        assert!(fields["commit_latency_ms"].is_u64());
+    }
+
+    #[tokio::test]
+    async fn stopping_between_blocks_leaves_storage_recoverable() {
+        let path = temp_dir();
+        let config = config();
+        let alice = new_key();
+        let mut state = State::new(None).unwrap();
+        let mut recent_txns = RecentTxns::new(config.replay_window);
+        let mut pending_blocks = HashMap::new();
+        let mut roots = vec![];
+        for nonce in 0..4 {
+            let txns = vec![sign(&alice, nonce, set_kv("key", &nonce.to_string()))];
+            let (block, receipts) = execute_block(&mut state, &config, &mut recent_txns, txns);
+            roots.push(state.get_state_root().0);
+            pending_blocks.insert(
+                block.header.number,
+                (state.get_state_root().clone(), block, receipts, None),
+            );
+        }
+        let pending_blocks = Mutex::new(pending_blocks);
+
+        {
+            let storage = SledStorage::new(&path).unwrap();
+            for block_number in 1..=3 {
+                persist(block_number, &pending_blocks, &storage)
+                    .await
+                    .unwrap();
+            }
+            // Shutting down here drops block 4, executed but never committed,
+            // and flushes what was.
+            storage.flush().await.unwrap();
+        }
+        assert!(pending_blocks.lock().await.contains_key(&4));
+
+        let storage = SledStorage::new(&path).unwrap();
+        assert!(storage.get_block(4).await.unwrap().is_none());
+        let recovered = Arc::new(RwLock::new(State::new(None).unwrap()));
+        let latest = crate::recover_from_storage(
+            1,
+            &recovered,
+            &storage,
+            &config,
+            &mut RecentTxns::new(config.replay_window),
+            1,
+        )
+        .await
+        .unwrap();
+        assert_eq!(latest, 3);
+        let recovered = recovered.read().await;
+        assert_eq!(recovered.get_state_root().0, roots[2]);
+        let account = recovered.get_account(&alice.address()).unwrap();
+        assert_eq!(account.nonce, 3);
+        assert_eq!(account.kv_store.get("key").unwrap(), "2");
+        drop(storage);
+        std::fs::remove_dir_all(path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn readers_keep_the_committed_state_while_a_block_runs_on_a_copy() {
+        let config = config();
+        let keys = (0..200).map(|_| new_key()).collect::<Vec<_>>();
+        let mut recent_txns = RecentTxns::new(0);
+        let mut committed = State::new(None).unwrap();
+        let txns = keys
+            .iter()
+            .map(|key| sign(key, 0, set_kv("key", "old")))
+            .collect();
+        execute_block(&mut committed, &config, &mut recent_txns, txns);
+        let committed_root = committed.get_state_root().0;
+        let state = Arc::new(RwLock::new(committed));
+
+        // Executing on a copy, as `execute_block` does, while a reader holds
+        // the lock throughout: neither waits for the other.
+        let reader = state.read().await;
+        let mut next = reader.clone();
+        let txns = keys
+            .iter()
+            .map(|key| sign(key, 1, set_kv("key", "new")))
+            .collect();
+        execute_block(&mut next, &config, &mut recent_txns, txns);
+        assert_eq!(reader.get_current_block_number(), 1);
+        assert_eq!(reader.get_state_root().0, committed_root);
+        for key in &keys {
+            let account = reader.get_account(&key.address()).unwrap();
+            assert_eq!(account.kv_store.get("key").unwrap(), "old");
+        }
+        assert!(state.try_write().is_err());
+        drop(reader);
+
+        let next_root = next.get_state_root().0;
+        *state.try_write().unwrap() = next;
+        let state = state.read().await;
+        assert_eq!(state.get_current_block_number(), 2);
+        assert_eq!(state.get_state_root().0, next_root);
+        let account = state.get_account(&keys[0].address()).unwrap();
+        assert_eq!(account.kv_store.get("key").unwrap(), "new");
+    }
+
+    #[tokio::test]
+    async fn receipt_subscribers_see_commits_in_order_and_laggards_skip_ahead() {
+        let storage = MemStorage::new();
+        let config = config();
+        let mut state = State::new(None).unwrap();
+        let mut recent_txns = RecentTxns::new(0);
+        let mut pending_blocks = HashMap::new();
+        let mut signatures = vec![];
+        for _ in 0..3 {
+            let txns = vec![
+                sign(&new_key(), 0, set_kv("key", "value")),
+                sign(&new_key(), 0, set_kv("key", "value")),
+            ];
+            signatures.extend(txns.iter().map(|txn| txn.txn.signature.clone()));
+            let (block, receipts) = execute_block(&mut state, &config, &mut recent_txns, txns);
+            pending_blocks.insert(
+                block.header.number,
+                (state.get_state_root().clone(), block, receipts, None),
+            );
+        }
+        let pending_blocks = Mutex::new(pending_blocks);
+        let pool = mempool();
+        let (block_sender, _) = broadcast::channel(4);
+        let (receipt_sender, mut laggard) = broadcast::channel(4);
+
+        let mut received = vec![];
+        for block_number in 1..=3 {
+            let mut subscriber = receipt_sender.subscribe();
+            // Nobody reads `laggard`, which must not hold up the commit.
+            PipelineExecutor::persist_block(
+                block_number,
+                &pending_blocks,
+                &storage,
+                &pool,
+                &block_sender,
+                &receipt_sender,
+            )
+            .await
+            .unwrap();
+            while let Ok(receipt) = subscriber.try_recv() {
+                received.push(receipt.transaction.signature);
+            }
+        }
+        assert_eq!(received, signatures);
+        assert!(storage.get_block(3).await.unwrap().is_some());
+
+        // The laggard lost the oldest receipts past the capacity, and then
+        // resumes with the newest ones.
+        assert!(matches!(
+            laggard.try_recv(),
+            Err(broadcast::error::TryRecvError::Lagged(2))
+        ));
+        let mut rest = vec![];
+        while let Ok(receipt) = laggard.try_recv() {
+            rest.push(receipt.transaction.signature);
+        }
+        assert_eq!(rest, signatures[2..]);
+    }
+
+    #[tokio::test]
+    async fn auto_prune_keeps_the_finality_window() {
+        let window = 30;
+        let storage = MemStorage::new();
+        let config = config();
+        let mut state = State::new(None).unwrap();
+        let mut recent_txns = RecentTxns::new(0);
+        for block_number in 1..=2 * CHECKPOINT_INTERVAL {
+            let txns = vec![sign(&new_key(), 0, set_kv("key", "value"))];
+            commit_block(&storage, &mut state, &config, &mut recent_txns, txns).await;
+            PipelineExecutor::prune_final_blocks(&storage, block_number, window).await;
+            // Nothing inside the window is ever pruned.
+            for kept in block_number.saturating_sub(window).max(1)..=block_number {
+                assert!(storage.get_block(kept).await.unwrap().is_some());
+            }
+        }
+        let oldest = 2 * CHECKPOINT_INTERVAL - window;
+        assert!(storage.get_block(oldest - 1).await.unwrap().is_none());
+        assert!(storage.get_block(1).await.unwrap().is_none());
+        assert!(storage.get_block(oldest).await.unwrap().is_some());
+
+        // A window wider than the chain prunes nothing.
+        let storage = MemStorage::new();
+        let mut state = State::new(None).unwrap();
+        for block_number in 1..=CHECKPOINT_INTERVAL {
+            commit_block(&storage, &mut state, &config, &mut recent_txns, vec![]).await;
+            PipelineExecutor::prune_final_blocks(&storage, block_number, CHECKPOINT_INTERVAL).await;
+        }
+        assert!(storage.get_block(1).await.unwrap().is_some());
+    }
+
+    #[tokio::test]
+    async fn block_with_wrong_parent_root_is_rejected_and_kept_pending() {
+        let storage = MemStorage::new();
+        let mut pending = block_after_one(&storage).await;
+        pending.1.header.parent_state_root = [9; 32];
+        let pending_blocks = Mutex::new(HashMap::from([(2, pending)]));
+
+        let result = persist(2, &pending_blocks, &storage).await;
+        assert!(
+            matches!(
+                result,
+                Err(KvError::StateRootMismatch {
+                    block_number: 2,
+                    ..
+                })
+            ),
+            "{:?}",
+            result
+        );
+        assert!(storage.get_block(2).await.unwrap().is_none());
+        assert!(storage.get_state_root(2).await.unwrap().is_none());
+        assert!(pending_blocks.lock().await.contains_key(&2));
+    }
+
+    #[tokio::test]
+    async fn rejected_block_writes_nothing_and_is_persisted_whole_on_retry() {
+        let storage = MemStorage::new();
+        let mut pending = block_after_one(&storage).await;
+        let parent_state_root = pending.1.header.parent_state_root;
+        pending.1.header.parent_state_root = [9; 32];
+        let rejected_hash = pending.1.header.hash();
+        let txn = pending.1.transactions[0].clone();
+        let pending_blocks = Mutex::new(HashMap::from([(2, pending)]));
+
+        assert!(persist(2, &pending_blocks, &storage).await.is_err());
+        assert!(storage.get_block(2).await.unwrap().is_none());
+        assert!(storage
+            .get_block_by_hash(rejected_hash)
+            .await
+            .unwrap()
+            .is_none());
+        assert!(storage.get_state_root(2).await.unwrap().is_none());
+        assert!(storage
+            .get_transaction_receipt(&txn.txn)
+            .await
+            .unwrap()
+            .is_none());
+        let history = storage.get_account_transactions(&txn.address, 10, 0);
+        assert!(history.await.unwrap().is_empty());
+
+        // The block was put back pending, and once it extends the committed
+        // state every part of it is written.
+        pending_blocks
+            .lock()
+            .await
+            .get_mut(&2)
+            .unwrap()
+            .1
+            .header
+            .parent_state_root = parent_state_root;
+        assert_eq!(persist(2, &pending_blocks, &storage).await, Ok(()));
+        assert!(storage.get_state_root(2).await.unwrap().is_some());
+        assert!(storage
+            .get_transaction_receipt(&txn.txn)
+            .await
+            .unwrap()
+            .is_some());
+        let history = storage.get_account_transactions(&txn.address, 10, 0);
+        assert_eq!(history.await.unwrap().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn block_without_a_committed_parent_is_rejected() {
+        let storage = MemStorage::new();
+        let mut pending = block_after_one(&storage).await;
+        pending.1.header.number = 3;
+        let pending_blocks = Mutex::new(HashMap::from([(3, pending)]));
+
+        let result = persist(3, &pending_blocks, &storage).await;
+        assert_eq!(
+            result,
+            Err(KvError::NotFound("State root of block 2".to_string()))
+        );
+        assert!(storage.get_block(3).await.unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn block_that_was_never_executed_is_not_found() {
+        let storage = MemStorage::new();
+        let pending_blocks = Mutex::new(HashMap::new());
+
+        let result = persist(1, &pending_blocks, &storage).await;
+        assert!(matches!(result, Err(KvError::NotFound(_))), "{:?}", result);
+    }
+
+    #[test]
+    fn genesis_only_mode_creates_no_account() {
+        let (alice, bob, stranger) = (new_key(), new_key(), new_key());
+        let mut state = State::new(None).unwrap();
+        let mut recent_txns = RecentTxns::new(0);
+        // Alice and Bob stand in for genesis accounts.
+        let txns = vec![
+            sign(&alice, 0, set_kv("key", "value")),
+            sign(&bob, 0, set_kv("key", "value")),
+        ];
+        execute_block(&mut state, &config(), &mut recent_txns, txns);
+
+        let config = ExecutorConfig {
+            genesis_only: true,
+            faucet_address: Some(alice.address()),
+            ..config()
+        };
+        let transfer = |receiver: &AccountKey| TransactionKind::Transfer {
+            receiver: receiver.address(),
+            amount: 10,
+        };
+        let mint = TransactionKind::Mint {
+            receiver: stranger.address(),
+            amount: 10,
+        };
+        let txns = vec![
+            sign(&alice, 1, transfer(&stranger)),
+            sign(&alice, 2, mint),
+            sign(&alice, 3, transfer(&bob)),
+            sign(&stranger, 0, set_kv("key", "value")),
+        ];
+        let (_, receipts) = execute_block(&mut state, &config, &mut recent_txns, txns);
+
+        let statuses = receipts.iter().map(|r| r.status).collect::<Vec<_>>();
+        assert_eq!(statuses, [false, false, true]);
+        assert!(state.get_account(&stranger.address()).is_none());
+        assert_eq!(state.get_account(&alice.address()).unwrap().nonce, 4);
+        assert_eq!(state.get_account(&bob.address()).unwrap().balance, 1010);
+    }
+
+    #[test]
+    fn writing_into_another_store_needs_a_grant_until_it_is_revoked() {
+        let (alice, bob, carol) = (new_key(), new_key(), new_key());
+        let mut state = State::new(None).unwrap();
+        let mut recent_txns = RecentTxns::new(0);
+        let write_for = |key: &AccountKey, nonce, value: &str| {
+            sign(
+                key,
+                nonce,
+                TransactionKind::SetKVFor {
+                    target: bob.address(),
+                    key: "key".to_string(),
+                    value: value.to_string(),
+                },
+            )
+        };
+        let access = |grant: bool| {
+            let grantee = alice.address();
+            if grant {
+                TransactionKind::GrantWrite { grantee }
+            } else {
+                TransactionKind::RevokeWrite { grantee }
+            }
+        };
+        let mut run = |state: &mut State, txns| {
+            let (_, receipts) = execute_block(state, &config(), &mut recent_txns, txns);
+            receipts
+                .iter()
+                .map(|receipt| receipt.status)
+                .collect::<Vec<_>>()
+        };
+
+        // Without a grant, and even before the target exists, the write fails
+        // and only uses up the writer's nonce.
+        assert_eq!(
+            run(&mut state, vec![write_for(&alice, 0, "early")]),
+            [false]
+        );
+        assert!(state.get_account(&bob.address()).is_none());
+        assert_eq!(
+            run(
+                &mut state,
+                vec![
+                    sign(&bob, 0, set_kv("key", "own")),
+                    write_for(&alice, 1, "ungranted")
+                ]
+            ),
+            [true, false]
+        );
+        assert_eq!(state.get_account(&alice.address()).unwrap().nonce, 2);
+        assert_eq!(
+            state.get_account(&bob.address()).unwrap().kv_store["key"],
+            "own"
+        );
+
+        assert_eq!(run(&mut state, vec![sign(&bob, 1, access(true))]), [true]);
+        let root_before = state.get_state_root().0;
+        assert_eq!(
+            run(
+                &mut state,
+                vec![
+                    write_for(&alice, 2, "granted"),
+                    write_for(&carol, 0, "not granted")
+                ]
+            ),
+            [true, false]
+        );
+        assert_ne!(state.get_state_root().0, root_before);
+        let bob_state = state.get_account(&bob.address()).unwrap();
+        assert_eq!(bob_state.kv_store["key"], "granted");
+        assert_eq!(bob_state.nonce, 2);
+
+        assert_eq!(run(&mut state, vec![sign(&bob, 2, access(false))]), [true]);
+        assert_eq!(
+            run(&mut state, vec![write_for(&alice, 3, "revoked")]),
+            [false]
+        );
+        assert_eq!(
+            state.get_account(&bob.address()).unwrap().kv_store["key"],
+            "granted"
+        );
+    }
+
+    #[test]
+    fn named_accounts_are_canonicalized_before_execution() {
+        let (alice, bob) = (new_key(), new_key());
+        let mut state = State::new(None).unwrap();
+        let mut recent_txns = RecentTxns::new(0);
+        let txns = vec![
+            sign(&alice, 0, set_kv("key", "value")),
+            sign(
+                &bob,
+                0,
+                TransactionKind::GrantWrite {
+                    grantee: alice.address(),
+                },
+            ),
+        ];
+        execute_block(&mut state, &config(), &mut recent_txns, txns);
+
+        let shouted = format!("0x{}", bob.address().to_uppercase());
+        let transfer = sign(
+            &alice,
+            1,
+            TransactionKind::Transfer {
+                receiver: shouted.clone(),
+                amount: 10,
+            },
+        );
+        assert_eq!(
+            PipelineExecutor::touched_accounts(&transfer.txn, &recover_signer(&transfer.txn)),
+            [alice.address(), bob.address()]
+        );
+        let txns = vec![
+            transfer,
+            sign(
+                &alice,
+                2,
+                TransactionKind::SetKVFor {
+                    target: shouted,
+                    key: "key".to_string(),
+                    value: "from alice".to_string(),
+                },
+            ),
+            sign(
+                &alice,
+                3,
+                TransactionKind::Transfer {
+                    receiver: "not an address".to_string(),
+                    amount: 10,
+                },
+            ),
+        ];
+        let (_, receipts) = execute_block(&mut state, &config(), &mut recent_txns, txns);
+
+        let statuses = receipts.iter().map(|r| r.status).collect::<Vec<_>>();
+        assert_eq!(statuses, [true, true, false]);
+        let bob_state = state.get_account(&bob.address()).unwrap();
+        assert_eq!(bob_state.balance, 1010);
+        assert_eq!(bob_state.kv_store["key"], "from alice");
+        assert_eq!(state.get_account(&alice.address()).unwrap().balance, 990);
+    }
+
+    #[test]
+    fn transaction_with_a_bad_signature_fails_without_aborting_the_block() {
+        let mut txns = (0..PARALLEL_WAVE_THRESHOLD * 2)
+            .map(|_| sign(&new_key(), 0, set_kv("key", "value")))
+            .collect::<Vec<_>>();
+        let forged = &mut txns[PARALLEL_WAVE_THRESHOLD].txn;
+        forged.signature = "00".repeat(65);
+        let forged = forged.clone();
+
+        let mut state = State::new(None).unwrap();
+        let receipts = PipelineExecutor::apply_transactions(
+            &txns,
+            1,
+            &mut state,
+            &config(),
+            &RecentTxns::new(0),
+            4,
+        );
+
+        assert_eq!(receipts.len(), txns.len());
+        let rejected = &receipts[PARALLEL_WAVE_THRESHOLD];
+        assert_eq!(rejected.transaction.signature, forged.signature);
+        assert!(!rejected.status);
+        assert!(rejected.state_updates.is_empty());
+        assert_eq!(receipts.iter().filter(|r| r.status).count(), txns.len() - 1);
+    }
+
+    /// Executes `txns` as block 1 on a fresh state with `workers` threads.
+    fn run_block(
+        txns: &[TransactionWithAccount],
+        workers: usize,
+    ) -> (State, Vec<TransactionReceipt>) {
+        let mut state = State::new(None).unwrap();
+        let receipts = PipelineExecutor::apply_transactions(
+            txns,
+            1,
+            &mut state,
+            &config(),
+            &RecentTxns::new(0),
+            workers,
+        );
+        (state, receipts)
+    }
+
+    #[test]
+    fn future_nonce_is_skipped_without_affecting_its_block() {
+        let alice = new_key();
+        let future = sign(&alice, 5, set_kv("future", "value"));
+        let mut valid = vec![
+            sign(&alice, 0, set_kv("key", "0")),
+            sign(&alice, 1, set_kv("key", "1")),
+        ];
+        valid.extend(
+            (0..PARALLEL_WAVE_THRESHOLD * 2).map(|_| sign(&new_key(), 0, set_kv("key", "value"))),
+        );
+        let mut txns = valid.clone();
+        txns.insert(1, future.clone());
+        let future_hash = compute_transaction_hash(&future.txn.unsigned);
+
+        let (expected, _) = run_block(&valid, 1);
+        for workers in [1, 4] {
+            let (state, receipts) = run_block(&txns, workers);
+            assert_eq!(receipts.len(), valid.len());
+            assert!(receipts.iter().all(|receipt| receipt.status));
+            assert!(receipts
+                .iter()
+                .all(|receipt| receipt.transaction_hash != future_hash));
+            let account = state.get_account(&alice.address()).unwrap();
+            assert_eq!(account.nonce, 2);
+            assert_eq!(account.kv_store.get("key").unwrap(), "1");
+            assert!(!account.kv_store.contains_key("future"));
+            assert_eq!(state.get_state_root().0, expected.get_state_root().0);
+        }
+    }
+
+    #[test]
+    fn queried_accounts_stay_absent_until_a_transaction_creates_them() {
+        let (alice, bob, carol) = (new_key(), new_key(), new_key());
+        let mut state = State::new(None).unwrap();
+        for address in [alice.address(), bob.address(), carol.address()] {
+            assert!(state.get_account(&address).is_none());
+            assert!(!state.account_exists(&address));
+        }
+        assert_eq!(state.account_count(), 0);
+
+        let txns = vec![
+            sign(
+                &alice,
+                0,
+                TransactionKind::Transfer {
+                    receiver: bob.address(),
+                    amount: 0,
+                },
+            ),
+            // Skipped, so Carol is not created.
+            sign(&carol, 3, set_kv("key", "value")),
+        ];
+        let (_, receipts) = execute_block(&mut state, &config(), &mut RecentTxns::new(0), txns);
+        assert_eq!(receipts.len(), 1);
+        let created = receipts[0]
+            .logs
+            .iter()
+            .filter_map(|log| match log {
+                ReceiptLog::AccountCreated { address } => Some(address.clone()),
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(created, [alice.address(), bob.address()]);
+
+        // Bob exists with nothing in it, unlike Carol, who never did.
+        assert!(state.account_exists(&bob.address()));
+        let bob_account = state.get_account(&bob.address()).unwrap();
+        assert_eq!((bob_account.nonce, bob_account.balance), (0, 0));
+        assert!(!state.account_exists(&carol.address()));
+        assert_eq!(state.account_count(), 2);
+
+        // An existing account is not created again.
+        let txns = vec![sign(&bob, 0, set_kv("key", "value"))];
+        let (_, receipts) = execute_block(&mut state, &config(), &mut RecentTxns::new(0), txns);
+        assert!(!receipts[0]
+            .logs
+            .iter()
+            .any(|log| matches!(log, ReceiptLog::AccountCreated { .. })));
+    }
+
+    #[test]
+    fn new_senders_are_credited_the_default_balance_once() {
+        let (alice, bob) = (new_key(), new_key());
+        let config = ExecutorConfig {
+            default_balance: 500,
+            ..config()
+        };
+        let mut state = State::new(None).unwrap();
+        let mut recent_txns = RecentTxns::new(0);
+        let transfer = |nonce, amount| {
+            sign(
+                &alice,
+                nonce,
+                TransactionKind::Transfer {
+                    receiver: bob.address(),
+                    amount,
+                },
+            )
+        };
+
+        let (_, receipts) = execute_block(
+            &mut state,
+            &config,
+            &mut recent_txns,
+            vec![transfer(0, 100)],
+        );
+        assert!(receipts[0].status);
+        assert_eq!(state.get_account(&alice.address()).unwrap().balance, 400);
+        // A receiver is created with only what it was sent.
+        assert_eq!(state.get_account(&bob.address()).unwrap().balance, 100);
+
+        // Neither is credited again when seen later, Bob not even as a sender.
+        let txns = vec![transfer(1, 100), sign(&bob, 0, set_kv("key", "value"))];
+        let (_, receipts) = execute_block(&mut state, &config, &mut recent_txns, txns);
+        assert!(receipts.iter().all(|receipt| receipt.status));
+        assert_eq!(state.get_account(&alice.address()).unwrap().balance, 300);
+        assert_eq!(state.get_account(&bob.address()).unwrap().balance, 200);
+        assert_eq!(state.total_supply(), 500);
+    }
+
+    #[test]
+    fn supply_changes_only_by_mints_default_balances_and_fees() {
+        let (faucet, alice, bob) = (new_key(), new_key(), new_key());
+        let config = ExecutorConfig {
+            faucet_address: Some(faucet.address()),
+            gas_price: 1,
+            ..config()
+        };
+        let mut state = State::new(None).unwrap();
+        let mut recent_txns = RecentTxns::new(0);
+        let transfer = |key: &AccountKey, nonce, amount| {
+            sign(
+                key,
+                nonce,
+                TransactionKind::Transfer {
+                    receiver: bob.address(),
+                    amount,
+                },
+            )
+        };
+        let mint = TransactionKind::Mint {
+            receiver: bob.address(),
+            amount: 50,
+        };
+        // Both senders are new and credited, Bob is created empty, and Alice's
+        // transfer fails for more than her balance.
+        let txns = vec![
+            sign(&faucet, 0, mint),
+            transfer(&faucet, 1, 10),
+            transfer(&alice, 0, 1_000_000),
+        ];
+        let (_, receipts) = execute_block(&mut state, &config, &mut recent_txns, txns);
+        let statuses = receipts.iter().map(|r| r.status).collect::<Vec<_>>();
+        assert_eq!(statuses, [true, true, false]);
+
+        let fees = receipts[0].gas_used + receipts[1].gas_used;
+        let expected = expected_supply(0, &receipts, &config);
+        assert_eq!(*expected.end(), (2 * 1000 + 50 - fees) as u128);
+        assert_eq!(
+            expected.end() - expected.start(),
+            receipts[2].gas_used as u128
+        );
+        let supply = state.total_supply();
+        assert!(expected.contains(&supply));
+        assert!(!expected.contains(&(expected.end() + 1)));
+        assert!(!expected.contains(&(expected.start() - 1)));
+
+        // Without fees, a transfer between existing accounts keeps it exact.
+        let free = ExecutorConfig {
+            gas_price: 0,
+            ..config.clone()
+        };
+        let (_, receipts) = execute_block(
+            &mut state,
+            &free,
+            &mut recent_txns,
+            vec![transfer(&faucet, 2, 100)],
+        );
+        assert!(receipts[0].status);
+        assert_eq!(state.total_supply(), supply);
+        assert_eq!(expected_supply(supply, &receipts, &free), supply..=supply);
+    }
+
+    #[test]
+    fn set_kv_fails_unless_the_sender_can_pay_for_its_gas() {
+        let kind = || set_kv("key", "value");
+        let fee = compute_gas(&kind()) * 2;
+        for (balance, succeeds) in [(fee - 1, false), (fee, true)] {
+            let alice = new_key();
+            let config = ExecutorConfig {
+                default_balance: balance,
+                gas_price: 2,
+                ..config()
+            };
+            let mut state = State::new(None).unwrap();
+            let (_, receipts) = execute_block(
+                &mut state,
+                &config,
+                &mut RecentTxns::new(0),
+                vec![sign(&alice, 0, kind())],
+            );
+
+            assert_eq!(receipts[0].status, succeeds);
+            let account = state.get_account(&alice.address()).unwrap();
+            // Either way the nonce is used up.
+            assert_eq!(account.nonce, 1);
+            if succeeds {
+                assert_eq!(account.balance, 0);
+                assert_eq!(account.kv_store.get("key").unwrap(), "value");
+            } else {
+                // A sender that cannot pay the fee is not charged part of it.
+                assert_eq!(account.balance, fee - 1);
+                assert!(account.kv_store.is_empty());
+            }
+        }
+    }
+
+    #[test]
+    fn parallel_execution_matches_serial_execution() {
+        let keys = (0..8).map(|_| new_key()).collect::<Vec<_>>();
+        let mut txns = vec![];
+        // Independent writes, then transfers chaining every account to the
+        // next, which must run in order against both accounts they touch.
+        for (index, key) in keys.iter().enumerate() {
+            txns.push(sign(key, 0, set_kv("key", &index.to_string())));
+        }
+        for _ in 0..4 {
+            txns.extend(
+                (0..PARALLEL_WAVE_THRESHOLD * 2)
+                    .map(|index| sign(&new_key(), 0, set_kv("key", &index.to_string()))),
+            );
+        }
+        for (index, key) in keys.iter().enumerate() {
+            let receiver = &keys[(index + 1) % keys.len()];
+            txns.push(sign(
+                key,
+                1,
+                TransactionKind::Transfer {
+                    receiver: receiver.address(),
+                    amount: 100 * (index as u64 + 1),
+                },
+            ));
+        }
+
+        let (serial, serial_receipts) = run_block(&txns, 1);
+        let (parallel, parallel_receipts) = run_block(&txns, 4);
+
+        assert_eq!(serial.get_state_root().0, parallel.get_state_root().0);
+        let hashes = |receipts: &[TransactionReceipt]| {
+            receipts
+                .iter()
+                .map(|receipt| (receipt.transaction_hash, receipt.status))
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(hashes(&serial_receipts), hashes(&parallel_receipts));
+        assert!(serial_receipts.iter().all(|receipt| receipt.status));
+        // Each account sent 100 * (its index + 1) and received 100 * its index,
+        // the first one 800 from the last.
+        let balances = keys
+            .iter()
+            .map(|key| parallel.get_account(&key.address()).unwrap().balance)
+            .collect::<Vec<_>>();
+        assert_eq!(balances, [1700, 900, 900, 900, 900, 900, 900, 900]);
+    }
+
+    #[test]
+    fn conflicting_transactions_match_executing_them_one_at_a_time() {
+        let keys = (0..PARALLEL_WAVE_THRESHOLD * 2)
+            .map(|_| new_key())
+            .collect::<Vec<_>>();
+        let hot = &keys[0];
+        let mut txns = vec![];
+        // Every account pays the same receiver twice and writes in between, so
+        // most transactions conflict on the receiver or their own sender.
+        for (index, key) in keys.iter().enumerate().skip(1) {
+            let pay_hot = |nonce| {
+                sign(
+                    key,
+                    nonce,
+                    TransactionKind::Transfer {
+                        receiver: hot.address(),
+                        amount: index as u64,
+                    },
+                )
+            };
+            txns.push(pay_hot(0));
+            txns.push(sign(key, 1, set_kv("key", &index.to_string())));
+            txns.push(pay_hot(2));
+        }
+        txns.push(sign(
+            hot,
+            0,
+            TransactionKind::Transfer {
+                receiver: keys[1].address(),
+                amount: 100,
+            },
+        ));
+
+        let (parallel, parallel_receipts) = run_block(&txns, 4);
+        let mut sequential = State::new(None).unwrap();
+        let mut sequential_receipts = vec![];
+        for tx in &txns {
+            sequential_receipts.extend(PipelineExecutor::apply_transactions(
+                std::slice::from_ref(tx),
+                1,
+                &mut sequential,
+                &config(),
+                &RecentTxns::new(0),
+                1,
+            ));
+        }
+
+        assert_eq!(parallel.get_state_root().0, sequential.get_state_root().0);
+        let outcomes = |receipts: &[TransactionReceipt]| {
+            receipts
+                .iter()
+                .map(|receipt| (receipt.transaction_hash, receipt.status, receipt.gas_used))
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(outcomes(&parallel_receipts), outcomes(&sequential_receipts));
+        assert_eq!(parallel_receipts.len(), txns.len());
+        assert!(parallel_receipts.iter().all(|receipt| receipt.status));
+    }
+
+    /// Run with `cargo test --release -- --ignored --nocapture bench`.
+    #[test]
+    #[ignore]
+    fn bench_parallel_execution() {
+        let txns = (0..1000)
+            .map(|index| sign(&new_key(), 0, set_kv("key", &index.to_string())))
+            .collect::<Vec<_>>();
+        let workers = default_workers();
+
+        let start = Instant::now();
+        let (serial, _) = run_block(&txns, 1);
+        let serial_time = start.elapsed();
+        let start = Instant::now();
+        let (parallel, _) = run_block(&txns, workers);
+        let parallel_time = start.elapsed();
+
+        assert_eq!(serial.get_state_root().0, parallel.get_state_root().0);
+        println!(
+            "1000 independent SetKV: {:?} serial, {:?} on {} workers, {:.1}x",
+            serial_time,
+            parallel_time,
+            workers,
+            serial_time.as_secs_f64() / parallel_time.as_secs_f64()
+        );
+    }
+
+    #[test]
+    fn transaction_signed_for_another_chain_is_rejected() {
+        let alice = new_key();
+        let chain_two = ExecutorConfig {
+            chain_id: 2,
+            ..config()
+        };
+        let for_chain_one = TransactionBuilder::new(&alice, 1, 0).build(set_kv("key", "value"));
+        let for_chain_two = TransactionBuilder::new(&alice, 2, 0).build(set_kv("key", "value"));
+        assert_ne!(
+            compute_transaction_hash(&for_chain_one.txn.unsigned),
+            compute_transaction_hash(&for_chain_two.txn.unsigned)
+        );
+        // Relabeling the chain breaks the signature.
+        let mut relabeled = for_chain_one.txn.clone();
+        relabeled.unsigned.chain_id = 2;
+        assert_ne!(
+            recover_signer(&relabeled).map(|sender| sender.address.0),
+            Ok(alice.address())
+        );
+
+        let mut state = State::new(None).unwrap();
+        let mut recent_txns = RecentTxns::new(0);
+        let (_, receipts) = execute_block(
+            &mut state,
+            &chain_two,
+            &mut recent_txns,
+            vec![for_chain_one],
+        );
+        assert_eq!(receipts.len(), 1);
+        assert!(!receipts[0].status);
+        assert!(receipts[0].state_updates.is_empty());
+        assert!(state.get_account(&alice.address()).is_none());
+
+        // The rejected transaction did not use up the nonce.
+        let (_, receipts) = execute_block(
+            &mut state,
+            &chain_two,
+            &mut recent_txns,
+            vec![for_chain_two],
+        );
+        assert!(receipts[0].status);
+        assert_eq!(state.get_account(&alice.address()).unwrap().nonce, 1);
+    }
+
+    #[test]
+    fn memo_is_signed_and_kept_in_the_receipt_without_changing_execution() {
+        let alice = new_key();
+        let with_memo = |memo: &str| {
+            TransactionBuilder::new(&alice, CHAIN_ID, 0)
+                .memo(memo.to_string())
+                .build(set_kv("key", "value"))
+        };
+        let plain = sign(&alice, 0, set_kv("key", "value"));
+        let noted = with_memo("order 42");
+        let hash = compute_transaction_hash(&noted.txn.unsigned);
+        assert_ne!(hash, compute_transaction_hash(&plain.txn.unsigned));
+        assert_ne!(
+            hash,
+            compute_transaction_hash(&with_memo("order 43").txn.unsigned)
+        );
+        // Editing the memo after signing breaks the signature.
+        let mut edited = noted.txn.clone();
+        edited.unsigned.memo = Some("order 43".to_string());
+        assert_ne!(
+            recover_signer(&edited).map(|sender| sender.address.0),
+            Ok(alice.address())
+        );
+
+        // Limits are counted in bytes, and only at submission: a memo over the
+        // limit still executes.
+        let limits = PayloadLimits {
+            max_memo_bytes: 4,
+            ..PayloadLimits::default()
+        };
+        assert!(limits.check_memo(&with_memo("éé").txn.unsigned).is_ok());
+        assert!(limits.check_memo(&with_memo("ééé").txn.unsigned).is_err());
+        let small_limits = ExecutorConfig {
+            payload_limits: limits,
+            ..config()
+        };
+
+        let run = |txn| {
+            let mut state = State::new(None).unwrap();
+            let (_, receipts) = execute_block(
+                &mut state,
+                &small_limits,
+                &mut RecentTxns::new(0),
+                vec![txn],
+            );
+            (
+                state.get_state_root().0,
+                receipts.into_iter().next().unwrap(),
+            )
+        };
+        let (plain_root, plain_receipt) = run(plain);
+        let (noted_root, noted_receipt) = run(noted);
+        assert!(noted_receipt.status);
+        assert_eq!(noted_root, plain_root);
+        assert_eq!(noted_receipt.gas_used, plain_receipt.gas_used);
+        assert_eq!(
+            noted_receipt.transaction.unsigned.memo.as_deref(),
+            Some("order 42")
+        );
+        assert_eq!(
+            compute_transaction_hash(&noted_receipt.transaction.unsigned),
+            hash
+        );
+    }
+
+    #[test]
+    fn node_on_a_configured_chain_skips_default_chain_transactions_in_a_block() {
+        let (alice, bob) = (new_key(), new_key());
+        let chain_42 = ExecutorConfig {
+            chain_id: 42,
+            ..config()
+        };
+        let for_chain = |key: &AccountKey, chain_id, nonce| {
+            TransactionBuilder::new(key, chain_id, nonce).build(set_kv("key", "value"))
+        };
+        let txns = vec![
+            for_chain(&alice, CHAIN_ID, 0),
+            for_chain(&alice, 42, 0),
+            for_chain(&bob, CHAIN_ID, 0),
+            for_chain(&alice, 42, 1),
+        ];
+
+        let mut state = State::new(None).unwrap();
+        let (_, receipts) = execute_block(&mut state, &chain_42, &mut RecentTxns::new(0), txns);
+        let statuses = receipts.iter().map(|r| r.status).collect::<Vec<_>>();
+        assert_eq!(statuses, [false, true, false, true]);
+        assert!(state.get_account(&bob.address()).is_none());
+
+        // The block ends as if only the transactions for chain 42 were in it.
+        let mut expected = State::new(None).unwrap();
+        execute_block(
+            &mut expected,
+            &chain_42,
+            &mut RecentTxns::new(0),
+            vec![for_chain(&alice, 42, 0), for_chain(&alice, 42, 1)],
+        );
+        assert_eq!(state.get_state_root().0, expected.get_state_root().0);
+    }
+
+    #[test]
+    fn ed25519_transactions_execute_and_scheme_mismatches_are_rejected() {
+        let alice = AccountKey::from_hex(
+            &hex::encode(rand::random::<[u8; 32]>()),
+            SignatureScheme::Ed25519,
+        )
+        .unwrap();
+        let bob = new_key();
+        let mut state = State::new(None).unwrap();
+        let mut recent_txns = RecentTxns::new(0);
+
+        // A signature relabeled as the other scheme does not verify, from
+        // either side, and leaves the nonce unused.
+        let mut as_secp256k1 = sign(&alice, 0, set_kv("key", "value"));
+        as_secp256k1.txn.unsigned.scheme = SignatureScheme::Secp256k1;
+        let mut as_ed25519 = sign(&bob, 0, set_kv("key", "value"));
+        as_ed25519.txn.unsigned.scheme = SignatureScheme::Ed25519;
+        let (_, receipts) = execute_block(
+            &mut state,
+            &config(),
+            &mut recent_txns,
+            vec![as_secp256k1, as_ed25519],
+        );
+        assert_eq!(receipts.len(), 2);
+        for receipt in &receipts {
+            assert!(!receipt.status);
+            assert!(receipt.state_updates.is_empty());
+        }
+        assert!(state.get_account(&alice.address()).is_none());
+        assert!(state.get_account(&bob.address()).is_none());
+
+        let transfer = TransactionKind::Transfer {
+            receiver: bob.address(),
+            amount: 100,
+        };
+        let txns = vec![
+            sign(&alice, 0, set_kv("key", "value")),
+            sign(&alice, 1, transfer),
+        ];
+        let (_, receipts) = execute_block(&mut state, &config(), &mut recent_txns, txns);
+        assert!(receipts.iter().all(|receipt| receipt.status));
+        let account = state.get_account(&alice.address()).unwrap();
+        assert_eq!(account.nonce, 2);
+        assert_eq!(
+            account.kv_store.get("key").map(String::as_str),
+            Some("value")
+        );
+        assert_eq!(state.get_account(&bob.address()).unwrap().balance, 100);
+    }
+
+    /// Executes `txns` as block `block_number` on `state`, whose current block
+    /// may lag behind, as consensus may number blocks past it.
+    fn apply_at(
+        block_number: u64,
+        state: &mut State,
+        txns: &[TransactionWithAccount],
+    ) -> Vec<TransactionReceipt> {
+        PipelineExecutor::apply_transactions(
+            txns,
+            block_number,
+            state,
+            &config(),
+            &RecentTxns::new(0),
+            1,
+        )
+    }
+
+    #[test]
+    fn deadlines_are_checked_against_the_executed_block() {
+        let (alice, bob) = (new_key(), new_key());
+        let mut state = State::new(None).unwrap();
+        let deadline = |key: &AccountKey, valid_until_block: u64| {
+            TransactionBuilder::new(key, CHAIN_ID, 0)
+                .valid_until_block(valid_until_block)
+                .build(set_kv("key", "value"))
+        };
+        let txns = [deadline(&alice, 50), deadline(&bob, 49)];
+
+        let receipts = apply_at(50, &mut state, &txns);
+        assert_eq!(receipts.len(), 2);
+        assert!(receipts[0].status);
+        assert_eq!(
+            state.get_account(&alice.address()).unwrap().kv_store["key"],
+            "value"
+        );
+        // Past its deadline, the transaction fails but uses up its nonce.
+        assert!(!receipts[1].status);
+        let bob_state = state.get_account(&bob.address()).unwrap();
+        assert_eq!(bob_state.nonce, 1);
+        assert!(bob_state.kv_store.is_empty());
+    }
+
+    #[test]
+    fn ttl_keys_expire_at_the_executed_block() {
+        let key = new_key();
+        let mut state = State::new(None).unwrap();
+        let ttl = |nonce: u64, expires_at_block: u64| {
+            sign(
+                &key,
+                nonce,
+                TransactionKind::SetKVTtl {
+                    key: format!("key{}", nonce),
+                    value: "value".to_string(),
+                    expires_at_block,
+                },
+            )
+        };
+
+        // Expiring in the block it is written in, the first key is refused.
+        let receipts = apply_at(50, &mut state, &[ttl(0, 50), ttl(1, 60)]);
+        assert!(!receipts[0].status);
+        assert!(receipts[1].status);
+        let keys = |state: &State| {
+            let account = state.get_account(&key.address()).unwrap();
+            account.kv_store.into_keys().collect::<Vec<_>>()
+        };
+        assert_eq!(keys(&state), ["key1"]);
+
+        apply_at(59, &mut state, &[]);
+        assert_eq!(keys(&state), ["key1"]);
+        apply_at(60, &mut state, &[]);
+        assert!(keys(&state).is_empty());
+    }
+
+    #[test]
+    fn receipts_log_each_state_change_in_order() {
+        let (alice, bob, carol) = (new_key(), new_key(), new_key());
+        let transfer = |key: &AccountKey, nonce, amount| {
+            sign(
+                key,
+                nonce,
+                TransactionKind::Transfer {
+                    receiver: bob.address(),
+                    amount,
+                },
+            )
+        };
+        let txns = vec![
+            sign(&alice, 0, set_kv("k", "v")),
+            sign(&alice, 1, set_kv("k", "w")),
+            transfer(&alice, 2, 10),
+            // Carol cannot pay, so only her creation is logged.
+            transfer(&carol, 0, 5000),
+        ];
+        let mut state = State::new(None).unwrap();
+        let (_, receipts) = execute_block(&mut state, &config(), &mut RecentTxns::new(0), txns);
+
+        let logs = receipts
+            .into_iter()
+            .map(|receipt| receipt.logs)
+            .collect::<Vec<_>>();
+        assert_eq!(
+            logs,
+            [
+                vec![
+                    ReceiptLog::AccountCreated {
+                        address: alice.address(),
+                    },
+                    ReceiptLog::KvSet {
+                        key: "k".to_string(),
+                        old_value: None,
+                        new_value: "v".to_string(),
+                    },
+                ],
+                vec![ReceiptLog::KvSet {
+                    key: "k".to_string(),
+                    old_value: Some("v".to_string()),
+                    new_value: "w".to_string(),
+                }],
+                vec![
+                    ReceiptLog::AccountCreated {
+                        address: bob.address(),
+                    },
+                    ReceiptLog::Transfer {
+                        from: alice.address(),
+                        to: bob.address(),
+                        amount: 10,
+                    },
+                ],
+                vec![ReceiptLog::AccountCreated {
+                    address: carol.address(),
+                }],
+            ]
+        );
+    }
+
+    #[test]
+    fn restore_replaces_the_whole_store_or_nothing() {
+        let alice = new_key();
+        let mut state = State::new(None).unwrap();
+        let mut recent_txns = RecentTxns::new(0);
+        let restore = |entries: &[(&str, &str)]| TransactionKind::RestoreKV {
+            entries: entries
+                .iter()
+                .map(|(key, value)| (key.to_string(), value.to_string()))
+                .collect(),
+        };
+        let txns = vec![
+            sign(&alice, 0, set_kv("a", "1")),
+            sign(
+                &alice,
+                1,
+                TransactionKind::SetKVTtl {
+                    key: "b".to_string(),
+                    value: "2".to_string(),
+                    expires_at_block: 100,
+                },
+            ),
+            sign(
+                &alice,
+                2,
+                TransactionKind::SetKVEncoded {
+                    key: "c".to_string(),
+                    value: "ff".to_string(),
+                    encoding: ValueEncoding::Hex,
+                },
+            ),
+            sign(&alice, 3, restore(&[("y", "2"), ("x", "1")])),
+        ];
+        let (_, receipts) = execute_block(&mut state, &config(), &mut recent_txns, txns);
+        assert!(receipts.iter().all(|receipt| receipt.status));
+        assert_eq!(receipts[3].logs, [ReceiptLog::KvRestored { key_count: 2 }]);
+        let account = state.get_account(&alice.address()).unwrap();
+        assert_eq!(
+            account.kv_store.into_iter().collect::<Vec<_>>(),
+            [
+                ("x".to_string(), "1".to_string()),
+                ("y".to_string(), "2".to_string())
+            ]
+        );
+        assert!(account.expiries.is_empty());
+        assert!(account.encodings.is_empty());
+
+        // A restore with a duplicate key or over the size limit changes nothing.
+        let oversized = "v".repeat(MAX_RESTORE_BYTES);
+        let txns = vec![
+            sign(&alice, 4, restore(&[("z", "1"), ("z", "2")])),
+            sign(&alice, 5, restore(&[("z", &oversized)])),
+        ];
+        let (_, receipts) = execute_block(&mut state, &config(), &mut recent_txns, txns);
+        assert!(receipts.iter().all(|receipt| !receipt.status));
+        let account = state.get_account(&alice.address()).unwrap();
+        assert_eq!(account.nonce, 6);
+        assert_eq!(account.kv_store.len(), 2);
+        assert!(!account.kv_store.contains_key("z"));
+
+        // An empty restore clears the store.
+        let txns = vec![sign(&alice, 6, restore(&[]))];
+        let (_, receipts) = execute_block(&mut state, &config(), &mut recent_txns, txns);
+        assert!(receipts[0].status);
+        assert!(state
+            .get_account(&alice.address())
+            .unwrap()
+            .kv_store
+            .is_empty());
+    }
+
+    #[test]
+    fn add_kv_fails_on_overflow_underflow_or_a_non_integer() {
+        let alice = new_key();
+        let mut state = State::new(None).unwrap();
+        let mut recent_txns = RecentTxns::new(0);
+        let add = |nonce: u64, key: &str, delta: i64| {
+            sign(
+                &alice,
+                nonce,
+                TransactionKind::AddKV {
+                    key: key.to_string(),
+                    delta,
+                },
+            )
+        };
+        let txns = vec![
+            // A missing key counts from 0.
+            add(0, "max", i64::MAX - 1),
+            add(1, "max", 1),
+            add(2, "max", 1),
+            add(3, "min", i64::MIN + 1),
+            add(4, "min", -1),
+            add(5, "min", -1),
+            sign(&alice, 6, set_kv("text", "abc")),
+            add(7, "text", 1),
+            add(8, "min", i64::MAX),
+        ];
+        let (_, receipts) = execute_block(&mut state, &config(), &mut recent_txns, txns);
+
+        let statuses = receipts
+            .iter()
+            .map(|receipt| receipt.status)
+            .collect::<Vec<_>>();
+        assert_eq!(
+            statuses,
+            [true, true, false, true, true, false, true, false, true]
+        );
+        let account = state.get_account(&alice.address()).unwrap();
+        assert_eq!(account.nonce, 9);
+        assert_eq!(account.kv_store["max"], i64::MAX.to_string());
+        assert_eq!(account.kv_store["min"], "-1");
+        assert_eq!(account.kv_store["text"], "abc");
+        // A failed addition logs no write.
+        assert!(receipts[2].logs.is_empty());
+        assert_eq!(
+            receipts[4].logs,
+            [ReceiptLog::KvSet {
+                key: "min".to_string(),
+                old_value: Some((i64::MIN + 1).to_string()),
+                new_value: i64::MIN.to_string(),
+            }]
+        );
+    }
+
+    #[test]
+    fn transaction_fails_when_its_read_set_is_stale() {
+        let alice = new_key();
+        let mut state = State::new(None).unwrap();
+        let mut recent_txns = RecentTxns::new(0);
+        let txns = vec![sign(&alice, 0, set_kv("counter", "1"))];
+        execute_block(&mut state, &config(), &mut recent_txns, txns);
+
+        let compare_and_set = |nonce: u64, read: &[(&str, &str)], value: &str| {
+            TransactionBuilder::new(&alice, CHAIN_ID, nonce)
+                .read_set(
+                    read.iter()
+                        .map(|(key, value)| (key.to_string(), value.to_string()))
+                        .collect(),
+                )
+                .build(set_kv("counter", value))
+        };
+        let txns = vec![
+            compare_and_set(1, &[("counter", "1")], "2"),
+            // Reads the value the first transaction just replaced.
+            compare_and_set(2, &[("counter", "1")], "3"),
+            compare_and_set(3, &[("missing", "")], "4"),
+            compare_and_set(4, &[("counter", "2"), ("other", "x")], "5"),
+            compare_and_set(5, &[("counter", "2")], "6"),
+        ];
+        let (_, receipts) = execute_block(&mut state, &config(), &mut recent_txns, txns);
+
+        let statuses = receipts
+            .iter()
+            .map(|receipt| receipt.status)
+            .collect::<Vec<_>>();
+        assert_eq!(statuses, [true, false, false, false, true]);
+        let account = state.get_account(&alice.address()).unwrap();
+        assert_eq!(account.nonce, 6);
+        assert_eq!(account.kv_store["counter"], "6");
+        assert!(receipts[1].logs.is_empty());
+    }
+
+    #[test]
+    fn rewriting_the_same_value_refunds_the_per_byte_gas() {
+        let alice = new_key();
+        let config = ExecutorConfig {
+            default_balance: 1_000_000,
+            gas_price: 2,
+            ..config()
+        };
+        let mut state = State::new(None).unwrap();
+        let mut recent_txns = RecentTxns::new(0);
+        let write = set_kv("key", "value");
+        let txns = vec![
+            sign(&alice, 0, write.clone()),
+            sign(&alice, 1, write.clone()),
+            sign(&alice, 2, set_kv("key", "other")),
+        ];
+        let (_, receipts) = execute_block(&mut state, &config, &mut recent_txns, txns);
+
+        assert!(receipts.iter().all(|receipt| receipt.status));
+        let gas = receipts
+            .iter()
+            .map(|receipt| receipt.gas_used)
+            .collect::<Vec<_>>();
+        assert_eq!(
+            gas,
+            [
+                compute_gas(&write),
+                GAS_NOOP_WRITE,
+                compute_gas(&set_kv("key", "other"))
+            ]
+        );
+        assert_eq!(
+            receipts[1].logs,
+            [ReceiptLog::NoOpWrite {
+                key: "key".to_string()
+            }]
+        );
+        let balance = state.get_account(&alice.address()).unwrap().balance;
+        assert_eq!(balance, 1_000_000 - 2 * gas.iter().sum::<u64>());
+
+        // Rewriting a value that expires or has an encoding changes what the
+        // key holds, so it is charged in full.
+        let txns = vec![
+            sign(
+                &alice,
+                3,
+                TransactionKind::SetKVTtl {
+                    key: "key".to_string(),
+                    value: "other".to_string(),
+                    expires_at_block: 100,
+                },
+            ),
+            sign(&alice, 4, set_kv("key", "other")),
+        ];
+        let (_, receipts) = execute_block(&mut state, &config, &mut recent_txns, txns);
+        assert_eq!(receipts[1].gas_used, compute_gas(&set_kv("key", "other")));
+        assert!(!state
+            .get_account(&alice.address())
+            .unwrap()
+            .expiries
+            .contains_key("key"));
+    }
+
+    #[test]
+    fn only_the_configured_faucet_may_mint() {
+        let (faucet, mallory, alice) = (new_key(), new_key(), new_key());
+        let mint = |key: &AccountKey| {
+            sign(
+                key,
+                0,
+                TransactionKind::Mint {
+                    receiver: alice.address(),
+                    amount: 50,
+                },
+            )
+        };
+        let with_faucet = ExecutorConfig {
+            faucet_address: Some(faucet.address()),
+            ..config()
+        };
+        for (config, key, minted) in [
+            (&with_faucet, &faucet, true),
+            (&with_faucet, &mallory, false),
+            // Minting is disabled without a faucet.
+            (&config(), &faucet, false),
+        ] {
+            let mut state = State::new(None).unwrap();
+            let (_, receipts) =
+                execute_block(&mut state, config, &mut RecentTxns::new(0), vec![mint(key)]);
+            assert_eq!(receipts[0].status, minted);
+            let balance = state
+                .get_account(&alice.address())
+                .map_or(0, |account| account.balance);
+            assert_eq!(balance, if minted { 50 } else { 0 });
+            // The signer pays for a refused mint like any failed transaction.
+            assert_eq!(state.get_account(&key.address()).unwrap().nonce, 1);
+        }
+    }
+
+    #[test]
+    fn faucet_limits_the_amount_and_cools_down_by_executed_block() {
+        let (faucet, alice) = (new_key(), new_key());
+        let config = ExecutorConfig {
+            faucet_address: Some(faucet.address()),
+            faucet_max_amount: Some(100),
+            faucet_cooldown_blocks: 10,
+            ..config()
+        };
+        let mint = |nonce: u64, amount: u64| {
+            sign(
+                &faucet,
+                nonce,
+                TransactionKind::Mint {
+                    receiver: alice.address(),
+                    amount,
+                },
+            )
+        };
+        let mut state = State::new(None).unwrap();
+        let mut apply = |block_number: u64, txns: &[TransactionWithAccount]| {
+            let receipts = PipelineExecutor::apply_transactions(
+                txns,
+                block_number,
+                &mut state,
+                &config,
+                &RecentTxns::new(0),
+                1,
+            );
+            let balance = state
+                .get_account(&alice.address())
+                .map_or(0, |account| account.balance);
+            (receipts[0].status, balance)
+        };
+
+        assert_eq!(apply(50, &[mint(0, 101)]), (false, 0));
+        assert_eq!(apply(50, &[mint(1, 100)]), (true, 100));
+        // Alice last received funds in block 50, so block 59 is still cooling down.
+        assert_eq!(apply(59, &[mint(2, 100)]), (false, 100));
+        assert_eq!(apply(60, &[mint(3, 100)]), (true, 200));
+        assert_eq!(
+            state
+                .get_account(&alice.address())
+                .unwrap()
+                .last_faucet_block,
+            Some(60)
+        );
+    }
+
+    #[tokio::test]
+    async fn oversized_block_in_chunks_matches_executing_it_at_once() {
+        let (alice, bob) = (new_key(), new_key());
+        // Alice's nonces and transfers to Bob cross every chunk boundary.
+        let txns = (0..10)
+            .map(|nonce| {
+                let kind = match nonce % 3 {
+                    0 => TransactionKind::Transfer {
+                        receiver: bob.address(),
+                        amount: 10,
+                    },
+                    _ => set_kv(&format!("key{}", nonce), "value"),
+                };
+                sign(&alice, nonce, kind)
+            })
+            .chain([sign(&bob, 0, set_kv("key", "value"))])
+            .collect::<Vec<_>>();
+
+        let mut results = vec![];
+        for chunk_size in [1, 3, 4, txns.len()] {
+            let mut recent_txns = RecentTxns::new(0);
+            let (state, receipts) = PipelineExecutor::apply_in_chunks(
+                &txns,
+                1,
+                State::new(None).unwrap(),
+                &config(),
+                &mut recent_txns,
+                2,
+                chunk_size,
+            )
+            .await;
+            assert_eq!(receipts.len(), txns.len());
+            assert!(receipts.iter().all(|receipt| receipt.status));
+            let hashes = receipts
+                .iter()
+                .map(|receipt| receipt.transaction_hash)
+                .collect::<Vec<_>>();
+            results.push((state.get_state_root().0, hashes));
+        }
+        assert!(results.windows(2).all(|pair| pair[0] == pair[1]));
+    }
+
+    #[test]
+    fn ordered_block_is_cut_at_the_gas_limit_before_execution() {
+        let alice = new_key();
+        let txns = (0..4)
+            .map(|nonce| sign(&alice, nonce, set_kv("key", &nonce.to_string())))
+            .collect::<Vec<_>>();
+        let gas = compute_gas(&set_kv("key", "0"));
+
+        assert_eq!(gas_limit_prefix(&txns, 0), 0);
+        assert_eq!(gas_limit_prefix(&txns, gas), 1);
+        assert_eq!(gas_limit_prefix(&txns, 3 * gas - 1), 2);
+        assert_eq!(gas_limit_prefix(&txns, 3 * gas), 3);
+        assert_eq!(gas_limit_prefix(&txns, u64::MAX), 4);
+    }
+
+    #[test]
+    fn simulate_leaves_the_state_untouched() {
+        let (alice, bob) = (new_key(), new_key());
+        let mut state = State::new(None).unwrap();
+        let mut recent_txns = RecentTxns::new(0);
+        let txns = vec![sign(&alice, 0, set_kv("key", "value"))];
+        execute_block(&mut state, &config(), &mut recent_txns, txns);
+        let root = state.get_state_root().clone();
+        let alice_before = state.get_account(&alice.address());
+
+        let transfer = sign(
+            &alice,
+            1,
+            TransactionKind::Transfer {
+                receiver: bob.address(),
+                amount: 300,
+            },
+        );
+        let simulated = PipelineExecutor::simulate(&transfer.txn, &state, &config()).unwrap();
+        assert!(simulated.status);
+        assert_eq!(state.get_state_root().0, root.0);
+        assert_eq!(state.computed_state_root().0, root.0);
+        assert_eq!(state.get_account(&alice.address()), alice_before);
+        assert!(state.get_account(&bob.address()).is_none());
+
+        // Executing it for real then gives the simulated receipt.
+        let (_, receipts) = execute_block(&mut state, &config(), &mut recent_txns, vec![transfer]);
+        assert_eq!(receipts[0].gas_used, simulated.gas_used);
+        assert_eq!(receipts[0].state_updates, simulated.state_updates);
+    }
+
+    #[test]
+    fn compute_gas_charges_the_base_plus_each_written_byte() {
+        // Changing either constant changes every fee, so both are pinned here.
+        assert_eq!((GAS_BASE, GAS_PER_BYTE), (21000, 16));
+        let address = new_key().address();
+        let cases = [
+            (
+                TransactionKind::Transfer {
+                    receiver: address.clone(),
+                    amount: 10,
+                },
+                21000,
+            ),
+            (
+                TransactionKind::Mint {
+                    receiver: address.clone(),
+                    amount: 10,
+                },
+                21000,
+            ),
+            (
+                TransactionKind::GrantWrite {
+                    grantee: address.clone(),
+                },
+                21000,
+            ),
+            (
+                TransactionKind::RevokeWrite {
+                    grantee: address.clone(),
+                },
+                21000,
+            ),
+            (set_kv("", ""), 21000),
+            (set_kv("key", "value"), 21000 + 8 * 16),
+            (
+                TransactionKind::SetKVTtl {
+                    key: "key".to_string(),
+                    value: "value".to_string(),
+                    expires_at_block: 9,
+                },
+                21000 + 8 * 16,
+            ),
+            (
+                TransactionKind::SetKVEncoded {
+                    key: "key".to_string(),
+                    value: "aGk=".to_string(),
+                    encoding: ValueEncoding::Base64,
+                },
+                21000 + 7 * 16,
+            ),
+            // The target address is not charged for.
+            (
+                TransactionKind::SetKVFor {
+                    target: address,
+                    key: "key".to_string(),
+                    value: "value".to_string(),
+                },
+                21000 + 8 * 16,
+            ),
+            (
+                TransactionKind::RestoreKV {
+                    entries: vec![
+                        ("a".to_string(), "1".to_string()),
+                        ("bc".to_string(), "23".to_string()),
+                    ],
+                },
+                21000 + 6 * 16,
+            ),
+            // The delta is charged as 8 bytes, however large it is.
+            (
+                TransactionKind::AddKV {
+                    key: "n".to_string(),
+                    delta: -1,
+                },
+                21000 + 9 * 16,
+            ),
+        ];
+        for (kind, gas) in cases {
+            assert_eq!(compute_gas(&kind), gas, "{:?}", kind);
+        }
+        assert_eq!(
+            compute_gas(&set_kv("k", &"v".repeat(1000))),
+            21000 + 1001 * 16
+        );
+    }
+
+    #[test]
+    fn estimate_gas_matches_the_gas_execution_charges() {
+        let key = new_key();
+        let mut state = State::new(None).unwrap();
+        let mut recent_txns = RecentTxns::new(0);
+        let txns = vec![sign(&key, 0, set_kv("key", "value"))];
+        execute_block(&mut state, &config(), &mut recent_txns, txns);
+        let estimate = |kind| PipelineExecutor::estimate_gas(&sign(&key, 1, kind).txn, &state);
+
+        let write = set_kv("key", "other");
+        assert_eq!(estimate(write.clone()).unwrap(), compute_gas(&write));
+        assert_eq!(estimate(set_kv("key", "value")).unwrap(), GAS_NOOP_WRITE);
+        // Failing for lack of funds or authorization still costs gas.
+        for kind in [
+            TransactionKind::Transfer {
+                receiver: new_key().address(),
+                amount: u64::MAX,
+            },
+            TransactionKind::Mint {
+                receiver: new_key().address(),
+                amount: 1,
+            },
+        ] {
+            assert_eq!(estimate(kind.clone()).unwrap(), compute_gas(&kind));
+        }
+        // Other chains' transactions are estimated as if they were for this one.
+        let other_chain = TransactionBuilder::new(&key, CHAIN_ID + 1, 1).build(write.clone());
+        assert_eq!(
+            PipelineExecutor::estimate_gas(&other_chain.txn, &state).unwrap(),
+            compute_gas(&write)
+        );
+
+        let estimated = estimate(write.clone()).unwrap();
+        let (_, receipts) = execute_block(
+            &mut state,
+            &config(),
+            &mut recent_txns,
+            vec![sign(&key, 1, write)],
+        );
+        assert_eq!(receipts[0].gas_used, estimated);
+        assert!(matches!(
+            PipelineExecutor::estimate_gas(&sign(&key, 1, set_kv("a", "b")).txn, &state),
+            Err(KvError::InvalidNonce { .. })
+        ));
+    }
+
+    #[test]
+    fn simulate_reports_why_a_transaction_would_not_execute() {
+        let key = new_key();
+        let mut state = State::new(None).unwrap();
+        let mut recent_txns = RecentTxns::new(0);
+        let txns = vec![sign(&key, 0, set_kv("key", "value"))];
+        execute_block(&mut state, &config(), &mut recent_txns, txns);
+
+        let stale = sign(&key, 0, set_kv("key", "other")).txn;
+        assert_eq!(
+            PipelineExecutor::simulate(&stale, &state, &config()).unwrap_err(),
+            KvError::InvalidNonce {
+                expected: 1,
+                actual: 0
+            }
+        );
+
+        let stranger = sign(&new_key(), 0, set_kv("key", "value")).txn;
+        let genesis_only = ExecutorConfig {
+            genesis_only: true,
+            ..config()
+        };
+        assert!(matches!(
+            PipelineExecutor::simulate(&stranger, &state, &genesis_only),
+            Err(KvError::UnknownSender(_))
+        ));
+
+        let mut forged = sign(&key, 1, set_kv("key", "other")).txn;
+        forged.signature = "00".repeat(65);
+        assert!(matches!(
+            PipelineExecutor::simulate(&forged, &state, &config()),
+            Err(KvError::InvalidSignature(_))
+        ));
+    }
+}
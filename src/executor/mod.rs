@@ -1,3 +1,15 @@
 mod executor;
 
 pub use executor::*;
+
+mod recover;
+
+pub use recover::*;
+
+mod backoff;
+
+pub use backoff::*;
+
+mod recent_txns;
+
+pub use recent_txns::*;
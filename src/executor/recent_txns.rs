@@ -0,0 +1,77 @@
+use std::collections::{HashMap, VecDeque};
+
+use sha3::{Digest, Keccak256};
+
+use crate::{compute_transaction_hash, Transaction};
+
+/// Key a transaction is remembered under: the hash of the unsigned
+/// transaction together with its signature. The unsigned hash alone does not
+/// name the sender, so two accounts signing the same payload would collide.
+pub fn replay_key(txn: &Transaction) -> [u8; 32] {
+    let mut hasher = Keccak256::new();
+    hasher.update(compute_transaction_hash(&txn.unsigned));
+    hasher.update(txn.signature.as_bytes());
+    hasher.finalize().into()
+}
+
+/// Replay keys of the transactions executed in the last `window` blocks, so a
+/// transaction that was already executed is never executed again, whatever its
+/// nonce says.
+///
+/// Blocks are forgotten oldest first once more than `window` are held. Nothing
+/// is remembered with a window of 0.
+#[derive(Debug, Default)]
+pub struct RecentTxns {
+    window: u64,
+    blocks: VecDeque<(u64, Vec<[u8; 32]>)>,
+    keys: HashMap<[u8; 32], usize>,
+}
+
+impl RecentTxns {
+    pub fn new(window: u64) -> Self {
+        Self {
+            window,
+            ..Self::default()
+        }
+    }
+
+    /// Number of blocks remembered.
+    pub fn window(&self) -> u64 {
+        self.window
+    }
+
+    /// The last block recorded, if it is still remembered.
+    pub fn last_block(&self) -> Option<u64> {
+        self.blocks.back().map(|(block_number, _)| *block_number)
+    }
+
+    /// Whether `txn` was executed in one of the remembered blocks.
+    pub fn contains(&self, txn: &Transaction) -> bool {
+        self.keys.contains_key(&replay_key(txn))
+    }
+
+    /// Remembers the replay `keys` of the transactions executed in
+    /// `block_number`, forgetting the blocks that fall out of the window.
+    /// Blocks must be recorded in order.
+    pub fn record(&mut self, block_number: u64, keys: Vec<[u8; 32]>) {
+        if self.window == 0 {
+            return;
+        }
+        for key in &keys {
+            *self.keys.entry(*key).or_default() += 1;
+        }
+        self.blocks.push_back((block_number, keys));
+        while self.blocks.len() as u64 > self.window {
+            if let Some((_, keys)) = self.blocks.pop_front() {
+                for key in keys {
+                    if let Some(count) = self.keys.get_mut(&key) {
+                        *count -= 1;
+                        if *count == 0 {
+                            self.keys.remove(&key);
+                        }
+                    }
+                }
+            }
+        }
+    }
+}
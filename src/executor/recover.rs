@@ -0,0 +1,342 @@
+use crate::{
+    receipt_replay_keys, replay_key, ExecutorConfig, KvError, PipelineExecutor, RecentTxns, State,
+    Storage,
+};
+
+use std::sync::Arc;
+use tokio::sync::RwLock;
+use tracing::info;
+
+/// Replays the blocks persisted in `storage`, starting at `start`, into `state`.
+///
+/// If a checkpoint at or after `start` is stored, it is loaded first and replay
+/// resumes from the block following it. Each block's transactions are re-executed in order and the resulting state root
+/// is checked against the root stored for that block. Replay stops at the first
+/// missing block and returns the number of the last block applied.
+///
+/// Transactions are verified and executed on up to `workers` threads, which
+/// gives the same state as any other number.
+///
+/// Transactions in `recent_txns` are skipped exactly as during execution. It
+/// is rebuilt from storage unless it already ends at the block before replay
+/// resumes, and holds the replayed blocks on return.
+pub async fn recover_from_storage(
+    start: u64,
+    state: &Arc<RwLock<State>>,
+    storage: &dyn Storage,
+    config: &ExecutorConfig,
+    recent_txns: &mut RecentTxns,
+    workers: usize,
+) -> Result<u64, KvError> {
+    let mut state = state.write().await;
+    let mut block_number = start;
+    if let Some(checkpoint) = storage.get_checkpoint().await? {
+        if checkpoint.block_number >= start {
+            info!("Loading checkpoint at block {}", checkpoint.block_number);
+            block_number = checkpoint.block_number + 1;
+            state.restore_checkpoint(checkpoint);
+        }
+    }
+    if recent_txns.last_block() != block_number.checked_sub(1) {
+        *recent_txns = rebuild_recent_txns(block_number, recent_txns.window(), storage).await?;
+    }
+    while let Some(block) = storage.get_block(block_number).await? {
+        let expected = storage
+            .get_state_root(block_number)
+            .await?
+            .ok_or_else(|| KvError::NotFound(format!("State root of block {}", block_number)))?;
+        // A block that fails the check is undone, leaving `state` at the last good block.
+        let snapshot = state.snapshot();
+        let receipts = PipelineExecutor::apply_transactions(
+            &block.transactions,
+            block_number,
+            &mut state,
+            config,
+            recent_txns,
+            workers,
+        );
+        let actual = state.get_state_root().clone();
+        if actual.0 != expected.0 {
+            state.rollback(snapshot);
+            return Err(KvError::StateRootMismatch {
+                block_number,
+                expected: expected.to_hex(),
+                actual: actual.to_hex(),
+            });
+        }
+        state.release(snapshot);
+        state.set_current_block_number(block_number);
+        recent_txns.record(block_number, receipt_replay_keys(&receipts));
+        block_number += 1;
+    }
+    let latest = state.get_current_block_number();
+    info!("Recovered state up to block {}", latest);
+    Ok(latest)
+}
+
+/// The transactions executed in the `window` blocks before `next`, as recorded
+/// by their stored receipts. A transaction counts as executed when it has a
+/// receipt.
+async fn rebuild_recent_txns(
+    next: u64,
+    window: u64,
+    storage: &dyn Storage,
+) -> Result<RecentTxns, KvError> {
+    let mut recent_txns = RecentTxns::new(window);
+    for block_number in next.saturating_sub(window)..next {
+        let Some(block) = storage.get_block(block_number).await? else {
+            continue;
+        };
+        let mut keys = vec![];
+        for tx in &block.transactions {
+            if storage.get_transaction_receipt(&tx.txn).await?.is_some() {
+                keys.push(replay_key(&tx.txn));
+            }
+        }
+        recent_txns.record(block_number, keys);
+    }
+    Ok(recent_txns)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        compute_transaction_hash, test_utils::*, MemStorage, TransactionKind, CHECKPOINT_INTERVAL,
+    };
+
+    #[test]
+    fn replay_is_skipped_but_a_distinct_sender_is_not() {
+        let (alice, bob) = (new_key(), new_key());
+        let from_alice = sign(&alice, 0, set_kv("key", "value"));
+        let from_bob = sign(&bob, 0, set_kv("key", "value"));
+        // Same payload, so the same unsigned hash, from two senders.
+        assert_eq!(
+            compute_transaction_hash(&from_alice.txn.unsigned),
+            compute_transaction_hash(&from_bob.txn.unsigned)
+        );
+
+        let mut recent_txns = RecentTxns::new(10);
+        recent_txns.record(1, vec![replay_key(&from_alice.txn)]);
+        let mut state = State::new(None).unwrap();
+        let receipts = PipelineExecutor::apply_transactions(
+            &[from_alice, from_bob.clone()],
+            2,
+            &mut state,
+            &config(),
+            &recent_txns,
+            1,
+        );
+
+        assert_eq!(receipts.len(), 1);
+        assert_eq!(receipts[0].transaction.signature, from_bob.txn.signature);
+        assert!(state.get_account(&alice.address()).is_none());
+        assert!(state.get_account(&bob.address()).is_some());
+    }
+
+    #[tokio::test]
+    async fn recovery_rebuilds_the_window_and_reproduces_the_root() {
+        let storage = MemStorage::new();
+        let config = config();
+        let (alice, bob) = (new_key(), new_key());
+        let first = sign(&alice, 0, set_kv("key", "value"));
+        let same_payload = sign(&bob, 0, set_kv("key", "value"));
+        let future_nonce = sign(&bob, 5, set_kv("other", "value"));
+
+        let mut state = State::new(None).unwrap();
+        let mut recent_txns = RecentTxns::new(config.replay_window);
+        let receipts = commit_block(
+            &storage,
+            &mut state,
+            &config,
+            &mut recent_txns,
+            vec![first.clone()],
+        )
+        .await;
+        assert_eq!(receipts.len(), 1);
+        let receipts = commit_block(
+            &storage,
+            &mut state,
+            &config,
+            &mut recent_txns,
+            vec![first.clone(), same_payload.clone(), future_nonce.clone()],
+        )
+        .await;
+        assert_eq!(receipts.len(), 1);
+
+        let recovered = Arc::new(RwLock::new(State::new(None).unwrap()));
+        let mut recovered_txns = RecentTxns::new(config.replay_window);
+        let latest = recover_from_storage(1, &recovered, &storage, &config, &mut recovered_txns, 1)
+            .await
+            .unwrap();
+        assert_eq!(latest, 2);
+        assert_eq!(
+            recovered.read().await.get_state_root().0,
+            state.get_state_root().0
+        );
+        assert_eq!(recovered_txns.last_block(), Some(2));
+
+        for txns in [&recent_txns, &recovered_txns] {
+            assert!(txns.contains(&first.txn));
+            assert!(txns.contains(&same_payload.txn));
+            assert!(!txns.contains(&future_nonce.txn));
+        }
+
+        // A follower resuming after block 2 rebuilds the window from receipts,
+        // which keep Alice's and Bob's transactions with the same payload apart.
+        let rebuilt = rebuild_recent_txns(3, config.replay_window, &storage)
+            .await
+            .unwrap();
+        assert!(rebuilt.contains(&first.txn));
+        assert!(rebuilt.contains(&same_payload.txn));
+        assert!(!rebuilt.contains(&future_nonce.txn));
+        assert_eq!(rebuilt.last_block(), Some(2));
+    }
+
+    #[tokio::test]
+    async fn recovery_works_after_pruning_up_to_the_checkpoint() {
+        let storage = MemStorage::new();
+        let config = config();
+        let (alice, bob) = (new_key(), new_key());
+        let mut state = State::new(None).unwrap();
+        let mut recent_txns = RecentTxns::new(config.replay_window);
+        let from_alice = vec![sign(&alice, 0, set_kv("key", "value"))];
+        commit_block(&storage, &mut state, &config, &mut recent_txns, from_alice).await;
+        for _ in 2..=CHECKPOINT_INTERVAL {
+            commit_block(&storage, &mut state, &config, &mut recent_txns, vec![]).await;
+        }
+        // Bob's transaction has the same unsigned hash as Alice's, pruned below.
+        let from_bob = sign(&bob, 0, set_kv("key", "value"));
+        commit_block(
+            &storage,
+            &mut state,
+            &config,
+            &mut recent_txns,
+            vec![from_bob.clone()],
+        )
+        .await;
+
+        let checkpoint = CHECKPOINT_INTERVAL;
+        assert_eq!(
+            storage.prune_below(checkpoint + 1).await.unwrap(),
+            checkpoint - 1
+        );
+        assert!(storage.get_block(checkpoint - 1).await.unwrap().is_none());
+        assert!(storage.get_block(checkpoint).await.unwrap().is_some());
+        assert!(storage.get_state_root(checkpoint).await.unwrap().is_some());
+        let receipts = storage
+            .get_transaction_receipts(compute_transaction_hash(&from_bob.txn.unsigned))
+            .await
+            .unwrap();
+        assert_eq!(receipts.len(), 1);
+        assert_eq!(receipts[0].transaction.signature, from_bob.txn.signature);
+
+        let recovered = Arc::new(RwLock::new(State::new(None).unwrap()));
+        let mut recovered_txns = RecentTxns::new(config.replay_window);
+        let latest = recover_from_storage(1, &recovered, &storage, &config, &mut recovered_txns, 4)
+            .await
+            .unwrap();
+        assert_eq!(latest, checkpoint + 1);
+        assert_eq!(
+            recovered.read().await.get_state_root().0,
+            state.get_state_root().0
+        );
+    }
+
+    #[tokio::test]
+    async fn recovery_replays_the_blocks_committed_after_the_checkpoint() {
+        let storage = MemStorage::new();
+        let config = config();
+        let alice = new_key();
+        let mut state = State::new(None).unwrap();
+        let mut recent_txns = RecentTxns::new(config.replay_window);
+        let first = vec![sign(&alice, 0, set_kv("before", "value"))];
+        commit_block(&storage, &mut state, &config, &mut recent_txns, first).await;
+        for _ in 2..=CHECKPOINT_INTERVAL {
+            commit_block(&storage, &mut state, &config, &mut recent_txns, vec![]).await;
+        }
+        let mut after = vec![];
+        for nonce in 1..=3 {
+            let txn = sign(&alice, nonce, set_kv(&format!("after{}", nonce), "value"));
+            after.push(txn.clone());
+            commit_block(&storage, &mut state, &config, &mut recent_txns, vec![txn]).await;
+        }
+        let checkpoint = storage.get_checkpoint().await.unwrap().unwrap();
+        assert_eq!(checkpoint.block_number, CHECKPOINT_INTERVAL);
+
+        let recovered = Arc::new(RwLock::new(State::new(None).unwrap()));
+        let mut recovered_txns = RecentTxns::new(config.replay_window);
+        let latest = recover_from_storage(1, &recovered, &storage, &config, &mut recovered_txns, 1)
+            .await
+            .unwrap();
+        assert_eq!(latest, CHECKPOINT_INTERVAL + 3);
+        let recovered = recovered.read().await;
+        assert_eq!(
+            recovered.get_current_block_number(),
+            CHECKPOINT_INTERVAL + 3
+        );
+        assert_eq!(recovered.get_state_root().0, state.get_state_root().0);
+        assert_eq!(recovered.get_account(&alice.address()).unwrap().nonce, 4);
+        assert_eq!(
+            recovered.scan_prefix(&alice.address(), "", 10),
+            vec![
+                ("after1".to_string(), "value".to_string()),
+                ("after2".to_string(), "value".to_string()),
+                ("after3".to_string(), "value".to_string()),
+                ("before".to_string(), "value".to_string()),
+            ]
+        );
+        assert_eq!(recovered_txns.last_block(), Some(CHECKPOINT_INTERVAL + 3));
+        assert!(after.iter().all(|txn| recovered_txns.contains(&txn.txn)));
+    }
+
+    #[tokio::test]
+    async fn recovery_gives_the_same_state_on_any_number_of_workers() {
+        let storage = MemStorage::new();
+        let config = config();
+        let keys = (0..64).map(|_| new_key()).collect::<Vec<_>>();
+        let mut state = State::new(None).unwrap();
+        let mut recent_txns = RecentTxns::new(config.replay_window);
+        let mut txns = keys
+            .iter()
+            .map(|key| sign(key, 0, set_kv("key", &key.address())))
+            .collect::<Vec<_>>();
+        txns[7].txn.signature = "00".repeat(65);
+        commit_block(&storage, &mut state, &config, &mut recent_txns, txns).await;
+        let transfers = keys
+            .iter()
+            .enumerate()
+            .map(|(index, key)| {
+                sign(
+                    key,
+                    1,
+                    TransactionKind::Transfer {
+                        receiver: keys[(index + 1) % keys.len()].address(),
+                        amount: index as u64,
+                    },
+                )
+            })
+            .collect();
+        commit_block(&storage, &mut state, &config, &mut recent_txns, transfers).await;
+
+        for workers in [1, 8] {
+            let recovered = Arc::new(RwLock::new(State::new(None).unwrap()));
+            let mut recovered_txns = RecentTxns::new(config.replay_window);
+            let latest = recover_from_storage(
+                1,
+                &recovered,
+                &storage,
+                &config,
+                &mut recovered_txns,
+                workers,
+            )
+            .await
+            .unwrap();
+            assert_eq!(latest, 2);
+            assert_eq!(
+                recovered.read().await.get_state_root().0,
+                state.get_state_root().0
+            );
+        }
+    }
+}
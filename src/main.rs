@@ -2,29 +2,36 @@ pub mod app;
 pub mod cli;
 pub mod crypto;
 pub mod executor;
+pub mod metrics;
 pub mod state;
+pub mod tools;
 pub mod txpool;
 
+#[cfg(test)]
+mod test_utils;
+
 pub use crypto::*;
 pub use executor::*;
 pub use state::*;
 pub use txpool::*;
 
-use app::Shell;
 use app::ServerApp;
+use app::Shell;
 use clap::Parser;
-use cli::Cli;
+use cli::{Cli, LogFormat, StorageBackend, ToolCli};
 use gravity_sdk::api::{
     check_bootstrap_config,
     consensus_api::{ConsensusEngine, ConsensusEngineArgs},
 };
 use gravity_sdk::gaptos::api_types::on_chain_config::validator_set::ValidatorSet;
 use gravity_sdk::gaptos::api_types::{
-        config_storage::{ConfigStorage, OnChainConfig, OnChainConfigResType},
-        on_chain_config::{validator_config::ValidatorConfig, validator_info::ValidatorInfo},
-        u256_define::AccountAddress,
-    };
-use std::{error::Error, fs::File, path::PathBuf, sync::Arc};
+    config_storage::{ConfigStorage, OnChainConfig, OnChainConfigResType},
+    on_chain_config::{validator_config::ValidatorConfig, validator_info::ValidatorInfo},
+    u256_define::AccountAddress,
+};
+use std::{error::Error, fs::File, path::PathBuf, sync::Arc, time::Duration};
+use tokio::sync::watch;
+use tracing::{info, warn};
 
 pub struct KvOnChainConfig;
 
@@ -74,18 +81,22 @@ impl ConfigStorage for KvOnChainConfig {
 }
 
 /// **Note:** This code serves as a minimum viable implementation for demonstrating how to build a DApp using `gravity-sdk`.
-/// It does not include account balance validation, comprehensive error handling, or robust runtime fault tolerance.
+/// It does not include comprehensive error handling or robust runtime fault tolerance.
 /// Current limitations and future tasks include:
-/// 1. Block Synchronization: Block synchronization is not yet implemented.
-/// A basic Recover API implementation is required for block synchronization functionality.
+/// 1. Block Synchronization: A node cannot fetch missing blocks from its peers. A read-only
+/// replica can only follow a node on the same machine, by reading its RocksDB database.
 ///
-/// 2. State Persistence: The server does not load persisted state data on restart,
-/// leading to state resets after each restart.
+/// 2. State Persistence: Accounts are only persisted in checkpoints, every `CHECKPOINT_INTERVAL`
+/// blocks, so on restart the blocks committed since the latest one are replayed.
 ///
-/// 3. Execution Pipeline: Although the execution layer pipeline is designed with
-/// five stages, it currently executes blocks serially instead of in a pipelined manner.
+/// 3. Execution Pipeline: Blocks are executed one after the other, and committed in order by a
+/// separate task. Only the transactions of a block run in parallel, in waves touching disjoint
+/// accounts.
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn Error>> {
+    if let Some(tool) = ToolCli::parse_if_tool() {
+        return tools::run(tool.command).await;
+    }
     let cli = Cli::parse();
     let log_dir = cli.log_dir.clone();
     let log_dir = PathBuf::from(log_dir);
@@ -93,51 +104,173 @@ async fn main() -> Result<(), Box<dyn Error>> {
     let file = File::create(&log_file)
         .unwrap_or_else(|_| panic!("无法创建日志文件: {}", log_file.display()));
 
-    tracing_subscriber::fmt()
-        .with_writer(file)
-        .with_ansi(false) // 文件中不使用颜色代码
-        .init();
+    let subscriber = tracing_subscriber::fmt().with_writer(file).with_ansi(false); // 文件中不使用颜色代码
+    match cli.log_format {
+        LogFormat::Text => subscriber.init(),
+        LogFormat::Json => subscriber.json().init(),
+    }
     let gcei_config = check_bootstrap_config(cli.gravity_node_config.node_config_path.clone());
-    let storage = Arc::new(SledStorage::new(cli.db_dir.clone())?);
+    let storage: Arc<dyn Storage> = match (cli.storage_backend, &cli.primary_db_dir) {
+        (StorageBackend::Rocksdb, Some(primary_db_dir)) if cli.replica => Arc::new(
+            RocksStorage::open_secondary(primary_db_dir, cli.db_dir.clone())?,
+        ),
+        (StorageBackend::Sled, _) if cli.replica => {
+            return Err("--replica requires --storage_backend rocksdb".into())
+        }
+        (StorageBackend::Sled, _) => Arc::new(SledStorage::new(cli.db_dir.clone())?),
+        (StorageBackend::Rocksdb, _) => Arc::new(RocksStorage::new(cli.db_dir.clone())?),
+    };
     let genesis_path = cli.genesis_path.clone();
-    let blockchain = Blockchain::new(storage.clone(), genesis_path);
-    let listen_url = cli.listen_url.clone();
-    let state = blockchain.state();
-    let mempool = KvStoreTxPool::new();
+    let payload_limits = PayloadLimits {
+        max_key_bytes: cli.max_key_bytes,
+        max_value_bytes: cli.max_value_bytes,
+        max_memo_bytes: cli.max_memo_bytes,
+    };
+    let chain_id = cli.chain_id;
+    let executor_config = ExecutorConfig {
+        chain_id,
+        default_balance: cli.default_balance,
+        genesis_only: cli.genesis_only,
+        gas_price: cli.gas_price,
+        faucet_address: cli.faucet_address.clone(),
+        faucet_max_amount: cli.faucet_max_amount,
+        faucet_cooldown_blocks: cli.faucet_cooldown_blocks,
+        payload_limits,
+        block_gas_limit: cli.block_gas_limit,
+        replay_window: cli.finality_window,
+    };
+    let workers = cli.executor_workers.unwrap_or_else(default_workers);
+    let blockchain = Arc::new(
+        Blockchain::new(storage.clone(), genesis_path, executor_config)?.with_workers(workers),
+    );
+    let latest_block_number = match blockchain.recover_checked(cli.force_recover).await {
+        Err(e @ KvError::SelfCheckFailed(_)) => {
+            return Err(format!("{}. Pass --force_recover to start anyway", e).into())
+        }
+        result => result?,
+    };
+    if !cli.replica {
+        blockchain
+            .init_genesis_block(cli.genesis_timestamp_usecs)
+            .await?;
+    }
+    let listen_url = cli.listen_url;
+    let replica = cli.replica;
+    let health_stall = Duration::from_secs(cli.health_stall_secs);
+    let tx_rate_limit = cli.tx_rate_limit;
+    let max_block_txns = cli.max_block_txns;
+    let flush_every_n_blocks = cli.flush_every_n_blocks;
+    let prune_window = cli.auto_prune.then_some(cli.finality_window);
+    let mempool = KvStoreTxPool::new(
+        payload_limits,
+        max_block_txns,
+        cli.block_gas_limit,
+        cli.mempool_ordering,
+    );
     let mempool_clone = mempool.clone();
-    let state_clone = state.clone();
-    let storage_clone = storage.clone();
-    tokio::spawn(async move {
-        let server = ServerApp::new(state_clone, storage_clone, mempool_clone);
-        server.start(listen_url.as_str()).await.unwrap();
+    let blockchain_clone = blockchain.clone();
+    let (shutdown_sender, shutdown) = watch::channel(false);
+    let server_shutdown = shutdown.clone();
+    let server_task = tokio::spawn(async move {
+        let server = ServerApp::new(
+            blockchain_clone,
+            mempool_clone,
+            replica,
+            health_stall,
+            tx_rate_limit,
+        );
+        server.start(listen_url, server_shutdown).await.unwrap();
     });
     let mempool_clone = mempool.clone();
-    let mut shell = Shell::new(state, storage, mempool_clone);
-    let shell_task = tokio::spawn(async move {
-        shell.run().await;
+    let mut shell = Shell::new(blockchain.clone(), mempool_clone, chain_id, replica);
+    let shell_interrupt = shell.interrupt();
+    let exec_script = cli.exec_script.clone();
+    let continue_on_error = cli.continue_on_error;
+    let history_file = cli.history_file.clone();
+    let mut shell_task = tokio::spawn(async move {
+        match exec_script {
+            Some(path) => match shell.run_script(&path, continue_on_error).await {
+                Ok(()) => 0,
+                Err(e) => {
+                    println!("{}", e);
+                    1
+                }
+            },
+            None => {
+                shell.run(&history_file).await;
+                0
+            }
+        }
     });
 
-    let mempool_clone = mempool.clone();
-    let _consensus_engine = ConsensusEngine::init(
-        ConsensusEngineArgs {
-            node_config: gcei_config,
-            chain_id: 1337,
-            latest_block_number: 0,
-            config_storage: Some(Arc::new(KvOnChainConfig)),
-        },
-        Box::new(mempool_clone),
-    )
-    .await;
+    // A replica only follows blocks another node persists, so it never joins consensus.
+    let _consensus_engine = if replica {
+        None
+    } else {
+        let mempool_clone = mempool.clone();
+        Some(
+            ConsensusEngine::init(
+                ConsensusEngineArgs {
+                    node_config: gcei_config,
+                    chain_id,
+                    latest_block_number,
+                    config_storage: Some(Arc::new(KvOnChainConfig)),
+                },
+                Box::new(mempool_clone),
+            )
+            .await,
+        )
+    };
 
+    let backoff = Backoff::new(
+        Duration::from_millis(cli.poll_interval_ms),
+        Duration::from_millis(cli.max_backoff_ms),
+    );
     let blockchain_task = tokio::spawn(async move {
-        blockchain.run(mempool).await;
+        if replica {
+            blockchain.follow(backoff, shutdown).await;
+        } else {
+            blockchain
+                .run(
+                    mempool,
+                    max_block_txns,
+                    flush_every_n_blocks,
+                    prune_window,
+                    backoff,
+                    shutdown,
+                )
+                .await;
+        }
     });
 
-    tokio::select! {
-        _ = shell_task => {},
-        _ = blockchain_task => {},
-    }
+    // Ctrl+C stops the shell's `watch` command when one is running, and the node otherwise.
+    let exit_code = loop {
+        tokio::select! {
+            code = &mut shell_task => break code.unwrap_or(1),
+            _ = tokio::signal::ctrl_c() => {
+                if !shell_interrupt.interrupt() {
+                    break 0;
+                }
+            }
+        }
+    };
 
-    tokio::signal::ctrl_c().await.unwrap();
+    // Shut down in order: stop accepting transactions, let the executor finish
+    // the blocks it is working on, then flush storage.
+    info!("Shutting down");
+    let _ = shutdown_sender.send(true);
+    if let Err(e) = server_task.await {
+        warn!("Server stopped with an error: {}", e);
+    }
+    if let Err(e) = blockchain_task.await {
+        warn!("Executor stopped with an error: {}", e);
+    }
+    if !replica {
+        storage.flush().await?;
+    }
+    info!("Shutdown complete");
+    if exit_code != 0 {
+        std::process::exit(exit_code);
+    }
     Ok(())
 }
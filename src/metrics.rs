@@ -0,0 +1,239 @@
+//! Process-wide counters exported in the Prometheus text format on `/metrics`.
+
+use std::{
+    fmt::Write,
+    sync::atomic::{AtomicU64, Ordering},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use crate::TransactionKindTag;
+
+pub static METRICS: Metrics = Metrics::new();
+
+pub struct Metrics {
+    blocks_committed: AtomicU64,
+    transactions_committed: AtomicU64,
+    last_block_transactions: AtomicU64,
+    committed_height: AtomicU64,
+    execution_micros: AtomicU64,
+    blocks_executed: AtomicU64,
+    commit_micros: AtomicU64,
+    /// Unix time in milliseconds of the last persisted or followed block, 0 before the first.
+    last_commit_millis: AtomicU64,
+    /// Committed transactions of each kind, indexed like `TransactionKindTag::ALL`.
+    transactions_by_kind: [AtomicU64; TransactionKindTag::ALL.len()],
+}
+
+impl Metrics {
+    const fn new() -> Self {
+        Self {
+            blocks_committed: AtomicU64::new(0),
+            transactions_committed: AtomicU64::new(0),
+            last_block_transactions: AtomicU64::new(0),
+            committed_height: AtomicU64::new(0),
+            execution_micros: AtomicU64::new(0),
+            blocks_executed: AtomicU64::new(0),
+            commit_micros: AtomicU64::new(0),
+            last_commit_millis: AtomicU64::new(0),
+            transactions_by_kind: [const { AtomicU64::new(0) }; TransactionKindTag::ALL.len()],
+        }
+    }
+
+    pub fn record_execution(&self, elapsed: Duration) {
+        self.blocks_executed.fetch_add(1, Ordering::Relaxed);
+        self.execution_micros
+            .fetch_add(elapsed.as_micros() as u64, Ordering::Relaxed);
+    }
+
+    pub fn record_commit(&self, block_number: u64, txn_count: usize, elapsed: Duration) {
+        self.blocks_committed.fetch_add(1, Ordering::Relaxed);
+        self.transactions_committed
+            .fetch_add(txn_count as u64, Ordering::Relaxed);
+        self.last_block_transactions
+            .store(txn_count as u64, Ordering::Relaxed);
+        self.committed_height.store(block_number, Ordering::Relaxed);
+        self.commit_micros
+            .fetch_add(elapsed.as_micros() as u64, Ordering::Relaxed);
+        self.last_commit_millis
+            .store(unix_millis(), Ordering::Relaxed);
+    }
+
+    /// Counts a committed transaction of kind `tag`.
+    pub fn record_transaction_kind(&self, tag: TransactionKindTag) {
+        self.transactions_by_kind[tag as usize].fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Records that a replica applied `block_number` from the node it follows.
+    pub fn record_followed(&self, block_number: u64) {
+        self.committed_height.store(block_number, Ordering::Relaxed);
+        self.last_commit_millis
+            .store(unix_millis(), Ordering::Relaxed);
+    }
+
+    /// Number of the last persisted or followed block.
+    pub fn committed_height(&self) -> u64 {
+        self.committed_height.load(Ordering::Relaxed)
+    }
+
+    /// Time since the last block was persisted or followed, or `None` before the first.
+    pub fn since_last_commit(&self) -> Option<Duration> {
+        match self.last_commit_millis.load(Ordering::Relaxed) {
+            0 => None,
+            millis => Some(Duration::from_millis(unix_millis().saturating_sub(millis))),
+        }
+    }
+
+    /// Renders every metric, plus the current mempool size, in the Prometheus
+    /// text exposition format. Block rates are left to `rate()` over the counters.
+    pub fn render(&self, mempool_size: usize) -> String {
+        let load = |metric: &AtomicU64| metric.load(Ordering::Relaxed);
+        let seconds = |micros: u64| micros as f64 / 1_000_000.0;
+        let mut out = String::new();
+        let mut metric = |name: &str, kind: &str, help: &str, value: String| {
+            let _ = writeln!(out, "# HELP {} {}", name, help);
+            let _ = writeln!(out, "# TYPE {} {}", name, kind);
+            let _ = writeln!(out, "{} {}", name, value);
+        };
+        metric(
+            "kvstore_mempool_size",
+            "gauge",
+            "Transactions waiting in the mempool.",
+            mempool_size.to_string(),
+        );
+        metric(
+            "kvstore_blocks_committed_total",
+            "counter",
+            "Blocks persisted since start.",
+            load(&self.blocks_committed).to_string(),
+        );
+        metric(
+            "kvstore_transactions_committed_total",
+            "counter",
+            "Transactions in blocks persisted since start.",
+            load(&self.transactions_committed).to_string(),
+        );
+        metric(
+            "kvstore_last_block_transactions",
+            "gauge",
+            "Transactions in the last persisted block.",
+            load(&self.last_block_transactions).to_string(),
+        );
+        metric(
+            "kvstore_committed_block_height",
+            "gauge",
+            "Number of the last persisted block.",
+            load(&self.committed_height).to_string(),
+        );
+        metric(
+            "kvstore_block_execution_seconds_sum",
+            "counter",
+            "Total time spent executing blocks.",
+            seconds(load(&self.execution_micros)).to_string(),
+        );
+        metric(
+            "kvstore_block_execution_seconds_count",
+            "counter",
+            "Blocks executed since start.",
+            load(&self.blocks_executed).to_string(),
+        );
+        metric(
+            "kvstore_block_commit_seconds_sum",
+            "counter",
+            "Total time spent persisting blocks.",
+            seconds(load(&self.commit_micros)).to_string(),
+        );
+        metric(
+            "kvstore_block_commit_seconds_count",
+            "counter",
+            "Blocks persisted since start.",
+            load(&self.blocks_committed).to_string(),
+        );
+        let name = "kvstore_transactions_committed_by_kind_total";
+        let _ = writeln!(
+            out,
+            "# HELP {} Transactions persisted since start, by kind.",
+            name
+        );
+        let _ = writeln!(out, "# TYPE {} counter", name);
+        for tag in TransactionKindTag::ALL {
+            let count = load(&self.transactions_by_kind[tag as usize]);
+            let _ = writeln!(out, "{}{{kind=\"{}\"}} {}", name, tag.as_str(), count);
+        }
+        out
+    }
+}
+
+fn unix_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |elapsed| elapsed.as_millis() as u64)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    /// Value of every sample in `rendered`, by name and labels.
+    fn samples(rendered: &str) -> HashMap<&str, &str> {
+        rendered
+            .lines()
+            .filter(|line| !line.starts_with('#'))
+            .map(|line| line.rsplit_once(' ').unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn render_exports_every_metric_in_the_text_format() {
+        let metrics = Metrics::new();
+        assert!(metrics.since_last_commit().is_none());
+        metrics.record_execution(Duration::from_millis(500));
+        metrics.record_commit(4, 2, Duration::from_millis(250));
+        metrics.record_commit(5, 3, Duration::from_millis(250));
+        metrics.record_transaction_kind(TransactionKindTag::SetKV);
+        metrics.record_transaction_kind(TransactionKindTag::SetKV);
+        metrics.record_transaction_kind(TransactionKindTag::Mint);
+
+        let rendered = metrics.render(7);
+        let samples = samples(&rendered);
+        for (name, value) in [
+            ("kvstore_mempool_size", "7"),
+            ("kvstore_blocks_committed_total", "2"),
+            ("kvstore_transactions_committed_total", "5"),
+            ("kvstore_last_block_transactions", "3"),
+            ("kvstore_committed_block_height", "5"),
+            ("kvstore_block_execution_seconds_sum", "0.5"),
+            ("kvstore_block_execution_seconds_count", "1"),
+            ("kvstore_block_commit_seconds_sum", "0.5"),
+            ("kvstore_block_commit_seconds_count", "2"),
+            (
+                "kvstore_transactions_committed_by_kind_total{kind=\"set_kv\"}",
+                "2",
+            ),
+            (
+                "kvstore_transactions_committed_by_kind_total{kind=\"mint\"}",
+                "1",
+            ),
+            (
+                "kvstore_transactions_committed_by_kind_total{kind=\"transfer\"}",
+                "0",
+            ),
+        ] {
+            assert_eq!(samples.get(name), Some(&value), "{}", name);
+        }
+        assert_eq!(samples.len(), 9 + TransactionKindTag::ALL.len());
+
+        // Every metric is described once, before its samples.
+        for name in samples
+            .keys()
+            .map(|sample| sample.split('{').next().unwrap())
+        {
+            let help = format!("# HELP {} ", name);
+            let kind = format!("# TYPE {} ", name);
+            assert_eq!(rendered.matches(&help).count(), 1, "{}", name);
+            assert_eq!(rendered.matches(&kind).count(), 1, "{}", name);
+            assert!(rendered.find(&kind).unwrap() < rendered.find(&format!("\n{}", name)).unwrap());
+        }
+        assert!(metrics.since_last_commit().is_some());
+    }
+}
@@ -1,46 +1,498 @@
-use crate::{AccountId, AccountState, KvStoreTxPool, PipelineExecutor};
+use crate::{
+    default_workers, metrics::METRICS, recover_from_storage, AccountId, AccountState, Backoff,
+    Block, BlockHeader, BlockSummary, ExecutorConfig, KvError, KvStoreTxPool, PipelineExecutor,
+    RecentTxns, TransactionReceipt,
+};
 
 use super::*;
 use std::sync::Arc;
-use tokio::sync::RwLock;
+use tokio::sync::{broadcast, watch, Mutex, RwLock};
+
+/// Capacity of the committed block notification channel.
+const BLOCK_CHANNEL_CAPACITY: usize = 1024;
+/// Capacity of the committed receipt notification channel.
+const RECEIPT_CHANNEL_CAPACITY: usize = 4096;
 
 pub struct Blockchain {
     pub state: Arc<RwLock<State>>,
     pub storage: Arc<dyn Storage>,
+    block_sender: broadcast::Sender<BlockSummary>,
+    receipt_sender: broadcast::Sender<TransactionReceipt>,
+    config: ExecutorConfig,
+    /// Transactions executed in the last `config.replay_window` blocks applied
+    /// to `state`, carried from recovery to following or executing blocks.
+    recent_txns: Mutex<RecentTxns>,
+    /// Threads each block's transactions are verified and executed on, both
+    /// when replaying stored blocks and when executing new ones.
+    workers: usize,
 }
 
 impl Blockchain {
-    pub fn new(storage: Arc<dyn Storage>, genesis_path: Option<String>) -> Self {
-        Self {
-            state: Arc::new(RwLock::new(State::new(genesis_path))),
+    pub fn new(
+        storage: Arc<dyn Storage>,
+        genesis_path: Option<String>,
+        config: ExecutorConfig,
+    ) -> Result<Self, KvError> {
+        let (block_sender, _) = broadcast::channel(BLOCK_CHANNEL_CAPACITY);
+        let (receipt_sender, _) = broadcast::channel(RECEIPT_CHANNEL_CAPACITY);
+        Ok(Self {
+            state: Arc::new(RwLock::new(State::new(genesis_path)?)),
             storage,
-        }
+            block_sender,
+            receipt_sender,
+            recent_txns: Mutex::new(RecentTxns::new(config.replay_window)),
+            config,
+            workers: default_workers(),
+        })
+    }
+
+    /// Verifies and executes transactions on `workers` threads instead of one
+    /// per available core.
+    pub fn with_workers(mut self, workers: usize) -> Self {
+        self.workers = workers.max(1);
+        self
     }
 
     pub fn state(&self) -> Arc<RwLock<State>> {
         self.state.clone()
     }
 
+    /// Chain rules blocks are executed with.
+    pub fn config(&self) -> &ExecutorConfig {
+        &self.config
+    }
+
+    /// Subscribes to a summary of every block persisted from now on.
+    pub fn subscribe_blocks(&self) -> broadcast::Receiver<BlockSummary> {
+        self.block_sender.subscribe()
+    }
+
+    /// Publishes `block` to block subscribers as if it was just persisted.
+    #[cfg(test)]
+    pub(crate) fn publish_block(&self, block: &Block) {
+        let _ = self.block_sender.send(BlockSummary::from(block));
+    }
+
+    /// Subscribes to the receipt of every transaction persisted from now on, in
+    /// commit order. A subscriber that falls more than the channel capacity behind
+    /// skips the receipts it missed.
+    pub fn subscribe_receipts(&self) -> broadcast::Receiver<TransactionReceipt> {
+        self.receipt_sender.subscribe()
+    }
+
     pub async fn get_account_state(
         &self,
         account_id: &AccountId,
-    ) -> Result<Option<AccountState>, String> {
+    ) -> Result<Option<AccountState>, KvError> {
         let state = self.state.read().await;
         if let Some(account) = state.get_account(&account_id.0) {
             Ok(Some(AccountState {
                 nonce: account.nonce,
                 balance: account.balance,
                 kv_store: account.kv_store.clone(),
+                expiries: account.expiries.clone(),
+                last_faucet_block: account.last_faucet_block,
+                encodings: account.encodings.clone(),
+                writers: account.writers.clone(),
             }))
         } else {
             Ok(None)
         }
     }
 
-    pub async fn run(&self, pool: KvStoreTxPool) {
+    /// Rebuilds `state` from the blocks already persisted in `storage`.
+    /// Returns the number of the last recovered block.
+    pub async fn recover(&self) -> Result<u64, KvError> {
+        let mut recent_txns = self.recent_txns.lock().await;
+        recover_from_storage(
+            1,
+            &self.state,
+            self.storage.as_ref(),
+            &self.config,
+            &mut recent_txns,
+            self.workers,
+        )
+        .await
+    }
+
+    /// Recovers the state like `recover`, then checks it with
+    /// `verify_state_root`. A state root mismatch found by either fails with
+    /// `SelfCheckFailed` unless `force` is set, in which case it is logged and
+    /// the node starts from the last block that passed the check. Blocks
+    /// stored after it are executed again as consensus delivers them.
+    /// Returns the number of the last recovered block.
+    pub async fn recover_checked(&self, force: bool) -> Result<u64, KvError> {
+        let check = match self.recover().await {
+            Ok(_) => self.verify_state_root().await,
+            Err(e @ KvError::StateRootMismatch { .. }) => Err(e),
+            Err(e) => return Err(e),
+        };
+        if let Err(e) = check {
+            if !force {
+                return Err(KvError::SelfCheckFailed(Box::new(e)));
+            }
+            tracing::warn!(
+                "Startup self-check failed: {}. Starting anyway because of --force_recover",
+                e
+            );
+        }
+        Ok(self.state.read().await.get_current_block_number())
+    }
+
+    /// Checks that the recovered accounts hash to the state root stored for the
+    /// latest block, so a corrupt checkpoint or database is caught before the
+    /// node builds on it. A fresh database, with no root stored for block 0
+    /// yet, passes.
+    pub async fn verify_state_root(&self) -> Result<(), KvError> {
+        let state = self.state.read().await;
+        let block_number = state.get_current_block_number();
+        let expected = match self.storage.get_state_root(block_number).await? {
+            Some(expected) => expected,
+            None if block_number == 0 => return Ok(()),
+            None => {
+                return Err(KvError::NotFound(format!(
+                    "State root of block {}",
+                    block_number
+                )))
+            }
+        };
+        let actual = state.computed_state_root();
+        if actual.0 != expected.0 {
+            return Err(KvError::StateRootMismatch {
+                block_number,
+                expected: expected.to_hex(),
+                actual: actual.to_hex(),
+            });
+        }
+        Ok(())
+    }
+
+    /// Persists block 0 of a fresh chain: no transactions, the state root of
+    /// the genesis accounts and `usecs` as its timestamp. Does nothing once any
+    /// block has been executed, so databases created before block 0 was
+    /// persisted keep starting at block 1.
+    pub async fn init_genesis_block(&self, usecs: u64) -> Result<(), KvError> {
+        let state_root = {
+            let state = self.state.read().await;
+            if state.get_current_block_number() != 0 {
+                return Ok(());
+            }
+            state.get_state_root().clone()
+        };
+        if self.storage.get_block(0).await?.is_some() {
+            return Ok(());
+        }
+        let block = Block {
+            header: BlockHeader {
+                number: 0,
+                parent_state_root: [0; 32],
+                state_root: state_root.0,
+                usecs,
+                txn_root: txn_root(&[]),
+            },
+            transactions: vec![],
+        };
+        self.storage
+            .commit_block_atomic(&block, &[], &state_root, None)
+            .await?;
+        tracing::info!(
+            "Persisted genesis block with state root {}",
+            state_root.to_hex()
+        );
+        Ok(())
+    }
+
+    /// Follows blocks persisted to `storage` by another node instead of
+    /// executing blocks from consensus, until `shutdown` is set to true.
+    ///
+    /// Every new block is replayed and checked against its stored state root,
+    /// exactly as on recovery. Nothing is written to storage.
+    pub async fn follow(&self, mut backoff: Backoff, mut shutdown: watch::Receiver<bool>) {
+        while !*shutdown.borrow() {
+            let next = self.state.read().await.get_current_block_number() + 1;
+            let delay = match self.follow_step(next).await {
+                Ok(true) => {
+                    backoff.reset();
+                    continue;
+                }
+                Ok(false) => backoff.idle_delay(),
+                Err(e) => {
+                    let delay = backoff.error_delay();
+                    if backoff.should_log_error() {
+                        tracing::warn!(
+                            "failed to follow block {} ({} consecutive failures): {}",
+                            next,
+                            backoff.consecutive_errors(),
+                            e
+                        );
+                    }
+                    delay
+                }
+            };
+            tokio::select! {
+                _ = tokio::time::sleep(delay) => {}
+                _ = shutdown.changed() => break,
+            }
+        }
+    }
+
+    /// Applies the blocks from `next` on if any are stored. Returns whether one was.
+    async fn follow_step(&self, next: u64) -> Result<bool, KvError> {
+        self.storage.catch_up().await?;
+        if self.storage.get_block(next).await?.is_none() {
+            return Ok(false);
+        }
+        let mut recent_txns = self.recent_txns.lock().await;
+        let latest = recover_from_storage(
+            next,
+            &self.state,
+            self.storage.as_ref(),
+            &self.config,
+            &mut recent_txns,
+            self.workers,
+        )
+        .await?;
+        METRICS.record_followed(latest);
+        Ok(true)
+    }
+
+    /// Executes and commits blocks until `shutdown` is set to true, waiting
+    /// according to `backoff` whenever the block buffer is empty or failing.
+    /// Each block's transactions are executed in chunks of at most `max_block_txns`. Storage is flushed every
+    /// `flush_every_n_blocks` committed blocks, and blocks more than
+    /// `prune_window` blocks old are pruned when it is set.
+    pub async fn run(
+        &self,
+        pool: KvStoreTxPool,
+        max_block_txns: Option<usize>,
+        flush_every_n_blocks: u64,
+        prune_window: Option<u64>,
+        backoff: Backoff,
+        shutdown: watch::Receiver<bool>,
+    ) {
         let start_block = self.state.read().await.get_current_block_number() + 1;
         let state = self.state.clone();
         let storage = self.storage.clone();
-        PipelineExecutor::run(start_block, storage, state, pool).await;
+        let block_sender = self.block_sender.clone();
+        let receipt_sender = self.receipt_sender.clone();
+        let config = self.config.clone();
+        let recent_txns = std::mem::take(&mut *self.recent_txns.lock().await);
+        PipelineExecutor::run(
+            start_block,
+            storage,
+            state,
+            pool,
+            block_sender,
+            receipt_sender,
+            config,
+            recent_txns,
+            self.workers,
+            max_block_txns,
+            flush_every_n_blocks,
+            prune_window,
+            backoff,
+            shutdown,
+        )
+        .await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{test_utils::*, MemStorage};
+
+    /// Storage holding two committed blocks, each setting a key for a new account.
+    async fn two_blocks() -> Arc<MemStorage> {
+        let storage = Arc::new(MemStorage::new());
+        let config = config();
+        let mut state = State::new(None).unwrap();
+        let mut recent_txns = RecentTxns::new(config.replay_window);
+        for _ in 0..2 {
+            let txns = vec![sign(&new_key(), 0, set_kv("key", "value"))];
+            commit_block(
+                storage.as_ref(),
+                &mut state,
+                &config,
+                &mut recent_txns,
+                txns,
+            )
+            .await;
+        }
+        storage
+    }
+
+    fn blockchain(storage: &Arc<MemStorage>) -> Blockchain {
+        Blockchain::new(storage.clone(), None, config()).unwrap()
+    }
+
+    /// Waits until `replica` applied block `number`.
+    async fn wait_for_block(replica: &Blockchain, number: u64) {
+        for _ in 0..200 {
+            if replica.state.read().await.get_current_block_number() >= number {
+                return;
+            }
+            tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+        }
+        panic!("replica did not reach block {}", number);
+    }
+
+    #[tokio::test]
+    async fn replica_follows_the_blocks_the_primary_persists() {
+        let storage = Arc::new(MemStorage::new());
+        let config = config();
+        let alice = new_key();
+        let mut primary = State::new(None).unwrap();
+        let mut recent_txns = RecentTxns::new(config.replay_window);
+        let txns = |nonce: u64| vec![sign(&alice, nonce, set_kv("key", &nonce.to_string()))];
+        commit_block(
+            storage.as_ref(),
+            &mut primary,
+            &config,
+            &mut recent_txns,
+            txns(0),
+        )
+        .await;
+
+        let replica = Arc::new(blockchain(&storage));
+        replica.recover().await.unwrap();
+        let (shutdown_sender, shutdown) = watch::channel(false);
+        let backoff = Backoff::new(
+            std::time::Duration::from_millis(5),
+            std::time::Duration::from_millis(20),
+        );
+        let following = tokio::spawn({
+            let replica = replica.clone();
+            async move { replica.follow(backoff, shutdown).await }
+        });
+
+        for nonce in 1..3 {
+            commit_block(
+                storage.as_ref(),
+                &mut primary,
+                &config,
+                &mut recent_txns,
+                txns(nonce),
+            )
+            .await;
+        }
+        wait_for_block(&replica, 3).await;
+        {
+            let state = replica.state.read().await;
+            assert_eq!(state.get_current_block_number(), 3);
+            assert_eq!(state.get_state_root().0, primary.get_state_root().0);
+            let account = state.get_account(&alice.address()).unwrap();
+            assert_eq!(account.kv_store.get("key").unwrap(), "2");
+        }
+        // Following never writes: the primary's storage holds only its blocks.
+        assert!(storage.get_block(4).await.unwrap().is_none());
+
+        shutdown_sender.send(true).unwrap();
+        following.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn untampered_storage_passes_the_check() {
+        let storage = two_blocks().await;
+        assert_eq!(blockchain(&storage).recover_checked(false).await, Ok(2));
+    }
+
+    #[tokio::test]
+    async fn tampered_block_root_refuses_startup_unless_forced() {
+        let storage = two_blocks().await;
+        storage
+            .save_state_root(2, StateRoot([7; 32]))
+            .await
+            .unwrap();
+
+        match blockchain(&storage).recover_checked(false).await {
+            Err(KvError::SelfCheckFailed(e)) => {
+                assert!(matches!(
+                    *e,
+                    KvError::StateRootMismatch {
+                        block_number: 2,
+                        ..
+                    }
+                ))
+            }
+            other => panic!("expected a failed self-check, got {:?}", other),
+        }
+
+        let forced = blockchain(&storage);
+        assert_eq!(forced.recover_checked(true).await, Ok(1));
+        let expected = storage.get_state_root(1).await.unwrap().unwrap();
+        assert_eq!(forced.state.read().await.get_state_root().0, expected.0);
+    }
+
+    #[tokio::test]
+    async fn tampered_checkpoint_refuses_startup_unless_forced() {
+        let storage = two_blocks().await;
+        // A checkpoint at the latest block whose accounts do not match its root.
+        let mut state = State::new(None).unwrap();
+        state.set_current_block_number(2);
+        let mut checkpoint = state.checkpoint();
+        checkpoint.state_root = storage.get_state_root(2).await.unwrap().unwrap();
+        storage.save_checkpoint(&checkpoint).await.unwrap();
+
+        match blockchain(&storage).recover_checked(false).await {
+            Err(KvError::SelfCheckFailed(e)) => {
+                assert!(matches!(
+                    *e,
+                    KvError::StateRootMismatch {
+                        block_number: 2,
+                        ..
+                    }
+                ))
+            }
+            other => panic!("expected a failed self-check, got {:?}", other),
+        }
+        assert_eq!(blockchain(&storage).recover_checked(true).await, Ok(2));
+    }
+
+    #[tokio::test]
+    async fn fresh_node_persists_an_empty_genesis_block_over_the_genesis_accounts() {
+        let genesis_path = temp_dir().to_str().unwrap().to_string();
+        let (genesis, _) = crate::generate_genesis(3, 500);
+        crate::write_genesis(&genesis_path, &genesis).unwrap();
+        let storage = Arc::new(MemStorage::new());
+        let node = Blockchain::new(storage.clone(), Some(genesis_path.clone()), config()).unwrap();
+        node.init_genesis_block(1_700_000_000_000_000)
+            .await
+            .unwrap();
+
+        let block = storage.get_block_by_number(0).await.unwrap().unwrap();
+        assert_eq!(block.header.number, 0);
+        assert_eq!(block.header.usecs, 1_700_000_000_000_000);
+        assert_eq!(block.header.parent_state_root, [0; 32]);
+        assert_eq!(block.header.txn_root, txn_root(&[]));
+        assert!(block.transactions.is_empty());
+        let genesis_root = State::new(Some(genesis_path.clone()))
+            .unwrap()
+            .get_state_root()
+            .0;
+        assert_ne!(genesis_root, State::new(None).unwrap().get_state_root().0);
+        assert_eq!(block.header.state_root, genesis_root);
+        assert_eq!(
+            storage.get_state_root(0).await.unwrap().unwrap().0,
+            genesis_root
+        );
+        let by_hash = storage
+            .get_block_by_hash(block.header.hash())
+            .await
+            .unwrap();
+        assert_eq!(by_hash.unwrap().header.number, 0);
+
+        // Restarting with another timestamp keeps the block 0 already stored.
+        node.init_genesis_block(1).await.unwrap();
+        let block = storage.get_block(0).await.unwrap().unwrap();
+        assert_eq!(block.header.usecs, 1_700_000_000_000_000);
+        std::fs::remove_file(&genesis_path).unwrap();
+
+        // A database from before block 0 was persisted does not get one.
+        let storage = two_blocks().await;
+        let node = blockchain(&storage);
+        node.recover().await.unwrap();
+        node.init_genesis_block(1).await.unwrap();
+        assert!(storage.get_block(0).await.unwrap().is_none());
     }
 }
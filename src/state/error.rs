@@ -0,0 +1,44 @@
+/// Errors returned by the storage, state and executor APIs.
+///
+/// Callers that still report errors as text can use `?` on a `KvError` in a
+/// function returning `Result<_, String>` thanks to `From<KvError> for String`.
+#[derive(Debug, Clone, PartialEq, Eq, thiserror::Error)]
+pub enum KvError {
+    /// The database failed to read or write.
+    #[error("{0}")]
+    Storage(String),
+    /// A stored or submitted value could not be encoded or decoded.
+    #[error("{0}")]
+    Serialization(String),
+    /// The named block, receipt or account does not exist.
+    #[error("{0} not found")]
+    NotFound(String),
+    #[error("Invalid signature: {0}")]
+    InvalidSignature(String),
+    /// The transaction's nonce is not the sender's next one.
+    #[error("Invalid nonce: expected {expected}, got {actual}")]
+    InvalidNonce { expected: u64, actual: u64 },
+    /// The sender is not in the state and only genesis accounts may send.
+    #[error("Unknown sender {0}")]
+    UnknownSender(String),
+    /// Executing a block did not give the state root stored or expected for it.
+    #[error("State root mismatch at block {block_number}: expected {expected}, got {actual}")]
+    StateRootMismatch {
+        block_number: u64,
+        expected: String,
+        actual: String,
+    },
+    /// The genesis file is missing or malformed.
+    #[error("{0}")]
+    Genesis(String),
+    /// The recovered state does not match the stored state roots, so the node
+    /// refuses to start on it.
+    #[error("Startup self-check failed: {0}")]
+    SelfCheckFailed(Box<KvError>),
+}
+
+impl From<KvError> for String {
+    fn from(error: KvError) -> Self {
+        error.to_string()
+    }
+}
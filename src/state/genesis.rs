@@ -0,0 +1,90 @@
+use std::{
+    collections::{BTreeMap, BTreeSet, HashMap},
+    fs::File,
+    io::BufWriter,
+};
+
+use crate::{generate_keypair, public_key_to_address, AccountState, KeyPair};
+
+/// Generates `accounts` fresh keypairs and a genesis map giving each of their
+/// addresses `balance`. The keypairs are returned in the same order as created.
+pub fn generate_genesis(
+    accounts: usize,
+    balance: u64,
+) -> (HashMap<String, AccountState>, Vec<KeyPair>) {
+    let keypairs = (0..accounts)
+        .map(|_| generate_keypair())
+        .collect::<Vec<_>>();
+    let genesis = keypairs
+        .iter()
+        .map(|keypair| {
+            let account = AccountState {
+                nonce: 0,
+                balance,
+                kv_store: BTreeMap::new(),
+                expiries: BTreeMap::new(),
+                last_faucet_block: None,
+                encodings: BTreeMap::new(),
+                writers: BTreeSet::new(),
+            };
+            (public_key_to_address(&keypair.public_key), account)
+        })
+        .collect();
+    (genesis, keypairs)
+}
+
+/// Writes `genesis` to `path` in the format `State::new` reads.
+pub fn write_genesis(path: &str, genesis: &HashMap<String, AccountState>) -> Result<(), String> {
+    let file =
+        File::create(path).map_err(|e| format!("Failed to create genesis file {}: {}", path, e))?;
+    serde_json::to_writer_pretty(BufWriter::new(file), genesis)
+        .map_err(|e| format!("Failed to write genesis file {}: {}", path, e))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{test_utils::*, AccountKey, ExecutorConfig, RecentTxns, State, TransactionKind};
+
+    #[test]
+    fn generated_genesis_loads_and_funds_its_accounts() {
+        let (genesis, keypairs) = generate_genesis(3, 1000);
+        assert_eq!(genesis.len(), 3);
+        let path = temp_dir().with_extension("json");
+        let path = path.to_str().unwrap();
+        write_genesis(path, &genesis).unwrap();
+
+        let mut state = State::new(Some(path.to_string())).unwrap();
+        std::fs::remove_file(path).unwrap();
+        assert_eq!(state.account_count(), 3);
+        assert_eq!(state.total_supply(), 3000);
+        let keys = keypairs
+            .into_iter()
+            .map(AccountKey::from)
+            .collect::<Vec<_>>();
+        for key in &keys {
+            let account = state.get_account(&key.address()).unwrap();
+            assert_eq!((account.nonce, account.balance), (0, 1000));
+        }
+
+        // The printed keys control the funded accounts, even when only
+        // genesis accounts may send.
+        let config = ExecutorConfig {
+            genesis_only: true,
+            ..config()
+        };
+        let transfer = TransactionKind::Transfer {
+            receiver: keys[1].address(),
+            amount: 400,
+        };
+        let (_, receipts) = execute_block(
+            &mut state,
+            &config,
+            &mut RecentTxns::new(0),
+            vec![sign(&keys[0], 0, transfer)],
+        );
+        assert!(receipts[0].status);
+        assert_eq!(state.get_account(&keys[0].address()).unwrap().balance, 600);
+        assert_eq!(state.get_account(&keys[1].address()).unwrap().balance, 1400);
+    }
+}
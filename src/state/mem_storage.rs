@@ -0,0 +1,227 @@
+use async_trait::async_trait;
+use std::{
+    collections::{BTreeMap, HashMap},
+    sync::Mutex,
+};
+
+use crate::{
+    receipt_key, AccountId, AccountState, Block, KvError, StateCheckpoint, StateRoot, Storage,
+    TransactionReceipt,
+};
+
+#[derive(Default)]
+struct MemStorageInner {
+    blocks: HashMap<u64, Block>,
+    block_hashes: HashMap<[u8; 32], u64>,
+    /// By `receipt_key`, sorted so the receipts of an unsigned hash are adjacent.
+    receipts: BTreeMap<Vec<u8>, TransactionReceipt>,
+    state_roots: HashMap<u64, StateRoot>,
+    accounts: HashMap<AccountId, AccountState>,
+    account_txns: HashMap<String, Vec<Vec<u8>>>,
+    checkpoint: Option<StateCheckpoint>,
+}
+
+/// `Storage` kept entirely in memory, for tests and throwaway nodes.
+#[derive(Default)]
+pub struct MemStorage {
+    inner: Mutex<MemStorageInner>,
+}
+
+impl MemStorage {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+#[async_trait]
+impl Storage for MemStorage {
+    async fn save_block(&self, block: &Block) -> Result<(), KvError> {
+        let hash = block.header.hash();
+        let mut inner = self.inner.lock().unwrap();
+        inner.blocks.insert(block.header.number, block.clone());
+        inner.block_hashes.insert(hash, block.header.number);
+        Ok(())
+    }
+
+    async fn get_block(&self, number: u64) -> Result<Option<Block>, KvError> {
+        Ok(self.inner.lock().unwrap().blocks.get(&number).cloned())
+    }
+
+    async fn get_block_by_number(&self, number: u64) -> Result<Option<Block>, KvError> {
+        self.get_block(number).await
+    }
+
+    async fn get_block_by_hash(&self, hash: [u8; 32]) -> Result<Option<Block>, KvError> {
+        let inner = self.inner.lock().unwrap();
+        Ok(inner
+            .block_hashes
+            .get(&hash)
+            .and_then(|number| inner.blocks.get(number))
+            .cloned())
+    }
+
+    async fn save_transaction_receipts(
+        &self,
+        receipts: Vec<TransactionReceipt>,
+    ) -> Result<(), KvError> {
+        let mut inner = self.inner.lock().unwrap();
+        for receipt in receipts {
+            inner
+                .receipts
+                .insert(receipt_key(&receipt.transaction), receipt);
+        }
+        Ok(())
+    }
+
+    async fn get_transaction_receipts(
+        &self,
+        transaction_hash: [u8; 32],
+    ) -> Result<Vec<TransactionReceipt>, KvError> {
+        Ok(self
+            .inner
+            .lock()
+            .unwrap()
+            .receipts
+            .range(transaction_hash.to_vec()..)
+            .take_while(|(key, _)| key.starts_with(&transaction_hash))
+            .map(|(_, receipt)| receipt.clone())
+            .collect())
+    }
+
+    async fn save_state_root(&self, block_number: u64, root: StateRoot) -> Result<(), KvError> {
+        self.inner
+            .lock()
+            .unwrap()
+            .state_roots
+            .insert(block_number, root);
+        Ok(())
+    }
+
+    async fn get_state_root(&self, block_number: u64) -> Result<Option<StateRoot>, KvError> {
+        Ok(self
+            .inner
+            .lock()
+            .unwrap()
+            .state_roots
+            .get(&block_number)
+            .cloned())
+    }
+
+    async fn save_account_state(
+        &self,
+        account_id: &AccountId,
+        state: &AccountState,
+    ) -> Result<(), KvError> {
+        self.inner
+            .lock()
+            .unwrap()
+            .accounts
+            .insert(account_id.clone(), state.clone());
+        Ok(())
+    }
+
+    async fn get_account_state(
+        &self,
+        account_id: &AccountId,
+    ) -> Result<Option<AccountState>, KvError> {
+        Ok(self.inner.lock().unwrap().accounts.get(account_id).cloned())
+    }
+
+    async fn save_checkpoint(&self, checkpoint: &StateCheckpoint) -> Result<(), KvError> {
+        self.inner.lock().unwrap().checkpoint = Some(checkpoint.clone());
+        Ok(())
+    }
+
+    async fn commit_block_atomic(
+        &self,
+        block: &Block,
+        receipts: &[TransactionReceipt],
+        state_root: &StateRoot,
+        checkpoint: Option<&StateCheckpoint>,
+    ) -> Result<(), KvError> {
+        let hash = block.header.hash();
+        let mut inner = self.inner.lock().unwrap();
+        inner.blocks.insert(block.header.number, block.clone());
+        inner.block_hashes.insert(hash, block.header.number);
+        for receipt in receipts {
+            inner
+                .receipts
+                .insert(receipt_key(&receipt.transaction), receipt.clone());
+        }
+        for txn in &block.transactions {
+            inner
+                .account_txns
+                .entry(txn.address.clone())
+                .or_default()
+                .push(receipt_key(&txn.txn));
+        }
+        inner
+            .state_roots
+            .insert(block.header.number, state_root.clone());
+        if let Some(checkpoint) = checkpoint {
+            inner.checkpoint = Some(checkpoint.clone());
+        }
+        Ok(())
+    }
+
+    async fn get_checkpoint(&self) -> Result<Option<StateCheckpoint>, KvError> {
+        Ok(self.inner.lock().unwrap().checkpoint.clone())
+    }
+
+    async fn get_account_transactions(
+        &self,
+        address: &str,
+        limit: usize,
+        offset: usize,
+    ) -> Result<Vec<TransactionReceipt>, KvError> {
+        let inner = self.inner.lock().unwrap();
+        let keys = match inner.account_txns.get(address) {
+            Some(keys) => keys,
+            None => return Ok(vec![]),
+        };
+        Ok(keys
+            .iter()
+            .rev()
+            .skip(offset)
+            .filter_map(|key| inner.receipts.get(key).cloned())
+            .take(limit)
+            .collect())
+    }
+
+    async fn prune_below(&self, block_number: u64) -> Result<u64, KvError> {
+        let mut inner = self.inner.lock().unwrap();
+        let checkpoint_number = match &inner.checkpoint {
+            Some(checkpoint) => checkpoint.block_number,
+            None => return Ok(0),
+        };
+        let end = block_number.min(checkpoint_number);
+
+        let numbers = inner
+            .blocks
+            .keys()
+            .filter(|number| **number < end)
+            .cloned()
+            .collect::<Vec<_>>();
+        for number in &numbers {
+            let block = inner.blocks.remove(number).unwrap();
+            for txn in &block.transactions {
+                let key = receipt_key(&txn.txn);
+                inner.receipts.remove(&key);
+                if let Some(keys) = inner.account_txns.get_mut(&txn.address) {
+                    // History is oldest first, so this is the pruned block's entry.
+                    if let Some(position) = keys.iter().position(|k| *k == key) {
+                        keys.remove(position);
+                    }
+                }
+            }
+            inner.block_hashes.remove(&block.header.hash());
+        }
+        inner.state_roots.retain(|number, _| *number >= end);
+
+        Ok(numbers.len() as u64)
+    }
+
+    async fn flush(&self) -> Result<(), KvError> {
+        Ok(())
+    }
+}
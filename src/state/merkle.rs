@@ -0,0 +1,504 @@
+use serde::{Deserialize, Serialize};
+use sha3::{Digest, Keccak256};
+use std::{collections::BTreeSet, sync::Arc};
+
+use crate::{AccountState, StateRoot, ValueEncoding};
+
+const LEAF_PREFIX: u8 = 0;
+const NODE_PREFIX: u8 = 1;
+
+/// One level of a Merkle path: the sibling hash and which side it sits on.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct MerkleStep {
+    pub sibling: [u8; 32],
+    pub sibling_on_left: bool,
+}
+
+/// Proof that `key` holds a given value in an account, checked against a state root.
+///
+/// `kv_path` leads from the key-value leaf to the account's `kv_root`, and
+/// `account_path` leads from the account leaf to the state root.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MerkleProof {
+    pub nonce: u64,
+    pub balance: u64,
+    pub kv_root: [u8; 32],
+    /// Block the account last received a faucet grant at, if it is tracked.
+    #[serde(default)]
+    pub last_faucet_block: Option<u64>,
+    /// Accounts allowed to write into the account's store.
+    #[serde(default)]
+    pub writers: BTreeSet<String>,
+    /// Block the key expires at, if it was set with `SetKVTtl`.
+    #[serde(default)]
+    pub expires_at: Option<u64>,
+    /// Encoding of the value, if it was set with `SetKVEncoded` in other than UTF-8.
+    #[serde(default)]
+    pub encoding: Option<ValueEncoding>,
+    pub kv_path: Vec<MerkleStep>,
+    pub account_path: Vec<MerkleStep>,
+}
+
+/// Proof that a transaction is the `index`th of block `block_number`, checked
+/// against the block header's `txn_root` with `verify_inclusion_proof`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct InclusionProof {
+    pub block_number: u64,
+    pub index: usize,
+    pub path: Vec<MerkleStep>,
+}
+
+/// A key-value entry as committed in the account's key-value tree.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct KvEntry {
+    pub key: String,
+    pub value: String,
+    pub expires_at: Option<u64>,
+    #[serde(default)]
+    pub encoding: Option<ValueEncoding>,
+}
+
+impl KvEntry {
+    fn leaf_hash(&self) -> [u8; 32] {
+        kv_leaf_hash(&self.key, &self.value, self.expires_at, self.encoding)
+    }
+}
+
+/// Proof that `entries` are all the keys of an account within a key range,
+/// checked against a state root with `verify_range_proof`.
+///
+/// The entries are proven together with the keys right before and after the
+/// range, when there are any, so a key inside the range cannot be left out.
+/// `first_index` is the position of the first of those leaves among the
+/// account's `leaf_count` leaves, and the siblings are the nodes left and right
+/// of them needed to rebuild the account's `kv_root`, lowest level first.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RangeProof {
+    pub entries: Vec<KvEntry>,
+    pub nonce: u64,
+    pub balance: u64,
+    #[serde(default)]
+    pub last_faucet_block: Option<u64>,
+    #[serde(default)]
+    pub writers: BTreeSet<String>,
+    pub leaf_count: usize,
+    pub first_index: usize,
+    pub left_neighbor: Option<KvEntry>,
+    pub right_neighbor: Option<KvEntry>,
+    pub left_siblings: Vec<[u8; 32]>,
+    pub right_siblings: Vec<[u8; 32]>,
+    pub account_path: Vec<MerkleStep>,
+}
+
+fn hash_leaf(data: &[u8]) -> [u8; 32] {
+    let mut hasher = Keccak256::new();
+    hasher.update([LEAF_PREFIX]);
+    hasher.update(data);
+    hasher.finalize().into()
+}
+
+fn hash_node(left: &[u8; 32], right: &[u8; 32]) -> [u8; 32] {
+    let mut hasher = Keccak256::new();
+    hasher.update([NODE_PREFIX]);
+    hasher.update(left);
+    hasher.update(right);
+    hasher.finalize().into()
+}
+
+/// Leaf of a key-value entry. The expiry of a key set with `SetKVTtl` and the
+/// encoding of a value set with `SetKVEncoded` are committed too, while other
+/// leaves hash the same as before either existed.
+pub fn kv_leaf_hash(
+    key: &str,
+    value: &str,
+    expires_at: Option<u64>,
+    encoding: Option<ValueEncoding>,
+) -> [u8; 32] {
+    match (expires_at, encoding) {
+        (_, Some(encoding)) => {
+            hash_leaf(&bincode::serialize(&(key, value, expires_at, encoding)).unwrap())
+        }
+        (Some(expires_at), None) => {
+            hash_leaf(&bincode::serialize(&(key, value, expires_at)).unwrap())
+        }
+        (None, None) => hash_leaf(&bincode::serialize(&(key, value)).unwrap()),
+    }
+}
+
+fn txn_leaf_hash(txn_hash: &[u8; 32]) -> [u8; 32] {
+    hash_leaf(txn_hash)
+}
+
+/// Root over the hashes of a block's transactions, in block order.
+pub fn txn_root(txn_hashes: &[[u8; 32]]) -> [u8; 32] {
+    let leaves = txn_hashes.iter().map(txn_leaf_hash).collect::<Vec<_>>();
+    merkle_root(&leaves)
+}
+
+/// Builds a proof that `txn_hashes[index]` is included under `txn_root(txn_hashes)`.
+pub fn txn_inclusion_proof(
+    block_number: u64,
+    txn_hashes: &[[u8; 32]],
+    index: usize,
+) -> InclusionProof {
+    let leaves = txn_hashes.iter().map(txn_leaf_hash).collect::<Vec<_>>();
+    InclusionProof {
+        block_number,
+        index,
+        path: merkle_path(&leaves, index),
+    }
+}
+
+/// Checks that `txn_hash` is included in the block whose header has `txn_root`.
+pub fn verify_inclusion_proof(
+    txn_root: &[u8; 32],
+    txn_hash: &[u8; 32],
+    proof: &InclusionProof,
+) -> bool {
+    root_from_path(txn_leaf_hash(txn_hash), &proof.path) == *txn_root
+}
+
+/// Leaf of an account. The block of its last faucet grant, when tracked, and
+/// the accounts granted write access, when any, are committed too, while other
+/// leaves hash the same as before either existed.
+pub fn account_leaf_hash(
+    address: &str,
+    nonce: u64,
+    balance: u64,
+    kv_root: &[u8; 32],
+    last_faucet_block: Option<u64>,
+    writers: &BTreeSet<String>,
+) -> [u8; 32] {
+    let fields = (address, nonce, balance, kv_root);
+    match (last_faucet_block, writers.is_empty()) {
+        (None, true) => hash_leaf(&bincode::serialize(&fields).unwrap()),
+        (Some(block), true) => hash_leaf(&bincode::serialize(&(fields, block)).unwrap()),
+        (_, false) => {
+            hash_leaf(&bincode::serialize(&(fields, last_faucet_block, writers)).unwrap())
+        }
+    }
+}
+
+/// Root of the account's key-value tree, over entries in key order.
+pub fn kv_root(account: &AccountState) -> [u8; 32] {
+    let leaves = account
+        .kv_store
+        .iter()
+        .map(|(key, value)| {
+            kv_leaf_hash(
+                key,
+                value,
+                account.expiries.get(key).copied(),
+                account.encodings.get(key).copied(),
+            )
+        })
+        .collect::<Vec<_>>();
+    merkle_root(&leaves)
+}
+
+/// Hashes `level` pairwise. A node without a sibling is carried up unchanged.
+fn next_level(level: &[[u8; 32]]) -> Vec<[u8; 32]> {
+    level
+        .chunks(2)
+        .map(|pair| match pair {
+            [left, right] => hash_node(left, right),
+            [single] => *single,
+            _ => unreachable!(),
+        })
+        .collect()
+}
+
+/// Root over `leaves`. A node without a sibling is carried up unchanged.
+pub fn merkle_root(leaves: &[[u8; 32]]) -> [u8; 32] {
+    if leaves.is_empty() {
+        return [0; 32];
+    }
+    let mut level = leaves.to_vec();
+    while level.len() > 1 {
+        level = next_level(&level);
+    }
+    level[0]
+}
+
+/// Sibling path from `leaves[index]` up to the root.
+pub fn merkle_path(leaves: &[[u8; 32]], mut index: usize) -> Vec<MerkleStep> {
+    let mut path = vec![];
+    let mut level = leaves.to_vec();
+    while level.len() > 1 {
+        let sibling = index ^ 1;
+        if sibling < level.len() {
+            path.push(MerkleStep {
+                sibling: level[sibling],
+                sibling_on_left: sibling < index,
+            });
+        }
+        level = next_level(&level);
+        index /= 2;
+    }
+    path
+}
+
+/// Merkle tree over the account leaves in address order, with every level kept
+/// so that rewriting an account only rehashes the nodes above its leaf. Levels
+/// are shared between clones until one of them is updated.
+#[derive(Debug, Clone, Default)]
+pub struct AccountTree {
+    addresses: Arc<Vec<String>>,
+    /// Leaves first, up to the root. Empty for a tree without leaves.
+    levels: Arc<Vec<Vec<[u8; 32]>>>,
+}
+
+impl AccountTree {
+    /// Builds the tree over `leaves`, which must be sorted by address.
+    pub fn new(addresses: Vec<String>, leaves: Vec<[u8; 32]>) -> Self {
+        debug_assert_eq!(addresses.len(), leaves.len());
+        let mut tree = Self {
+            addresses: Arc::new(addresses),
+            levels: Arc::new(vec![leaves]),
+        };
+        tree.rebuild_levels();
+        tree
+    }
+
+    fn rebuild_levels(&mut self) {
+        let levels = Arc::make_mut(&mut self.levels);
+        levels.truncate(1);
+        while levels.last().is_some_and(|level| level.len() > 1) {
+            let next = next_level(levels.last().unwrap());
+            levels.push(next);
+        }
+    }
+
+    /// Sets the leaf of each address, or removes it when `None`. Only the paths
+    /// above rewritten leaves are rehashed, unless an account was added or
+    /// removed, which shifts the leaves after it and rebuilds every level.
+    pub fn update(&mut self, changes: impl IntoIterator<Item = (String, Option<[u8; 32]>)>) {
+        let addresses = Arc::make_mut(&mut self.addresses);
+        let levels = Arc::make_mut(&mut self.levels);
+        if levels.is_empty() {
+            levels.push(vec![]);
+        }
+        let mut dirty = BTreeSet::new();
+        let mut reshaped = false;
+        for (address, leaf) in changes {
+            match (addresses.binary_search(&address), leaf) {
+                (Ok(index), Some(leaf)) => {
+                    levels[0][index] = leaf;
+                    dirty.insert(index);
+                }
+                (Ok(index), None) => {
+                    addresses.remove(index);
+                    levels[0].remove(index);
+                    reshaped = true;
+                }
+                (Err(index), Some(leaf)) => {
+                    addresses.insert(index, address);
+                    levels[0].insert(index, leaf);
+                    reshaped = true;
+                }
+                (Err(_), None) => {}
+            }
+        }
+        if reshaped {
+            self.rebuild_levels();
+            return;
+        }
+        for depth in 0..levels.len() - 1 {
+            dirty = dirty
+                .into_iter()
+                .map(|index| {
+                    let parent = index / 2;
+                    let left = levels[depth][parent * 2];
+                    levels[depth + 1][parent] = match levels[depth].get(parent * 2 + 1) {
+                        Some(right) => hash_node(&left, right),
+                        None => left,
+                    };
+                    parent
+                })
+                .collect();
+        }
+    }
+
+    /// Same as `merkle_root` over the leaves.
+    pub fn root(&self) -> [u8; 32] {
+        match self.levels.last() {
+            Some(top) if top.len() == 1 => top[0],
+            _ => [0; 32],
+        }
+    }
+
+    /// Same as `merkle_path` from the leaf of `address`.
+    pub fn path(&self, address: &str) -> Option<Vec<MerkleStep>> {
+        let mut index = self
+            .addresses
+            .binary_search_by(|a| a.as_str().cmp(address))
+            .ok()?;
+        let mut path = vec![];
+        for level in self.levels.iter().take(self.levels.len().saturating_sub(1)) {
+            let sibling = index ^ 1;
+            if sibling < level.len() {
+                path.push(MerkleStep {
+                    sibling: level[sibling],
+                    sibling_on_left: sibling < index,
+                });
+            }
+            index /= 2;
+        }
+        Some(path)
+    }
+}
+
+/// Nodes left and right of `leaves[first..=last]` needed to rebuild the root
+/// from that run of leaves, lowest level first.
+pub fn merkle_range_siblings(
+    leaves: &[[u8; 32]],
+    mut first: usize,
+    mut last: usize,
+) -> (Vec<[u8; 32]>, Vec<[u8; 32]>) {
+    let (mut left, mut right) = (vec![], vec![]);
+    let mut level = leaves.to_vec();
+    while level.len() > 1 {
+        if first % 2 == 1 {
+            left.push(level[first - 1]);
+        }
+        if last % 2 == 0 && last + 1 < level.len() {
+            right.push(level[last + 1]);
+        }
+        level = next_level(&level);
+        first /= 2;
+        last /= 2;
+    }
+    (left, right)
+}
+
+/// Root of a tree of `leaf_count` leaves rebuilt from the run `leaves` starting
+/// at `first` and the siblings from `merkle_range_siblings`. Returns `None` if
+/// the siblings do not fit the shape of the tree.
+fn root_from_range(
+    leaves: &[[u8; 32]],
+    mut first: usize,
+    mut leaf_count: usize,
+    left: &[[u8; 32]],
+    right: &[[u8; 32]],
+) -> Option<[u8; 32]> {
+    if leaves.is_empty() || first + leaves.len() > leaf_count {
+        return None;
+    }
+    let (mut left, mut right) = (left.iter(), right.iter());
+    let mut level = leaves.to_vec();
+    while leaf_count > 1 {
+        let last = first + level.len() - 1;
+        if first % 2 == 1 {
+            level.insert(0, *left.next()?);
+            first -= 1;
+        }
+        if last % 2 == 0 && last + 1 < leaf_count {
+            level.push(*right.next()?);
+        }
+        level = next_level(&level);
+        first /= 2;
+        leaf_count = leaf_count.div_ceil(2);
+    }
+    if left.next().is_some() || right.next().is_some() {
+        return None;
+    }
+    Some(level[0])
+}
+
+pub fn root_from_path(leaf: [u8; 32], path: &[MerkleStep]) -> [u8; 32] {
+    path.iter().fold(leaf, |hash, step| {
+        if step.sibling_on_left {
+            hash_node(&step.sibling, &hash)
+        } else {
+            hash_node(&hash, &step.sibling)
+        }
+    })
+}
+
+/// Checks that `key` maps to `value` in `address`'s store under `root`.
+pub fn verify_proof(
+    root: &StateRoot,
+    address: &str,
+    key: &str,
+    value: &str,
+    proof: &MerkleProof,
+) -> bool {
+    let kv_leaf = kv_leaf_hash(key, value, proof.expires_at, proof.encoding);
+    if root_from_path(kv_leaf, &proof.kv_path) != proof.kv_root {
+        return false;
+    }
+    let account_leaf = account_leaf_hash(
+        address,
+        proof.nonce,
+        proof.balance,
+        &proof.kv_root,
+        proof.last_faucet_block,
+        &proof.writers,
+    );
+    root_from_path(account_leaf, &proof.account_path) == root.0
+}
+
+/// Checks that `proof.entries` are exactly the keys of `address`'s store in
+/// `start..end` under `root`, in key order.
+pub fn verify_range_proof(
+    root: &StateRoot,
+    address: &str,
+    start: &str,
+    end: &str,
+    proof: &RangeProof,
+) -> bool {
+    let in_range = |entry: &KvEntry| start <= entry.key.as_str() && entry.key.as_str() < end;
+    if !proof.entries.iter().all(in_range) {
+        return false;
+    }
+    // Without a neighbor on one side, the run must reach that end of the tree.
+    let known = proof
+        .left_neighbor
+        .iter()
+        .chain(&proof.entries)
+        .chain(&proof.right_neighbor)
+        .collect::<Vec<_>>();
+    match &proof.left_neighbor {
+        Some(left) if left.key.as_str() >= start => return false,
+        None if proof.first_index != 0 => return false,
+        _ => {}
+    }
+    match &proof.right_neighbor {
+        Some(right) if right.key.as_str() < end => return false,
+        None if proof.first_index + known.len() != proof.leaf_count => return false,
+        _ => {}
+    }
+    if !known.windows(2).all(|pair| pair[0].key < pair[1].key)
+        || (proof.leaf_count == 0 && !known.is_empty())
+    {
+        return false;
+    }
+
+    let kv_root = if proof.leaf_count == 0 {
+        [0; 32]
+    } else {
+        let leaves = known
+            .iter()
+            .map(|entry| entry.leaf_hash())
+            .collect::<Vec<_>>();
+        match root_from_range(
+            &leaves,
+            proof.first_index,
+            proof.leaf_count,
+            &proof.left_siblings,
+            &proof.right_siblings,
+        ) {
+            Some(kv_root) => kv_root,
+            None => return false,
+        }
+    };
+    let account_leaf = account_leaf_hash(
+        address,
+        proof.nonce,
+        proof.balance,
+        &kv_root,
+        proof.last_faucet_block,
+        &proof.writers,
+    );
+    root_from_path(account_leaf, &proof.account_path) == root.0
+}
@@ -1,3 +1,7 @@
+mod error;
+
+pub use error::*;
+
 mod state;
 
 pub use state::*;
@@ -9,3 +13,19 @@ pub use storage::*;
 mod blockchain;
 
 pub use blockchain::*;
+
+mod mem_storage;
+
+pub use mem_storage::*;
+
+mod rocks_storage;
+
+pub use rocks_storage::*;
+
+mod merkle;
+
+pub use merkle::*;
+
+mod genesis;
+
+pub use genesis::*;
@@ -0,0 +1,423 @@
+use async_trait::async_trait;
+use rocksdb::{ColumnFamily, Direction, IteratorMode, Options, WriteBatch, DB};
+use std::path::Path;
+
+use crate::{
+    compute_transaction_hash, decode_receipt, is_history_receipt, receipt_key, AccountId,
+    AccountState, Block, KvError, StateCheckpoint, StateRoot, Storage, TransactionReceipt,
+};
+
+const BLOCKS_CF: &str = "blocks";
+const BLOCK_HASHES_CF: &str = "block_hashes";
+const RECEIPTS_CF: &str = "receipts";
+const STATE_ROOTS_CF: &str = "state_roots";
+const ACCOUNTS_CF: &str = "accounts";
+const ACCOUNT_TXNS_CF: &str = "account_txns";
+const META_CF: &str = "meta";
+
+const COLUMN_FAMILIES: [&str; 7] = [
+    BLOCKS_CF,
+    BLOCK_HASHES_CF,
+    RECEIPTS_CF,
+    STATE_ROOTS_CF,
+    ACCOUNTS_CF,
+    ACCOUNT_TXNS_CF,
+    META_CF,
+];
+
+const CHECKPOINT_KEY: &[u8] = b"checkpoint";
+const PRUNED_BELOW_KEY: &[u8] = b"pruned_below";
+
+/// `Storage` backed by RocksDB, with one column family per kind of record.
+pub struct RocksStorage {
+    db: DB,
+}
+
+impl RocksStorage {
+    pub fn new<P: AsRef<Path>>(path: P) -> Result<Self, KvError> {
+        let mut opts = Options::default();
+        opts.create_if_missing(true);
+        opts.create_missing_column_families(true);
+        let db = DB::open_cf(&opts, path, COLUMN_FAMILIES)
+            .map_err(|e| KvError::Storage(format!("Failed to open database: {}", e)))?;
+        Ok(Self { db })
+    }
+
+    /// Opens the database at `primary_path`, written by another node, as a
+    /// read-only follower keeping its own files in `secondary_path`.
+    /// Call `catch_up` to see the primary's new writes.
+    pub fn open_secondary<P: AsRef<Path>, S: AsRef<Path>>(
+        primary_path: P,
+        secondary_path: S,
+    ) -> Result<Self, KvError> {
+        let mut opts = Options::default();
+        opts.set_max_open_files(-1);
+        let db = DB::open_cf_as_secondary(&opts, primary_path, secondary_path, COLUMN_FAMILIES)
+            .map_err(|e| {
+                KvError::Storage(format!("Failed to open database as secondary: {}", e))
+            })?;
+        Ok(Self { db })
+    }
+
+    fn cf(&self, name: &str) -> Result<&ColumnFamily, KvError> {
+        self.db
+            .cf_handle(name)
+            .ok_or_else(|| KvError::Storage(format!("Missing column family {}", name)))
+    }
+
+    fn get_cf(&self, name: &str, key: impl AsRef<[u8]>) -> Result<Option<Vec<u8>>, KvError> {
+        self.db
+            .get_cf(self.cf(name)?, key)
+            .map_err(|e| KvError::Storage(format!("Failed to read {}: {}", name, e)))
+    }
+
+    fn get_receipt_by_key(&self, key: &[u8]) -> Result<Option<TransactionReceipt>, KvError> {
+        match self.get_cf(RECEIPTS_CF, key)? {
+            Some(data) => Ok(Some(decode_receipt(&data)?)),
+            None => Ok(None),
+        }
+    }
+
+    /// History entries sort by address, then block number, then position in the block.
+    fn account_txn_key(address: &str, block_number: u64, index: u32) -> Vec<u8> {
+        let mut key = format!("{}:", address).into_bytes();
+        key.extend_from_slice(&block_number.to_be_bytes());
+        key.extend_from_slice(&index.to_be_bytes());
+        key
+    }
+
+    fn pruned_below(&self) -> Result<u64, KvError> {
+        match self.get_cf(META_CF, PRUNED_BELOW_KEY)? {
+            Some(data) => {
+                let number: [u8; 8] = data
+                    .as_slice()
+                    .try_into()
+                    .map_err(|_| KvError::Storage("Corrupted prune marker".to_string()))?;
+                Ok(u64::from_be_bytes(number))
+            }
+            None => Ok(0),
+        }
+    }
+}
+
+#[async_trait]
+impl Storage for RocksStorage {
+    async fn save_block(&self, block: &Block) -> Result<(), KvError> {
+        let encoded = bincode::serialize(block)
+            .map_err(|e| KvError::Serialization(format!("Failed to serialize block: {}", e)))?;
+        let number = block.header.number.to_be_bytes();
+
+        let mut batch = WriteBatch::default();
+        batch.put_cf(self.cf(BLOCKS_CF)?, number, encoded);
+        batch.put_cf(self.cf(BLOCK_HASHES_CF)?, block.header.hash(), number);
+        self.db
+            .write(batch)
+            .map_err(|e| KvError::Storage(format!("Failed to save block: {}", e)))?;
+
+        Ok(())
+    }
+
+    async fn get_block(&self, number: u64) -> Result<Option<Block>, KvError> {
+        match self.get_cf(BLOCKS_CF, number.to_be_bytes())? {
+            Some(data) => {
+                let block = bincode::deserialize(&data).map_err(|e| {
+                    KvError::Serialization(format!("Failed to deserialize block: {}", e))
+                })?;
+                Ok(Some(block))
+            }
+            None => Ok(None),
+        }
+    }
+
+    async fn get_block_by_number(&self, number: u64) -> Result<Option<Block>, KvError> {
+        self.get_block(number).await
+    }
+
+    async fn get_block_by_hash(&self, hash: [u8; 32]) -> Result<Option<Block>, KvError> {
+        match self.get_cf(BLOCK_HASHES_CF, hash)? {
+            Some(data) => {
+                let number: [u8; 8] = data
+                    .as_slice()
+                    .try_into()
+                    .map_err(|_| KvError::Storage("Corrupted block hash index".to_string()))?;
+                self.get_block(u64::from_be_bytes(number)).await
+            }
+            None => Ok(None),
+        }
+    }
+
+    async fn save_transaction_receipts(
+        &self,
+        receipts: Vec<TransactionReceipt>,
+    ) -> Result<(), KvError> {
+        let cf = self.cf(RECEIPTS_CF)?;
+        let mut batch = WriteBatch::default();
+        for receipt in &receipts {
+            let encoded = bincode::serialize(receipt).map_err(|e| {
+                KvError::Serialization(format!("Failed to serialize transaction receipt: {}", e))
+            })?;
+            batch.put_cf(cf, receipt_key(&receipt.transaction), encoded);
+        }
+        self.db
+            .write(batch)
+            .map_err(|e| KvError::Storage(format!("Failed to save transaction receipts: {}", e)))?;
+
+        Ok(())
+    }
+
+    async fn get_transaction_receipts(
+        &self,
+        transaction_hash: [u8; 32],
+    ) -> Result<Vec<TransactionReceipt>, KvError> {
+        // The bare hash of a receipt written before they were keyed by sender
+        // is matched by the prefix too.
+        let mut receipts = vec![];
+        let iter = self.db.iterator_cf(
+            self.cf(RECEIPTS_CF)?,
+            IteratorMode::From(&transaction_hash, Direction::Forward),
+        );
+        for entry in iter {
+            let (key, data) = entry.map_err(|e| {
+                KvError::Storage(format!("Failed to read transaction receipts: {}", e))
+            })?;
+            if !key.starts_with(&transaction_hash) {
+                break;
+            }
+            receipts.push(decode_receipt(&data)?);
+        }
+        Ok(receipts)
+    }
+
+    async fn save_state_root(&self, block_number: u64, root: StateRoot) -> Result<(), KvError> {
+        let encoded = bincode::serialize(&root).map_err(|e| {
+            KvError::Serialization(format!("Failed to serialize state root: {}", e))
+        })?;
+
+        self.db
+            .put_cf(
+                self.cf(STATE_ROOTS_CF)?,
+                block_number.to_be_bytes(),
+                encoded,
+            )
+            .map_err(|e| KvError::Storage(format!("Failed to save state root: {}", e)))?;
+
+        Ok(())
+    }
+
+    async fn get_state_root(&self, block_number: u64) -> Result<Option<StateRoot>, KvError> {
+        match self.get_cf(STATE_ROOTS_CF, block_number.to_be_bytes())? {
+            Some(data) => {
+                let root = bincode::deserialize(&data).map_err(|e| {
+                    KvError::Serialization(format!("Failed to deserialize state root: {}", e))
+                })?;
+                Ok(Some(root))
+            }
+            None => Ok(None),
+        }
+    }
+
+    async fn save_account_state(
+        &self,
+        account_id: &AccountId,
+        state: &AccountState,
+    ) -> Result<(), KvError> {
+        let encoded = bincode::serialize(state).map_err(|e| {
+            KvError::Serialization(format!("Failed to serialize account state: {}", e))
+        })?;
+
+        self.db
+            .put_cf(self.cf(ACCOUNTS_CF)?, account_id.0.as_bytes(), encoded)
+            .map_err(|e| KvError::Storage(format!("Failed to save account state: {}", e)))?;
+
+        Ok(())
+    }
+
+    async fn get_account_state(
+        &self,
+        account_id: &AccountId,
+    ) -> Result<Option<AccountState>, KvError> {
+        match self.get_cf(ACCOUNTS_CF, account_id.0.as_bytes())? {
+            Some(data) => {
+                let state = bincode::deserialize(&data).map_err(|e| {
+                    KvError::Serialization(format!("Failed to deserialize account state: {}", e))
+                })?;
+                Ok(Some(state))
+            }
+            None => Ok(None),
+        }
+    }
+
+    async fn save_checkpoint(&self, checkpoint: &StateCheckpoint) -> Result<(), KvError> {
+        let encoded = bincode::serialize(checkpoint).map_err(|e| {
+            KvError::Serialization(format!("Failed to serialize checkpoint: {}", e))
+        })?;
+
+        self.db
+            .put_cf(self.cf(META_CF)?, CHECKPOINT_KEY, encoded)
+            .map_err(|e| KvError::Storage(format!("Failed to save checkpoint: {}", e)))?;
+
+        Ok(())
+    }
+
+    async fn commit_block_atomic(
+        &self,
+        block: &Block,
+        receipts: &[TransactionReceipt],
+        state_root: &StateRoot,
+        checkpoint: Option<&StateCheckpoint>,
+    ) -> Result<(), KvError> {
+        let number = block.header.number.to_be_bytes();
+        let mut batch = WriteBatch::default();
+
+        let encoded = bincode::serialize(block)
+            .map_err(|e| KvError::Serialization(format!("Failed to serialize block: {}", e)))?;
+        batch.put_cf(self.cf(BLOCKS_CF)?, number, encoded);
+        batch.put_cf(self.cf(BLOCK_HASHES_CF)?, block.header.hash(), number);
+
+        for receipt in receipts {
+            let encoded = bincode::serialize(receipt).map_err(|e| {
+                KvError::Serialization(format!("Failed to serialize transaction receipt: {}", e))
+            })?;
+            batch.put_cf(
+                self.cf(RECEIPTS_CF)?,
+                receipt_key(&receipt.transaction),
+                encoded,
+            );
+        }
+
+        for (index, txn) in block.transactions.iter().enumerate() {
+            batch.put_cf(
+                self.cf(ACCOUNT_TXNS_CF)?,
+                Self::account_txn_key(&txn.address, block.header.number, index as u32),
+                receipt_key(&txn.txn),
+            );
+        }
+
+        let encoded = bincode::serialize(state_root).map_err(|e| {
+            KvError::Serialization(format!("Failed to serialize state root: {}", e))
+        })?;
+        batch.put_cf(self.cf(STATE_ROOTS_CF)?, number, encoded);
+
+        if let Some(checkpoint) = checkpoint {
+            let encoded = bincode::serialize(checkpoint).map_err(|e| {
+                KvError::Serialization(format!("Failed to serialize checkpoint: {}", e))
+            })?;
+            batch.put_cf(self.cf(META_CF)?, CHECKPOINT_KEY, encoded);
+        }
+
+        self.db
+            .write(batch)
+            .map_err(|e| KvError::Storage(format!("Failed to commit block: {}", e)))?;
+
+        Ok(())
+    }
+
+    async fn get_checkpoint(&self) -> Result<Option<StateCheckpoint>, KvError> {
+        match self.get_cf(META_CF, CHECKPOINT_KEY)? {
+            Some(data) => {
+                let checkpoint = bincode::deserialize(&data).map_err(|e| {
+                    KvError::Serialization(format!("Failed to deserialize checkpoint: {}", e))
+                })?;
+                Ok(Some(checkpoint))
+            }
+            None => Ok(None),
+        }
+    }
+
+    async fn get_account_transactions(
+        &self,
+        address: &str,
+        limit: usize,
+        offset: usize,
+    ) -> Result<Vec<TransactionReceipt>, KvError> {
+        let prefix = format!("{}:", address).into_bytes();
+        let mut keys = vec![];
+        let iter = self.db.iterator_cf(
+            self.cf(ACCOUNT_TXNS_CF)?,
+            IteratorMode::From(&prefix, Direction::Forward),
+        );
+        for entry in iter {
+            let (key, receipt_key) = entry
+                .map_err(|e| KvError::Storage(format!("Failed to read account history: {}", e)))?;
+            if !key.starts_with(&prefix) {
+                break;
+            }
+            keys.push(receipt_key);
+        }
+
+        let mut receipts = vec![];
+        for key in keys.into_iter().rev().skip(offset) {
+            if receipts.len() >= limit {
+                break;
+            }
+            let receipt = match <[u8; 32]>::try_from(key.as_ref()) {
+                Ok(hash) => self
+                    .get_transaction_receipts(hash)
+                    .await?
+                    .into_iter()
+                    .find(|receipt| is_history_receipt(receipt, address)),
+                Err(_) => self.get_receipt_by_key(&key)?,
+            };
+            receipts.extend(receipt);
+        }
+        Ok(receipts)
+    }
+
+    async fn prune_below(&self, block_number: u64) -> Result<u64, KvError> {
+        let checkpoint = match self.get_checkpoint().await? {
+            Some(checkpoint) => checkpoint,
+            None => return Ok(0),
+        };
+        let start = self.pruned_below()?;
+        let end = block_number.min(checkpoint.block_number);
+        if end <= start {
+            return Ok(0);
+        }
+
+        let mut batch = WriteBatch::default();
+        let mut pruned = 0;
+        for number in start..end {
+            if let Some(block) = self.get_block(number).await? {
+                for (index, txn) in block.transactions.iter().enumerate() {
+                    batch.delete_cf(self.cf(RECEIPTS_CF)?, receipt_key(&txn.txn));
+                    // A receipt stored under the bare hash may be another sender's.
+                    let hash = compute_transaction_hash(&txn.txn.unsigned);
+                    let legacy = self.get_receipt_by_key(&hash)?;
+                    if legacy.is_some_and(|r| r.transaction.signature == txn.txn.signature) {
+                        batch.delete_cf(self.cf(RECEIPTS_CF)?, hash);
+                    }
+                    batch.delete_cf(
+                        self.cf(ACCOUNT_TXNS_CF)?,
+                        Self::account_txn_key(&txn.address, number, index as u32),
+                    );
+                }
+                batch.delete_cf(self.cf(BLOCK_HASHES_CF)?, block.header.hash());
+                batch.delete_cf(self.cf(BLOCKS_CF)?, number.to_be_bytes());
+                pruned += 1;
+            }
+            batch.delete_cf(self.cf(STATE_ROOTS_CF)?, number.to_be_bytes());
+        }
+        batch.put_cf(self.cf(META_CF)?, PRUNED_BELOW_KEY, end.to_be_bytes());
+
+        self.db
+            .write(batch)
+            .map_err(|e| KvError::Storage(format!("Failed to prune blocks: {}", e)))?;
+
+        Ok(pruned)
+    }
+
+    async fn catch_up(&self) -> Result<(), KvError> {
+        self.db
+            .try_catch_up_with_primary()
+            .map_err(|e| KvError::Storage(format!("Failed to catch up with primary: {}", e)))
+    }
+
+    async fn flush(&self) -> Result<(), KvError> {
+        for name in COLUMN_FAMILIES {
+            self.db
+                .flush_cf(self.cf(name)?)
+                .map_err(|e| KvError::Storage(format!("Failed to flush {}: {}", name, e)))?;
+        }
+        Ok(())
+    }
+}
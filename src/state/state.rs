@@ -1,35 +1,147 @@
-use sha3::Digest;
 use std::{
-    collections::HashMap,
+    borrow::Cow,
+    collections::{BTreeSet, HashMap},
     fs::File,
-    hash::{DefaultHasher, Hash, Hasher},
-    io::BufReader,
+    io::{BufRead, BufReader, Write},
 };
 
-use crate::{AccountId, AccountState, StateRoot};
+use serde::{Deserialize, Serialize};
+use serde_json::error::Category;
 
-#[derive(Debug)]
+use crate::{
+    account_leaf_hash, canonical_address, kv_leaf_hash, kv_root, merkle_path,
+    merkle_range_siblings, merkle_root, AccountId, AccountState, AccountTree, KvEntry, KvError,
+    MerkleProof, RangeProof, StateCheckpoint, StateRoot,
+};
+
+/// Accounts are kept in a persistent map, so cloning a `State` is cheap: the
+/// clone shares every account with the original until either one writes it.
+/// The executor relies on this to run a block on a copy while readers keep
+/// using the committed state.
+#[derive(Debug, Clone)]
 pub struct State {
-    accounts: HashMap<String, AccountState>,
+    accounts: im::HashMap<String, AccountState>,
+    block_number: u64,
+    state_root: StateRoot,
+    /// Previous value of every account written while a snapshot is open, oldest first.
+    journal: Vec<(String, Option<AccountState>)>,
+    open_snapshots: usize,
+    /// Addresses with a key expiring at each block, so expired keys are swept
+    /// without scanning every account. Entries are only added, and those
+    /// whose key was overwritten or removed since are skipped when swept.
+    expiry_index: im::OrdMap<u64, im::OrdSet<String>>,
+    /// Merkle tree over the accounts as of the last `recompute_state_root`.
+    account_tree: AccountTree,
+    /// Accounts written since the last `recompute_state_root`.
+    dirty_accounts: BTreeSet<String>,
+}
+
+/// First line of an NDJSON state export.
+#[derive(Serialize, Deserialize)]
+struct ExportHeader {
+    block_number: u64,
+    state_root: String,
+}
+
+/// Every line after the header of an NDJSON state export.
+#[derive(Serialize, Deserialize)]
+struct ExportedAccount<'a> {
+    address: Cow<'a, str>,
+    account: Cow<'a, AccountState>,
+}
+
+/// Writes `value` to `writer` as one line of JSON.
+fn write_ndjson_line<W: Write, T: Serialize>(writer: &mut W, value: &T) -> Result<(), KvError> {
+    serde_json::to_writer(&mut *writer, value)
+        .map_err(|e| KvError::Storage(format!("Failed to write state export: {}", e)))?;
+    writer
+        .write_all(b"\n")
+        .map_err(|e| KvError::Storage(format!("Failed to write state export: {}", e)))
+}
+
+/// Leaf of the account at `address` in the state tree.
+fn account_leaf(address: &str, account: &AccountState) -> [u8; 32] {
+    account_leaf_hash(
+        address,
+        account.nonce,
+        account.balance,
+        &kv_root(account),
+        account.last_faucet_block,
+        &account.writers,
+    )
+}
+
+/// A point `State` can be rolled back to, taken with `State::snapshot`.
+///
+/// Only accounts written after the snapshot are copied, so taking one is cheap
+/// regardless of the size of the state.
+#[derive(Debug)]
+#[must_use = "a snapshot must be passed to `rollback` or `release`"]
+pub struct StateSnapshot {
+    journal_len: usize,
     block_number: u64,
     state_root: StateRoot,
 }
 
 impl State {
-    pub fn new(genesis_path: Option<String>) -> Self {
-        let accounts = if genesis_path.is_some() {
-            let file = File::open(genesis_path.unwrap()).unwrap();
-            let reader = BufReader::new(file);
-            serde_json::from_reader(reader).unwrap()
-        } else {
-            HashMap::new()
+    /// Creates the state from the accounts in the genesis file at `genesis_path`,
+    /// or an empty state without one.
+    pub fn new(genesis_path: Option<String>) -> Result<Self, KvError> {
+        let accounts = match genesis_path {
+            Some(path) => Self::load_genesis(&path)?.into_iter().collect(),
+            None => im::HashMap::new(),
         };
 
-        Self {
+        let mut state = Self {
             accounts,
             block_number: 0,
             state_root: StateRoot::default(),
+            journal: vec![],
+            open_snapshots: 0,
+            expiry_index: im::OrdMap::new(),
+            account_tree: AccountTree::default(),
+            dirty_accounts: BTreeSet::new(),
+        };
+        state.rebuild_expiry_index();
+        state.rebuild_account_tree();
+        state.recompute_state_root();
+        Ok(state)
+    }
+
+    fn load_genesis(path: &str) -> Result<HashMap<String, AccountState>, KvError> {
+        Self::read_genesis(path).map_err(KvError::Genesis)
+    }
+
+    fn read_genesis(path: &str) -> Result<HashMap<String, AccountState>, String> {
+        let file = File::open(path).map_err(|e| match e.kind() {
+            std::io::ErrorKind::NotFound => format!("Genesis file not found: {}", path),
+            _ => format!("Failed to open genesis file {}: {}", path, e),
+        })?;
+        let GenesisAccounts(accounts) =
+            serde_json::from_reader(BufReader::new(file)).map_err(|e| match e.classify() {
+                Category::Data => format!(
+                    "Genesis file {} does not match the schema, expected a map from \
+                     address to {{nonce, balance, kv_store}} with non-negative integers: {}",
+                    path, e
+                ),
+                Category::Io => format!("Failed to read genesis file {}: {}", path, e),
+                Category::Syntax | Category::Eof => {
+                    format!("Genesis file {} is not valid JSON: {}", path, e)
+                }
+            })?;
+        for address in accounts.keys() {
+            match canonical_address(address) {
+                Ok(canonical) if canonical == *address => {}
+                _ => {
+                    return Err(format!(
+                        "Genesis file {} does not match the schema: account {} is not \
+                         40 lowercase hex digits",
+                        path, address
+                    ))
+                }
+            }
         }
+        Ok(accounts)
     }
 
     pub fn get_state_root(&self) -> &StateRoot {
@@ -40,20 +152,990 @@ impl State {
         self.block_number
     }
 
+    pub fn set_current_block_number(&mut self, block_number: u64) {
+        self.block_number = block_number;
+    }
+
+    pub fn checkpoint(&self) -> StateCheckpoint {
+        StateCheckpoint {
+            block_number: self.block_number,
+            state_root: self.state_root.clone(),
+            accounts: self.accounts.clone().into_iter().collect(),
+        }
+    }
+
+    pub fn restore_checkpoint(&mut self, checkpoint: StateCheckpoint) {
+        self.accounts = checkpoint.accounts.into_iter().collect();
+        self.block_number = checkpoint.block_number;
+        self.state_root = checkpoint.state_root;
+        self.rebuild_expiry_index();
+        self.rebuild_account_tree();
+    }
+
+    /// Writes the state to `writer` as newline-delimited JSON: a header line
+    /// with the block number and state root, then one line per account, sorted
+    /// by address. Expired keys are kept so the root can be recomputed on import.
+    /// Returns the number of accounts written.
+    pub fn export_ndjson<W: Write>(&self, mut writer: W) -> Result<usize, KvError> {
+        let header = ExportHeader {
+            block_number: self.block_number,
+            state_root: self.state_root.to_hex(),
+        };
+        write_ndjson_line(&mut writer, &header)?;
+        let mut addresses = self.accounts.keys().collect::<Vec<_>>();
+        addresses.sort();
+        for address in &addresses {
+            let line = ExportedAccount {
+                address: Cow::Borrowed(address.as_str()),
+                account: Cow::Borrowed(&self.accounts[*address]),
+            };
+            write_ndjson_line(&mut writer, &line)?;
+        }
+        writer
+            .flush()
+            .map_err(|e| KvError::Storage(format!("Failed to write state export: {}", e)))?;
+        Ok(addresses.len())
+    }
+
+    /// Rebuilds a state written by `export_ndjson`, reading one line at a time.
+    /// Fails if a line is malformed or the recomputed state root differs from
+    /// the exported one.
+    pub fn import_ndjson<R: BufRead>(reader: R) -> Result<Self, KvError> {
+        let mut lines = reader.lines().enumerate();
+        let mut next_line = || -> Result<Option<(usize, String)>, KvError> {
+            match lines.next() {
+                Some((index, Ok(line))) => Ok(Some((index + 1, line))),
+                Some((_, Err(e))) => Err(KvError::Storage(format!(
+                    "Failed to read state export: {}",
+                    e
+                ))),
+                None => Ok(None),
+            }
+        };
+        let parse_error = |line_number: usize, e: serde_json::Error| {
+            KvError::Serialization(format!(
+                "Invalid state export at line {}: {}",
+                line_number, e
+            ))
+        };
+
+        let (line_number, line) =
+            next_line()?.ok_or_else(|| KvError::Serialization("Empty state export".to_string()))?;
+        let header: ExportHeader =
+            serde_json::from_str(&line).map_err(|e| parse_error(line_number, e))?;
+        let mut accounts = im::HashMap::new();
+        while let Some((line_number, line)) = next_line()? {
+            if line.trim().is_empty() {
+                continue;
+            }
+            let entry: ExportedAccount =
+                serde_json::from_str(&line).map_err(|e| parse_error(line_number, e))?;
+            accounts.insert(entry.address.into_owned(), entry.account.into_owned());
+        }
+
+        let mut state = Self {
+            accounts,
+            block_number: header.block_number,
+            state_root: StateRoot::default(),
+            journal: vec![],
+            open_snapshots: 0,
+            expiry_index: im::OrdMap::new(),
+            account_tree: AccountTree::default(),
+            dirty_accounts: BTreeSet::new(),
+        };
+        state.rebuild_expiry_index();
+        state.rebuild_account_tree();
+        state.recompute_state_root();
+        if state.state_root.to_hex() != header.state_root {
+            return Err(KvError::StateRootMismatch {
+                block_number: header.block_number,
+                expected: header.state_root,
+                actual: state.state_root.to_hex(),
+            });
+        }
+        Ok(state)
+    }
+
+    /// Returns the account at `address`, without the keys expired at the
+    /// current block.
     pub fn get_account(&self, address: &str) -> Option<AccountState> {
-        self.accounts.get(address).cloned()
+        let mut account = self.accounts.get(address).cloned()?;
+        account.purge_expired(self.block_number);
+        Some(account)
+    }
+
+    /// Whether `address` has been created, either by the genesis file or by a
+    /// committed transaction. Querying an account never creates it.
+    pub fn account_exists(&self, address: &str) -> bool {
+        self.accounts.contains_key(address)
+    }
+
+    /// Number of accounts in the state.
+    pub fn account_count(&self) -> usize {
+        self.accounts.len()
+    }
+
+    /// Sum of the balances of every account.
+    pub fn total_supply(&self) -> u128 {
+        self.accounts
+            .values()
+            .map(|account| account.balance as u128)
+            .sum()
+    }
+
+    /// Returns up to `limit` accounts sorted by address, skipping the first
+    /// `offset`, without the keys expired at the current block.
+    pub fn list_accounts(&self, offset: usize, limit: usize) -> Vec<(String, AccountState)> {
+        let mut addresses = self.accounts.keys().collect::<Vec<_>>();
+        addresses.sort();
+        addresses
+            .into_iter()
+            .skip(offset)
+            .take(limit)
+            .map(|address| {
+                let mut account = self.accounts[address].clone();
+                account.purge_expired(self.block_number);
+                (address.clone(), account)
+            })
+            .collect()
+    }
+
+    /// Returns the key-value pairs of `address` whose key starts with `prefix`,
+    /// sorted by key and capped at `limit` entries.
+    pub fn scan_prefix(&self, address: &str, prefix: &str, limit: usize) -> Vec<(String, String)> {
+        let account = match self.accounts.get(address) {
+            Some(account) => account,
+            None => return vec![],
+        };
+        account
+            .kv_store
+            .range(prefix.to_string()..)
+            .take_while(|(key, _)| key.starts_with(prefix))
+            .filter(|(key, _)| {
+                account
+                    .expiries
+                    .get(*key)
+                    .map_or(true, |expires_at| *expires_at > self.block_number)
+            })
+            .take(limit)
+            .map(|(key, value)| (key.clone(), value.clone()))
+            .collect()
     }
 
     pub async fn update_account_state(
         &mut self,
         account_id: &AccountId,
         state_state: AccountState,
-    ) -> Result<(), String> {
-        let mut hasher = DefaultHasher::new();
-        hasher.write(account_id.0.as_bytes());
-        state_state.hash(&mut hasher);
-        self.accounts.insert(account_id.0.clone(), state_state);
-        self.state_root = self.state_root.update(hasher.finish());
+    ) -> Result<(), KvError> {
+        self.put_account(account_id, state_state);
+        self.recompute_state_root();
         Ok(())
     }
+
+    /// Stores `account` without touching the state root. Callers must call
+    /// `recompute_state_root` once they are done updating accounts.
+    pub fn put_account(&mut self, account_id: &AccountId, account: AccountState) {
+        let previous_expiries = self
+            .accounts
+            .get(&account_id.0)
+            .map(|previous| &previous.expiries);
+        let new_expiries = account
+            .expiries
+            .iter()
+            .filter(|(key, expires_at)| {
+                previous_expiries.and_then(|expiries| expiries.get(*key)) != Some(*expires_at)
+            })
+            .map(|(_, expires_at)| *expires_at)
+            .collect::<Vec<_>>();
+        for expires_at in new_expiries {
+            self.index_expiry(expires_at, &account_id.0);
+        }
+        let previous = self.accounts.insert(account_id.0.clone(), account);
+        self.dirty_accounts.insert(account_id.0.clone());
+        if self.open_snapshots > 0 {
+            self.journal.push((account_id.0.clone(), previous));
+        }
+    }
+
+    fn index_expiry(&mut self, expires_at: u64, address: &str) {
+        let mut addresses = self
+            .expiry_index
+            .get(&expires_at)
+            .cloned()
+            .unwrap_or_default();
+        addresses.insert(address.to_string());
+        self.expiry_index.insert(expires_at, addresses);
+    }
+
+    fn index_expiries(&mut self, address: &str) {
+        let expiries = match self.accounts.get(address) {
+            Some(account) => account.expiries.values().copied().collect::<Vec<_>>(),
+            None => return,
+        };
+        for expires_at in expiries {
+            self.index_expiry(expires_at, address);
+        }
+    }
+
+    fn rebuild_expiry_index(&mut self) {
+        self.expiry_index = im::OrdMap::new();
+        let addresses = self.accounts.keys().cloned().collect::<Vec<_>>();
+        for address in addresses {
+            self.index_expiries(&address);
+        }
+    }
+
+    /// Removes the keys expired at `block_number` from every account. Executing
+    /// a block sweeps them first, so the state root only ever commits to live keys.
+    ///
+    /// Only the accounts indexed under a block up to `block_number` are read.
+    pub fn purge_expired(&mut self, block_number: u64) {
+        let due = self
+            .expiry_index
+            .range(..=block_number)
+            .map(|(expires_at, _)| *expires_at)
+            .collect::<Vec<_>>();
+        let mut addresses = BTreeSet::new();
+        for expires_at in due {
+            if let Some(indexed) = self.expiry_index.remove(&expires_at) {
+                addresses.extend(indexed);
+            }
+        }
+        for address in addresses {
+            let Some(mut account) = self.accounts.get(&address).cloned() else {
+                continue;
+            };
+            if account.purge_expired(block_number) {
+                self.put_account(&AccountId(address), account);
+            }
+        }
+    }
+
+    /// Starts recording account writes so they can be undone with `rollback`.
+    /// Snapshots nest; each must be passed to `rollback` or `release`, innermost first.
+    pub fn snapshot(&mut self) -> StateSnapshot {
+        self.open_snapshots += 1;
+        StateSnapshot {
+            journal_len: self.journal.len(),
+            block_number: self.block_number,
+            state_root: self.state_root.clone(),
+        }
+    }
+
+    /// Undoes every account write made since `snapshot` was taken and restores
+    /// its block number and state root.
+    pub fn rollback(&mut self, snapshot: StateSnapshot) {
+        let undone = self
+            .journal
+            .drain(snapshot.journal_len..)
+            .rev()
+            .collect::<Vec<_>>();
+        for (address, previous) in undone {
+            match previous {
+                Some(account) => self.accounts.insert(address.clone(), account),
+                None => self.accounts.remove(&address),
+            };
+            // A restored key may have been swept from the index since.
+            self.index_expiries(&address);
+            self.dirty_accounts.insert(address);
+        }
+        self.block_number = snapshot.block_number;
+        self.state_root = snapshot.state_root;
+        self.close_snapshot();
+    }
+
+    /// Keeps the writes made since `snapshot` was taken.
+    pub fn release(&mut self, snapshot: StateSnapshot) {
+        debug_assert!(snapshot.journal_len <= self.journal.len());
+        self.close_snapshot();
+    }
+
+    fn close_snapshot(&mut self) {
+        self.open_snapshots -= 1;
+        if self.open_snapshots == 0 {
+            self.journal.clear();
+        }
+    }
+
+    /// Accounts sorted by address, with the leaf hash of each.
+    fn account_leaves(&self) -> (Vec<&String>, Vec<[u8; 32]>) {
+        let mut accounts = self.accounts.iter().collect::<Vec<_>>();
+        accounts.sort_by(|a, b| a.0.cmp(b.0));
+        accounts
+            .into_iter()
+            .map(|(address, account)| (address, account_leaf(address, account)))
+            .unzip()
+    }
+
+    fn rebuild_account_tree(&mut self) {
+        let (addresses, leaves) = self.account_leaves();
+        let addresses = addresses.into_iter().cloned().collect();
+        self.account_tree = AccountTree::new(addresses, leaves);
+        self.dirty_accounts.clear();
+    }
+
+    /// Sets the state root to the Merkle root over every account. Each account
+    /// leaf commits to its nonce, balance and the Merkle root of its kv_store.
+    ///
+    /// Only the leaves of accounts written since the last call are rehashed,
+    /// along with the nodes above them.
+    pub fn recompute_state_root(&mut self) {
+        let changes = std::mem::take(&mut self.dirty_accounts)
+            .into_iter()
+            .map(|address| {
+                let leaf = self
+                    .accounts
+                    .get(&address)
+                    .map(|account| account_leaf(&address, account));
+                (address, leaf)
+            })
+            .collect::<Vec<_>>();
+        self.account_tree.update(changes);
+        self.state_root = StateRoot(self.account_tree.root());
+    }
+
+    /// The Merkle root over every account, computed from the accounts rather
+    /// than read from the cached state root.
+    pub fn computed_state_root(&self) -> StateRoot {
+        let (_, leaves) = self.account_leaves();
+        StateRoot(merkle_root(&leaves))
+    }
+
+    /// Builds a proof of `key`'s current value in `address`'s store, verifiable
+    /// with `verify_proof` against the current state root.
+    pub fn prove(&self, address: &str, key: &str) -> Option<MerkleProof> {
+        let account = self.accounts.get(address)?;
+        let expires_at = account.expiries.get(key).copied();
+        if expires_at.is_some_and(|expires_at| expires_at <= self.block_number) {
+            return None;
+        }
+        let kv_leaves = account
+            .kv_store
+            .iter()
+            .map(|(key, value)| {
+                kv_leaf_hash(
+                    key,
+                    value,
+                    account.expiries.get(key).copied(),
+                    account.encodings.get(key).copied(),
+                )
+            })
+            .collect::<Vec<_>>();
+        let kv_index = account.kv_store.keys().position(|k| k == key)?;
+        let account_path = self.account_tree.path(address)?;
+
+        Some(MerkleProof {
+            nonce: account.nonce,
+            balance: account.balance,
+            kv_root: merkle_root(&kv_leaves),
+            last_faucet_block: account.last_faucet_block,
+            writers: account.writers.clone(),
+            expires_at,
+            encoding: account.encodings.get(key).copied(),
+            kv_path: merkle_path(&kv_leaves, kv_index),
+            account_path,
+        })
+    }
+
+    /// Builds a proof of every key of `address`'s store in `start..end`,
+    /// verifiable with `verify_range_proof` against the current state root.
+    pub fn prove_range(&self, address: &str, start: &str, end: &str) -> Option<RangeProof> {
+        let account = self.accounts.get(address)?;
+        let entries = account
+            .kv_store
+            .iter()
+            .map(|(key, value)| KvEntry {
+                key: key.clone(),
+                value: value.clone(),
+                expires_at: account.expiries.get(key).copied(),
+                encoding: account.encodings.get(key).copied(),
+            })
+            .collect::<Vec<_>>();
+        let kv_leaves = entries
+            .iter()
+            .map(|entry| kv_leaf_hash(&entry.key, &entry.value, entry.expires_at, entry.encoding))
+            .collect::<Vec<_>>();
+        let from = entries.partition_point(|entry| entry.key.as_str() < start);
+        let to = entries
+            .partition_point(|entry| entry.key.as_str() < end)
+            .max(from);
+
+        let left_neighbor = from.checked_sub(1).map(|index| entries[index].clone());
+        let right_neighbor = entries.get(to).cloned();
+        let first_index = from.saturating_sub(1);
+        let (left_siblings, right_siblings) = if entries.is_empty() {
+            (vec![], vec![])
+        } else {
+            let last_index = to.min(entries.len() - 1);
+            merkle_range_siblings(&kv_leaves, first_index, last_index)
+        };
+
+        let account_path = self.account_tree.path(address)?;
+
+        Some(RangeProof {
+            entries: entries[from..to].to_vec(),
+            nonce: account.nonce,
+            balance: account.balance,
+            last_faucet_block: account.last_faucet_block,
+            writers: account.writers.clone(),
+            leaf_count: entries.len(),
+            first_index,
+            left_neighbor,
+            right_neighbor,
+            left_siblings,
+            right_siblings,
+            account_path,
+        })
+    }
+}
+
+/// The accounts of a genesis file. An address listed twice is an error rather
+/// than collapsed into its last entry, as a map would do.
+struct GenesisAccounts(HashMap<String, AccountState>);
+
+impl<'de> Deserialize<'de> for GenesisAccounts {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct AccountsVisitor;
+
+        impl<'de> serde::de::Visitor<'de> for AccountsVisitor {
+            type Value = GenesisAccounts;
+
+            fn expecting(&self, formatter: &mut std::fmt::Formatter) -> std::fmt::Result {
+                formatter.write_str("a map from address to account")
+            }
+
+            fn visit_map<A: serde::de::MapAccess<'de>>(
+                self,
+                mut map: A,
+            ) -> Result<Self::Value, A::Error> {
+                let mut accounts = HashMap::new();
+                while let Some((address, account)) = map.next_entry::<String, AccountState>()? {
+                    if accounts.contains_key(&address) {
+                        return Err(serde::de::Error::custom(format!(
+                            "account {} is listed twice",
+                            address
+                        )));
+                    }
+                    accounts.insert(address, account);
+                }
+                Ok(GenesisAccounts(accounts))
+            }
+        }
+
+        deserializer.deserialize_map(AccountsVisitor)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{verify_proof, verify_range_proof};
+    use serde_json::json;
+
+    const ADDRESS: &str = "00000000000000000000000000000000000000aa";
+
+    /// A state holding one account at `ADDRESS` with `entries`.
+    fn state_with(entries: &[(&str, &str)]) -> State {
+        let mut state = State::new(None).unwrap();
+        state.put_account(&AccountId(ADDRESS.to_string()), account(entries));
+        state.recompute_state_root();
+        state
+    }
+
+    fn account(entries: &[(&str, &str)]) -> AccountState {
+        AccountState {
+            nonce: 0,
+            balance: 0,
+            kv_store: pairs(entries).into_iter().collect(),
+            expiries: Default::default(),
+            last_faucet_block: None,
+            encodings: Default::default(),
+            writers: Default::default(),
+        }
+    }
+
+    /// `state_with(entries)` among other accounts, so proofs have an account
+    /// path to walk.
+    fn state_among_others(entries: &[(&str, &str)]) -> State {
+        let mut state = state_with(entries);
+        for address in [
+            "00000000000000000000000000000000000000a0",
+            "00000000000000000000000000000000000000ff",
+        ] {
+            state.put_account(
+                &AccountId(address.to_string()),
+                account(&[("key", address)]),
+            );
+        }
+        state.recompute_state_root();
+        state
+    }
+
+    fn pairs(entries: &[(&str, &str)]) -> Vec<(String, String)> {
+        entries
+            .iter()
+            .map(|(key, value)| (key.to_string(), value.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn keys_iterate_in_order_and_the_root_ignores_insertion_order() {
+        let forwards = state_with(&[("a", "1"), ("b", "2"), ("c", "3")]);
+        let backwards = state_with(&[("c", "3"), ("b", "2"), ("a", "1")]);
+
+        let keys = backwards
+            .get_account(ADDRESS)
+            .unwrap()
+            .kv_store
+            .into_keys()
+            .collect::<Vec<_>>();
+        assert_eq!(keys, ["a", "b", "c"]);
+        assert_eq!(forwards.get_state_root().0, backwards.get_state_root().0);
+    }
+
+    #[test]
+    fn genesis_json_deserializes_into_ordered_stores() {
+        let path = crate::test_utils::temp_dir().with_extension("json");
+        std::fs::write(
+            &path,
+            json!({
+                ADDRESS: {
+                    "nonce": 0,
+                    "balance": 5,
+                    "kv_store": { "b": "2", "a": "1", "c": "3" },
+                }
+            })
+            .to_string(),
+        )
+        .unwrap();
+
+        let state = State::new(Some(path.to_string_lossy().into_owned())).unwrap();
+        let account = state.get_account(ADDRESS).unwrap();
+        assert_eq!(account.balance, 5);
+        assert_eq!(
+            account.kv_store.into_iter().collect::<Vec<_>>(),
+            pairs(&[("a", "1"), ("b", "2"), ("c", "3")])
+        );
+        std::fs::remove_file(path).unwrap();
+    }
+
+    /// The error `State::new` gives for a genesis file holding `contents`.
+    fn genesis_error(contents: &str) -> String {
+        let path = crate::test_utils::temp_dir().with_extension("json");
+        std::fs::write(&path, contents).unwrap();
+        let result = State::new(Some(path.to_string_lossy().into_owned()));
+        std::fs::remove_file(path).unwrap();
+        match result {
+            Err(KvError::Genesis(message)) => message,
+            other => panic!("expected a genesis error, got {:?}", other.map(|_| ())),
+        }
+    }
+
+    #[test]
+    fn invalid_genesis_files_are_rejected_with_the_reason() {
+        let missing = crate::test_utils::temp_dir().with_extension("json");
+        match State::new(Some(missing.to_string_lossy().into_owned())) {
+            Err(KvError::Genesis(message)) => {
+                assert!(message.starts_with("Genesis file not found"), "{}", message)
+            }
+            other => panic!("expected a genesis error, got {:?}", other.map(|_| ())),
+        }
+
+        let account = r#"{"nonce": 0, "balance": 5, "kv_store": {}}"#;
+        for (contents, reason) in [
+            (
+                format!(r#"{{"{}": {}"#, ADDRESS, account),
+                "is not valid JSON",
+            ),
+            ("not json".to_string(), "is not valid JSON"),
+            (
+                format!(
+                    r#"{{"{}": {{"nonce": 0, "balance": -5, "kv_store": {{}}}}}}"#,
+                    ADDRESS
+                ),
+                "does not match the schema",
+            ),
+            (format!(r#"[{}]"#, account), "does not match the schema"),
+            (
+                format!(r#"{{"0x{}": {}}}"#, ADDRESS, account),
+                "is not 40 lowercase hex digits",
+            ),
+            (
+                format!(r#"{{"{}": {}}}"#, ADDRESS.to_uppercase(), account),
+                "is not 40 lowercase hex digits",
+            ),
+            (
+                format!(r#"{{"12": {}}}"#, account),
+                "is not 40 lowercase hex digits",
+            ),
+            (
+                format!(r#"{{"{0}": {1}, "{0}": {1}}}"#, ADDRESS, account),
+                "is listed twice",
+            ),
+        ] {
+            let message = genesis_error(&contents);
+            assert!(message.contains(reason), "{}: {}", contents, message);
+        }
+    }
+
+    #[test]
+    fn proof_of_a_present_key_verifies_against_the_root() {
+        let state = state_among_others(&[("a", "1"), ("b", "2"), ("c", "3")]);
+        let root = state.get_state_root();
+
+        for (key, value) in [("a", "1"), ("b", "2"), ("c", "3")] {
+            let proof = state.prove(ADDRESS, key).unwrap();
+            assert!(verify_proof(root, ADDRESS, key, value, &proof));
+        }
+        assert!(state.prove(ADDRESS, "missing").is_none());
+        assert!(state
+            .prove("00000000000000000000000000000000000000bb", "a")
+            .is_none());
+    }
+
+    #[test]
+    fn proof_rejects_a_tampered_value_key_or_account() {
+        let state = state_among_others(&[("a", "1"), ("b", "2"), ("c", "3")]);
+        let root = state.get_state_root();
+        let proof = state.prove(ADDRESS, "b").unwrap();
+
+        assert!(!verify_proof(root, ADDRESS, "b", "tampered", &proof));
+        assert!(!verify_proof(root, ADDRESS, "a", "2", &proof));
+        assert!(!verify_proof(
+            root,
+            "00000000000000000000000000000000000000a0",
+            "b",
+            "2",
+            &proof
+        ));
+        let mut richer = proof.clone();
+        richer.balance += 1;
+        assert!(!verify_proof(root, ADDRESS, "b", "2", &richer));
+        assert!(!verify_proof(
+            &StateRoot([1; 32]),
+            ADDRESS,
+            "b",
+            "2",
+            &proof
+        ));
+    }
+
+    const RANGE_ENTRIES: [(&str, &str); 5] =
+        [("a", "1"), ("b", "2"), ("c", "3"), ("d", "4"), ("e", "5")];
+
+    #[test]
+    fn range_proof_proves_every_key_in_the_range() {
+        let state = state_among_others(&RANGE_ENTRIES);
+        let root = state.get_state_root();
+
+        for (start, end, expected) in [
+            ("b", "d", &["b", "c"][..]),
+            ("", "c", &["a", "b"]),
+            ("c", "z", &["c", "d", "e"]),
+            ("", "z", &["a", "b", "c", "d", "e"]),
+            ("bb", "bc", &[]),
+            ("x", "z", &[]),
+        ] {
+            let proof = state.prove_range(ADDRESS, start, end).unwrap();
+            let keys = proof
+                .entries
+                .iter()
+                .map(|entry| entry.key.as_str())
+                .collect::<Vec<_>>();
+            assert_eq!(keys, expected, "{}..{}", start, end);
+            assert!(
+                verify_range_proof(root, ADDRESS, start, end, &proof),
+                "{}..{}",
+                start,
+                end
+            );
+        }
+    }
+
+    #[test]
+    fn range_proof_with_an_omitted_or_tampered_entry_is_invalid() {
+        let state = state_among_others(&RANGE_ENTRIES);
+        let root = state.get_state_root();
+        let proof = state.prove_range(ADDRESS, "b", "e").unwrap();
+        assert!(verify_range_proof(root, ADDRESS, "b", "e", &proof));
+
+        for omitted in 0..proof.entries.len() {
+            let mut incomplete = proof.clone();
+            incomplete.entries.remove(omitted);
+            assert!(!verify_range_proof(root, ADDRESS, "b", "e", &incomplete));
+        }
+        let mut tampered = proof.clone();
+        tampered.entries[1].value = "tampered".to_string();
+        assert!(!verify_range_proof(root, ADDRESS, "b", "e", &tampered));
+        // The proof does not cover keys outside the range it was built for.
+        assert!(!verify_range_proof(root, ADDRESS, "a", "e", &proof));
+        assert!(!verify_range_proof(root, ADDRESS, "b", "f", &proof));
+    }
+
+    #[test]
+    fn rollback_restores_balances_accounts_and_root() {
+        use crate::{test_utils::*, RecentTxns, TransactionKind};
+
+        let (alice, bob) = (new_key(), new_key());
+        let mut state = State::new(None).unwrap();
+        let mut recent_txns = RecentTxns::new(0);
+        let txns = vec![sign(&alice, 0, set_kv("key", "value"))];
+        execute_block(&mut state, &config(), &mut recent_txns, txns);
+        let root = state.get_state_root().clone();
+        let alice_before = state.get_account(&alice.address());
+
+        let snapshot = state.snapshot();
+        let txns = vec![
+            sign(
+                &alice,
+                1,
+                TransactionKind::Transfer {
+                    receiver: bob.address(),
+                    amount: 300,
+                },
+            ),
+            sign(&alice, 2, set_kv("key", "other")),
+        ];
+        execute_block(&mut state, &config(), &mut recent_txns, txns);
+        assert_eq!(state.get_account(&bob.address()).unwrap().balance, 300);
+        assert_ne!(state.get_state_root().0, root.0);
+
+        state.rollback(snapshot);
+        assert_eq!(state.get_state_root().0, root.0);
+        assert_eq!(state.computed_state_root().0, root.0);
+        assert_eq!(state.get_current_block_number(), 1);
+        assert_eq!(state.get_account(&alice.address()), alice_before);
+        assert!(state.get_account(&bob.address()).is_none());
+    }
+
+    #[test]
+    fn nested_snapshots_undo_only_their_own_writes() {
+        let mut state = state_with(&[("a", "1")]);
+        let id = AccountId(ADDRESS.to_string());
+        let root = state.get_state_root().clone();
+
+        let outer = state.snapshot();
+        state.put_account(&id, account(&[("a", "2")]));
+        state.recompute_state_root();
+        let outer_root = state.get_state_root().clone();
+        let inner = state.snapshot();
+        state.put_account(&id, account(&[("a", "3")]));
+        state.recompute_state_root();
+        state.rollback(inner);
+        assert_eq!(state.get_state_root().0, outer_root.0);
+        assert_eq!(state.get_account(ADDRESS).unwrap().kv_store["a"], "2");
+
+        let inner = state.snapshot();
+        state.put_account(&id, account(&[("a", "4")]));
+        state.recompute_state_root();
+        state.release(inner);
+        state.rollback(outer);
+        assert_eq!(state.get_state_root().0, root.0);
+        assert_eq!(state.get_account(ADDRESS).unwrap().kv_store["a"], "1");
+    }
+
+    #[test]
+    fn incremental_root_matches_a_full_recomputation() {
+        let mut state = State::new(None).unwrap();
+        let address = |i: usize| format!("{:040x}", i * 7919 % 1000);
+        for i in 0..37 {
+            state.put_account(&AccountId(address(i)), account(&[("key", "0")]));
+        }
+        state.recompute_state_root();
+        assert_eq!(state.get_state_root().0, state.computed_state_root().0);
+
+        for round in 1..6 {
+            // Rewrite some accounts, then add one, and check both trees agree.
+            for i in (round..37).step_by(round + 2) {
+                let value = format!("{}", round * i);
+                state.put_account(&AccountId(address(i)), account(&[("key", &value)]));
+            }
+            state.recompute_state_root();
+            assert_eq!(state.get_state_root().0, state.computed_state_root().0);
+
+            let snapshot = state.snapshot();
+            state.put_account(&AccountId(address(100 + round)), account(&[("new", "1")]));
+            state.put_account(&AccountId(address(round)), account(&[("key", "undone")]));
+            state.recompute_state_root();
+            assert_eq!(state.get_state_root().0, state.computed_state_root().0);
+            if round % 2 == 0 {
+                state.rollback(snapshot);
+            } else {
+                state.release(snapshot);
+            }
+            state.recompute_state_root();
+            assert_eq!(state.get_state_root().0, state.computed_state_root().0);
+
+            let proof = state.prove(&address(round), "key").unwrap();
+            assert!(verify_proof(
+                state.get_state_root(),
+                &address(round),
+                "key",
+                &state.get_account(&address(round)).unwrap().kv_store["key"],
+                &proof
+            ));
+        }
+    }
+
+    #[test]
+    fn expired_keys_are_swept_at_their_block_and_survive_a_rollback() {
+        let id = AccountId(ADDRESS.to_string());
+        let mut expiring = account(&[("soon", "1"), ("later", "2"), ("kept", "3")]);
+        expiring.expiries = [("soon".to_string(), 5), ("later".to_string(), 8)]
+            .into_iter()
+            .collect();
+        let mut state = State::new(None).unwrap();
+        state.put_account(&id, expiring);
+        let keys = |state: &State| {
+            let account = &state.accounts[ADDRESS];
+            account.kv_store.keys().cloned().collect::<Vec<_>>()
+        };
+
+        state.purge_expired(4);
+        assert_eq!(keys(&state), ["kept", "later", "soon"]);
+        state.purge_expired(5);
+        assert_eq!(keys(&state), ["kept", "later"]);
+
+        // A key whose expiry moved is swept at its new block only.
+        let mut moved = state.accounts[ADDRESS].clone();
+        moved.expiries.insert("later".to_string(), 10);
+        state.put_account(&id, moved);
+        state.purge_expired(8);
+        assert_eq!(keys(&state), ["kept", "later"]);
+
+        let snapshot = state.snapshot();
+        state.purge_expired(10);
+        assert_eq!(keys(&state), ["kept"]);
+        state.rollback(snapshot);
+        assert_eq!(keys(&state), ["kept", "later"]);
+        state.purge_expired(10);
+        assert_eq!(keys(&state), ["kept"]);
+        assert!(state.expiry_index.is_empty());
+    }
+
+    #[test]
+    fn expiry_index_is_rebuilt_from_a_checkpoint() {
+        let id = AccountId(ADDRESS.to_string());
+        let mut expiring = account(&[("soon", "1")]);
+        expiring.expiries.insert("soon".to_string(), 3);
+        let mut state = State::new(None).unwrap();
+        state.put_account(&id, expiring);
+        state.recompute_state_root();
+
+        let mut restored = State::new(None).unwrap();
+        restored.restore_checkpoint(state.checkpoint());
+        restored.purge_expired(3);
+        assert!(restored.accounts[ADDRESS].kv_store.is_empty());
+    }
+
+    #[test]
+    fn ndjson_export_round_trips_every_account() {
+        use crate::{test_utils::*, RecentTxns, TransactionKind, ValueEncoding};
+
+        let (alice, bob) = (new_key(), new_key());
+        let mut state = State::new(None).unwrap();
+        let mut recent_txns = RecentTxns::new(0);
+        let txns = vec![
+            sign(&alice, 0, set_kv("plain", "value")),
+            sign(
+                &alice,
+                1,
+                TransactionKind::SetKVTtl {
+                    key: "ttl".to_string(),
+                    value: "soon".to_string(),
+                    expires_at_block: 10,
+                },
+            ),
+            sign(
+                &alice,
+                2,
+                TransactionKind::SetKVEncoded {
+                    key: "bytes".to_string(),
+                    value: "00ff".to_string(),
+                    encoding: ValueEncoding::Hex,
+                },
+            ),
+            sign(
+                &alice,
+                3,
+                TransactionKind::GrantWrite {
+                    grantee: bob.address(),
+                },
+            ),
+            sign(
+                &bob,
+                0,
+                TransactionKind::Transfer {
+                    receiver: new_key().address(),
+                    amount: 5,
+                },
+            ),
+        ];
+        execute_block(&mut state, &config(), &mut recent_txns, txns);
+
+        let mut export = vec![];
+        assert_eq!(state.export_ndjson(&mut export).unwrap(), 3);
+        let imported = State::import_ndjson(export.as_slice()).unwrap();
+        assert_eq!(imported.get_state_root().0, state.get_state_root().0);
+        assert_eq!(imported.get_current_block_number(), 1);
+        assert_eq!(imported.list_accounts(0, 10), state.list_accounts(0, 10));
+
+        let tampered = String::from_utf8(export)
+            .unwrap()
+            .replace("\"value\"", "\"other\"");
+        assert!(matches!(
+            State::import_ndjson(tampered.as_bytes()),
+            Err(KvError::StateRootMismatch {
+                block_number: 1,
+                ..
+            })
+        ));
+    }
+
+    #[test]
+    fn scan_prefix_returns_matching_keys_in_order_up_to_the_limit() {
+        let state = state_with(&[
+            ("user:2", "bob"),
+            ("item:1", "apple"),
+            ("user:1", "alice"),
+            ("user:3", "carol"),
+            ("users", "all"),
+        ]);
+
+        assert_eq!(
+            state.scan_prefix(ADDRESS, "user:", 10),
+            pairs(&[("user:1", "alice"), ("user:2", "bob"), ("user:3", "carol")])
+        );
+        assert_eq!(
+            state.scan_prefix(ADDRESS, "user:", 2),
+            pairs(&[("user:1", "alice"), ("user:2", "bob")])
+        );
+        assert_eq!(state.scan_prefix(ADDRESS, "user:", 3).len(), 3);
+        assert!(state.scan_prefix(ADDRESS, "user:", 0).is_empty());
+    }
+
+    #[test]
+    fn scan_prefix_with_an_empty_prefix_returns_every_key_up_to_the_limit() {
+        let state = state_with(&[("b", "2"), ("a", "1"), ("c", "3")]);
+
+        assert_eq!(
+            state.scan_prefix(ADDRESS, "", 10),
+            pairs(&[("a", "1"), ("b", "2"), ("c", "3")])
+        );
+        assert_eq!(
+            state.scan_prefix(ADDRESS, "", 2),
+            pairs(&[("a", "1"), ("b", "2")])
+        );
+    }
+
+    #[test]
+    fn scan_prefix_without_a_match_is_empty() {
+        let state = state_with(&[("a", "1"), ("c", "3")]);
+
+        assert!(state.scan_prefix(ADDRESS, "b", 10).is_empty());
+        assert!(state.scan_prefix(ADDRESS, "d", 10).is_empty());
+        assert!(state
+            .scan_prefix("00000000000000000000000000000000000000bb", "", 10)
+            .is_empty());
+    }
 }
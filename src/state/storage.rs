@@ -1,32 +1,213 @@
 use async_trait::async_trait;
+use serde::Deserialize;
 use sled::{transaction::TransactionError, Db};
-use std::{collections::HashMap, path::Path};
+use std::{
+    collections::{BTreeMap, HashMap},
+    path::Path,
+};
 
-use crate::{AccountId, AccountState, Block, StateRoot, TransactionReceipt};
+use crate::{
+    compute_transaction_hash, replay_key, txn_inclusion_proof, AccountDiff, AccountId,
+    AccountState, Block, InclusionProof, KvError, ReceiptLog, StateCheckpoint, StateRoot,
+    Transaction, TransactionReceipt,
+};
+
+const CHECKPOINT_KEY: &[u8] = b"checkpoint";
+const PRUNED_BELOW_KEY: &[u8] = b"pruned_below";
+
+/// Key a receipt is stored under: the unsigned hash of its transaction, so
+/// every receipt for a hash shares a prefix, followed by its replay key, which
+/// tells apart senders that signed the same payload. Receipts written before
+/// were stored under the bare unsigned hash, and are still read.
+pub fn receipt_key(txn: &Transaction) -> Vec<u8> {
+    let mut key = compute_transaction_hash(&txn.unsigned).to_vec();
+    key.extend_from_slice(&replay_key(txn));
+    key
+}
+
+/// Whether a history entry of `address` found among `receipts` for its unsigned
+/// hash is `receipt`. Entries written before receipts were keyed by sender
+/// hold the bare unsigned hash, so the sender is recovered from the signature.
+pub(crate) fn is_history_receipt(receipt: &TransactionReceipt, address: &str) -> bool {
+    receipt.sender().is_ok_and(|sender| sender.0 == address)
+}
+
+/// A receipt as stored before the signer's public key was recorded.
+#[derive(Deserialize)]
+struct LegacyTransactionReceipt {
+    transaction: Transaction,
+    transaction_hash: [u8; 32],
+    status: bool,
+    gas_used: u64,
+    state_updates: Vec<(AccountId, AccountState)>,
+    logs: Vec<ReceiptLog>,
+}
+
+/// Decodes a stored receipt. bincode cannot default a missing field, so a
+/// receipt written before `signer_public_key` existed is decoded in its old
+/// layout and read back without it.
+pub(crate) fn decode_receipt(data: &[u8]) -> Result<TransactionReceipt, KvError> {
+    bincode::deserialize(data).or_else(|e| {
+        let legacy: LegacyTransactionReceipt = bincode::deserialize(data).map_err(|_| {
+            KvError::Serialization(format!("Failed to deserialize transaction receipt: {}", e))
+        })?;
+        Ok(TransactionReceipt {
+            transaction: legacy.transaction,
+            transaction_hash: legacy.transaction_hash,
+            status: legacy.status,
+            gas_used: legacy.gas_used,
+            state_updates: legacy.state_updates,
+            logs: legacy.logs,
+            signer_public_key: None,
+        })
+    })
+}
 
 #[async_trait]
 pub trait Storage: Send + Sync + 'static {
-    async fn save_block(&self, block: &Block) -> Result<(), String>;
-    async fn get_block(&self, number: u64) -> Result<Option<Block>, String>;
+    async fn save_block(&self, block: &Block) -> Result<(), KvError>;
+    async fn get_block(&self, number: u64) -> Result<Option<Block>, KvError>;
+    async fn get_block_by_number(&self, number: u64) -> Result<Option<Block>, KvError>;
+    async fn get_block_by_hash(&self, hash: [u8; 32]) -> Result<Option<Block>, KvError>;
     async fn save_transaction_receipts(
         &self,
         receipts: Vec<TransactionReceipt>,
-    ) -> Result<(), String>;
-    async fn get_transaction_receipt(
+    ) -> Result<(), KvError>;
+    /// Receipts of the committed transactions whose unsigned hash is
+    /// `transaction_hash`, one per sender that signed the same payload.
+    async fn get_transaction_receipts(
         &self,
         transaction_hash: [u8; 32],
-    ) -> Result<Option<TransactionReceipt>, String>;
-    async fn save_state_root(&self, block_number: u64, root: StateRoot) -> Result<(), String>;
-    async fn get_state_root(&self, block_number: u64) -> Result<Option<StateRoot>, String>;
+    ) -> Result<Vec<TransactionReceipt>, KvError>;
+    /// Receipt of the committed transaction `txn`, told apart by its signature
+    /// from other senders' transactions with the same unsigned hash.
+    async fn get_transaction_receipt(
+        &self,
+        txn: &Transaction,
+    ) -> Result<Option<TransactionReceipt>, KvError> {
+        let receipts = self
+            .get_transaction_receipts(compute_transaction_hash(&txn.unsigned))
+            .await?;
+        Ok(receipts
+            .into_iter()
+            .find(|receipt| receipt.transaction.signature == txn.signature))
+    }
+    async fn save_state_root(&self, block_number: u64, root: StateRoot) -> Result<(), KvError>;
+    async fn get_state_root(&self, block_number: u64) -> Result<Option<StateRoot>, KvError>;
     async fn save_account_state(
         &self,
         account_id: &AccountId,
         state: &AccountState,
-    ) -> Result<(), String>;
+    ) -> Result<(), KvError>;
     async fn get_account_state(
         &self,
         account_id: &AccountId,
-    ) -> Result<Option<AccountState>, String>;
+    ) -> Result<Option<AccountState>, KvError>;
+    async fn save_checkpoint(&self, checkpoint: &StateCheckpoint) -> Result<(), KvError>;
+    /// Writes a committed block, its receipts, its state root and an optional
+    /// checkpoint in a single atomic batch.
+    async fn commit_block_atomic(
+        &self,
+        block: &Block,
+        receipts: &[TransactionReceipt],
+        state_root: &StateRoot,
+        checkpoint: Option<&StateCheckpoint>,
+    ) -> Result<(), KvError>;
+    async fn get_checkpoint(&self) -> Result<Option<StateCheckpoint>, KvError>;
+    /// Receipts of the transactions sent by `address`, newest first, skipping
+    /// the `offset` newest and returning at most `limit`. Transactions that were
+    /// included in a block but produced no receipt are left out.
+    async fn get_account_transactions(
+        &self,
+        address: &str,
+        limit: usize,
+        offset: usize,
+    ) -> Result<Vec<TransactionReceipt>, KvError>;
+    /// Deletes blocks, their receipts, history entries and state roots below `block_number`.
+    ///
+    /// Nothing at or after the latest checkpoint is removed, since the checkpoint's
+    /// root verifies it on recovery and the blocks after it are replayed on top of
+    /// it. Returns the number of deleted blocks.
+    async fn prune_below(&self, block_number: u64) -> Result<u64, KvError>;
+    /// Waits until every write made so far is durable on disk.
+    async fn flush(&self) -> Result<(), KvError>;
+    /// Makes writes by another process sharing the database visible, for
+    /// storages opened as read-only followers. Does nothing by default.
+    async fn catch_up(&self) -> Result<(), KvError> {
+        Ok(())
+    }
+    /// Proof that the transaction with `txn_hash` is in block `block_number`,
+    /// or `None` if the block is not stored or does not contain it.
+    async fn prove_txn_inclusion(
+        &self,
+        block_number: u64,
+        txn_hash: [u8; 32],
+    ) -> Result<Option<InclusionProof>, KvError> {
+        let block = match self.get_block(block_number).await? {
+            Some(block) => block,
+            None => return Ok(None),
+        };
+        let txn_hashes = block
+            .transactions
+            .iter()
+            .map(|txn| compute_transaction_hash(&txn.txn.unsigned))
+            .collect::<Vec<_>>();
+        Ok(txn_hashes
+            .iter()
+            .position(|hash| *hash == txn_hash)
+            .map(|index| txn_inclusion_proof(block_number, &txn_hashes, index)))
+    }
+    /// Accounts changed by the blocks `from..=to`, sorted by address, built from
+    /// the state updates in their receipts.
+    ///
+    /// Each account's earlier state is found by walking back from `from` to its
+    /// latest change before the range, so this reads every block back to the
+    /// oldest such change. Fails if a block in the range is not stored.
+    async fn state_diff(&self, from: u64, to: u64) -> Result<Vec<AccountDiff>, KvError> {
+        let mut after = BTreeMap::new();
+        for number in from..=to {
+            let block = self
+                .get_block(number)
+                .await?
+                .ok_or_else(|| KvError::NotFound(format!("Block {}", number)))?;
+            for txn in &block.transactions {
+                if let Some(receipt) = self.get_transaction_receipt(&txn.txn).await? {
+                    for (account_id, state) in receipt.state_updates {
+                        after.insert(account_id.0, state);
+                    }
+                }
+            }
+        }
+
+        let mut before = HashMap::new();
+        for number in (1..from).rev() {
+            if before.len() == after.len() {
+                break;
+            }
+            let block = match self.get_block(number).await? {
+                Some(block) => block,
+                None => break,
+            };
+            for txn in block.transactions.iter().rev() {
+                if let Some(receipt) = self.get_transaction_receipt(&txn.txn).await? {
+                    for (account_id, state) in receipt.state_updates.into_iter().rev() {
+                        if after.contains_key(&account_id.0) {
+                            before.entry(account_id.0).or_insert(state);
+                        }
+                    }
+                }
+            }
+        }
+
+        Ok(after
+            .into_iter()
+            .map(|(address, state)| {
+                let previous = before.remove(&address);
+                AccountDiff::new(address, previous, state)
+            })
+            .filter(|diff| diff.before.as_ref() != Some(&diff.after))
+            .collect())
+    }
 }
 
 #[derive(Clone)]
@@ -35,8 +216,9 @@ pub struct SledStorage {
 }
 
 impl SledStorage {
-    pub fn new<P: AsRef<Path>>(path: P) -> Result<Self, String> {
-        let db = sled::open(path).map_err(|e| format!("Failed to open database: {}", e))?;
+    pub fn new<P: AsRef<Path>>(path: P) -> Result<Self, KvError> {
+        let db = sled::open(path)
+            .map_err(|e| KvError::Storage(format!("Failed to open database: {}", e)))?;
         Ok(Self { db })
     }
 
@@ -44,6 +226,10 @@ impl SledStorage {
         format!("block:{}", number).into_bytes()
     }
 
+    fn block_hash_key(hash: &[u8; 32]) -> Vec<u8> {
+        format!("block_hash:{}", hex::encode(hash)).into_bytes()
+    }
+
     fn state_root_key(number: u64) -> Vec<u8> {
         format!("state_root:{}", number).into_bytes()
     }
@@ -51,47 +237,116 @@ impl SledStorage {
     fn account_key(account_id: &AccountId) -> Vec<u8> {
         format!("account:{}", account_id.0).into_bytes()
     }
+
+    fn account_txns_prefix(address: &str) -> Vec<u8> {
+        format!("account_txn:{}:", address).into_bytes()
+    }
+
+    /// History entries sort by block number, then by position in the block.
+    fn account_txn_key(address: &str, block_number: u64, index: u32) -> Vec<u8> {
+        let mut key = Self::account_txns_prefix(address);
+        key.extend_from_slice(&block_number.to_be_bytes());
+        key.extend_from_slice(&index.to_be_bytes());
+        key
+    }
+
+    fn get_receipt_by_key(&self, key: &[u8]) -> Result<Option<TransactionReceipt>, KvError> {
+        match self.db.get(key) {
+            Ok(Some(data)) => Ok(Some(decode_receipt(&data)?)),
+            Ok(None) => Ok(None),
+            Err(e) => Err(KvError::Storage(format!(
+                "Failed to get transaction receipt: {}",
+                e
+            ))),
+        }
+    }
+
+    fn pruned_below(&self) -> Result<u64, KvError> {
+        match self.db.get(PRUNED_BELOW_KEY) {
+            Ok(Some(data)) => {
+                let number: [u8; 8] = data
+                    .as_ref()
+                    .try_into()
+                    .map_err(|_| KvError::Storage("Corrupted prune marker".to_string()))?;
+                Ok(u64::from_be_bytes(number))
+            }
+            Ok(None) => Ok(0),
+            Err(e) => Err(KvError::Storage(format!(
+                "Failed to get prune marker: {}",
+                e
+            ))),
+        }
+    }
 }
 
 #[async_trait]
 impl Storage for SledStorage {
-    async fn save_block(&self, block: &Block) -> Result<(), String> {
-        let encoded =
-            bincode::serialize(block).map_err(|e| format!("Failed to serialize block: {}", e))?;
+    async fn save_block(&self, block: &Block) -> Result<(), KvError> {
+        let encoded = bincode::serialize(block)
+            .map_err(|e| KvError::Serialization(format!("Failed to serialize block: {}", e)))?;
 
         self.db
             .insert(Self::block_key(block.header.number), encoded)
-            .map_err(|e| format!("Failed to save block: {}", e))?;
+            .map_err(|e| KvError::Storage(format!("Failed to save block: {}", e)))?;
+
+        self.db
+            .insert(
+                Self::block_hash_key(&block.header.hash()),
+                &block.header.number.to_be_bytes(),
+            )
+            .map_err(|e| KvError::Storage(format!("Failed to save block hash index: {}", e)))?;
 
         self.db
             .flush()
-            .map_err(|e| format!("Failed to flush database: {}", e))?;
+            .map_err(|e| KvError::Storage(format!("Failed to flush database: {}", e)))?;
 
         Ok(())
     }
 
-    async fn get_block(&self, number: u64) -> Result<Option<Block>, String> {
+    async fn get_block(&self, number: u64) -> Result<Option<Block>, KvError> {
         match self.db.get(Self::block_key(number)) {
             Ok(Some(data)) => {
-                let block = bincode::deserialize(&data)
-                    .map_err(|e| format!("Failed to deserialize block: {}", e))?;
+                let block = bincode::deserialize(&data).map_err(|e| {
+                    KvError::Serialization(format!("Failed to deserialize block: {}", e))
+                })?;
                 Ok(Some(block))
             }
             Ok(None) => Ok(None),
-            Err(e) => Err(format!("Failed to get block: {}", e)),
+            Err(e) => Err(KvError::Storage(format!("Failed to get block: {}", e))),
+        }
+    }
+
+    async fn get_block_by_number(&self, number: u64) -> Result<Option<Block>, KvError> {
+        self.get_block(number).await
+    }
+
+    async fn get_block_by_hash(&self, hash: [u8; 32]) -> Result<Option<Block>, KvError> {
+        match self.db.get(Self::block_hash_key(&hash)) {
+            Ok(Some(data)) => {
+                let number: [u8; 8] = data
+                    .as_ref()
+                    .try_into()
+                    .map_err(|_| KvError::Storage("Corrupted block hash index".to_string()))?;
+                self.get_block(u64::from_be_bytes(number)).await
+            }
+            Ok(None) => Ok(None),
+            Err(e) => Err(KvError::Storage(format!(
+                "Failed to get block hash index: {}",
+                e
+            ))),
         }
     }
 
     async fn save_transaction_receipts(
         &self,
         receipts: Vec<TransactionReceipt>,
-    ) -> Result<(), String> {
+    ) -> Result<(), KvError> {
         let encodes = receipts
             .iter()
             .map(|receipt| {
                 let encoded =
                     bincode::serialize(receipt).expect("Failed to serialize transaction receipt");
-                (receipt.transaction_hash.clone(), encoded)
+                (receipt_key(&receipt.transaction), encoded)
             })
             .collect::<HashMap<_, _>>();
 
@@ -102,50 +357,55 @@ impl Storage for SledStorage {
                 }
                 Ok(())
             })
-            .map_err(|e: TransactionError| format!("Failed to save transaction receipts"))?;
+            .map_err(|e: TransactionError| {
+                KvError::Storage(format!("Failed to save transaction receipts: {}", e))
+            })?;
 
         Ok(())
     }
 
-    async fn get_transaction_receipt(
+    async fn get_transaction_receipts(
         &self,
         transaction_hash: [u8; 32],
-    ) -> Result<Option<TransactionReceipt>, String> {
-        match self.db.get(transaction_hash) {
-            Ok(Some(data)) => {
-                let receipt = bincode::deserialize(&data)
-                    .map_err(|e| format!("Failed to deserialize block: {}", e))?;
-                Ok(receipt)
-            }
-            Ok(None) => Ok(None),
-            Err(e) => Err(format!("Failed to get block: {}", e)),
+    ) -> Result<Vec<TransactionReceipt>, KvError> {
+        // The bare hash of a receipt written before they were keyed by sender
+        // is matched by the prefix too.
+        let mut receipts = vec![];
+        for entry in self.db.scan_prefix(transaction_hash) {
+            let (_, data) = entry.map_err(|e| {
+                KvError::Storage(format!("Failed to get transaction receipts: {}", e))
+            })?;
+            receipts.push(decode_receipt(&data)?);
         }
+        Ok(receipts)
     }
 
-    async fn save_state_root(&self, block_number: u64, root: StateRoot) -> Result<(), String> {
-        let encoded = bincode::serialize(&root)
-            .map_err(|e| format!("Failed to serialize state root: {}", e))?;
+    async fn save_state_root(&self, block_number: u64, root: StateRoot) -> Result<(), KvError> {
+        let encoded = bincode::serialize(&root).map_err(|e| {
+            KvError::Serialization(format!("Failed to serialize state root: {}", e))
+        })?;
 
         self.db
             .insert(Self::state_root_key(block_number), encoded)
-            .map_err(|e| format!("Failed to save state root: {}", e))?;
+            .map_err(|e| KvError::Storage(format!("Failed to save state root: {}", e)))?;
 
         self.db
             .flush()
-            .map_err(|e| format!("Failed to flush database: {}", e))?;
+            .map_err(|e| KvError::Storage(format!("Failed to flush database: {}", e)))?;
 
         Ok(())
     }
 
-    async fn get_state_root(&self, block_number: u64) -> Result<Option<StateRoot>, String> {
+    async fn get_state_root(&self, block_number: u64) -> Result<Option<StateRoot>, KvError> {
         match self.db.get(Self::state_root_key(block_number)) {
             Ok(Some(data)) => {
-                let root = bincode::deserialize(&data)
-                    .map_err(|e| format!("Failed to deserialize state root: {}", e))?;
+                let root = bincode::deserialize(&data).map_err(|e| {
+                    KvError::Serialization(format!("Failed to deserialize state root: {}", e))
+                })?;
                 Ok(Some(root))
             }
             Ok(None) => Ok(None),
-            Err(e) => Err(format!("Failed to get state root: {}", e)),
+            Err(e) => Err(KvError::Storage(format!("Failed to get state root: {}", e))),
         }
     }
 
@@ -153,17 +413,18 @@ impl Storage for SledStorage {
         &self,
         account_id: &AccountId,
         state: &AccountState,
-    ) -> Result<(), String> {
-        let encoded = bincode::serialize(state)
-            .map_err(|e| format!("Failed to serialize account state: {}", e))?;
+    ) -> Result<(), KvError> {
+        let encoded = bincode::serialize(state).map_err(|e| {
+            KvError::Serialization(format!("Failed to serialize account state: {}", e))
+        })?;
 
         self.db
             .insert(Self::account_key(account_id), encoded)
-            .map_err(|e| format!("Failed to save account state: {}", e))?;
+            .map_err(|e| KvError::Storage(format!("Failed to save account state: {}", e)))?;
 
         self.db
             .flush()
-            .map_err(|e| format!("Failed to flush database: {}", e))?;
+            .map_err(|e| KvError::Storage(format!("Failed to flush database: {}", e)))?;
 
         Ok(())
     }
@@ -171,15 +432,670 @@ impl Storage for SledStorage {
     async fn get_account_state(
         &self,
         account_id: &AccountId,
-    ) -> Result<Option<AccountState>, String> {
+    ) -> Result<Option<AccountState>, KvError> {
         match self.db.get(Self::account_key(account_id)) {
             Ok(Some(data)) => {
-                let state = bincode::deserialize(&data)
-                    .map_err(|e| format!("Failed to deserialize account state: {}", e))?;
+                let state = bincode::deserialize(&data).map_err(|e| {
+                    KvError::Serialization(format!("Failed to deserialize account state: {}", e))
+                })?;
                 Ok(Some(state))
             }
             Ok(None) => Ok(None),
-            Err(e) => Err(format!("Failed to get account state: {}", e)),
+            Err(e) => Err(KvError::Storage(format!(
+                "Failed to get account state: {}",
+                e
+            ))),
+        }
+    }
+
+    async fn save_checkpoint(&self, checkpoint: &StateCheckpoint) -> Result<(), KvError> {
+        let encoded = bincode::serialize(checkpoint).map_err(|e| {
+            KvError::Serialization(format!("Failed to serialize checkpoint: {}", e))
+        })?;
+
+        self.db
+            .insert(CHECKPOINT_KEY, encoded)
+            .map_err(|e| KvError::Storage(format!("Failed to save checkpoint: {}", e)))?;
+
+        self.db
+            .flush()
+            .map_err(|e| KvError::Storage(format!("Failed to flush database: {}", e)))?;
+
+        Ok(())
+    }
+
+    async fn commit_block_atomic(
+        &self,
+        block: &Block,
+        receipts: &[TransactionReceipt],
+        state_root: &StateRoot,
+        checkpoint: Option<&StateCheckpoint>,
+    ) -> Result<(), KvError> {
+        let mut batch = sled::Batch::default();
+
+        let encoded = bincode::serialize(block)
+            .map_err(|e| KvError::Serialization(format!("Failed to serialize block: {}", e)))?;
+        batch.insert(Self::block_key(block.header.number), encoded);
+        batch.insert(
+            Self::block_hash_key(&block.header.hash()),
+            &block.header.number.to_be_bytes(),
+        );
+
+        for receipt in receipts {
+            let encoded = bincode::serialize(receipt).map_err(|e| {
+                KvError::Serialization(format!("Failed to serialize transaction receipt: {}", e))
+            })?;
+            batch.insert(receipt_key(&receipt.transaction), encoded);
+        }
+
+        for (index, txn) in block.transactions.iter().enumerate() {
+            batch.insert(
+                Self::account_txn_key(&txn.address, block.header.number, index as u32),
+                receipt_key(&txn.txn),
+            );
+        }
+
+        let encoded = bincode::serialize(state_root).map_err(|e| {
+            KvError::Serialization(format!("Failed to serialize state root: {}", e))
+        })?;
+        batch.insert(Self::state_root_key(block.header.number), encoded);
+
+        if let Some(checkpoint) = checkpoint {
+            let encoded = bincode::serialize(checkpoint).map_err(|e| {
+                KvError::Serialization(format!("Failed to serialize checkpoint: {}", e))
+            })?;
+            batch.insert(CHECKPOINT_KEY, encoded);
+        }
+
+        self.db
+            .apply_batch(batch)
+            .map_err(|e| KvError::Storage(format!("Failed to commit block: {}", e)))?;
+
+        // The batch is applied atomically; the commit task decides how often
+        // it is made durable with `flush`.
+        Ok(())
+    }
+
+    async fn get_checkpoint(&self) -> Result<Option<StateCheckpoint>, KvError> {
+        match self.db.get(CHECKPOINT_KEY) {
+            Ok(Some(data)) => {
+                let checkpoint = bincode::deserialize(&data).map_err(|e| {
+                    KvError::Serialization(format!("Failed to deserialize checkpoint: {}", e))
+                })?;
+                Ok(Some(checkpoint))
+            }
+            Ok(None) => Ok(None),
+            Err(e) => Err(KvError::Storage(format!("Failed to get checkpoint: {}", e))),
+        }
+    }
+
+    async fn get_account_transactions(
+        &self,
+        address: &str,
+        limit: usize,
+        offset: usize,
+    ) -> Result<Vec<TransactionReceipt>, KvError> {
+        let mut receipts = vec![];
+        for entry in self
+            .db
+            .scan_prefix(Self::account_txns_prefix(address))
+            .rev()
+            .skip(offset)
+        {
+            if receipts.len() >= limit {
+                break;
+            }
+            let (_, key) = entry
+                .map_err(|e| KvError::Storage(format!("Failed to get account history: {}", e)))?;
+            let receipt = match <[u8; 32]>::try_from(key.as_ref()) {
+                Ok(hash) => self
+                    .get_transaction_receipts(hash)
+                    .await?
+                    .into_iter()
+                    .find(|receipt| is_history_receipt(receipt, address)),
+                Err(_) => self.get_receipt_by_key(&key)?,
+            };
+            receipts.extend(receipt);
+        }
+        Ok(receipts)
+    }
+
+    async fn prune_below(&self, block_number: u64) -> Result<u64, KvError> {
+        let checkpoint = match self.get_checkpoint().await? {
+            Some(checkpoint) => checkpoint,
+            None => return Ok(0),
+        };
+        let start = self.pruned_below()?;
+        let end = block_number.min(checkpoint.block_number);
+        if end <= start {
+            return Ok(0);
+        }
+
+        let mut batch = sled::Batch::default();
+        let mut pruned = 0;
+        for number in start..end {
+            if let Some(block) = self.get_block(number).await? {
+                for (index, txn) in block.transactions.iter().enumerate() {
+                    batch.remove(receipt_key(&txn.txn));
+                    // A receipt stored under the bare hash may be another sender's.
+                    let hash = compute_transaction_hash(&txn.txn.unsigned);
+                    let legacy = self.get_receipt_by_key(&hash)?;
+                    if legacy.is_some_and(|r| r.transaction.signature == txn.txn.signature) {
+                        batch.remove(&hash);
+                    }
+                    batch.remove(Self::account_txn_key(&txn.address, number, index as u32));
+                }
+                batch.remove(Self::block_hash_key(&block.header.hash()));
+                batch.remove(Self::block_key(number));
+                pruned += 1;
+            }
+            batch.remove(Self::state_root_key(number));
+        }
+        batch.insert(PRUNED_BELOW_KEY, &end.to_be_bytes());
+
+        self.db
+            .apply_batch(batch)
+            .map_err(|e| KvError::Storage(format!("Failed to prune blocks: {}", e)))?;
+
+        self.db
+            .flush()
+            .map_err(|e| KvError::Storage(format!("Failed to flush database: {}", e)))?;
+
+        Ok(pruned)
+    }
+
+    async fn flush(&self) -> Result<(), KvError> {
+        self.db
+            .flush_async()
+            .await
+            .map_err(|e| KvError::Storage(format!("Failed to flush database: {}", e)))?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        test_utils::*, verify_inclusion_proof, AccountKey, KeyDiff, MemStorage, RecentTxns,
+        RocksStorage, State, CHECKPOINT_INTERVAL,
+    };
+    use std::{future::Future, sync::Arc};
+
+    /// Runs `check` against every backend, each on a fresh database.
+    async fn for_each_backend<F, Fut>(check: F)
+    where
+        F: Fn(Arc<dyn Storage>) -> Fut,
+        Fut: Future<Output = ()>,
+    {
+        check(Arc::new(MemStorage::new())).await;
+        let path = temp_dir();
+        check(Arc::new(SledStorage::new(&path).unwrap())).await;
+        std::fs::remove_dir_all(path).unwrap();
+        let path = temp_dir();
+        check(Arc::new(RocksStorage::new(&path).unwrap())).await;
+        std::fs::remove_dir_all(path).unwrap();
+    }
+
+    /// Commits two blocks to `storage` and checks every record reads back as written.
+    async fn check_round_trip(storage: &dyn Storage) {
+        let config = config();
+        let alice = new_key();
+        let mut state = State::new(None).unwrap();
+        let mut recent_txns = RecentTxns::new(0);
+        let first = vec![
+            sign(&alice, 0, set_kv("a", "1")),
+            sign(&alice, 1, set_kv("b", "2")),
+        ];
+        commit_block(
+            storage,
+            &mut state,
+            &config,
+            &mut recent_txns,
+            first.clone(),
+        )
+        .await;
+        let second = vec![sign(&alice, 2, set_kv("c", "3"))];
+        commit_block(storage, &mut state, &config, &mut recent_txns, second).await;
+
+        let block = storage.get_block(1).await.unwrap().unwrap();
+        assert_eq!(block.transactions.len(), 2);
+        let by_hash = storage
+            .get_block_by_hash(block.header.hash())
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(by_hash.header.number, 1);
+        assert!(storage.get_block_by_number(3).await.unwrap().is_none());
+        assert_eq!(
+            storage.get_state_root(2).await.unwrap().unwrap().0,
+            state.get_state_root().0
+        );
+
+        let receipt = storage
+            .get_transaction_receipt(&first[1].txn)
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(receipt.status);
+        assert_eq!(receipt.transaction.signature, first[1].txn.signature);
+        let public_key = match &alice {
+            AccountKey::Secp256k1(keypair) => hex::encode(keypair.public_key.serialize()),
+            AccountKey::Ed25519(_) => unreachable!(),
+        };
+        assert_eq!(receipt.signer_public_key, Some(public_key));
+
+        let nonces = |receipts: Vec<TransactionReceipt>| {
+            receipts
+                .iter()
+                .map(|receipt| receipt.transaction.unsigned.nonce)
+                .collect::<Vec<_>>()
+        };
+        let history = storage
+            .get_account_transactions(&alice.address(), 2, 0)
+            .await
+            .unwrap();
+        assert_eq!(nonces(history), [2, 1]);
+        let history = storage
+            .get_account_transactions(&alice.address(), 10, 1)
+            .await
+            .unwrap();
+        assert_eq!(nonces(history), [1, 0]);
+
+        storage.save_checkpoint(&state.checkpoint()).await.unwrap();
+        assert_eq!(
+            storage
+                .get_checkpoint()
+                .await
+                .unwrap()
+                .unwrap()
+                .block_number,
+            2
+        );
+        let account_id = AccountId(alice.address());
+        let account = state.get_account(&alice.address()).unwrap();
+        storage
+            .save_account_state(&account_id, &account)
+            .await
+            .unwrap();
+        assert_eq!(
+            storage.get_account_state(&account_id).await.unwrap(),
+            Some(account)
+        );
+        storage.flush().await.unwrap();
+    }
+
+    /// Commits three blocks, the middle one empty, and looks each up both by
+    /// number and by header hash.
+    async fn check_block_lookup(storage: &dyn Storage) {
+        let config = config();
+        let alice = new_key();
+        let mut state = State::new(None).unwrap();
+        let mut recent_txns = RecentTxns::new(0);
+        for txns in [
+            vec![sign(&alice, 0, set_kv("a", "1"))],
+            vec![],
+            vec![
+                sign(&alice, 1, set_kv("b", "2")),
+                sign(&alice, 2, set_kv("c", "3")),
+            ],
+        ] {
+            commit_block(storage, &mut state, &config, &mut recent_txns, txns).await;
+        }
+
+        let mut hashes = vec![];
+        for (number, txn_count) in [(1, 1), (2, 0), (3, 2)] {
+            let block = storage.get_block_by_number(number).await.unwrap().unwrap();
+            assert_eq!(block.header.number, number);
+            assert_eq!(block.transactions.len(), txn_count);
+            let hash = block.header.hash();
+            let by_hash = storage.get_block_by_hash(hash).await.unwrap().unwrap();
+            assert_eq!(by_hash.header.number, number);
+            assert_eq!(by_hash.header.hash(), hash);
+            assert_eq!(by_hash.header.txn_root, block.header.txn_root);
+            hashes.push(hash);
+        }
+        hashes.dedup();
+        assert_eq!(hashes.len(), 3);
+        assert!(storage.get_block_by_number(4).await.unwrap().is_none());
+        assert!(storage.get_block_by_hash([0; 32]).await.unwrap().is_none());
+    }
+
+    /// Prunes a chain checkpointed at `CHECKPOINT_INTERVAL` whose last block
+    /// holds a transaction with the same unsigned hash as one in block 1.
+    async fn check_prune(storage: &dyn Storage) {
+        let config = config();
+        let (alice, bob) = (new_key(), new_key());
+        let mut state = State::new(None).unwrap();
+        let mut recent_txns = RecentTxns::new(0);
+        let from_alice = vec![sign(&alice, 0, set_kv("key", "value"))];
+        commit_block(storage, &mut state, &config, &mut recent_txns, from_alice).await;
+        for _ in 2..=CHECKPOINT_INTERVAL {
+            commit_block(storage, &mut state, &config, &mut recent_txns, vec![]).await;
+        }
+        let from_bob = vec![sign(&bob, 0, set_kv("key", "value"))];
+        commit_block(storage, &mut state, &config, &mut recent_txns, from_bob).await;
+
+        let checkpoint = CHECKPOINT_INTERVAL;
+        assert_eq!(
+            storage.prune_below(checkpoint + 1).await.unwrap(),
+            checkpoint - 1
+        );
+        assert!(storage.get_block(1).await.unwrap().is_none());
+        assert!(storage
+            .get_state_root(checkpoint - 1)
+            .await
+            .unwrap()
+            .is_none());
+        assert!(storage.get_block(checkpoint).await.unwrap().is_some());
+        assert!(storage.get_state_root(checkpoint).await.unwrap().is_some());
+        let history = |address: String| async move {
+            storage
+                .get_account_transactions(&address, 10, 0)
+                .await
+                .unwrap()
+        };
+        assert!(history(alice.address()).await.is_empty());
+        assert_eq!(history(bob.address()).await.len(), 1);
+        assert_eq!(storage.prune_below(checkpoint + 1).await.unwrap(), 0);
+    }
+
+    /// Commits a block in which two senders signed the same payload, so both
+    /// transactions have the same unsigned hash, and checks neither receipt
+    /// hides the other.
+    async fn check_same_payload(storage: &dyn Storage) {
+        let config = config();
+        let (alice, bob) = (new_key(), new_key());
+        let mut state = State::new(None).unwrap();
+        let mut recent_txns = RecentTxns::new(0);
+        let from_alice = sign(&alice, 0, set_kv("key", "value"));
+        let from_bob = sign(&bob, 0, set_kv("key", "value"));
+        let hash = compute_transaction_hash(&from_alice.txn.unsigned);
+        assert_eq!(hash, compute_transaction_hash(&from_bob.txn.unsigned));
+        commit_block(
+            storage,
+            &mut state,
+            &config,
+            &mut recent_txns,
+            vec![from_alice.clone(), from_bob.clone()],
+        )
+        .await;
+
+        let mut signatures = storage
+            .get_transaction_receipts(hash)
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|receipt| receipt.transaction.signature)
+            .collect::<Vec<_>>();
+        signatures.sort();
+        let mut expected = vec![
+            from_alice.txn.signature.clone(),
+            from_bob.txn.signature.clone(),
+        ];
+        expected.sort();
+        assert_eq!(signatures, expected);
+
+        for (key, txn) in [(&alice, &from_alice), (&bob, &from_bob)] {
+            let receipt = storage
+                .get_transaction_receipt(&txn.txn)
+                .await
+                .unwrap()
+                .unwrap();
+            assert_eq!(receipt.transaction.signature, txn.txn.signature);
+            assert_eq!(receipt.sender().unwrap().0, key.address());
+            let history = storage
+                .get_account_transactions(&key.address(), 10, 0)
+                .await
+                .unwrap();
+            assert_eq!(history.len(), 1);
+            assert_eq!(history[0].transaction.signature, txn.txn.signature);
+        }
+
+        let changed = storage
+            .state_diff(1, 1)
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|diff| diff.address)
+            .collect::<Vec<_>>();
+        let mut addresses = vec![alice.address(), bob.address()];
+        addresses.sort();
+        assert_eq!(changed, addresses);
+    }
+
+    /// Commits three blocks and checks the diffs over ranges of them.
+    async fn check_state_diff(storage: &dyn Storage) {
+        let config = config();
+        let (alice, bob, carol) = (new_key(), new_key(), new_key());
+        let mut state = State::new(None).unwrap();
+        let mut recent_txns = RecentTxns::new(0);
+        let blocks = vec![
+            vec![
+                sign(&alice, 0, set_kv("a", "1")),
+                sign(&alice, 1, set_kv("b", "2")),
+            ],
+            vec![sign(&bob, 0, set_kv("x", "1"))],
+            vec![
+                sign(&alice, 2, set_kv("a", "10")),
+                sign(&alice, 3, set_kv("c", "3")),
+                sign(&carol, 0, set_kv("y", "1")),
+            ],
+        ];
+        let mut after_block = vec![];
+        for txns in blocks {
+            commit_block(storage, &mut state, &config, &mut recent_txns, txns).await;
+            after_block.push(state.get_account(&alice.address()).unwrap());
+        }
+
+        let diffs = storage.state_diff(3, 3).await.unwrap();
+        let mut addresses = vec![alice.address(), carol.address()];
+        addresses.sort();
+        assert_eq!(
+            diffs
+                .iter()
+                .map(|diff| diff.address.clone())
+                .collect::<Vec<_>>(),
+            addresses
+        );
+        let alice_diff = diffs
+            .iter()
+            .find(|diff| diff.address == alice.address())
+            .unwrap();
+        // The earlier state comes from block 1, past the block that did not touch alice.
+        assert_eq!(alice_diff.before.as_ref(), Some(&after_block[0]));
+        assert_eq!(alice_diff.after, after_block[2]);
+        let key_diff = |key: &str, before: Option<&str>, after: Option<&str>| KeyDiff {
+            key: key.to_string(),
+            before: before.map(str::to_string),
+            after: after.map(str::to_string),
+        };
+        assert_eq!(
+            alice_diff.changed_keys,
+            [
+                key_diff("a", Some("1"), Some("10")),
+                key_diff("c", None, Some("3"))
+            ]
+        );
+        let carol_diff = diffs
+            .iter()
+            .find(|diff| diff.address == carol.address())
+            .unwrap();
+        assert!(carol_diff.before.is_none());
+        assert_eq!(carol_diff.changed_keys, [key_diff("y", None, Some("1"))]);
+
+        // Over the whole chain nothing existed before, so every key is new.
+        let diffs = storage.state_diff(1, 3).await.unwrap();
+        assert_eq!(diffs.len(), 3);
+        assert!(diffs.iter().all(|diff| diff.before.is_none()));
+        let alice_diff = diffs
+            .iter()
+            .find(|diff| diff.address == alice.address())
+            .unwrap();
+        assert_eq!(
+            alice_diff.changed_keys,
+            [
+                key_diff("a", None, Some("10")),
+                key_diff("b", None, Some("2")),
+                key_diff("c", None, Some("3"))
+            ]
+        );
+
+        assert_eq!(
+            storage.state_diff(3, 4).await.unwrap_err(),
+            KvError::NotFound("Block 4".to_string())
+        );
+    }
+
+    /// Saves a root for several blocks and reads each back.
+    async fn check_state_root_history(storage: &dyn Storage) {
+        let roots = (1..=5u8).map(|byte| [byte; 32]).collect::<Vec<_>>();
+        for (number, root) in (1..).zip(&roots) {
+            storage
+                .save_state_root(number, StateRoot(*root))
+                .await
+                .unwrap();
+        }
+        for (number, root) in (1..).zip(&roots) {
+            let stored = storage.get_state_root(number).await.unwrap().unwrap();
+            assert_eq!(stored.0, *root, "block {}", number);
+        }
+        assert!(storage.get_state_root(0).await.unwrap().is_none());
+        assert!(storage.get_state_root(6).await.unwrap().is_none());
+        assert!(storage.get_state_root(u64::MAX).await.unwrap().is_none());
+    }
+
+    /// Flushes `storage` before and after committing a block, which must
+    /// succeed and leave what was committed readable.
+    async fn check_flush(storage: &dyn Storage) {
+        storage.flush().await.unwrap();
+        let mut state = State::new(None).unwrap();
+        let txns = vec![sign(&new_key(), 0, set_kv("key", "value"))];
+        commit_block(
+            storage,
+            &mut state,
+            &config(),
+            &mut RecentTxns::new(0),
+            txns.clone(),
+        )
+        .await;
+        storage.flush().await.unwrap();
+        storage.flush().await.unwrap();
+        let block = storage.get_block(1).await.unwrap().unwrap();
+        assert_eq!(block.header.state_root, state.get_state_root().0);
+        assert!(storage
+            .get_transaction_receipt(&txns[0].txn)
+            .await
+            .unwrap()
+            .is_some());
+    }
+
+    #[tokio::test]
+    async fn inclusion_proofs_verify_against_the_block_txn_root() {
+        let storage = MemStorage::new();
+        let mut state = State::new(None).unwrap();
+        let txns = (0..5)
+            .map(|index| sign(&new_key(), 0, set_kv("key", &index.to_string())))
+            .collect::<Vec<_>>();
+        let mut recent_txns = RecentTxns::new(0);
+        commit_block(
+            &storage,
+            &mut state,
+            &config(),
+            &mut recent_txns,
+            txns.clone(),
+        )
+        .await;
+        let txn_root = storage.get_block(1).await.unwrap().unwrap().header.txn_root;
+
+        for (index, txn) in txns.iter().enumerate() {
+            let hash = compute_transaction_hash(&txn.txn.unsigned);
+            let proof = storage.prove_txn_inclusion(1, hash).await.unwrap().unwrap();
+            assert_eq!((proof.block_number, proof.index), (1, index));
+            assert!(verify_inclusion_proof(&txn_root, &hash, &proof));
+            assert!(!verify_inclusion_proof(&txn_root, &[0; 32], &proof));
         }
+
+        let stranger = sign(&new_key(), 0, set_kv("key", "other"));
+        let hash = compute_transaction_hash(&stranger.txn.unsigned);
+        assert!(storage
+            .prove_txn_inclusion(1, hash)
+            .await
+            .unwrap()
+            .is_none());
+        assert!(storage
+            .prove_txn_inclusion(2, hash)
+            .await
+            .unwrap()
+            .is_none());
+    }
+
+    #[tokio::test]
+    async fn every_backend_reads_back_what_it_committed() {
+        for_each_backend(|storage| async move { check_round_trip(storage.as_ref()).await }).await;
+    }
+
+    #[tokio::test]
+    async fn every_backend_finds_blocks_by_number_and_by_hash() {
+        for_each_backend(|storage| async move { check_block_lookup(storage.as_ref()).await }).await;
+    }
+
+    #[tokio::test]
+    async fn every_backend_prunes_up_to_the_checkpoint() {
+        for_each_backend(|storage| async move { check_prune(storage.as_ref()).await }).await;
+    }
+
+    #[tokio::test]
+    async fn every_backend_keeps_receipts_of_senders_with_the_same_payload_apart() {
+        for_each_backend(|storage| async move { check_same_payload(storage.as_ref()).await }).await;
+    }
+
+    #[tokio::test]
+    async fn every_backend_diffs_the_accounts_changed_by_a_range_of_blocks() {
+        for_each_backend(|storage| async move { check_state_diff(storage.as_ref()).await }).await;
+    }
+
+    #[tokio::test]
+    async fn every_backend_keeps_the_state_root_of_each_block() {
+        for_each_backend(|storage| async move { check_state_root_history(storage.as_ref()).await })
+            .await;
+    }
+
+    #[tokio::test]
+    async fn every_backend_flushes_successfully() {
+        for_each_backend(|storage| async move { check_flush(storage.as_ref()).await }).await;
+    }
+
+    #[test]
+    fn receipts_stored_without_a_signer_public_key_are_still_read() {
+        let mut state = State::new(None).unwrap();
+        let txn = sign(&new_key(), 0, set_kv("key", "value"));
+        let (_, receipts) =
+            execute_block(&mut state, &config(), &mut RecentTxns::new(0), vec![txn]);
+        let receipt = receipts.into_iter().next().unwrap();
+        assert!(receipt.signer_public_key.is_some());
+
+        // bincode encodes a struct as the tuple of its fields.
+        let legacy = bincode::serialize(&(
+            &receipt.transaction,
+            receipt.transaction_hash,
+            receipt.status,
+            receipt.gas_used,
+            &receipt.state_updates,
+            &receipt.logs,
+        ))
+        .unwrap();
+        let decoded = decode_receipt(&legacy).unwrap();
+        assert_eq!(decoded.signer_public_key, None);
+        assert_eq!(decoded.transaction_hash, receipt.transaction_hash);
+        assert_eq!(decoded.logs, receipt.logs);
+        assert_eq!(decoded.state_updates, receipt.state_updates);
+
+        let current = bincode::serialize(&receipt).unwrap();
+        assert_eq!(
+            decode_receipt(&current).unwrap().signer_public_key,
+            receipt.signer_public_key
+        );
+        assert!(decode_receipt(b"garbage").is_err());
     }
 }
@@ -0,0 +1,138 @@
+//! Helpers shared by the unit tests: keys, signed transactions and blocks
+//! executed and committed the way the executor does it.
+
+use std::{net::SocketAddr, sync::Arc, time::Duration};
+
+use tokio::sync::watch;
+
+use crate::{
+    app::ServerApp, compute_transaction_hash, generate_keypair, receipt_replay_keys, txn_root,
+    AccountKey, Block, BlockHeader, Blockchain, ExecutorConfig, KvStoreTxPool, PayloadLimits,
+    PipelineExecutor, RecentTxns, State, Storage, TransactionBuilder, TransactionKind,
+    TransactionReceipt, TransactionWithAccount, TxnOrdering, CHECKPOINT_INTERVAL,
+};
+
+pub const CHAIN_ID: u64 = 1337;
+
+/// A fresh directory under the system temp dir for a test database.
+pub fn temp_dir() -> std::path::PathBuf {
+    std::env::temp_dir().join(format!("gravity-kvstore-test-{}", rand::random::<u64>()))
+}
+
+/// Config the helpers execute blocks with: new senders start with 1000.
+pub fn config() -> ExecutorConfig {
+    ExecutorConfig {
+        chain_id: CHAIN_ID,
+        default_balance: 1000,
+        replay_window: 10,
+        ..ExecutorConfig::default()
+    }
+}
+
+pub fn new_key() -> AccountKey {
+    AccountKey::from(generate_keypair())
+}
+
+pub fn set_kv(key: &str, value: &str) -> TransactionKind {
+    TransactionKind::SetKV {
+        key: key.to_string(),
+        value: value.to_string(),
+    }
+}
+
+pub fn sign(key: &AccountKey, nonce: u64, kind: TransactionKind) -> TransactionWithAccount {
+    TransactionBuilder::new(key, CHAIN_ID, nonce).build(kind)
+}
+
+/// Builds the block following `state`'s from `txns` and the state it results in.
+pub fn execute_block(
+    state: &mut State,
+    config: &ExecutorConfig,
+    recent_txns: &mut RecentTxns,
+    txns: Vec<TransactionWithAccount>,
+) -> (Block, Vec<TransactionReceipt>) {
+    let number = state.get_current_block_number() + 1;
+    let parent_state_root = state.get_state_root().0;
+    let receipts =
+        PipelineExecutor::apply_transactions(&txns, number, state, config, recent_txns, 1);
+    recent_txns.record(number, receipt_replay_keys(&receipts));
+    state.set_current_block_number(number);
+    let hashes = txns
+        .iter()
+        .map(|tx| compute_transaction_hash(&tx.txn.unsigned))
+        .collect::<Vec<_>>();
+    let block = Block {
+        header: BlockHeader {
+            number,
+            parent_state_root,
+            state_root: state.get_state_root().0,
+            usecs: number,
+            txn_root: txn_root(&hashes),
+        },
+        transactions: txns,
+    };
+    (block, receipts)
+}
+
+/// Executes the block following `state`'s and commits it to `storage`, with a
+/// checkpoint every `CHECKPOINT_INTERVAL` blocks. Returns its receipts.
+pub async fn commit_block(
+    storage: &dyn Storage,
+    state: &mut State,
+    config: &ExecutorConfig,
+    recent_txns: &mut RecentTxns,
+    txns: Vec<TransactionWithAccount>,
+) -> Vec<TransactionReceipt> {
+    let (block, receipts) = execute_block(state, config, recent_txns, txns);
+    let checkpoint = (block.header.number % CHECKPOINT_INTERVAL == 0).then(|| state.checkpoint());
+    storage
+        .commit_block_atomic(
+            &block,
+            &receipts,
+            state.get_state_root(),
+            checkpoint.as_ref(),
+        )
+        .await
+        .unwrap();
+    receipts
+}
+
+pub fn mempool() -> KvStoreTxPool {
+    KvStoreTxPool::new(PayloadLimits::default(), None, None, TxnOrdering::default())
+}
+
+/// Starts a `ServerApp` over `blockchain` and `mempool` on a free local port
+/// and waits until it accepts connections. Returns its base URL. The server
+/// stops once the returned sender is dropped.
+pub async fn start_server(
+    blockchain: Arc<Blockchain>,
+    mempool: KvStoreTxPool,
+) -> (String, watch::Sender<bool>) {
+    start_app(ServerApp::new(
+        blockchain,
+        mempool,
+        false,
+        Duration::from_secs(60),
+        None,
+    ))
+    .await
+}
+
+/// Like `start_server`, for a `ServerApp` built by the caller.
+pub async fn start_app(server: ServerApp) -> (String, watch::Sender<bool>) {
+    let addr: SocketAddr = std::net::TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap();
+    let (shutdown_sender, shutdown) = watch::channel(false);
+    tokio::spawn(async move {
+        server.start(addr, shutdown).await.unwrap();
+    });
+    for _ in 0..100 {
+        if tokio::net::TcpStream::connect(addr).await.is_ok() {
+            return (format!("http://{}", addr), shutdown_sender);
+        }
+        tokio::time::sleep(Duration::from_millis(20)).await;
+    }
+    panic!("Server did not start on {}", addr);
+}
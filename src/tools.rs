@@ -0,0 +1,313 @@
+use std::{
+    error::Error,
+    fs::File,
+    io::{BufReader, BufWriter},
+    path::Path,
+};
+
+use serde_json::{json, Value};
+
+use crate::{
+    canonical_address,
+    cli::{GenesisCommand, InspectCommand, ToolCommand},
+    generate_genesis, public_key_to_address, write_genesis, AccountKey, SignatureScheme,
+    SledStorage, State, Storage, TransactionBuilder, TransactionKind,
+};
+
+/// Runs an offline tool to completion.
+pub async fn run(command: ToolCommand) -> Result<(), Box<dyn Error>> {
+    match command {
+        ToolCommand::Genesis(GenesisCommand::Init {
+            accounts,
+            balance,
+            out,
+        }) => {
+            let (genesis, keypairs) = generate_genesis(accounts, balance);
+            write_genesis(&out, &genesis)?;
+            println!(
+                "Wrote {} accounts with balance {} to {}",
+                accounts, balance, out
+            );
+            for keypair in keypairs {
+                println!(
+                    "{} {}",
+                    public_key_to_address(&keypair.public_key),
+                    hex::encode(keypair.secret_key.secret_bytes())
+                );
+            }
+            Ok(())
+        }
+        ToolCommand::Inspect { db_dir, command } => {
+            // Opening a missing directory would create an empty database.
+            if !Path::new(&db_dir).is_dir() {
+                return Err(format!("Database directory not found: {}", db_dir).into());
+            }
+            let storage = SledStorage::new(&db_dir)?;
+            let value = inspect(&storage, command).await?;
+            println!("{}", serde_json::to_string_pretty(&value)?);
+            Ok(())
+        }
+        ToolCommand::ExportState { db_dir, out } => {
+            if !Path::new(&db_dir).is_dir() {
+                return Err(format!("Database directory not found: {}", db_dir).into());
+            }
+            let storage = SledStorage::new(&db_dir)?;
+            let checkpoint = storage
+                .get_checkpoint()
+                .await?
+                .ok_or("No checkpoint stored; the state is only persisted in checkpoints")?;
+            let mut state = State::new(None)?;
+            state.restore_checkpoint(checkpoint);
+            let file =
+                File::create(&out).map_err(|e| format!("Failed to create {}: {}", out, e))?;
+            let accounts = state.export_ndjson(BufWriter::new(file))?;
+            println!(
+                "Exported {} accounts at block {} to {}",
+                accounts,
+                state.get_current_block_number(),
+                out
+            );
+            Ok(())
+        }
+        ToolCommand::Send {
+            url,
+            key,
+            ed25519,
+            chain_id,
+            nonce,
+            set,
+            transfer,
+        } => {
+            let scheme = if ed25519 {
+                SignatureScheme::Ed25519
+            } else {
+                SignatureScheme::Secp256k1
+            };
+            let key = AccountKey::from_hex(&key, scheme)?;
+            // clap guarantees exactly one of them, with two values.
+            let kind = match (set, transfer) {
+                (Some(set), _) => TransactionKind::SetKV {
+                    key: set[0].clone(),
+                    value: set[1].clone(),
+                },
+                (None, Some(transfer)) => TransactionKind::Transfer {
+                    receiver: canonical_address(&transfer[0])?,
+                    amount: transfer[1]
+                        .parse()
+                        .map_err(|e| format!("Invalid amount {}: {}", transfer[1], e))?,
+                },
+                (None, None) => return Err("Either --set or --transfer is required".into()),
+            };
+            let url = url.trim_end_matches('/');
+            let client = reqwest::Client::new();
+            let nonce = match nonce {
+                Some(nonce) => nonce,
+                None => committed_nonce(&client, url, &key.address()).await?,
+            };
+            let txn = TransactionBuilder::new(&key, chain_id, nonce).build(kind);
+            let response = client
+                .post(format!("{}/tx", url))
+                .json(&txn.txn)
+                .send()
+                .await?;
+            let status = response.status();
+            let body = response.text().await?;
+            if !status.is_success() {
+                return Err(format!("Node rejected the transaction ({}): {}", status, body).into());
+            }
+            let body: Value = serde_json::from_str(&body)?;
+            println!("{}", body["txn_hash"].as_str().unwrap_or_default());
+            Ok(())
+        }
+        ToolCommand::ImportState { db_dir, input } => {
+            let file =
+                File::open(&input).map_err(|e| format!("Failed to open {}: {}", input, e))?;
+            let state = State::import_ndjson(BufReader::new(file))?;
+            let storage = SledStorage::new(&db_dir)?;
+            if storage.get_checkpoint().await?.is_some()
+                || storage.get_block(0).await?.is_some()
+                || latest_block_number(&storage).await? > 0
+            {
+                return Err(format!("Database {} is not empty", db_dir).into());
+            }
+            let block_number = state.get_current_block_number();
+            // The root is stored with the checkpoint so the next block is checked
+            // against it when it is persisted.
+            storage.save_checkpoint(&state.checkpoint()).await?;
+            storage
+                .save_state_root(block_number, state.get_state_root().clone())
+                .await?;
+            storage.flush().await?;
+            println!(
+                "Imported state at block {} with root {} into {}",
+                block_number,
+                state.get_state_root().to_hex(),
+                db_dir
+            );
+            Ok(())
+        }
+    }
+}
+
+async fn inspect(
+    storage: &dyn Storage,
+    command: InspectCommand,
+) -> Result<serde_json::Value, Box<dyn Error>> {
+    match command {
+        InspectCommand::Latest => {
+            let block_number = latest_block_number(storage).await?;
+            Ok(json!({ "block_number": block_number }))
+        }
+        InspectCommand::Block { number } => match storage.get_block(number).await? {
+            Some(block) => Ok(serde_json::to_value(&block)?),
+            None => Err(format!("Block {} not found", number).into()),
+        },
+        InspectCommand::Account { address } => {
+            let address = canonical_address(&address)?;
+            let checkpoint = storage
+                .get_checkpoint()
+                .await?
+                .ok_or("No checkpoint stored; accounts are only persisted in checkpoints")?;
+            let account = checkpoint.accounts.get(&address).ok_or_else(|| {
+                format!(
+                    "Account {} not found at block {}",
+                    address, checkpoint.block_number
+                )
+            })?;
+            Ok(json!({
+                "address": address,
+                "block_number": checkpoint.block_number,
+                "account": account,
+            }))
+        }
+    }
+}
+
+/// Nonce of `address` committed on the node at `url`, or 0 for an account the
+/// node does not know yet.
+async fn committed_nonce(
+    client: &reqwest::Client,
+    url: &str,
+    address: &str,
+) -> Result<u64, Box<dyn Error>> {
+    let response = client
+        .post(format!("{}/get_account", url))
+        .json(address)
+        .send()
+        .await?;
+    if response.status() == reqwest::StatusCode::NOT_FOUND {
+        return Ok(0);
+    }
+    let response = response.error_for_status()?;
+    let account: Value = response.json().await?;
+    account["nonce"]
+        .as_u64()
+        .ok_or_else(|| format!("Unexpected get_account response: {}", account).into())
+}
+
+/// Number of the last persisted block, found by walking forward from the
+/// latest checkpoint, or 0 if no block is persisted.
+async fn latest_block_number(storage: &dyn Storage) -> Result<u64, String> {
+    let mut latest = match storage.get_checkpoint().await? {
+        Some(checkpoint) => checkpoint.block_number,
+        None => 0,
+    };
+    while storage.get_block(latest + 1).await?.is_some() {
+        latest += 1;
+    }
+    Ok(latest)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{compute_transaction_hash, test_utils::*, Blockchain, MemStorage, RecentTxns};
+    use std::sync::Arc;
+
+    #[tokio::test]
+    async fn inspect_reads_blocks_and_accounts_from_a_stopped_node_database() {
+        let db_dir = temp_dir();
+        let storage = SledStorage::new(&db_dir).unwrap();
+        let config = config();
+        let alice = new_key();
+        let mut state = State::new(None).unwrap();
+        let mut recent_txns = RecentTxns::new(0);
+        let txns = vec![sign(&alice, 0, set_kv("key", "value"))];
+        commit_block(&storage, &mut state, &config, &mut recent_txns, txns).await;
+        storage.save_checkpoint(&state.checkpoint()).await.unwrap();
+        let txns = vec![sign(&alice, 1, set_kv("key", "other"))];
+        commit_block(&storage, &mut state, &config, &mut recent_txns, txns).await;
+
+        // The latest block is found past the checkpoint, at block 1.
+        let latest = inspect(&storage, InspectCommand::Latest).await.unwrap();
+        assert_eq!(latest, json!({ "block_number": 2 }));
+        let block = inspect(&storage, InspectCommand::Block { number: 2 })
+            .await
+            .unwrap();
+        assert_eq!(block["header"]["number"], 2);
+        assert_eq!(block["transactions"].as_array().unwrap().len(), 1);
+        let account = inspect(
+            &storage,
+            InspectCommand::Account {
+                address: format!("0x{}", alice.address().to_uppercase()),
+            },
+        )
+        .await
+        .unwrap();
+        assert_eq!(account["address"], alice.address());
+        assert_eq!(account["block_number"], 1);
+        assert_eq!(account["account"]["nonce"], 1);
+        assert_eq!(account["account"]["kv_store"]["key"], "value");
+
+        assert!(inspect(&storage, InspectCommand::Block { number: 3 })
+            .await
+            .is_err());
+        let stranger = InspectCommand::Account {
+            address: new_key().address(),
+        };
+        assert!(inspect(&storage, stranger).await.is_err());
+        drop(storage);
+        std::fs::remove_dir_all(&db_dir).unwrap();
+
+        // A missing database is reported rather than created empty.
+        let missing = ToolCommand::Inspect {
+            db_dir: db_dir.to_str().unwrap().to_string(),
+            command: InspectCommand::Latest,
+        };
+        assert!(run(missing).await.is_err());
+        assert!(!db_dir.exists());
+    }
+
+    #[tokio::test]
+    async fn send_submits_a_signed_transaction_to_a_running_node() {
+        let blockchain =
+            Arc::new(Blockchain::new(Arc::new(MemStorage::new()), None, config()).unwrap());
+        let mempool = mempool();
+        let (url, _shutdown) = start_server(blockchain, mempool.clone()).await;
+        let key = new_key();
+        let send = |set, transfer| ToolCommand::Send {
+            url: url.clone(),
+            key: hex::encode(key.secret_bytes()),
+            ed25519: false,
+            chain_id: CHAIN_ID,
+            nonce: None,
+            set,
+            transfer,
+        };
+
+        run(send(
+            Some(vec!["key".to_string(), "value".to_string()]),
+            None,
+        ))
+        .await
+        .unwrap();
+        let expected = sign(&key, 0, set_kv("key", "value"));
+        let hash = compute_transaction_hash(&expected.txn.unsigned);
+        let (queued, _) = mempool.get_txn(&hash).unwrap();
+        assert_eq!(queued.address, key.address());
+
+        let transfer = vec!["not an address".to_string(), "10".to_string()];
+        assert!(run(send(None, Some(transfer))).await.is_err());
+        assert_eq!(mempool.len(), 1);
+    }
+}
@@ -2,12 +2,27 @@ use gravity_sdk::block_buffer_manager::TxPool;
 use gravity_sdk::gaptos::api_types::account::ExternalAccountAddress;
 use gravity_sdk::gaptos::api_types::u256_define::TxnHash;
 use gravity_sdk::gaptos::api_types::VerifiedTxn;
-use std::collections::{BTreeMap, HashMap};
-use std::ops::Deref;
-use std::sync::Arc;
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::ops::Bound;
+use std::sync::{Arc, Mutex, RwLock};
 use tracing::warn;
 
-use crate::{compute_transaction_hash, TransactionWithAccount};
+use crate::{
+    compute_gas, compute_transaction_hash, PayloadLimits, TransactionKindTag,
+    TransactionWithAccount, UnsignedTransaction,
+};
+
+/// Why the pool refused a transaction.
+#[derive(Debug, Clone, PartialEq, Eq, thiserror::Error)]
+pub enum TxnRejection {
+    /// A key or value, written or in the read set, is over the payload limits.
+    #[error("{0}")]
+    PayloadTooLarge(String),
+    /// The transaction could never be included as it is: its memo is over the
+    /// limit, or it alone uses more than the block gas limit.
+    #[error("{0}")]
+    Invalid(String),
+}
 
 #[derive(Clone, Debug, PartialEq)]
 pub enum TxnStatus {
@@ -15,6 +30,18 @@ pub enum TxnStatus {
     Waiting,
 }
 
+/// Order in which queued transactions of different accounts are proposed.
+/// Each account's transactions are always proposed in nonce order.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum TxnOrdering {
+    /// All of one account's transactions, then all of the next account's.
+    #[default]
+    Sequential,
+    /// One transaction of each account per pass, so an account with a deep
+    /// queue cannot fill a block on its own.
+    RoundRobin,
+}
+
 #[derive(Clone, Debug)]
 pub struct MempoolTxn {
     raw_txn: TransactionWithAccount,
@@ -24,46 +51,271 @@ pub struct MempoolTxn {
 #[derive(Clone)]
 pub struct KvStoreTxPool {
     mempool: Arc<MempoolInner>,
+    limits: PayloadLimits,
+    max_block_txns: Option<usize>,
+    block_gas_limit: Option<u64>,
+    ordering: TxnOrdering,
 }
 
 impl KvStoreTxPool {
-    pub fn new() -> Self {
+    /// Creates an empty pool rejecting transactions whose payload exceeds `limits`
+    /// and proposing at most `max_block_txns` transactions, using at most
+    /// `block_gas_limit` gas, per block, taken from the accounts in `ordering`.
+    pub fn new(
+        limits: PayloadLimits,
+        max_block_txns: Option<usize>,
+        block_gas_limit: Option<u64>,
+        ordering: TxnOrdering,
+    ) -> Self {
         KvStoreTxPool {
             mempool: MempoolInner::new(),
+            limits,
+            max_block_txns,
+            block_gas_limit,
+            ordering,
         }
     }
 
-    pub fn add_verified_txn(&self, txn: VerifiedTxn) -> TxnHash {
-        self.mempool.add_verified_txn(txn)
+    pub fn add_verified_txn(&self, txn: VerifiedTxn) -> Result<TxnHash, TxnRejection> {
+        let raw_txn = TransactionWithAccount::from(txn.clone());
+        self.check(&raw_txn.txn.unsigned)?;
+        Ok(self.mempool.add_verified_txn(txn))
     }
 
-    pub fn add_raw_txn(&self, raw_txn: TransactionWithAccount) -> TxnHash {
-        self.mempool.add_raw_txn(raw_txn)
+    pub fn add_raw_txn(&self, raw_txn: TransactionWithAccount) -> Result<TxnHash, TxnRejection> {
+        self.check(&raw_txn.txn.unsigned)?;
+        Ok(self.mempool.add_raw_txn(raw_txn))
+    }
+
+    /// Rejects transactions over the payload or memo limits, and those that
+    /// could never fit in a block because they alone use more than the block
+    /// gas limit.
+    fn check(&self, txn: &UnsignedTransaction) -> Result<(), TxnRejection> {
+        self.limits
+            .check(txn)
+            .map_err(TxnRejection::PayloadTooLarge)?;
+        self.limits.check_memo(txn).map_err(TxnRejection::Invalid)?;
+        if let Some(limit) = self.block_gas_limit {
+            let gas = compute_gas(&txn.kind);
+            if gas > limit {
+                return Err(TxnRejection::Invalid(format!(
+                    "Transaction uses {} gas, over the block gas limit of {}",
+                    gas, limit
+                )));
+            }
+        }
+        Ok(())
     }
 
     pub fn remove_txn(&self, sender: &ExternalAccountAddress, seq: u64) {
         self.mempool.remove_txn(sender, seq)
     }
+
+    /// The queued transaction with `hash`, and whether it is pending or still
+    /// waiting for an earlier nonce.
+    pub fn get_txn(&self, hash: &[u8; 32]) -> Option<(TransactionWithAccount, TxnStatus)> {
+        let pool = self.mempool.mempool.read().unwrap();
+        pool.values()
+            .flat_map(|txns| txns.values())
+            .find(|txn| compute_transaction_hash(&txn.raw_txn.txn.unsigned) == *hash)
+            .map(|txn| (txn.raw_txn.clone(), txn.status.clone()))
+    }
+
+    /// Drops queued transactions that can no longer be included in a block
+    /// numbered `next_block_number` or later, returning how many were dropped.
+    pub fn remove_expired(&self, next_block_number: u64) -> usize {
+        let is_live = |txn: &MempoolTxn| match txn.raw_txn.txn.unsigned.valid_until_block {
+            Some(valid_until) => valid_until >= next_block_number,
+            None => true,
+        };
+        let mut pool = self.mempool.mempool.write().unwrap();
+        let mut removed = 0;
+        for (_, txns) in pool.iter_mut() {
+            // Only queues holding an expired transaction are copied if shared.
+            if txns.values().all(is_live) {
+                continue;
+            }
+            let txns = Arc::make_mut(txns);
+            let before = txns.len();
+            txns.retain(|_, txn| is_live(txn));
+            removed += before - txns.len();
+        }
+        removed
+    }
+
+    /// Number of transactions queued across all accounts.
+    pub fn len(&self) -> usize {
+        let pool = self.mempool.mempool.read().unwrap();
+        pool.values().map(|txns| txns.len()).sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Next nonce to use for `account`, accounting for transactions still queued
+    /// in the mempool on top of the committed `state_nonce`.
+    pub fn next_nonce(&self, account: &ExternalAccountAddress, state_nonce: u64) -> u64 {
+        let pool = self.mempool.mempool.read().unwrap();
+        let highest_queued = pool
+            .get(account)
+            .and_then(|txns| txns.keys().next_back().cloned());
+        match highest_queued {
+            Some(seq) => state_nonce.max(seq + 1),
+            None => state_nonce,
+        }
+    }
+
+    /// Nonces queued for `account`, in order.
+    pub fn queued_nonces(&self, account: &ExternalAccountAddress) -> Vec<u64> {
+        let pool = self.mempool.mempool.read().unwrap();
+        pool.get(account)
+            .map(|txns| txns.keys().cloned().collect())
+            .unwrap_or_default()
+    }
+
+    /// Nonces missing between `account`'s water mark and its highest queued
+    /// nonce. Until they are sent, the transactions queued after them can never
+    /// become pending.
+    pub fn nonce_gaps(&self, account: &ExternalAccountAddress) -> Vec<u64> {
+        let pool = self.mempool.mempool.read().unwrap();
+        let water_mark = self.mempool.water_mark.lock().unwrap();
+        let txns = match pool.get(account) {
+            Some(txns) => txns,
+            None => return vec![],
+        };
+        let highest_queued = match txns.keys().next_back() {
+            Some(seq) => *seq,
+            None => return vec![],
+        };
+        let next_pending = water_mark.get(account).cloned().unwrap_or(0);
+        (next_pending..highest_queued)
+            .filter(|seq| !txns.contains_key(seq))
+            .collect()
+    }
+
+    /// Like `best_txns`, but only proposes transactions whose kind passes
+    /// `kind_filter`, e.g. to build a block of transfers only. An account's
+    /// transactions are proposed up to its first one that does not pass, so
+    /// proposed nonces never skip one.
+    pub fn best_txns_by_kind(
+        &self,
+        kind_filter: impl Fn(TransactionKindTag) -> bool + 'static,
+        filter: Option<Box<dyn Fn((ExternalAccountAddress, u64, TxnHash)) -> bool>>,
+    ) -> Box<dyn Iterator<Item = VerifiedTxn>> {
+        self.select_txns(Arc::new(kind_filter), filter)
+    }
+
+    fn select_txns(
+        &self,
+        kind_filter: Arc<dyn Fn(TransactionKindTag) -> bool>,
+        filter: Option<Box<dyn Fn((ExternalAccountAddress, u64, TxnHash)) -> bool>>,
+    ) -> Box<dyn Iterator<Item = VerifiedTxn>> {
+        // The pool is a persistent map, so this snapshot is taken in constant
+        // time and adds made while proposing do not wait for it.
+        let txns = self.mempool.mempool.read().unwrap().clone();
+        let filter = Arc::new(filter);
+
+        let per_account = txns.into_iter().map(move |(addr, txns)| {
+            let addr_clone = addr.clone();
+            let filter_clone = filter.clone();
+            let kind_filter = kind_filter.clone();
+            let account_txns = iter_shared(txns)
+                .take_while(move |(_, txn)| kind_filter(txn.raw_txn.txn.unsigned.kind_tag()))
+                .filter_map(move |(seq, txn)| {
+                    let gas = compute_gas(&txn.raw_txn.txn.unsigned.kind);
+                    let verified_txn = txn.raw_txn.clone().into_verified();
+                    if let Some(filter) = filter_clone.as_ref() {
+                        if !filter((
+                            addr_clone.clone(),
+                            seq,
+                            TxnHash::new(verified_txn.committed_hash()),
+                        )) {
+                            return None;
+                        }
+                    }
+                    tracing::info!(
+                        "sending txn: sender {:?} nonce {:?}",
+                        verified_txn.sender(),
+                        verified_txn.seq_number()
+                    );
+                    Some((gas, verified_txn))
+                });
+            Box::new(account_txns) as Box<dyn Iterator<Item = (u64, VerifiedTxn)>>
+        });
+        let res: Box<dyn Iterator<Item = (u64, VerifiedTxn)>> = match self.ordering {
+            TxnOrdering::Sequential => Box::new(per_account.flatten()),
+            TxnOrdering::RoundRobin => Box::new(round_robin(per_account.collect())),
+        };
+        // The block ends before the first transaction that would go over the
+        // gas limit; it and those after it stay queued for the next block.
+        let mut gas_used = 0u64;
+        let block_gas_limit = self.block_gas_limit.unwrap_or(u64::MAX);
+        let res = res
+            .take_while(move |(gas, _)| {
+                gas_used = gas_used.saturating_add(*gas);
+                gas_used <= block_gas_limit
+            })
+            .map(|(_, txn)| txn);
+        match self.max_block_txns {
+            Some(max) => Box::new(res.take(max)),
+            None => Box::new(res),
+        }
+    }
 }
 
+/// Takes one item from each iterator in turn, dropping those that run out.
+fn round_robin<T>(iters: VecDeque<Box<dyn Iterator<Item = T>>>) -> impl Iterator<Item = T> {
+    let mut queue = iters;
+    std::iter::from_fn(move || {
+        while let Some(mut iter) = queue.pop_front() {
+            if let Some(item) = iter.next() {
+                queue.push_back(iter);
+                return Some(item);
+            }
+        }
+        None
+    })
+}
+
+/// Iterates `txns` in nonce order, cloning each transaction only once it is
+/// reached, so proposing a few transactions does not copy the whole queue.
+fn iter_shared(txns: AccountTxns) -> impl Iterator<Item = (u64, MempoolTxn)> {
+    let mut last = None;
+    std::iter::from_fn(move || {
+        let (seq, txn) = match last {
+            None => txns.iter().next()?,
+            Some(last) => txns
+                .range((Bound::Excluded(last), Bound::Unbounded))
+                .next()?,
+        };
+        last = Some(*seq);
+        Some((*seq, txn.clone()))
+    })
+}
+
+/// Transactions queued for one account, by nonce. A queue is shared with the
+/// snapshots proposers iterate, and only copied when written while shared.
+type AccountTxns = Arc<BTreeMap<u64, MempoolTxn>>;
+
 struct MempoolInner {
-    water_mark: std::sync::Mutex<HashMap<ExternalAccountAddress, u64>>, // next pending sequence number
-    mempool: std::sync::Mutex<HashMap<ExternalAccountAddress, BTreeMap<u64, MempoolTxn>>>,
+    water_mark: Mutex<HashMap<ExternalAccountAddress, u64>>, // next pending sequence number
+    mempool: RwLock<im::HashMap<ExternalAccountAddress, AccountTxns>>,
 }
 
 impl MempoolInner {
     fn new() -> Arc<Self> {
         Arc::new(MempoolInner {
-            water_mark: std::sync::Mutex::new(HashMap::new()),
-            mempool: std::sync::Mutex::new(HashMap::new()),
+            water_mark: Mutex::new(HashMap::new()),
+            mempool: RwLock::new(im::HashMap::new()),
         })
     }
 
     pub fn remove_txn(&self, sender: &ExternalAccountAddress, seq: u64) {
-        let mut pool = self.mempool.lock().unwrap();
+        let mut pool = self.mempool.write().unwrap();
         match pool.get_mut(sender) {
             Some(sender_txns) => {
-                sender_txns.remove(&seq);
+                Arc::make_mut(sender_txns).remove(&seq);
             }
             None => {
                 warn!("might be follower");
@@ -79,12 +331,11 @@ impl MempoolInner {
             raw_txn: txn.into(),
             status,
         };
-        self.mempool
-            .lock()
-            .unwrap()
-            .entry(account.clone())
-            .or_insert(BTreeMap::new())
-            .insert(sequence_number, mempool_txn);
+        {
+            let mut pool = self.mempool.write().unwrap();
+            Arc::make_mut(pool.entry(account.clone()).or_insert_with(Default::default))
+                .insert(sequence_number, mempool_txn);
+        }
         self.process_txn(account);
         TxnHash::random()
     }
@@ -96,11 +347,8 @@ impl MempoolInner {
         let txn_hash = TxnHash::from_bytes(&compute_transaction_hash(&raw_txn.txn.unsigned));
         let txn = MempoolTxn { raw_txn, status };
         {
-            self.mempool
-                .lock()
-                .unwrap()
-                .entry(account.clone())
-                .or_insert(BTreeMap::new())
+            let mut pool = self.mempool.write().unwrap();
+            Arc::make_mut(pool.entry(account.clone()).or_insert_with(Default::default))
                 .insert(sequence_number, txn);
         }
         self.process_txn(account);
@@ -108,9 +356,9 @@ impl MempoolInner {
     }
 
     pub fn process_txn(&self, account: ExternalAccountAddress) {
-        let mut mempool = self.mempool.lock().unwrap();
+        let mut mempool = self.mempool.write().unwrap();
         let mut water_mark = self.water_mark.lock().unwrap();
-        let account_mempool = mempool.get_mut(&account).unwrap();
+        let account_mempool = Arc::make_mut(mempool.get_mut(&account).unwrap());
         let sequence_number = water_mark.entry(account).or_insert(0);
         for txn in account_mempool.values_mut() {
             if txn.raw_txn.sequence_number() == *sequence_number {
@@ -126,31 +374,241 @@ impl TxPool for KvStoreTxPool {
         &self,
         filter: Option<Box<dyn Fn((ExternalAccountAddress, u64, TxnHash)) -> bool>>,
     ) -> Box<dyn Iterator<Item = VerifiedTxn>> {
-        let txns = { (*self.mempool.mempool.lock().unwrap().deref()).clone() };
-        let filter = Arc::new(filter);
+        self.select_txns(Arc::new(|_: TransactionKindTag| true), filter)
+    }
+}
 
-        let res = Box::new(txns.into_iter().flat_map(move |(addr, txns)| {
-            let addr_clone = addr.clone();
-            let filter_clone = filter.clone();
-            txns.into_iter().filter_map(move |(seq, txn)| {
-                let verified_txn = txn.raw_txn.clone().into_verified();
-                if let Some(filter) = filter_clone.as_ref() {
-                    if !filter((
-                        addr_clone.clone(),
-                        seq,
-                        TxnHash::new(verified_txn.committed_hash()),
-                    )) {
-                        return None;
-                    }
-                }
-                tracing::info!(
-                    "sending txn: sender {:?} nonce {:?}",
-                    verified_txn.sender(),
-                    verified_txn.seq_number()
-                );
-                Some(verified_txn)
-            })
-        }));
-        res
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{external_account_address, test_utils::*, TransactionKind, GAS_BASE, GAS_PER_BYTE};
+
+    /// A pool proposing at most `block_gas_limit` gas per block.
+    fn gas_limited_pool(block_gas_limit: u64) -> KvStoreTxPool {
+        KvStoreTxPool::new(
+            PayloadLimits::default(),
+            None,
+            Some(block_gas_limit),
+            TxnOrdering::default(),
+        )
+    }
+
+    #[test]
+    fn proposal_stops_before_the_transaction_over_the_gas_limit() {
+        let gas = compute_gas(&set_kv("key", "value"));
+        let pool = gas_limited_pool(2 * gas + gas / 2);
+        let alice = new_key();
+        for nonce in 0..3 {
+            pool.add_raw_txn(sign(&alice, nonce, set_kv("key", "value")))
+                .unwrap();
+        }
+
+        let proposed = pool.best_txns(None).collect::<Vec<_>>();
+        assert_eq!(proposed.len(), 2);
+        assert_eq!(proposed[1].seq_number(), 1);
+        // The third transaction stays queued for the next block.
+        assert_eq!(pool.len(), 3);
+    }
+
+    #[test]
+    fn kind_filter_proposes_each_account_up_to_its_first_other_kind() {
+        let pool = mempool();
+        let (alice, bob) = (new_key(), new_key());
+        let transfer = || TransactionKind::Transfer {
+            receiver: new_key().address(),
+            amount: 1,
+        };
+        let txns = vec![
+            sign(&alice, 0, transfer()),
+            sign(&alice, 1, transfer()),
+            sign(&alice, 2, set_kv("key", "value")),
+            sign(&alice, 3, transfer()),
+            sign(&bob, 0, set_kv("key", "value")),
+            sign(&bob, 1, transfer()),
+        ];
+        for txn in txns {
+            pool.add_raw_txn(txn).unwrap();
+        }
+
+        let proposed = pool
+            .best_txns_by_kind(|tag| tag == TransactionKindTag::Transfer, None)
+            .map(|txn| (txn.sender().clone(), txn.seq_number()))
+            .collect::<Vec<_>>();
+        let alice_account = external_account_address(&alice.address()).unwrap();
+        assert_eq!(proposed, [(alice_account.clone(), 0), (alice_account, 1)]);
+        assert_eq!(
+            pool.best_txns_by_kind(|_| true, None).count(),
+            pool.best_txns(None).count()
+        );
+        assert_eq!(pool.len(), 6);
+    }
+
+    #[test]
+    fn round_robin_takes_one_transaction_per_account_each_pass() {
+        let pool = KvStoreTxPool::new(
+            PayloadLimits::default(),
+            None,
+            None,
+            TxnOrdering::RoundRobin,
+        );
+        let (alice, bob, carol) = (new_key(), new_key(), new_key());
+        for (key, count) in [(&alice, 3), (&bob, 1), (&carol, 2)] {
+            for nonce in 0..count {
+                pool.add_raw_txn(sign(key, nonce, set_kv("key", "value")))
+                    .unwrap();
+            }
+        }
+
+        // Accounts come in the pool's order, but every pass visits each
+        // account that still has transactions once, in nonce order.
+        let proposed = pool
+            .best_txns(None)
+            .map(|txn| (txn.sender().clone(), txn.seq_number()))
+            .collect::<Vec<_>>();
+        let nonces = proposed.iter().map(|(_, nonce)| *nonce).collect::<Vec<_>>();
+        assert_eq!(nonces, [0, 0, 0, 1, 1, 2]);
+        let senders = |pass: &[(ExternalAccountAddress, u64)]| {
+            pass.iter()
+                .map(|(sender, _)| sender.clone())
+                .collect::<std::collections::HashSet<_>>()
+        };
+        assert_eq!(senders(&proposed[..3]).len(), 3);
+        assert_eq!(senders(&proposed[3..5]).len(), 2);
+        let alice_account = external_account_address(&alice.address()).unwrap();
+        let bob_account = external_account_address(&bob.address()).unwrap();
+        assert!(!senders(&proposed[3..]).contains(&bob_account));
+        assert_eq!(proposed[5].0, alice_account);
+
+        // A block cut short still holds one transaction of every account.
+        let pool = KvStoreTxPool {
+            max_block_txns: Some(3),
+            ..pool
+        };
+        assert_eq!(
+            senders(
+                &pool
+                    .best_txns(None)
+                    .map(|txn| (txn.sender().clone(), txn.seq_number()))
+                    .collect::<Vec<_>>()
+            )
+            .len(),
+            3
+        );
+    }
+
+    #[test]
+    fn adds_do_not_wait_for_a_proposal_in_progress() {
+        let pool = mempool();
+        let alice = new_key();
+        for nonce in 0..100 {
+            pool.add_raw_txn(sign(&alice, nonce, set_kv("key", "value")))
+                .unwrap();
+        }
+        let mut proposal = pool.best_txns(None);
+        assert_eq!(proposal.next().unwrap().seq_number(), 0);
+
+        // Adding from another thread while the proposal is half read, both to
+        // the account being proposed and to a new one.
+        let (done, finished) = std::sync::mpsc::channel();
+        let adder = pool.clone();
+        let bob = new_key();
+        let added = vec![
+            sign(&alice, 100, set_kv("key", "value")),
+            sign(&bob, 0, set_kv("key", "value")),
+        ];
+        std::thread::spawn(move || {
+            for txn in added {
+                adder.add_raw_txn(txn).unwrap();
+            }
+            done.send(()).unwrap();
+        });
+        finished
+            .recv_timeout(std::time::Duration::from_secs(5))
+            .expect("adding blocked on the proposal");
+
+        // The proposal keeps the snapshot it started from.
+        assert_eq!(proposal.count(), 99);
+        assert_eq!(pool.len(), 102);
+        assert_eq!(pool.best_txns(None).count(), 102);
+    }
+
+    #[test]
+    fn transaction_over_the_gas_limit_alone_is_rejected_as_invalid() {
+        let pool = gas_limited_pool(GAS_BASE + 10 * GAS_PER_BYTE);
+        let alice = new_key();
+
+        pool.add_raw_txn(sign(&alice, 0, set_kv("key", "1234567")))
+            .unwrap();
+        assert!(matches!(
+            pool.add_raw_txn(sign(&alice, 1, set_kv("key", "12345678"))),
+            Err(TxnRejection::Invalid(_))
+        ));
+    }
+
+    #[test]
+    fn oversized_payloads_and_memos_are_rejected_differently() {
+        let pool = mempool();
+        let alice = new_key();
+        let value = "v".repeat(PayloadLimits::default().max_value_bytes + 1);
+        assert!(matches!(
+            pool.add_raw_txn(sign(&alice, 0, set_kv("key", &value))),
+            Err(TxnRejection::PayloadTooLarge(_))
+        ));
+
+        let memo = "m".repeat(PayloadLimits::default().max_memo_bytes + 1);
+        let txn = crate::crypto::TransactionBuilder::new(&alice, CHAIN_ID, 0)
+            .memo(memo)
+            .build(set_kv("key", "value"));
+        assert!(matches!(
+            pool.add_raw_txn(txn),
+            Err(TxnRejection::Invalid(_))
+        ));
+        assert_eq!(pool.len(), 0);
+    }
+
+    #[test]
+    fn next_nonce_follows_the_highest_queued_transaction() {
+        let pool = mempool();
+        let alice = new_key();
+        let account = external_account_address(&alice.address()).unwrap();
+        assert_eq!(pool.next_nonce(&account, 0), 0);
+        assert_eq!(pool.next_nonce(&account, 4), 4);
+
+        for nonce in 0..3 {
+            pool.add_raw_txn(sign(&alice, nonce, set_kv("key", &nonce.to_string())))
+                .unwrap();
+        }
+        assert_eq!(pool.next_nonce(&account, 0), 3);
+        // Queued transactions below the committed nonce are already executed.
+        assert_eq!(pool.next_nonce(&account, 5), 5);
+
+        // A gap does not lower it: the next nonce follows the highest queued.
+        pool.add_raw_txn(sign(&alice, 7, set_kv("key", "7")))
+            .unwrap();
+        assert_eq!(pool.next_nonce(&account, 0), 8);
+
+        let bob = external_account_address(&new_key().address()).unwrap();
+        assert_eq!(pool.next_nonce(&bob, 2), 2);
+    }
+
+    #[test]
+    fn nonce_gaps_lists_the_missing_nonces_below_the_highest_queued() {
+        let pool = mempool();
+        let alice = new_key();
+        let account = external_account_address(&alice.address()).unwrap();
+        assert!(pool.nonce_gaps(&account).is_empty());
+
+        for nonce in [0, 1, 3, 6] {
+            pool.add_raw_txn(sign(&alice, nonce, set_kv("key", &nonce.to_string())))
+                .unwrap();
+        }
+        assert_eq!(pool.nonce_gaps(&account), [2, 4, 5]);
+
+        pool.add_raw_txn(sign(&alice, 2, set_kv("key", "2")))
+            .unwrap();
+        assert_eq!(pool.nonce_gaps(&account), [4, 5]);
+
+        let bob = external_account_address(&new_key().address()).unwrap();
+        assert!(pool.nonce_gaps(&bob).is_empty());
     }
 }
@@ -7,9 +7,12 @@ use gravity_sdk::gaptos::api_types::{
     VerifiedTxn,
 };
 use serde::{Deserialize, Serialize};
+use sha3::{Digest, Keccak256};
+
+use crate::verify_signature;
 use std::hash::Hash;
 use std::{
-    collections::{HashMap, HashSet},
+    collections::{BTreeMap, BTreeSet, HashMap, HashSet},
     hash::{DefaultHasher, Hasher},
 };
 #[derive(Debug, Clone, Hash, Eq, PartialEq, Serialize, Deserialize)]
@@ -19,19 +22,299 @@ pub struct AccountId(pub String);
 pub struct Account {
     pub balance: u64,
     pub nonce: u64,
-    pub kv_store: HashMap<String, String>,
+    pub kv_store: BTreeMap<String, String>,
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub enum TransactionKind {
-    Transfer { receiver: String, amount: u64 },
-    SetKV { key: String, value: String },
+    Transfer {
+        receiver: String,
+        amount: u64,
+    },
+    SetKV {
+        key: String,
+        value: String,
+    },
+    /// Like `SetKV`, but the key reads as absent from block `expires_at_block`
+    /// on and is then removed.
+    SetKVTtl {
+        key: String,
+        value: String,
+        expires_at_block: u64,
+    },
+    /// Replaces the sender's whole store with `entries`, for instance ones
+    /// exported from `/account/{addr}/export`. Keys must be unique.
+    RestoreKV {
+        entries: Vec<(String, String)>,
+    },
+    /// Adds `delta` to the integer stored at `key`, or to 0 if the key is
+    /// absent. Fails if the value is not an `i64` or the sum overflows.
+    AddKV {
+        key: String,
+        delta: i64,
+    },
+    /// Credits `amount` to `receiver` out of thin air. Only the configured
+    /// faucet address may send it.
+    Mint {
+        receiver: String,
+        amount: u64,
+    },
+    /// Like `SetKV`, but records that `value` holds bytes in `encoding`, so
+    /// readers can decode it. Fails if `value` is not valid in that encoding.
+    SetKVEncoded {
+        key: String,
+        value: String,
+        encoding: ValueEncoding,
+    },
+    /// Like `SetKV`, but writes into `target`'s store. Fails unless `target`
+    /// granted the sender write access with `GrantWrite`.
+    SetKVFor {
+        target: String,
+        key: String,
+        value: String,
+    },
+    /// Allows `grantee` to write into the sender's store with `SetKVFor`.
+    GrantWrite {
+        grantee: String,
+    },
+    /// Withdraws a write access given with `GrantWrite`.
+    RevokeWrite {
+        grantee: String,
+    },
+}
+
+/// How a stored value is to be read. Values are UTF-8 text unless they were
+/// set with `SetKVEncoded` and another encoding.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+pub enum ValueEncoding {
+    #[default]
+    Utf8,
+    Base64,
+    Hex,
+}
+
+impl ValueEncoding {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ValueEncoding::Utf8 => "utf8",
+            ValueEncoding::Base64 => "base64",
+            ValueEncoding::Hex => "hex",
+        }
+    }
+
+    /// Decodes `value` into the bytes it encodes.
+    pub fn decode(&self, value: &str) -> Result<Vec<u8>, String> {
+        use base64::Engine;
+        match self {
+            ValueEncoding::Utf8 => Ok(value.as_bytes().to_vec()),
+            ValueEncoding::Base64 => base64::engine::general_purpose::STANDARD
+                .decode(value)
+                .map_err(|e| format!("Invalid base64 value: {}", e)),
+            ValueEncoding::Hex => {
+                hex::decode(value).map_err(|e| format!("Invalid hex value: {}", e))
+            }
+        }
+    }
+}
+
+impl std::str::FromStr for ValueEncoding {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "utf8" => Ok(ValueEncoding::Utf8),
+            "base64" => Ok(ValueEncoding::Base64),
+            "hex" => Ok(ValueEncoding::Hex),
+            _ => Err(format!(
+                "Unknown encoding {}, expected utf8, base64 or hex",
+                s
+            )),
+        }
+    }
+}
+
+/// Variant of a `TransactionKind` without its payload, cheap to copy and
+/// compare when filtering or counting transactions.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum TransactionKindTag {
+    Transfer,
+    SetKV,
+    SetKVTtl,
+    RestoreKV,
+    AddKV,
+    Mint,
+    SetKVEncoded,
+    SetKVFor,
+    GrantWrite,
+    RevokeWrite,
+}
+
+impl TransactionKindTag {
+    pub const ALL: [TransactionKindTag; 10] = [
+        TransactionKindTag::Transfer,
+        TransactionKindTag::SetKV,
+        TransactionKindTag::SetKVTtl,
+        TransactionKindTag::RestoreKV,
+        TransactionKindTag::AddKV,
+        TransactionKindTag::Mint,
+        TransactionKindTag::SetKVEncoded,
+        TransactionKindTag::SetKVFor,
+        TransactionKindTag::GrantWrite,
+        TransactionKindTag::RevokeWrite,
+    ];
+
+    /// Name of the kind as it appears in metric labels.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            TransactionKindTag::Transfer => "transfer",
+            TransactionKindTag::SetKV => "set_kv",
+            TransactionKindTag::SetKVTtl => "set_kv_ttl",
+            TransactionKindTag::RestoreKV => "restore_kv",
+            TransactionKindTag::AddKV => "add_kv",
+            TransactionKindTag::Mint => "mint",
+            TransactionKindTag::SetKVEncoded => "set_kv_encoded",
+            TransactionKindTag::SetKVFor => "set_kv_for",
+            TransactionKindTag::GrantWrite => "grant_write",
+            TransactionKindTag::RevokeWrite => "revoke_write",
+        }
+    }
+}
+
+impl TransactionKind {
+    pub fn tag(&self) -> TransactionKindTag {
+        match self {
+            TransactionKind::Transfer { .. } => TransactionKindTag::Transfer,
+            TransactionKind::SetKV { .. } => TransactionKindTag::SetKV,
+            TransactionKind::SetKVTtl { .. } => TransactionKindTag::SetKVTtl,
+            TransactionKind::RestoreKV { .. } => TransactionKindTag::RestoreKV,
+            TransactionKind::AddKV { .. } => TransactionKindTag::AddKV,
+            TransactionKind::Mint { .. } => TransactionKindTag::Mint,
+            TransactionKind::SetKVEncoded { .. } => TransactionKindTag::SetKVEncoded,
+            TransactionKind::SetKVFor { .. } => TransactionKindTag::SetKVFor,
+            TransactionKind::GrantWrite { .. } => TransactionKindTag::GrantWrite,
+            TransactionKind::RevokeWrite { .. } => TransactionKindTag::RevokeWrite,
+        }
+    }
+}
+
+/// Algorithm a transaction is signed with. It is part of the signed payload, so
+/// a signature cannot be reinterpreted under the other scheme.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum SignatureScheme {
+    /// 65-byte recoverable ECDSA signature, `r || s || v` with `v` of 27 or 28.
+    #[default]
+    Secp256k1,
+    /// 32-byte public key followed by the 64-byte signature.
+    Ed25519,
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct UnsignedTransaction {
+    /// Chain the transaction is meant for, so it cannot be replayed on another deployment.
+    pub chain_id: u64,
     pub nonce: u64,
     pub kind: TransactionKind,
+    /// Keys of the sender's store and the values they must hold for the
+    /// transaction to take effect. It fails if any of them differs or is absent.
+    #[serde(default)]
+    pub read_set: Vec<(String, String)>,
+    #[serde(default)]
+    pub scheme: SignatureScheme,
+    /// Last block the transaction may be included in. It fails in any later
+    /// block, and is dropped from the mempool once that block is committed.
+    #[serde(default)]
+    pub valid_until_block: Option<u64>,
+    /// Free-form note for off-chain correlation. It is signed and hashed with
+    /// the transaction and kept in its receipt, but never affects execution.
+    #[serde(default)]
+    pub memo: Option<String>,
+}
+
+impl UnsignedTransaction {
+    pub fn kind_tag(&self) -> TransactionKindTag {
+        self.kind.tag()
+    }
+}
+
+/// Largest key and value a transaction may carry, checked when it enters the
+/// mempool and again when it is executed.
+#[derive(Debug, Clone, Copy)]
+pub struct PayloadLimits {
+    pub max_key_bytes: usize,
+    pub max_value_bytes: usize,
+    /// Longest memo, checked only when the transaction enters the mempool.
+    pub max_memo_bytes: usize,
+}
+
+impl Default for PayloadLimits {
+    fn default() -> Self {
+        Self {
+            max_key_bytes: 1024,
+            max_value_bytes: 64 * 1024,
+            max_memo_bytes: 256,
+        }
+    }
+}
+
+impl PayloadLimits {
+    /// Checks every key and value `txn` writes or reads against the limits.
+    pub fn check(&self, txn: &UnsignedTransaction) -> Result<(), String> {
+        let mut pairs = txn
+            .read_set
+            .iter()
+            .map(|(key, value)| (key.as_str(), Some(value.as_str())))
+            .collect::<Vec<_>>();
+        match &txn.kind {
+            TransactionKind::Transfer { .. }
+            | TransactionKind::Mint { .. }
+            | TransactionKind::GrantWrite { .. }
+            | TransactionKind::RevokeWrite { .. } => {}
+            TransactionKind::SetKV { key, value }
+            | TransactionKind::SetKVTtl { key, value, .. }
+            | TransactionKind::SetKVEncoded { key, value, .. }
+            | TransactionKind::SetKVFor { key, value, .. } => {
+                pairs.push((key, Some(value)));
+            }
+            TransactionKind::RestoreKV { entries } => {
+                pairs.extend(
+                    entries
+                        .iter()
+                        .map(|(key, value)| (key.as_str(), Some(value.as_str()))),
+                );
+            }
+            TransactionKind::AddKV { key, .. } => pairs.push((key, None)),
+        }
+        for (key, value) in pairs {
+            if key.len() > self.max_key_bytes {
+                return Err(format!(
+                    "Key of {} bytes exceeds the limit of {} bytes",
+                    key.len(),
+                    self.max_key_bytes
+                ));
+            }
+            if let Some(value) = value.filter(|value| value.len() > self.max_value_bytes) {
+                return Err(format!(
+                    "Value of {} bytes exceeds the limit of {} bytes",
+                    value.len(),
+                    self.max_value_bytes
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    /// Checks the memo of `txn` against the limit. Unlike `check`, this is not
+    /// repeated at execution, so a memo can never make a transaction fail.
+    pub fn check_memo(&self, txn: &UnsignedTransaction) -> Result<(), String> {
+        match &txn.memo {
+            Some(memo) if memo.len() > self.max_memo_bytes => Err(format!(
+                "Memo of {} bytes exceeds the limit of {} bytes",
+                memo.len(),
+                self.max_memo_bytes
+            )),
+            _ => Ok(()),
+        }
+    }
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
@@ -40,6 +323,21 @@ pub struct Transaction {
     pub signature: String,
 }
 
+impl Transaction {
+    /// Encodes the transaction in its binary wire format: the canonical BCS
+    /// encoding of the struct, accepted by `/tx` as `application/x-bcs`.
+    /// Decoding it gives back the same transaction, so the same hash.
+    pub fn to_wire_bytes(&self) -> Vec<u8> {
+        bcs::to_bytes(self).expect("a transaction is within the BCS size limits")
+    }
+
+    /// Decodes a transaction encoded with `to_wire_bytes`. Non-canonical or
+    /// trailing bytes are rejected.
+    pub fn from_wire_bytes(bytes: &[u8]) -> Result<Self, String> {
+        bcs::from_bytes(bytes).map_err(|e| format!("Invalid transaction bytes: {}", e))
+    }
+}
+
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct TransactionWithAccount {
     pub txn: Transaction,
@@ -65,7 +363,7 @@ impl From<VerifiedTxn> for TransactionWithAccount {
 // }
 
 fn convert_account(acc: &str) -> Result<[u8; 32], String> {
-    let acc_bytes = hex::decode(acc).unwrap();
+    let acc_bytes = hex::decode(acc).map_err(|e| format!("Invalid hex string: {}", e))?;
 
     if acc_bytes.len() != 20 {
         return Err(format!(
@@ -80,7 +378,18 @@ fn convert_account(acc: &str) -> Result<[u8; 32], String> {
     Ok(bytes)
 }
 
+/// Account address as used by the mempool and consensus, from a hex account id.
+pub fn external_account_address(address: &str) -> Result<ExternalAccountAddress, String> {
+    convert_account(address).map(ExternalAccountAddress::new)
+}
+
 impl TransactionWithAccount {
+    /// Wraps `txn` with the sender recovered from its signature.
+    pub fn from_signed(txn: Transaction) -> Result<Self, String> {
+        let address = verify_signature(&txn)?.0;
+        Ok(Self { txn, address })
+    }
+
     pub fn from_bytes(bytes: Vec<u8>) -> Self {
         let txn: TransactionWithAccount = serde_json::from_slice(&bytes).unwrap();
         txn
@@ -120,6 +429,17 @@ pub struct BlockHeader {
     pub parent_state_root: [u8; 32],
     pub state_root: [u8; 32],
     pub usecs: u64,
+    /// Merkle root over the hashes of the block's transactions, in block order.
+    pub txn_root: [u8; 32],
+}
+
+impl BlockHeader {
+    /// Keccak-256 hash of the encoded header, identifying the block. It covers
+    /// every header field, so it changes whenever any of them does.
+    pub fn hash(&self) -> [u8; 32] {
+        let encoded = bincode::serialize(self).expect("Failed to serialize block header");
+        Keccak256::digest(encoded).into()
+    }
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -140,6 +460,36 @@ pub struct Block {
     pub transactions: Vec<TransactionWithAccount>,
 }
 
+/// Summary of a committed block, published to block subscribers.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct BlockSummary {
+    pub block_number: u64,
+    pub state_root: String,
+    pub timestamp: u64,
+    pub txn_count: usize,
+}
+
+impl From<&Block> for BlockSummary {
+    fn from(block: &Block) -> Self {
+        Self {
+            block_number: block.header.number,
+            state_root: hex::encode(block.header.state_root),
+            timestamp: block.header.usecs,
+            txn_count: block.transactions.len(),
+        }
+    }
+}
+
+/// Fields logged when a block is persisted.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct BlockCommitLog {
+    pub block_number: u64,
+    pub state_root: String,
+    pub txn_count: usize,
+    pub gas_used: u64,
+    pub commit_latency_ms: u64,
+}
+
 #[derive(Debug, Clone)]
 pub struct BlockExecutionResult {
     pub block_number: u64,
@@ -154,21 +504,107 @@ pub struct TransactionReceipt {
     pub status: bool,
     pub gas_used: u64,
     pub state_updates: Vec<(AccountId, AccountState)>,
-    pub logs: Vec<Log>,
+    pub logs: Vec<ReceiptLog>,
+    /// Hex of the public key that signed the transaction: the compressed key for
+    /// secp256k1, the key itself for ed25519. Absent from older receipts.
+    #[serde(default)]
+    pub signer_public_key: Option<String>,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
-pub struct Log {
-    pub address: String,
-    pub topics: Vec<[u8; 32]>,
-    pub data: Vec<u8>,
+impl TransactionReceipt {
+    /// The account that signed the transaction. Other senders may have
+    /// committed a transaction with the same `transaction_hash`.
+    pub fn sender(&self) -> Result<AccountId, String> {
+        verify_signature(&self.transaction)
+    }
+}
+
+/// A state change made by a transaction, in the order it was applied. A failed
+/// transaction only logs the creation of its sender.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub enum ReceiptLog {
+    /// `address` was not in the state before this transaction.
+    AccountCreated {
+        address: String,
+    },
+    /// `key` of the sender's store was written. `old_value` is `None` for a new key.
+    KvSet {
+        key: String,
+        old_value: Option<String>,
+        new_value: String,
+    },
+    /// `key` of `target`'s store was written by a `SetKVFor`.
+    KvSetFor {
+        target: String,
+        key: String,
+        old_value: Option<String>,
+        new_value: String,
+    },
+    /// A `SetKV` wrote the value `key` already held, so only `GAS_NOOP_WRITE` was charged.
+    NoOpWrite {
+        key: String,
+    },
+    /// The sender's store was replaced by a `RestoreKV` holding `key_count` keys.
+    KvRestored {
+        key_count: usize,
+    },
+    Transfer {
+        from: String,
+        to: String,
+        amount: u64,
+    },
+    Mint {
+        to: String,
+        amount: u64,
+    },
+    /// `grantee` was allowed to write into the sender's store.
+    WriteGranted {
+        grantee: String,
+    },
+    /// `grantee` may no longer write into the sender's store.
+    WriteRevoked {
+        grantee: String,
+    },
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize, Eq, PartialEq)]
 pub struct AccountState {
     pub nonce: u64,
     pub balance: u64,
-    pub kv_store: HashMap<String, String>,
+    pub kv_store: BTreeMap<String, String>,
+    /// Block from which each key set with `SetKVTtl` is expired. Keys without
+    /// an entry never expire.
+    #[serde(default)]
+    pub expiries: BTreeMap<String, u64>,
+    /// Block of the last `Mint` this account received while a faucet cooldown
+    /// was configured.
+    #[serde(default)]
+    pub last_faucet_block: Option<u64>,
+    /// Encoding of each value set with `SetKVEncoded` in other than UTF-8.
+    /// Values without an entry are UTF-8.
+    #[serde(default)]
+    pub encodings: BTreeMap<String, ValueEncoding>,
+    /// Accounts allowed to write into this store with `SetKVFor`.
+    #[serde(default)]
+    pub writers: BTreeSet<String>,
+}
+
+impl AccountState {
+    /// Removes the keys expired at `block_number`. Returns whether any was removed.
+    pub fn purge_expired(&mut self, block_number: u64) -> bool {
+        let expired = self
+            .expiries
+            .iter()
+            .filter(|(_, expires_at)| **expires_at <= block_number)
+            .map(|(key, _)| key.clone())
+            .collect::<Vec<_>>();
+        for key in &expired {
+            self.expiries.remove(key);
+            self.encodings.remove(key);
+            self.kv_store.remove(key);
+        }
+        !expired.is_empty()
+    }
 }
 
 impl Hash for AccountState {
@@ -179,6 +615,61 @@ impl Hash for AccountState {
             k.hash(state);
             v.hash(state);
         });
+        self.expiries.iter().for_each(|(k, expires_at)| {
+            k.hash(state);
+            expires_at.hash(state);
+        });
+        self.last_faucet_block.hash(state);
+        self.encodings.iter().for_each(|(k, encoding)| {
+            k.hash(state);
+            encoding.hash(state);
+        });
+        self.writers.iter().for_each(|writer| writer.hash(state));
+    }
+}
+
+/// How an account changed over a range of blocks.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AccountDiff {
+    pub address: String,
+    /// The account before the range, or `None` if no earlier change is stored,
+    /// e.g. because it was created in the range or only exists in the genesis file.
+    pub before: Option<AccountState>,
+    pub after: AccountState,
+    /// Keys whose value differs between `before` and `after`, sorted.
+    pub changed_keys: Vec<KeyDiff>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct KeyDiff {
+    pub key: String,
+    pub before: Option<String>,
+    pub after: Option<String>,
+}
+
+impl AccountDiff {
+    pub fn new(address: String, before: Option<AccountState>, after: AccountState) -> Self {
+        let empty = BTreeMap::new();
+        let before_kv = before.as_ref().map_or(&empty, |account| &account.kv_store);
+        let keys = before_kv
+            .keys()
+            .chain(after.kv_store.keys())
+            .collect::<BTreeSet<_>>();
+        let changed_keys = keys
+            .into_iter()
+            .filter(|key| before_kv.get(*key) != after.kv_store.get(*key))
+            .map(|key| KeyDiff {
+                key: key.clone(),
+                before: before_kv.get(key).cloned(),
+                after: after.kv_store.get(key).cloned(),
+            })
+            .collect();
+        Self {
+            address,
+            before,
+            after,
+            changed_keys,
+        }
     }
 }
 
@@ -198,6 +689,15 @@ pub struct TransactionWithLocation {
 #[derive(Debug, Clone, Serialize, Deserialize, Default)]
 pub struct StateRoot(pub [u8; 32]);
 
+/// Full copy of the account state as of `block_number`, persisted so that older
+/// blocks can be pruned without losing the ability to recover.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct StateCheckpoint {
+    pub block_number: u64,
+    pub state_root: StateRoot,
+    pub accounts: HashMap<String, AccountState>,
+}
+
 impl StateRoot {
     pub fn to_hex(&self) -> String {
         hex::encode(self.0)
@@ -213,3 +713,222 @@ impl StateRoot {
         Self(state_root)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{compute_transaction_hash, recover_signer, test_utils::*, TransactionBuilder};
+
+    #[test]
+    fn every_kind_maps_to_its_own_tag() {
+        let text = || "text".to_string();
+        let kinds = [
+            TransactionKind::Transfer {
+                receiver: text(),
+                amount: 1,
+            },
+            set_kv("key", "value"),
+            TransactionKind::SetKVTtl {
+                key: text(),
+                value: text(),
+                expires_at_block: 1,
+            },
+            TransactionKind::RestoreKV { entries: vec![] },
+            TransactionKind::AddKV {
+                key: text(),
+                delta: 1,
+            },
+            TransactionKind::Mint {
+                receiver: text(),
+                amount: 1,
+            },
+            TransactionKind::SetKVEncoded {
+                key: text(),
+                value: text(),
+                encoding: ValueEncoding::Hex,
+            },
+            TransactionKind::SetKVFor {
+                target: text(),
+                key: text(),
+                value: text(),
+            },
+            TransactionKind::GrantWrite { grantee: text() },
+            TransactionKind::RevokeWrite { grantee: text() },
+        ];
+        let tags = kinds.iter().map(TransactionKind::tag).collect::<Vec<_>>();
+        // Metrics index their per-kind counters by `tag as usize`.
+        assert_eq!(tags, TransactionKindTag::ALL);
+        for (index, tag) in TransactionKindTag::ALL.into_iter().enumerate() {
+            assert_eq!(tag as usize, index);
+        }
+        let names = TransactionKindTag::ALL
+            .iter()
+            .map(TransactionKindTag::as_str)
+            .collect::<BTreeSet<_>>();
+        assert_eq!(names.len(), TransactionKindTag::ALL.len());
+
+        let txn = sign(&new_key(), 0, kinds[4].clone()).txn;
+        assert_eq!(txn.unsigned.kind_tag(), TransactionKindTag::AddKV);
+    }
+
+    #[test]
+    fn header_hash_and_txn_root_detect_tampering() {
+        let (alice, bob) = (new_key(), new_key());
+        let mut state = crate::State::new(None).unwrap();
+        let mut recent_txns = crate::RecentTxns::new(0);
+        let txns = vec![
+            sign(&alice, 0, set_kv("key", "value")),
+            sign(&bob, 0, set_kv("key", "value")),
+        ];
+        let (block, _) = execute_block(&mut state, &config(), &mut recent_txns, txns);
+        let recomputed_root = |txns: &[TransactionWithAccount]| {
+            let hashes = txns
+                .iter()
+                .map(|txn| compute_transaction_hash(&txn.txn.unsigned))
+                .collect::<Vec<_>>();
+            crate::txn_root(&hashes)
+        };
+
+        // A verifier recomputes the same root and hash from the block alone.
+        assert_eq!(recomputed_root(&block.transactions), block.header.txn_root);
+        let hash = block.header.hash();
+        assert_eq!(block.header.clone().hash(), hash);
+
+        // Changing, reordering or dropping a transaction changes the root.
+        let mut tampered = block.transactions.clone();
+        tampered[0].txn.unsigned.nonce += 1;
+        assert_ne!(recomputed_root(&tampered), block.header.txn_root);
+        let mut reordered = block.transactions.clone();
+        reordered.reverse();
+        assert_ne!(recomputed_root(&reordered), block.header.txn_root);
+        assert_ne!(
+            recomputed_root(&block.transactions[..1]),
+            block.header.txn_root
+        );
+
+        // Every header field is covered by the hash.
+        let tamperings: [fn(&mut BlockHeader); 5] = [
+            |header| header.number += 1,
+            |header| header.parent_state_root[0] ^= 1,
+            |header| header.state_root[0] ^= 1,
+            |header| header.usecs += 1,
+            |header| header.txn_root[31] ^= 1,
+        ];
+        for tamper in tamperings {
+            let mut header = block.header.clone();
+            tamper(&mut header);
+            assert_ne!(header.hash(), hash);
+        }
+    }
+
+    #[test]
+    fn wire_bytes_round_trip_to_the_same_transaction() {
+        let key = new_key();
+        let txn = TransactionBuilder::new(&key, CHAIN_ID, 7)
+            .read_set(vec![("key".to_string(), "value".to_string())])
+            .valid_until_block(100)
+            .memo("order 42".to_string())
+            .build(TransactionKind::SetKVEncoded {
+                key: "bytes".to_string(),
+                value: "00ff".to_string(),
+                encoding: ValueEncoding::Hex,
+            })
+            .txn;
+
+        let decoded = Transaction::from_wire_bytes(&txn.to_wire_bytes()).unwrap();
+        assert_eq!(
+            serde_json::to_value(&decoded).unwrap(),
+            serde_json::to_value(&txn).unwrap()
+        );
+        assert_eq!(
+            compute_transaction_hash(&decoded.unsigned),
+            compute_transaction_hash(&txn.unsigned)
+        );
+        assert_eq!(recover_signer(&decoded).unwrap().address.0, key.address());
+        assert_eq!(decoded.to_wire_bytes(), txn.to_wire_bytes());
+    }
+
+    #[test]
+    fn payload_limits_allow_exactly_the_limit_and_reject_one_byte_over() {
+        let limits = PayloadLimits {
+            max_key_bytes: 8,
+            max_value_bytes: 16,
+            max_memo_bytes: 4,
+        };
+        let key = new_key();
+        let unsigned = |kind: TransactionKind, read_set: Vec<(String, String)>| {
+            TransactionBuilder::new(&key, CHAIN_ID, 0)
+                .read_set(read_set)
+                .build(kind)
+                .txn
+                .unsigned
+        };
+        let check = |key_len: usize, value_len: usize| {
+            limits.check(&unsigned(
+                set_kv(&"k".repeat(key_len), &"v".repeat(value_len)),
+                vec![],
+            ))
+        };
+
+        assert_eq!(check(8, 16), Ok(()));
+        assert_eq!(
+            check(9, 16),
+            Err("Key of 9 bytes exceeds the limit of 8 bytes".to_string())
+        );
+        assert_eq!(
+            check(8, 17),
+            Err("Value of 17 bytes exceeds the limit of 16 bytes".to_string())
+        );
+
+        // Read sets, restored entries and counter keys count too.
+        let read_set = |value_len: usize| vec![("k".to_string(), "v".repeat(value_len))];
+        assert!(limits
+            .check(&unsigned(set_kv("k", "v"), read_set(16)))
+            .is_ok());
+        assert!(limits
+            .check(&unsigned(set_kv("k", "v"), read_set(17)))
+            .is_err());
+        let restore = |value_len: usize| TransactionKind::RestoreKV {
+            entries: vec![
+                ("a".to_string(), "v".to_string()),
+                ("b".to_string(), "v".repeat(value_len)),
+            ],
+        };
+        assert!(limits.check(&unsigned(restore(16), vec![])).is_ok());
+        assert!(limits.check(&unsigned(restore(17), vec![])).is_err());
+        let add = |key_len: usize| TransactionKind::AddKV {
+            key: "k".repeat(key_len),
+            delta: 1,
+        };
+        assert!(limits.check(&unsigned(add(8), vec![])).is_ok());
+        assert!(limits.check(&unsigned(add(9), vec![])).is_err());
+
+        let memo = |len: usize| {
+            TransactionBuilder::new(&key, CHAIN_ID, 0)
+                .memo("m".repeat(len))
+                .build(set_kv("k", "v"))
+                .txn
+                .unsigned
+        };
+        assert_eq!(limits.check_memo(&memo(4)), Ok(()));
+        assert_eq!(
+            limits.check_memo(&memo(5)),
+            Err("Memo of 5 bytes exceeds the limit of 4 bytes".to_string())
+        );
+        // The memo is not a payload.
+        assert_eq!(limits.check(&memo(5)), Ok(()));
+    }
+
+    #[test]
+    fn truncated_or_padded_wire_bytes_are_rejected() {
+        let bytes = sign(&new_key(), 0, set_kv("key", "value"))
+            .txn
+            .to_wire_bytes();
+
+        assert!(Transaction::from_wire_bytes(&bytes[..bytes.len() - 1]).is_err());
+        let mut padded = bytes.clone();
+        padded.push(0);
+        assert!(Transaction::from_wire_bytes(&padded).is_err());
+        assert!(Transaction::from_wire_bytes(&[]).is_err());
+    }
+}